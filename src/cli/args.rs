@@ -1,8 +1,12 @@
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use clap::{Args, Parser, Subcommand, ValueEnum};
 use log::LevelFilter;
 
+use crate::lang::Role;
+use crate::lang::interpreter::Fallback;
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum EnumerableLevelFilter {
     Off,
@@ -26,6 +30,21 @@ impl From<EnumerableLevelFilter> for LevelFilter {
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum EnumerableRole {
+    Client,
+    Server,
+}
+
+impl From<EnumerableRole> for Role {
+    fn from(value: EnumerableRole) -> Self {
+        match value {
+            EnumerableRole::Client => Role::Client,
+            EnumerableRole::Server => Role::Server,
+        }
+    }
+}
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 /// Proteus: establish network communication tunnels using programmable protocols.
@@ -65,7 +84,32 @@ pub enum Command {
 }
 
 #[derive(Args)]
-pub struct SocksArgs {}
+pub struct SocksArgs {
+    /// The path to a specification file that defines the protocol to use
+    #[arg(required = true)]
+    pub protocol: PathBuf,
+    /// Run the client end (accepts SOCKS5 app connections) or the server end
+    /// (accepts proteus connections and dials the requested destinations).
+    #[arg(short, long, value_name = "ROLE", display_order = 2)]
+    pub role: EnumerableRole,
+    /// The local address on which to listen for incoming connections.
+    #[arg(long, value_name = "ADDR", display_order = 3)]
+    pub listen: SocketAddr,
+    /// The address of the proteus server to tunnel through (client role only).
+    #[arg(
+        long,
+        value_name = "ADDR",
+        required_if_eq("role", "client"),
+        display_order = 4
+    )]
+    pub remote: Option<SocketAddr>,
+    /// What the server does with connections from peers that fail to
+    /// authenticate, replay a message, or send fields that do not match the
+    /// protocol: 'close' them, 'tarpit' them by reading until the peer closes,
+    /// or splice them to a decoy with 'decoy:<ADDR>'.
+    #[arg(long, value_name = "POLICY", default_value_t, display_order = 5)]
+    pub fallback: Fallback,
+}
 
 #[derive(Args)]
 pub struct PtArgs {}
@@ -1,16 +1,19 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::{io, process};
 
+use anyhow::anyhow;
 use control::PtLogLevel;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, OnceCell};
 
 use super::args::PtArgs;
 use crate::cli::pt::config::{
     ClientConfig, CommonConfig, Config, ConfigError, ForwardProtocol, Mode, ServerConfig,
 };
 use crate::lang::Role;
-use crate::lang::compiler::Compiler;
-use crate::lang::interpreter::Interpreter;
+use crate::lang::compiler::{Compiler, TaskGraphImpl};
+use crate::lang::interpreter::{Fallback, Interpreter, ProbeDefense};
 use crate::lang::ir::bridge::{OldCompile, TaskProvider};
 use crate::net::proto::socks;
 use crate::net::{Connection, TcpConnector};
@@ -18,6 +21,12 @@ use crate::net::{Connection, TcpConnector};
 pub mod config;
 pub mod control;
 
+/// Client specs by the path of the PSF they were compiled from. Tor passes the
+/// path with every connection, but compiling derives the key from the password,
+/// which is deliberately slow, so we compile each path only once. Each path has
+/// its own cell, so that compiling one PSF does not hold up the others.
+type ClientSpecs = Arc<Mutex<HashMap<String, Arc<OnceCell<TaskGraphImpl>>>>>;
+
 pub async fn run(_args: PtArgs) -> anyhow::Result<()> {
     log::info!("Running in pt mode");
 
@@ -95,16 +104,48 @@ async fn run_client(_common_conf: CommonConfig, client_conf: ClientConfig) -> io
     );
     control::send_to_parent(control::Message::Status("BOOTSTRAPPED=Success"));
 
+    let specs = ClientSpecs::default();
+
     // Main loop waiting for connections from reverse socks5 clients.
     loop {
         let (rvs_stream, _) = listener.accept().await?;
         let conf = client_conf.clone();
+        let specs = specs.clone();
         // A failure in a connection does not stop the server.
-        tokio::spawn(async move { handle_client_connection(rvs_stream, conf).await });
+        tokio::spawn(async move { handle_client_connection(rvs_stream, conf, specs).await });
     }
 }
 
-async fn handle_client_connection(rvs_stream: TcpStream, _conf: ClientConfig) -> io::Result<()> {
+/// Returns the client spec for the PSF at `filepath`, which we compile the
+/// first time we see the path. Connections that ask for the same path while we
+/// compile it wait for that compilation instead of starting their own.
+async fn load_client_spec(specs: &ClientSpecs, filepath: &str) -> anyhow::Result<TaskGraphImpl> {
+    let cell = specs
+        .lock()
+        .await
+        .entry(filepath.to_string())
+        .or_default()
+        .clone();
+    let spec = cell
+        .get_or_try_init(|| {
+            let filepath = filepath.to_string();
+            async move {
+                // Compiling blocks while it derives the key, so keep it off the
+                // runtime's worker threads.
+                tokio::task::spawn_blocking(move || Compiler::parse_path(&filepath, Role::Client))
+                    .await
+                    .map_err(|e| anyhow!("Compiling the client spec panicked: {}", e))?
+            }
+        })
+        .await?;
+    Ok(spec.clone())
+}
+
+async fn handle_client_connection(
+    rvs_stream: TcpStream,
+    _conf: ClientConfig,
+    specs: ClientSpecs,
+) -> io::Result<()> {
     let rvs_addr = rvs_stream.peer_addr()?;
     log::debug!("Accepted new stream from client {}", rvs_addr);
 
@@ -130,10 +171,15 @@ async fn handle_client_connection(rvs_stream: TcpStream, _conf: ClientConfig) ->
                 None => HashMap::new(),
             };
 
-            // TODO double check, I think the PSF path can change for every Tor
-            // Browser connection, so we have to parse the PSF here on every connection.
+            // The PSF path may change for every Tor Browser connection.
             let filepath = options.get("psf").unwrap();
-            let client_spec = Compiler::parse_path(filepath, Role::Client).unwrap();
+            let client_spec = match load_client_spec(&specs, filepath).await {
+                Ok(spec) => spec,
+                Err(e) => {
+                    log::debug!("Failed to compile the PSF at {}: {}", filepath, e);
+                    return Ok(());
+                }
+            };
 
             log::debug!(
                 "Running Proteus client protocol to forward data from {}",
@@ -141,7 +187,7 @@ async fn handle_client_connection(rvs_stream: TcpStream, _conf: ClientConfig) ->
             );
 
             // Run the proteus protocol with the interpreter.
-            match Interpreter::run(pt_conn, rvs_conn, client_spec, options).await {
+            match Interpreter::run(pt_conn, rvs_conn, client_spec, options, None).await {
                 Ok(_) => log::debug!("Stream from peer {} succeeded Proteus protocol", rvs_addr),
                 Err(e) => log::debug!(
                     "Stream from peer {} failed during Proteus protocol: {}",
@@ -182,19 +228,35 @@ async fn run_server(_common_conf: CommonConfig, server_conf: ServerConfig) -> io
     let filepath = server_conf.options.get("psf").unwrap();
     let server_spec = Compiler::parse_path(filepath, Role::Server).unwrap();
 
+    // What we do with connections from peers that fail to authenticate.
+    let fallback = match server_conf.options.get("fallback") {
+        Some(value) => match value.parse::<Fallback>() {
+            Ok(fallback) => fallback,
+            Err(e) => {
+                control::send_to_parent(control::Message::ServerError("invalid fallback option"));
+                return Err(io::Error::other(e));
+            }
+        },
+        None => Fallback::default(),
+    };
+
     log::info!(
         "Proteus server listening for Proteus client connections on {:?}.",
         listener.local_addr()?
     );
     control::send_to_parent(control::Message::Status("BOOTSTRAPPED=Success"));
 
+    // Shared by all connections so we can detect messages replayed across them.
+    let defense = ProbeDefense::new(fallback);
+
     // Main loop waiting for connections from proteus proxy clients.
     loop {
         let (pt_stream, _) = listener.accept().await?;
         let conf = server_conf.clone();
         let spec = server_spec.clone();
+        let defense = defense.clone();
         // A failure in a connection does not stop the server.
-        tokio::spawn(async move { handle_server_connection(pt_stream, conf, spec).await });
+        tokio::spawn(async move { handle_server_connection(pt_stream, conf, spec, defense).await });
     }
 }
 
@@ -202,6 +264,7 @@ async fn handle_server_connection<T>(
     pt_stream: TcpStream,
     conf: ServerConfig,
     spec: T,
+    defense: ProbeDefense,
 ) -> io::Result<()>
 where
     T: TaskProvider + Clone + Send,
@@ -241,7 +304,7 @@ where
     );
 
     // Run the proteus protocol with the interpreter.
-    match Interpreter::run(pt_conn, fwd_conn, spec, conf.options).await {
+    match Interpreter::run(pt_conn, fwd_conn, spec, conf.options, Some(defense)).await {
         Ok(_) => log::debug!("Stream from peer {} succeeded Proteus protocol", pt_addr),
         Err(e) => log::debug!(
             "Stream from peer {} failed during Proteus protocol: {}",
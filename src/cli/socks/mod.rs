@@ -1,6 +1,328 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, anyhow, bail};
+use async_trait::async_trait;
+use tokio::io::DuplexStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+
 use super::args::SocksArgs;
+use crate::lang::Role;
+use crate::lang::compiler::Compiler;
+use crate::lang::interpreter::{Interpreter, ProbeDefense};
+use crate::lang::ir::bridge::{OldCompile, TaskProvider};
+use crate::net::proto::socks;
+use crate::net::{BufReader, Connection, Connector, TcpConnector, forward};
+
+/// Capacity of the in-memory pipes we place between the interpreter and the
+/// app or destination streams.
+const PIPE_NBYTES: usize = 2usize.pow(16u32); // 64 KiB
+
+type TcpConnection = Connection<BufReader<OwnedReadHalf>, OwnedWriteHalf>;
+type PipeConnection = Connection<BufReader<DuplexStream>, DuplexStream>;
 
-pub async fn run(_args: SocksArgs) -> anyhow::Result<()> {
+pub async fn run(args: SocksArgs) -> anyhow::Result<()> {
     log::info!("Running in socks mode");
-    unimplemented!()
+
+    let role = Role::from(args.role);
+    let spec = Compiler::parse_path(&args.protocol.to_string_lossy(), role)
+        .with_context(|| format!("compiling protocol specification {:?}", args.protocol))?;
+
+    let listener = TcpListener::bind(args.listen)
+        .await
+        .with_context(|| format!("listening on {}", args.listen))?;
+
+    match role {
+        // Unwrap OK: clap requires the remote address in the client role.
+        Role::Client => run_client(listener, args.remote.unwrap(), spec).await,
+        Role::Server => run_server(listener, spec, ProbeDefense::new(args.fallback)).await,
+    }
+}
+
+async fn run_client<T>(
+    listener: TcpListener,
+    server_addr: SocketAddr,
+    spec: T,
+) -> anyhow::Result<()>
+where
+    T: TaskProvider + Clone + Send + 'static,
+{
+    log::info!(
+        "Proteus client listening for SOCKS5 app connections on {:?}, tunneling through {}.",
+        listener.local_addr()?,
+        server_addr
+    );
+
+    // Main loop waiting for connections from socks5 clients.
+    loop {
+        let (app_stream, _) = listener.accept().await?;
+        let spec = spec.clone();
+        // A failure in a connection does not stop the client.
+        tokio::spawn(async move { handle_client_connection(app_stream, server_addr, spec).await });
+    }
+}
+
+async fn handle_client_connection<T>(app_stream: TcpStream, server_addr: SocketAddr, spec: T)
+where
+    T: TaskProvider + Clone + Send,
+{
+    let Ok(app_addr) = app_stream.peer_addr() else {
+        return;
+    };
+    log::debug!("Accepted new stream from client {}", app_addr);
+
+    let connector = TunnelConnector::new(server_addr);
+
+    let (app_conn, net_conn) =
+        match socks::run_socks5_server(Connection::from(app_stream), connector.clone()).await {
+            Ok((app_conn, net_conn, _)) => (app_conn, net_conn),
+            Err(e) => {
+                log::debug!(
+                    "Stream from peer {} failed during Socks5 protocol: {}",
+                    app_addr,
+                    e
+                );
+                return;
+            }
+        };
+
+    let result = async {
+        let dest_addr = connector.take_destination()?;
+        log::debug!(
+            "Socks5 with peer {} succeeded, tunneling to destination {}",
+            app_addr,
+            dest_addr
+        );
+
+        // The destination is the first thing the server reads from the tunnel.
+        let (pipe_interp, mut pipe_proxy) = pipe_pair();
+        socks::send_tunnel_destination(&mut pipe_proxy.dst, dest_addr).await?;
+
+        let (app_src, app_dst) = app_conn.into_split();
+        let (pipe_src, pipe_dst) = pipe_proxy.into_split();
+
+        let (interp_result, _, _) = tokio::join!(
+            Interpreter::run(net_conn, pipe_interp, spec, HashMap::new(), None),
+            forward(app_src, pipe_dst),
+            forward(pipe_src, app_dst),
+        );
+        interp_result
+    }
+    .await;
+
+    match result {
+        Ok(_) => log::debug!("Stream from peer {} succeeded Proteus protocol", app_addr),
+        Err(e) => log::debug!(
+            "Stream from peer {} failed during Proteus protocol: {}",
+            app_addr,
+            e
+        ),
+    }
+}
+
+async fn run_server<T>(listener: TcpListener, spec: T, defense: ProbeDefense) -> anyhow::Result<()>
+where
+    T: TaskProvider + Clone + Send + 'static,
+{
+    log::info!(
+        "Proteus server listening for Proteus client connections on {:?}.",
+        listener.local_addr()?
+    );
+
+    // Main loop waiting for connections from proteus proxy clients.
+    loop {
+        let (net_stream, _) = listener.accept().await?;
+        let spec = spec.clone();
+        let defense = defense.clone();
+        // A failure in a connection does not stop the server.
+        tokio::spawn(async move { handle_server_connection(net_stream, spec, defense).await });
+    }
+}
+
+async fn handle_server_connection<T>(net_stream: TcpStream, spec: T, defense: ProbeDefense)
+where
+    T: TaskProvider + Clone + Send,
+{
+    let Ok(net_addr) = net_stream.peer_addr() else {
+        return;
+    };
+    log::debug!("Accepted new stream from Proteus client {}", net_addr);
+
+    let (pipe_interp, pipe_proxy) = pipe_pair();
+    let (mut pipe_src, pipe_dst) = pipe_proxy.into_split();
+
+    // If we fail to reach the destination, the pipe closes and so does the
+    // interpreter.
+    let forward_to_dest = async move {
+        let dest_addr = socks::recv_tunnel_destination(&mut pipe_src).await?;
+        let dest_stream = TcpStream::connect(dest_addr)
+            .await
+            .with_context(|| format!("connecting to destination {}", dest_addr))?;
+        log::debug!("Connected to destination {}", dest_addr);
+
+        let (dest_src, dest_dst) = Connection::from(dest_stream).into_split();
+        let (_, _) = tokio::join!(forward(pipe_src, dest_dst), forward(dest_src, pipe_dst));
+        anyhow::Ok(())
+    };
+
+    let (interp_result, dest_result) = tokio::join!(
+        Interpreter::run(
+            Connection::from(net_stream),
+            pipe_interp,
+            spec,
+            HashMap::new(),
+            Some(defense),
+        ),
+        forward_to_dest,
+    );
+
+    match interp_result.and(dest_result) {
+        Ok(_) => log::debug!("Stream from peer {} succeeded Proteus protocol", net_addr),
+        Err(e) => log::debug!(
+            "Stream from peer {} failed during Proteus protocol: {}",
+            net_addr,
+            e
+        ),
+    }
+}
+
+/// Creates a pair of connected in-memory connections, so that we can interpose
+/// on the bytes flowing between the interpreter and the other end.
+fn pipe_pair() -> (PipeConnection, PipeConnection) {
+    let (a_w, b_r) = tokio::io::duplex(PIPE_NBYTES);
+    let (b_w, a_r) = tokio::io::duplex(PIPE_NBYTES);
+    (
+        Connection::new(BufReader::new(a_r), a_w),
+        Connection::new(BufReader::new(b_r), b_w),
+    )
+}
+
+/// Connects socks5 requests to the proteus server instead of the requested
+/// destination, which we remember so we can later send it through the tunnel.
+#[derive(Clone)]
+struct TunnelConnector {
+    server_addr: SocketAddr,
+    dest_addr: Arc<Mutex<Option<SocketAddr>>>,
+}
+
+impl TunnelConnector {
+    fn new(server_addr: SocketAddr) -> Self {
+        Self {
+            server_addr,
+            dest_addr: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn take_destination(&self) -> anyhow::Result<SocketAddr> {
+        match self.dest_addr.lock() {
+            Ok(mut dest) => dest
+                .take()
+                .ok_or_else(|| anyhow!("No socks5 destination was requested")),
+            Err(e) => bail!("Destination mutex was poisoned: {}", e.to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl Connector<BufReader<OwnedReadHalf>, OwnedWriteHalf> for TunnelConnector {
+    async fn connect(&self, addr: SocketAddr) -> anyhow::Result<(TcpConnection, SocketAddr)> {
+        let (conn, local_addr) = TcpConnector::new().connect(self.server_addr).await?;
+        match self.dest_addr.lock() {
+            Ok(mut dest) => *dest = Some(addr),
+            Err(e) => bail!("Destination mutex was poisoned: {}", e.to_string()),
+        }
+        Ok((conn, local_addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    const PSF_PATH: &str = "tests/fixtures/shadowsocks.psf";
+
+    /// Runs a destination that echoes what it receives and returns its address.
+    async fn run_echo_destination() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut src, mut dst) = stream.split();
+                    let _ = tokio::io::copy(&mut src, &mut dst).await;
+                });
+            }
+        });
+        addr
+    }
+
+    /// Asks the socks5 proxy at `proxy_addr` for `dest_addr`, sends `message`,
+    /// and returns what comes back.
+    async fn socks5_roundtrip(
+        proxy_addr: SocketAddr,
+        dest_addr: SocketAddr,
+        message: &[u8],
+    ) -> Vec<u8> {
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+
+        stream.write_all(&[5, 1, 0]).await.unwrap();
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [5, 0]);
+
+        let IpAddr::V4(ip) = dest_addr.ip() else {
+            panic!("destination is not IPv4");
+        };
+        let mut request = vec![5, 1, 0, 1];
+        request.extend(ip.octets());
+        request.extend(dest_addr.port().to_be_bytes());
+        stream.write_all(&request).await.unwrap();
+        let mut response = [0u8; 10];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], 0);
+
+        stream.write_all(message).await.unwrap();
+        let mut echoed = vec![0u8; message.len()];
+        stream.read_exact(&mut echoed).await.unwrap();
+        echoed
+    }
+
+    #[tokio::test]
+    async fn repeat_connections_to_same_destination() {
+        let dest_addr = run_echo_destination().await;
+
+        let server_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server_listener.local_addr().unwrap();
+        let server_spec = Compiler::parse_path(PSF_PATH, Role::Server).unwrap();
+        tokio::spawn(run_server(
+            server_listener,
+            server_spec,
+            ProbeDefense::default(),
+        ));
+
+        let client_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client_listener.local_addr().unwrap();
+        let client_spec = Compiler::parse_path(PSF_PATH, Role::Client).unwrap();
+        tokio::spawn(run_client(client_listener, server_addr, client_spec));
+
+        // Each connection starts with the same destination frame, which the
+        // server must not take for a replay.
+        for _ in 0..2 {
+            let echoed = tokio::time::timeout(
+                Duration::from_secs(10),
+                socks5_roundtrip(client_addr, dest_addr, b"hello"),
+            )
+            .await
+            .expect("connection through the tunnel stalled");
+            assert_eq!(echoed, b"hello");
+        }
+    }
 }
@@ -106,6 +106,7 @@ async fn run_interpreter<T: TaskProvider + Clone + Send>(
         app_conn,
         protospec,
         HashMap::<String, String>::new(),
+        None,
     )
     .await
 }
@@ -0,0 +1,478 @@
+use aes::{Aes128, Aes256};
+use aes_gcm::{Aes128Gcm, Aes256Gcm};
+use anyhow::anyhow;
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use ctr::Ctr128BE;
+use salsa20::Salsa20;
+use salsa20::cipher::{KeyIvInit, StreamCipher};
+
+use crate::crypto::kdf::SessionKeys;
+
+const MAC_NBYTES: usize = 16;
+// The nonce sequence of each direction starts from a fixed value. This is safe
+// only because every connection encrypts under its own keys, which are derived
+// from a per-connection salt or an ephemeral key agreement.
+const NONCE_A: [u8; 8] = [0xAA; 8];
+const NONCE_B: [u8; 8] = [0xBB; 8];
+
+type Payload = Vec<u8>;
+type Mac = [u8; MAC_NBYTES];
+
+/// The AEAD algorithms we support for encrypting fields.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum CipherAlgorithm {
+    ChaCha20Poly1305,
+    Aes128Gcm,
+    Aes256Gcm,
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum CipherKind {
+    Sender,
+    Receiver,
+}
+
+pub struct Cipher {
+    encryptor: EncryptionCipher,
+    decryptor: DecryptionCipher,
+}
+
+pub struct EncryptionCipher {
+    inner: CipherInner,
+    kind: CipherKind,
+}
+
+pub struct DecryptionCipher {
+    inner: CipherInner,
+    kind: CipherKind,
+}
+
+struct CipherInner {
+    algorithm: CipherAlgorithm,
+    no_mac: Keystream,
+    nonce_gen: Keystream,
+    cipher: AeadCipher,
+    n_bytes_ciphered: usize,
+}
+
+/// The stream cipher we use for unauthenticated fields and nonce generation.
+/// We use the keystream that matches the AEAD algorithm, so that we do not
+/// need more key material than the algorithm itself.
+enum Keystream {
+    Salsa20(Salsa20),
+    Aes128Ctr(Box<Ctr128BE<Aes128>>),
+    Aes256Ctr(Box<Ctr128BE<Aes256>>),
+}
+
+enum AeadCipher {
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    Aes128Gcm(Box<Aes128Gcm>),
+    Aes256Gcm(Box<Aes256Gcm>),
+}
+
+impl Cipher {
+    pub fn new(
+        algorithm: CipherAlgorithm,
+        keys: &SessionKeys,
+        kind: CipherKind,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            encryptor: EncryptionCipher::new(algorithm, keys, kind)?,
+            decryptor: DecryptionCipher::new(algorithm, keys, kind)?,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> anyhow::Result<(Payload, Mac)> {
+        self.encryptor.encrypt(plaintext)
+    }
+
+    #[cfg(test)]
+    pub fn encrypt_unauth(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        self.encryptor.encrypt_unauth(plaintext)
+    }
+
+    #[cfg(test)]
+    pub fn decrypt(&mut self, ciphertext: &[u8], mac: &Mac) -> anyhow::Result<Vec<u8>> {
+        self.decryptor.decrypt(ciphertext, mac)
+    }
+
+    #[cfg(test)]
+    pub fn decrypt_unauth(&mut self, ciphertext: &[u8]) -> Vec<u8> {
+        self.decryptor.decrypt_unauth(ciphertext)
+    }
+
+    pub fn into_split(self) -> (EncryptionCipher, DecryptionCipher) {
+        (self.encryptor, self.decryptor)
+    }
+
+    #[allow(dead_code)]
+    pub fn reunite(encryptor: EncryptionCipher, decryptor: DecryptionCipher) -> Cipher {
+        Cipher {
+            encryptor,
+            decryptor,
+        }
+    }
+}
+
+impl Keystream {
+    fn new(algorithm: CipherAlgorithm, key: &[u8], nonce: [u8; 8]) -> anyhow::Result<Self> {
+        let invalid = |_| anyhow!("Invalid key length {} for {:?}", key.len(), algorithm);
+
+        // The AES counter block is 16 bytes, the nonce fills its upper half.
+        let mut iv = [0u8; 16];
+        iv[..nonce.len()].copy_from_slice(&nonce);
+
+        Ok(match algorithm {
+            CipherAlgorithm::ChaCha20Poly1305 => {
+                Keystream::Salsa20(Salsa20::new_from_slices(key, &nonce).map_err(invalid)?)
+            }
+            CipherAlgorithm::Aes128Gcm => Keystream::Aes128Ctr(Box::new(
+                Ctr128BE::<Aes128>::new_from_slices(key, &iv).map_err(invalid)?,
+            )),
+            CipherAlgorithm::Aes256Gcm => Keystream::Aes256Ctr(Box::new(
+                Ctr128BE::<Aes256>::new_from_slices(key, &iv).map_err(invalid)?,
+            )),
+        })
+    }
+
+    fn apply_keystream(&mut self, buf: &mut [u8]) {
+        match self {
+            Keystream::Salsa20(c) => c.apply_keystream(buf),
+            Keystream::Aes128Ctr(c) => c.apply_keystream(buf),
+            Keystream::Aes256Ctr(c) => c.apply_keystream(buf),
+        }
+    }
+}
+
+impl AeadCipher {
+    fn new(algorithm: CipherAlgorithm, key: &[u8]) -> anyhow::Result<Self> {
+        let invalid = |_| anyhow!("Invalid key length {} for {:?}", key.len(), algorithm);
+
+        Ok(match algorithm {
+            CipherAlgorithm::ChaCha20Poly1305 => AeadCipher::ChaCha20Poly1305(
+                ChaCha20Poly1305::new_from_slice(key).map_err(invalid)?,
+            ),
+            CipherAlgorithm::Aes128Gcm => {
+                AeadCipher::Aes128Gcm(Box::new(Aes128Gcm::new_from_slice(key).map_err(invalid)?))
+            }
+            CipherAlgorithm::Aes256Gcm => {
+                AeadCipher::Aes256Gcm(Box::new(Aes256Gcm::new_from_slice(key).map_err(invalid)?))
+            }
+        })
+    }
+
+    fn encrypt(&self, nonce: &[u8; 12], plaintext: &[u8]) -> aes_gcm::aead::Result<Vec<u8>> {
+        match self {
+            AeadCipher::ChaCha20Poly1305(c) => c.encrypt(nonce.into(), plaintext),
+            AeadCipher::Aes128Gcm(c) => c.encrypt(nonce.into(), plaintext),
+            AeadCipher::Aes256Gcm(c) => c.encrypt(nonce.into(), plaintext),
+        }
+    }
+
+    fn decrypt(&self, nonce: &[u8; 12], ciphertext: &[u8]) -> aes_gcm::aead::Result<Vec<u8>> {
+        match self {
+            AeadCipher::ChaCha20Poly1305(c) => c.decrypt(nonce.into(), ciphertext),
+            AeadCipher::Aes128Gcm(c) => c.decrypt(nonce.into(), ciphertext),
+            AeadCipher::Aes256Gcm(c) => c.decrypt(nonce.into(), ciphertext),
+        }
+    }
+}
+
+impl CipherInner {
+    fn new(algorithm: CipherAlgorithm, secret_key: &[u8], nonce: [u8; 8]) -> anyhow::Result<Self> {
+        Ok(Self {
+            algorithm,
+            no_mac: Keystream::new(algorithm, secret_key, nonce)?,
+            nonce_gen: Keystream::new(algorithm, secret_key, nonce)?,
+            cipher: AeadCipher::new(algorithm, secret_key)?,
+            n_bytes_ciphered: 0,
+        })
+    }
+
+    fn generate_nonce(&mut self) -> [u8; 12] {
+        let mut buf: [u8; 12] = [0x00; 12];
+        self.nonce_gen.apply_keystream(&mut buf);
+        buf
+    }
+
+    /// Replaces the key and restarts the nonce sequence.
+    fn rekey(&mut self, secret_key: &[u8], nonce: [u8; 8]) -> anyhow::Result<()> {
+        *self = Self::new(self.algorithm, secret_key, nonce)?;
+        Ok(())
+    }
+}
+
+impl EncryptionCipher {
+    fn new(
+        algorithm: CipherAlgorithm,
+        keys: &SessionKeys,
+        kind: CipherKind,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            inner: CipherInner::new(algorithm, Self::key(keys, kind), Self::fixed_nonce(kind))?,
+            kind,
+        })
+    }
+
+    fn key(keys: &SessionKeys, kind: CipherKind) -> &[u8] {
+        match kind {
+            CipherKind::Sender => &keys.client_to_server,
+            CipherKind::Receiver => &keys.server_to_client,
+        }
+    }
+
+    fn fixed_nonce(kind: CipherKind) -> [u8; 8] {
+        match kind {
+            CipherKind::Sender => NONCE_A,
+            CipherKind::Receiver => NONCE_B,
+        }
+    }
+
+    /// Replaces the key with the session key for our sending direction, and
+    /// restarts the nonce sequence.
+    pub fn rekey(&mut self, keys: &SessionKeys) -> anyhow::Result<()> {
+        self.inner
+            .rekey(Self::key(keys, self.kind), Self::fixed_nonce(self.kind))
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> anyhow::Result<(Payload, Mac)> {
+        self.inner.n_bytes_ciphered += plaintext.len();
+
+        let nonce = self.inner.generate_nonce();
+
+        let mut ciphertext = self
+            .inner
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow!("Encryption failed"))?;
+
+        let mac: Mac = ciphertext
+            .drain(ciphertext.len() - 16..ciphertext.len())
+            .collect::<Vec<_>>()
+            .try_into()
+            .unwrap();
+        assert!(plaintext.len() == ciphertext.len());
+
+        Ok((ciphertext, mac))
+    }
+
+    pub fn encrypt_unauth(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let mut ciphertext: Vec<u8> = plaintext.to_vec();
+        self.inner.no_mac.apply_keystream(&mut ciphertext);
+        ciphertext
+    }
+}
+
+impl DecryptionCipher {
+    fn new(
+        algorithm: CipherAlgorithm,
+        keys: &SessionKeys,
+        kind: CipherKind,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            inner: CipherInner::new(algorithm, Self::key(keys, kind), Self::fixed_nonce(kind))?,
+            kind,
+        })
+    }
+
+    fn key(keys: &SessionKeys, kind: CipherKind) -> &[u8] {
+        match kind {
+            CipherKind::Sender => &keys.server_to_client,
+            CipherKind::Receiver => &keys.client_to_server,
+        }
+    }
+
+    fn fixed_nonce(kind: CipherKind) -> [u8; 8] {
+        match kind {
+            CipherKind::Sender => NONCE_B,
+            CipherKind::Receiver => NONCE_A,
+        }
+    }
+
+    /// Replaces the key with the session key for our receiving direction, and
+    /// restarts the nonce sequence.
+    pub fn rekey(&mut self, keys: &SessionKeys) -> anyhow::Result<()> {
+        self.inner
+            .rekey(Self::key(keys, self.kind), Self::fixed_nonce(self.kind))
+    }
+
+    pub fn decrypt(&mut self, ciphertext: &[u8], mac: &Mac) -> anyhow::Result<Vec<u8>> {
+        let ctext_and_mac: Vec<u8> = ciphertext.iter().chain(mac.iter()).copied().collect();
+
+        self.inner.n_bytes_ciphered += ciphertext.len();
+
+        let nonce = self.inner.generate_nonce();
+
+        self.inner
+            .cipher
+            .decrypt(&nonce, &ctext_and_mac[..])
+            .map_err(|_| anyhow!("Message authentication failed"))
+    }
+
+    pub fn decrypt_unauth(&mut self, ciphertext: &[u8]) -> Vec<u8> {
+        let mut plaintext: Vec<u8> = ciphertext.to_vec();
+        self.inner.no_mac.apply_keystream(&mut plaintext);
+        plaintext
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::crypto::kdf::{
+        DEFAULT_KEY_LABEL, DEFAULT_SALT, PasswordKdf, derive_key_256, derive_session_keys,
+    };
+
+    const ALGORITHMS: [(CipherAlgorithm, usize); 3] = [
+        (CipherAlgorithm::ChaCha20Poly1305, 32),
+        (CipherAlgorithm::Aes128Gcm, 16),
+        (CipherAlgorithm::Aes256Gcm, 32),
+    ];
+
+    fn make_keys(key_nbytes: usize) -> SessionKeys {
+        let key = derive_key_256("hunter2", DEFAULT_SALT, PasswordKdf::default()).unwrap();
+        derive_session_keys(&key, None, DEFAULT_KEY_LABEL, key_nbytes)
+    }
+
+    fn make_cipher(algorithm: CipherAlgorithm, key_nbytes: usize, kind: CipherKind) -> Cipher {
+        Cipher::new(algorithm, &make_keys(key_nbytes), kind).unwrap()
+    }
+
+    #[test]
+    fn test_encryption_decryption() {
+        for (algorithm, key_nbytes) in ALGORITHMS {
+            let mut send_cipher = make_cipher(algorithm, key_nbytes, CipherKind::Sender);
+            let mut recv_cipher = make_cipher(algorithm, key_nbytes, CipherKind::Receiver);
+
+            let original_plain_text: Vec<u8> = b"hello world".to_vec();
+
+            let (ctext, mac) = send_cipher.encrypt(&original_plain_text[..]).unwrap();
+            let recovered_plain_text = recv_cipher.decrypt(&ctext[..], &mac).unwrap();
+
+            assert_eq!(original_plain_text, recovered_plain_text);
+        }
+    }
+
+    #[test]
+    fn test_authentication_failure() {
+        for (algorithm, key_nbytes) in ALGORITHMS {
+            let mut send_cipher = make_cipher(algorithm, key_nbytes, CipherKind::Sender);
+            let mut recv_cipher = make_cipher(algorithm, key_nbytes, CipherKind::Receiver);
+
+            let (mut ctext, mac) = send_cipher.encrypt(b"hello world").unwrap();
+            ctext[0] ^= 1;
+            assert!(recv_cipher.decrypt(&ctext[..], &mac).is_err());
+        }
+    }
+
+    #[test]
+    fn test_split_encryption_decryption() {
+        for (algorithm, key_nbytes) in ALGORITHMS {
+            let (mut send_enc, mut send_dec) =
+                make_cipher(algorithm, key_nbytes, CipherKind::Sender).into_split();
+            let (mut recv_enc, mut recv_dec) =
+                make_cipher(algorithm, key_nbytes, CipherKind::Receiver).into_split();
+
+            let original_plain_text: Vec<u8> = b"hello world".to_vec();
+
+            let (ctext, mac) = send_enc.encrypt(&original_plain_text[..]).unwrap();
+            let recovered_plain_text = recv_dec.decrypt(&ctext[..], &mac).unwrap();
+            assert_eq!(original_plain_text, recovered_plain_text);
+
+            let (ctext, mac) = recv_enc.encrypt(&original_plain_text[..]).unwrap();
+            let recovered_plain_text = send_dec.decrypt(&ctext[..], &mac).unwrap();
+            assert_eq!(original_plain_text, recovered_plain_text);
+        }
+    }
+
+    #[test]
+    fn test_rekeyed_encryption_decryption() {
+        for (algorithm, key_nbytes) in ALGORITHMS {
+            let keys = derive_session_keys(&[42u8; 32], None, DEFAULT_KEY_LABEL, key_nbytes);
+
+            let (mut send_enc, mut send_dec) =
+                make_cipher(algorithm, key_nbytes, CipherKind::Sender).into_split();
+            let (mut recv_enc, mut recv_dec) =
+                make_cipher(algorithm, key_nbytes, CipherKind::Receiver).into_split();
+            for enc in [&mut send_enc, &mut recv_enc] {
+                enc.rekey(&keys).unwrap();
+            }
+            for dec in [&mut send_dec, &mut recv_dec] {
+                dec.rekey(&keys).unwrap();
+            }
+
+            let original_plain_text: Vec<u8> = b"hello world".to_vec();
+
+            let (ctext, mac) = send_enc.encrypt(&original_plain_text[..]).unwrap();
+            let recovered_plain_text = recv_dec.decrypt(&ctext[..], &mac).unwrap();
+            assert_eq!(original_plain_text, recovered_plain_text);
+
+            let (ctext, mac) = recv_enc.encrypt(&original_plain_text[..]).unwrap();
+            let recovered_plain_text = send_dec.decrypt(&ctext[..], &mac).unwrap();
+            assert_eq!(original_plain_text, recovered_plain_text);
+
+            // The password-derived key no longer decrypts session traffic.
+            let (mut stale_enc, _) =
+                make_cipher(algorithm, key_nbytes, CipherKind::Sender).into_split();
+            let (stale_ctext, _) = stale_enc.encrypt(&original_plain_text[..]).unwrap();
+            let (ctext, _) = send_enc.encrypt(&original_plain_text[..]).unwrap();
+            assert_ne!(stale_ctext, ctext);
+        }
+    }
+
+    #[test]
+    fn test_encryption_decryption_unauth() {
+        for (algorithm, key_nbytes) in ALGORITHMS {
+            let mut send_cipher = make_cipher(algorithm, key_nbytes, CipherKind::Sender);
+            let mut recv_cipher = make_cipher(algorithm, key_nbytes, CipherKind::Receiver);
+
+            let original_plain_text: Vec<u8> = b"hello world".to_vec();
+
+            let ctext = send_cipher.encrypt_unauth(&original_plain_text[..]);
+            let recovered_plain_text = recv_cipher.decrypt_unauth(&ctext[..]);
+
+            assert_eq!(original_plain_text, recovered_plain_text);
+        }
+    }
+
+    #[test]
+    fn test_algorithms_differ() {
+        let plain_text = b"hello world";
+        let ctexts: Vec<_> = ALGORITHMS
+            .iter()
+            .map(|(algorithm, key_nbytes)| {
+                make_cipher(*algorithm, *key_nbytes, CipherKind::Sender)
+                    .encrypt(plain_text)
+                    .unwrap()
+            })
+            .collect();
+        assert_ne!(ctexts[0], ctexts[1]);
+        assert_ne!(ctexts[0], ctexts[2]);
+        assert_ne!(ctexts[1], ctexts[2]);
+    }
+
+    #[test]
+    fn test_directions_differ() {
+        for (algorithm, key_nbytes) in ALGORITHMS {
+            let mut send_cipher = make_cipher(algorithm, key_nbytes, CipherKind::Sender);
+            let mut recv_cipher = make_cipher(algorithm, key_nbytes, CipherKind::Receiver);
+
+            // Each direction has its own key, so the same plaintext does not
+            // encrypt to the same ciphertext in both directions.
+            let (send_ctext, send_mac) = send_cipher.encrypt(b"hello world").unwrap();
+            let (recv_ctext, recv_mac) = recv_cipher.encrypt(b"hello world").unwrap();
+            assert_ne!(send_ctext, recv_ctext);
+            assert_ne!(send_mac, recv_mac);
+        }
+    }
+
+    #[test]
+    fn test_invalid_key_length() {
+        let keys = make_keys(32);
+        assert!(Cipher::new(CipherAlgorithm::Aes128Gcm, &keys, CipherKind::Sender).is_err());
+        let keys = make_keys(16);
+        assert!(Cipher::new(CipherAlgorithm::Aes256Gcm, &keys, CipherKind::Sender).is_err());
+    }
+}
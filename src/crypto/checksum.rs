@@ -0,0 +1,75 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// The CRC-32 of zlib and Ethernet, with the reflected polynomial 0xedb88320.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let crc = bytes.iter().fold(!0u32, |crc, byte| {
+        (0..8).fold(crc ^ u32::from(*byte), |crc, _| {
+            (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg())
+        })
+    });
+    !crc
+}
+
+/// The Adler-32 of zlib.
+pub fn adler32(bytes: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+    let (a, b) = bytes.iter().fold((1u32, 0u32), |(a, b), byte| {
+        let a = (a + u32::from(*byte)) % MODULUS;
+        (a, (b + a) % MODULUS)
+    });
+    (b << 16) | a
+}
+
+/// The ones' complement of the ones'-complement sum of the 16-bit words, as in
+/// IP, TCP, and UDP (RFC 1071). We pad an odd number of bytes with a zero.
+pub fn internet_checksum(bytes: &[u8]) -> u16 {
+    let sum = bytes.chunks(2).fold(0u32, |sum, word| {
+        let sum = sum + u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]));
+        // Fold the carry back in, which keeps the sum within 16 bits.
+        (sum & 0xffff) + (sum >> 16)
+    });
+    !(sum as u16)
+}
+
+pub fn sha256(bytes: &[u8]) -> [u8; 32] {
+    Sha256::digest(bytes).into()
+}
+
+pub fn hmac_sha256(key: &[u8], bytes: &[u8]) -> [u8; 32] {
+    // Unwrap OK: HMAC takes keys of any length.
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(bytes);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(b""), 1);
+        // The example of RFC 1071, and the same with a checksum that a
+        // receiver sums to zero.
+        let words = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(internet_checksum(&words), !0xddf2);
+        let mut checked = words.to_vec();
+        checked.extend_from_slice(&internet_checksum(&words).to_be_bytes());
+        assert_eq!(internet_checksum(&checked), 0);
+        assert_eq!(internet_checksum(&[0x01]), !0x0100);
+    }
+
+    #[test]
+    fn digests() {
+        assert_eq!(sha256(b"abc")[..4], [0xba, 0x78, 0x16, 0xbf]);
+        // RFC 4231, test case 2.
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?")[..8],
+            [0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e]
+        );
+    }
+}
@@ -1,5 +1,186 @@
-pub fn derive_key_256(password: &str, _: &str) -> [u8; 32] {
+use anyhow::anyhow;
+use argon2::{Algorithm, Argon2, Params, Version};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+/// The salt we use for protocols that do not set their own. Each protocol
+/// should set a unique salt so that a password cannot be attacked with a table
+/// precomputed for all protocols at once.
+pub const DEFAULT_SALT: &[u8] = b"proteus default salt";
+
+/// The label we mix into the session keys of protocols that do not set their
+/// own.
+pub const DEFAULT_KEY_LABEL: &[u8] = b"proteus";
+
+/// The fewest bytes of a salt that a sender picks for each connection, so that
+/// no two connections are likely to ever pick the same one.
+pub const MIN_SALT_NBYTES: usize = 16;
+
+/// How a password is turned into a key.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PasswordKdf {
+    /// Argon2id with the given memory cost in KiB, number of iterations, and
+    /// degree of parallelism.
+    Argon2id {
+        m_cost: u32,
+        t_cost: u32,
+        p_cost: u32,
+    },
+    /// A single HKDF-SHA256 extraction, which is cheap and therefore only
+    /// suitable for passwords that are already high-entropy keys.
+    Hkdf,
+}
+
+impl PasswordKdf {
+    pub const DEFAULT_M_COST: u32 = Params::DEFAULT_M_COST;
+    pub const DEFAULT_T_COST: u32 = Params::DEFAULT_T_COST;
+    pub const DEFAULT_P_COST: u32 = Params::DEFAULT_P_COST;
+}
+
+impl Default for PasswordKdf {
+    fn default() -> Self {
+        PasswordKdf::Argon2id {
+            m_cost: Self::DEFAULT_M_COST,
+            t_cost: Self::DEFAULT_T_COST,
+            p_cost: Self::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// Stretches the password into a 256-bit key, by default with Argon2id so that
+/// a weak password is expensive to brute-force offline.
+pub fn derive_key_256(password: &str, salt: &[u8], kdf: PasswordKdf) -> anyhow::Result<[u8; 32]> {
     let mut output_key_material = [0u8; 32];
-    output_key_material.clone_from_slice(hex::decode(sha256::digest(password)).unwrap().as_slice());
-    output_key_material
+    match kdf {
+        PasswordKdf::Argon2id {
+            m_cost,
+            t_cost,
+            p_cost,
+        } => {
+            let params = Params::new(m_cost, t_cost, p_cost, Some(output_key_material.len()))
+                .map_err(|e| anyhow!("Invalid Argon2id parameters: {e}"))?;
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password_into(password.as_bytes(), salt, &mut output_key_material)
+                .map_err(|e| anyhow!("Password key derivation failed: {e}"))?;
+        }
+        PasswordKdf::Hkdf => {
+            Hkdf::<Sha256>::new(Some(salt), password.as_bytes())
+                .expand(b"proteus password key", &mut output_key_material)
+                .map_err(|e| anyhow!("Password key derivation failed: {e}"))?;
+        }
+    }
+    Ok(output_key_material)
+}
+
+/// Independent keys for each direction of a session, so that neither side ever
+/// encrypts under the same key and nonce sequence as the other.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct SessionKeys {
+    pub client_to_server: Vec<u8>,
+    pub server_to_client: Vec<u8>,
+}
+
+/// Expands the input key material into per-direction session keys of
+/// `key_nbytes` each with HKDF-SHA256. The input is either a password-derived
+/// key or a Diffie-Hellman shared secret; in the latter case, the
+/// password-derived key can be passed as `salt` so that an active attacker who
+/// does not know the password cannot complete the key agreement on our behalf.
+/// The `label` separates the keys of protocols that share a password.
+pub fn derive_session_keys(
+    ikm: &[u8],
+    salt: Option<&[u8]>,
+    label: &[u8],
+    key_nbytes: usize,
+) -> SessionKeys {
+    let hkdf = Hkdf::<Sha256>::new(salt, ikm);
+    let expand = |direction: &[u8]| {
+        let mut key = vec![0u8; key_nbytes];
+        // Unwrap OK: our cipher keys are much shorter than the HKDF limit.
+        hkdf.expand_multi_info(&[label, direction], &mut key)
+            .unwrap();
+        key
+    };
+
+    SessionKeys {
+        client_to_server: expand(b" client to server"),
+        server_to_client: expand(b" server to client"),
+    }
+}
+
+/// Derives the key of keyed checksums from the password-derived key, apart
+/// from the session keys.
+pub fn derive_checksum_key(master_key: &[u8; 32], label: &[u8]) -> [u8; 32] {
+    let hkdf = Hkdf::<Sha256>::new(None, master_key);
+    let mut key = [0u8; 32];
+    // Unwrap OK: the key is much shorter than the HKDF limit.
+    hkdf.expand_multi_info(&[label, b" checksum"], &mut key)
+        .unwrap();
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_key() {
+        let kdf = PasswordKdf::default();
+        let key = derive_key_256("hunter2", DEFAULT_SALT, kdf).unwrap();
+        assert_eq!(key, derive_key_256("hunter2", DEFAULT_SALT, kdf).unwrap());
+        assert_ne!(key, derive_key_256("hunter3", DEFAULT_SALT, kdf).unwrap());
+        assert_ne!(
+            key,
+            derive_key_256("hunter2", b"pepper pepper", kdf).unwrap()
+        );
+        // Argon2 requires at least 8 bytes of salt.
+        assert!(derive_key_256("hunter2", b"pepper", kdf).is_err());
+    }
+
+    #[test]
+    fn password_kdf_params() {
+        let cheap = PasswordKdf::Argon2id {
+            m_cost: 64,
+            t_cost: 1,
+            p_cost: 1,
+        };
+        let key = derive_key_256("hunter2", DEFAULT_SALT, cheap).unwrap();
+        assert_eq!(key, derive_key_256("hunter2", DEFAULT_SALT, cheap).unwrap());
+        assert_ne!(
+            key,
+            derive_key_256("hunter2", DEFAULT_SALT, PasswordKdf::default()).unwrap()
+        );
+
+        let hkdf_key = derive_key_256("hunter2", DEFAULT_SALT, PasswordKdf::Hkdf).unwrap();
+        assert_ne!(key, hkdf_key);
+        assert_ne!(
+            hkdf_key,
+            derive_key_256("hunter2", b"pepper", PasswordKdf::Hkdf).unwrap()
+        );
+
+        // Argon2 requires at least 8 KiB of memory per lane.
+        let invalid = PasswordKdf::Argon2id {
+            m_cost: 8,
+            t_cost: 1,
+            p_cost: 2,
+        };
+        assert!(derive_key_256("hunter2", DEFAULT_SALT, invalid).is_err());
+    }
+
+    #[test]
+    fn session_keys() {
+        let secret = [7u8; 32];
+        let psk = [9u8; 32];
+
+        let label = DEFAULT_KEY_LABEL;
+        let keys = derive_session_keys(&secret, Some(&psk), label, 32);
+        assert_ne!(keys.client_to_server, keys.server_to_client);
+        assert_eq!(keys, derive_session_keys(&secret, Some(&psk), label, 32));
+        assert_ne!(keys, derive_session_keys(&secret, None, label, 32));
+        assert_ne!(keys, derive_session_keys(&[8u8; 32], Some(&psk), label, 32));
+        assert_ne!(keys, derive_session_keys(&secret, Some(&psk), b"other", 32));
+
+        let short_keys = derive_session_keys(&secret, Some(&psk), label, 16);
+        assert_eq!(short_keys.client_to_server.len(), 16);
+        assert_eq!(short_keys.server_to_client.len(), 16);
+    }
 }
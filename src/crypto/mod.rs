@@ -1,3 +1,4 @@
-pub mod chacha;
+pub mod aead;
+pub mod checksum;
 pub mod kdf;
 pub mod pubkey;
@@ -1,4 +1,6 @@
+use anyhow::{anyhow, bail};
 use asn1::{Asn1Read, Asn1Write};
+use x25519_dalek::{EphemeralSecret, PublicKey};
 
 // PEM Length: 115 bytes
 // DER length: 44  bytes
@@ -22,13 +24,7 @@ impl Default for X25519PubKey {
 
 impl X25519PubKey {
     pub fn new() -> Self {
-        use x25519_dalek::{EphemeralSecret, PublicKey};
-        let secret = EphemeralSecret::random();
-        let public = PublicKey::from(&secret);
-
-        X25519PubKey {
-            value: public.to_bytes(),
-        }
+        X25519KeyPair::new().public_key().clone()
     }
 
     pub fn from_bytes(value: &[u8]) -> Self {
@@ -94,6 +90,51 @@ impl std::convert::From<X25519PubKey> for [u8; 32] {
     }
 }
 
+/// An ephemeral X25519 key pair. The secret half is consumed by the single key
+/// agreement it takes part in, but the public half remains available so it can
+/// be repeated in later messages.
+pub struct X25519KeyPair {
+    secret: Option<EphemeralSecret>,
+    public: X25519PubKey,
+}
+
+impl Default for X25519KeyPair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl X25519KeyPair {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random();
+        let public = X25519PubKey {
+            value: PublicKey::from(&secret).to_bytes(),
+        };
+        Self {
+            secret: Some(secret),
+            public,
+        }
+    }
+
+    pub fn public_key(&self) -> &X25519PubKey {
+        &self.public
+    }
+
+    /// Computes the shared secret with the peer's public key, consuming our
+    /// ephemeral secret.
+    pub fn diffie_hellman(&mut self, peer: &X25519PubKey) -> anyhow::Result<[u8; 32]> {
+        let secret = self
+            .secret
+            .take()
+            .ok_or_else(|| anyhow!("Ephemeral secret was already used"))?;
+        let shared = secret.diffie_hellman(&PublicKey::from(peer.value));
+        if !shared.was_contributory() {
+            bail!("Peer sent a low-order public key");
+        }
+        Ok(shared.to_bytes())
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
@@ -113,4 +154,22 @@ pub mod tests {
         let key2 = X25519PubKey::from_pem(pem);
         assert!(key == key2);
     }
+
+    #[test]
+    fn test_diffie_hellman() {
+        let mut a = X25519KeyPair::new();
+        let mut b = X25519KeyPair::new();
+        let a_pub = a.public_key().clone();
+        let b_pub = b.public_key().clone();
+
+        let a_shared = a.diffie_hellman(&b_pub).unwrap();
+        let b_shared = b.diffie_hellman(&a_pub).unwrap();
+        assert_eq!(a_shared, b_shared);
+
+        // The secret is ephemeral and cannot be reused.
+        assert!(a.diffie_hellman(&b_pub).is_err());
+        // An all-zero public key would give away the shared secret.
+        let zero = X25519PubKey::from_bytes(&[0u8; 32]);
+        assert!(X25519KeyPair::new().diffie_hellman(&zero).is_err());
+    }
 }
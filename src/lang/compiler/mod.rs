@@ -1,121 +1,231 @@
 #![allow(dead_code)]
 
+use std::collections::HashMap;
 use std::fs;
 use std::iter::Iterator;
 
 use itertools::Itertools;
-use petgraph::Directed;
+use petgraph::graph::{EdgeReference, NodeIndex};
 use petgraph::visit::EdgeRef;
+use petgraph::{Directed, Direction};
 
+use crate::crypto::kdf;
 use crate::lang::Role;
 use crate::lang::ir::bridge::*;
 use crate::lang::ir::v1::*;
 use crate::lang::types::*;
 
 pub mod parser;
+pub mod validate;
 
 /*
- * Some assumptions the compiler is making that the parser should check for:
+ * Some assumptions the compiler is making that the validator checks for:
  *
  * - There is one length field and one payload field for payload-carrying messages
  * - The length field for the payload field is not undefined
- * - The fixed-size length field for the payload should be in the prefix
+ * - Every length field comes before its variable-length field, and those of
+ *   the fields after the payload come before the payload
+ * - Variable-length fields other than the payload and padding have fixed values
+ * - The LENGTH expression does not count varints, whose width depends on their
+ *   value, and no varint is encrypted
+ * - Consecutive bitfields fill whole bytes, which the LENGTH expression counts
+ *   together, and no bitfield is encrypted
  */
 
 /*
- * Identifier here is a format identifier
+ * Each edge is a message of the sequence
 */
-type Graph = petgraph::graph::Graph<(), (Role, Identifier), Directed, usize>;
+type Graph = petgraph::graph::Graph<(), SequenceSpecifier, Directed, usize>;
 
 #[derive(Clone)]
 pub struct TaskGraphImpl {
     graph: Graph,
     my_role: Role,
     psf: Psf,
+    // Deriving the key from the password is deliberately expensive, so we do
+    // it once here rather than for every connection.
+    secret_key: Option<[u8; 32]>,
 }
 
 impl TaskGraphImpl {
-    fn new(graph: Graph, my_role: Role, psf: Psf) -> TaskGraphImpl {
-        TaskGraphImpl {
+    fn new(graph: Graph, my_role: Role, psf: Psf) -> anyhow::Result<TaskGraphImpl> {
+        let secret_key = match psf.crypto_spec {
+            Some(CryptoSpec {
+                password: Some(ref password),
+                kdf,
+                ref salt,
+                ..
+            }) => Some(kdf::derive_key_256(
+                &password.0,
+                salt.as_deref().unwrap_or(kdf::DEFAULT_SALT),
+                kdf.into(),
+            )?),
+            _ => None,
+        };
+
+        Ok(TaskGraphImpl {
             graph,
             my_role,
             psf,
-        }
+            secret_key,
+        })
     }
 
     fn next(&self, task_completed: TaskID) -> TaskSet {
+        // In the order of the sequence, since a sender picks the first DATA
+        // format whose condition holds.
         let edges: Vec<_> = self
             .graph
             .edges(usize::from(task_completed).into())
+            .sorted_by_key(|edge| edge.id())
             .collect();
 
-        match edges.len() {
-            1 => {
-                let edge_role = &(edges[0].weight()).0;
-                let edge_format = &(edges[0].weight()).1;
+        let (out_edges, in_edges): (Vec<_>, Vec<_>) = edges
+            .iter()
+            .partition(|edge| edge.weight().role == self.my_role);
 
-                let mut ins =
-                    compile_message_to_instrs(self.my_role, *edge_role, edge_format, &self.psf);
+        let mut out_task = self.compile_task(&out_edges);
+        let in_task = self.compile_task(&in_edges);
 
-                // This adjusts read app instructions during the handshake phase to not necessarily
-                // require bytes
-                for i in &mut ins {
-                    if let InstructionV1::ReadApp(ReadAppArgs { from_len: x, .. }) = i {
-                        *x = 0..x.end;
-                    }
-                }
+        // This adjusts read app instructions during the handshake phase to not necessarily
+        // require bytes
+        if in_edges.is_empty()
+            && (out_edges.len() == 1
+                || out_edges
+                    .iter()
+                    .all(|edge| edge.weight().phase == Phase::Handshake))
+        {
+            if let Some(ref mut task) = out_task {
+                allow_empty_app_reads(&mut task.ins);
+            }
+        }
 
-                let t = Task {
-                    ins,
-                    id: edges[0].target().index().into(),
-                };
+        match (in_task, out_task) {
+            (Some(in_task), Some(out_task)) => {
+                TaskSet::InAndOutTasks(TaskPair { in_task, out_task })
+            }
+            (Some(in_task), None) => TaskSet::InTask(in_task),
+            (None, Some(out_task)) => TaskSet::OutTask(out_task),
+            // The validator checks that the sequence ends with DATA messages,
+            // which repeat, and that every state has transitions out of it.
+            (None, None) => unreachable!(
+                "the sequence leaves no message to send or receive after task {:?}",
+                task_completed
+            ),
+        }
+    }
 
-                // I'm the sender
-                if self.my_role == *edge_role {
-                    TaskSet::OutTask(t)
-                } else {
-                    TaskSet::InTask(t)
-                }
+    /// Compiles the messages one role may send next into a task. Messages of
+    /// different formats are DATA messages or transitions, which the sender
+    /// picks by their conditions and the receiver by their tags, and
+    /// transitions of the same format are picked by their guards.
+    fn compile_task(&self, edges: &[&EdgeReference<SequenceSpecifier, usize>]) -> Option<Task> {
+        let id: TaskID = edges.first()?.target().index().into();
+
+        if let [edge] = edges {
+            let flight = self.flight(**edge);
+            if flight.len() > 1 {
+                return Some(self.compile_flight(&flight));
             }
-            2 => {
-                let edge0_role = &(edges[0].weight()).0;
-                let edge0_format = &(edges[0].weight()).1;
+        }
 
-                let edge1_role = &(edges[1].weight()).0;
-                let edge1_format = &(edges[1].weight()).1;
+        // Unless every message leads to the same task, each tells us where to
+        // continue.
+        let goto = !edges.iter().map(|edge| edge.target()).all_equal();
+
+        let mut formats: Vec<(&SequenceSpecifier, Vec<GotoCase>)> = vec![];
+        for edge in edges {
+            let seqspec = edge.weight();
+            let case = GotoCase {
+                guard: seqspec
+                    .guard
+                    .as_ref()
+                    .map(|guard| (guard_value_heap_id(&guard.field), guard.op, guard.value)),
+                to_task_id: edge.target().index().into(),
+            };
+            match formats.iter_mut().find(|(s, _)| s.format == seqspec.format) {
+                Some((_, cases)) => cases.push(case),
+                None => formats.push((seqspec, vec![case])),
+            }
+        }
 
-                let ins0 =
-                    compile_message_to_instrs(self.my_role, *edge0_role, edge0_format, &self.psf);
+        let mut formats: Vec<(&SequenceSpecifier, Vec<InstructionV1>)> = formats
+            .into_iter()
+            .map(|(seqspec, cases)| {
+                let mut ins = compile_message_to_instrs(
+                    self.my_role,
+                    seqspec.role,
+                    &seqspec.format,
+                    &self.psf,
+                );
+                if goto {
+                    ins.push(GotoArgs { cases }.into());
+                }
+                (seqspec, ins)
+            })
+            .collect();
 
-                let ins1 =
-                    compile_message_to_instrs(self.my_role, *edge1_role, edge1_format, &self.psf);
+        let ins = match formats.len() {
+            1 => formats.remove(0).1,
+            _ if formats[0].0.role == self.my_role => vec![compile_select_format(formats)],
+            _ => vec![compile_dispatch_format(formats, &self.psf)],
+        };
 
-                let t0 = Task {
-                    ins: ins0,
-                    id: edges[0].target().index().into(),
-                };
+        Some(Task { ins, id })
+    }
 
-                let t1 = Task {
-                    ins: ins1,
-                    id: edges[1].target().index().into(),
-                };
+    /// Follows a HANDSHAKE message through the steps that only lead on to a
+    /// HANDSHAKE message of the same role, which together make up a flight
+    /// that the role sends before the other responds.
+    fn flight<'a>(
+        &'a self,
+        first: EdgeReference<'a, SequenceSpecifier, usize>,
+    ) -> Vec<EdgeReference<'a, SequenceSpecifier, usize>> {
+        let mut flight = vec![first];
+        if first.weight().phase != Phase::Handshake {
+            return flight;
+        }
 
-                if self.my_role == (edges[0].weight()).0 {
-                    // TaskSet::OutTask(t0)
-                    let tp = TaskPair {
-                        in_task: t1,
-                        out_task: t0,
-                    };
-                    TaskSet::InAndOutTasks(tp)
-                } else {
-                    let tp = TaskPair {
-                        in_task: t0,
-                        out_task: t1,
-                    };
-                    TaskSet::InAndOutTasks(tp)
-                }
-            }
-            _ => panic!(),
+        while let Some(node) = flight.last().map(|edge| edge.target()) {
+            if node == first.source()
+                || self.graph.edges_directed(node, Direction::Incoming).count() != 1
+            {
+                break;
+            }
+            let Ok(edge) = self.graph.edges(node).exactly_one() else {
+                break;
+            };
+            if edge.weight().role != first.weight().role || edge.weight().phase != Phase::Handshake
+            {
+                break;
+            }
+            flight.push(edge);
+        }
+
+        flight
+    }
+
+    /// Compiles the messages of a flight into one task, in which the sender
+    /// writes them one by one or at once, and the receiver reads them all.
+    fn compile_flight(&self, flight: &[EdgeReference<SequenceSpecifier, usize>]) -> Task {
+        let messages = flight
+            .iter()
+            .map(|edge| {
+                let seqspec = edge.weight();
+                compile_message_to_instrs(self.my_role, seqspec.role, &seqspec.format, &self.psf)
+            })
+            .collect();
+        let coalesce = flight[0].weight().role == self.my_role
+            && self
+                .psf
+                .options
+                .as_ref()
+                .is_some_and(|o| o.coalesce_flights_setting);
+
+        Task {
+            ins: vec![FlightArgs { messages, coalesce }.into()],
+            // Unwrap OK: a flight has messages.
+            id: flight.last().unwrap().target().index().into(),
         }
     }
 
@@ -123,17 +233,32 @@ impl TaskGraphImpl {
         let mut ins: Vec<InstructionV1> = vec![];
 
         if let Some(ref crypto_spec) = self.psf.crypto_spec {
-            if let Some(ref password) = crypto_spec.password {
+            if let Some(secret_key) = self.secret_key {
                 ins.push(
                     InitFixedSharedKeyArgs {
-                        password: password.0.clone(),
+                        secret_key,
+                        key_label: crypto_spec
+                            .key_label
+                            .as_ref()
+                            .map_or(kdf::DEFAULT_KEY_LABEL, |label| label.as_bytes())
+                            .to_vec(),
                         role: self.my_role,
+                        cipher: crypto_spec.cipher,
                     }
                     .into(),
                 );
             }
         }
 
+        if let Some(ref timing) = self.psf.timing_spec {
+            ins.push(
+                InitShaperArgs {
+                    timing: timing.clone(),
+                }
+                .into(),
+            );
+        }
+
         Task {
             id: Default::default(),
             ins,
@@ -156,9 +281,29 @@ pub fn compile_task_graph<'a, T: Iterator<Item = &'a SequenceSpecifier>>(itr: T)
 
     let start_node = graph.add_node(());
 
+    // The named states of a sequence of transitions, the first of which is the
+    // start.
+    let mut states: HashMap<Identifier, NodeIndex<usize>> = HashMap::new();
+    let mut state_node = |graph: &mut Graph, state: &Identifier| {
+        let nstates = states.len();
+        *states
+            .entry(state.clone())
+            .or_insert_with(|| match nstates {
+                0 => start_node,
+                _ => graph.add_node(()),
+            })
+    };
+
     let mut prev_node = start_node;
     for seqspec in itr {
-        let edge_weight = (seqspec.role, seqspec.format.clone());
+        let edge_weight = seqspec.clone();
+
+        if let Some(ref transition) = seqspec.transition {
+            let from_node = state_node(&mut graph, &transition.from);
+            let to_node = state_node(&mut graph, &transition.to);
+            graph.add_edge(from_node, to_node, edge_weight);
+            continue;
+        }
 
         match seqspec.phase {
             Phase::Handshake => {
@@ -187,7 +332,7 @@ impl OldCompile for Compiler {
     fn parse_content(psf_content: &str, role: Role) -> anyhow::Result<TaskGraphImpl> {
         let psf = crate::lang::compiler::parser::parse_psf(psf_content)?;
         let tg = crate::lang::compiler::compile_task_graph(psf.sequence.iter());
-        let tgi = TaskGraphImpl::new(tg, role, psf);
+        let tgi = TaskGraphImpl::new(tg, role, psf)?;
         Ok(tgi)
     }
 }
@@ -196,7 +341,13 @@ impl OldCompile for Compiler {
 struct HintsPadding {
     field_id: Identifier,
     length_field_id: Identifier,
-    length_field_nbytes: usize,
+    // None for a varint, whose width depends on its value.
+    length_field_nbytes: Option<usize>,
+    policy: PaddingPolicy,
+    // The block size of the cipher, if it has one.
+    block_size_nbytes: Option<usize>,
+    // The division of the LENGTH expression that counts the payload, if any.
+    alignment: Option<PaddingAlignment>,
 }
 
 #[derive(Debug)]
@@ -204,105 +355,247 @@ struct HintsDynamicPayload {
     payload_field_name: Identifier,
     length_field_name: Identifier,
     length_field_max: usize,
-    length_field_nbytes: usize,
-    static_prefix_last_field: Identifier,
+    // None for a varint, whose width depends on its value.
+    length_field_nbytes: Option<usize>,
+    // The value of the LENGTH field, with ranges expanded to their fields.
+    length_expr: LengthExpr,
+    // The most bytes of padding we add.
+    padding_max_nbytes: usize,
     // Padding fields
     hints_padding: Option<HintsPadding>,
 }
 
+impl HintsDynamicPayload {
+    /// The value of the LENGTH field for a payload and padding of the given
+    /// sizes, which we know before we run the protocol since the other
+    /// variable-length fields have fixed values.
+    fn static_length_value(
+        &self,
+        format: &Format,
+        semantics: &Semantics,
+        payload_nbytes: usize,
+        padding_nbytes: usize,
+    ) -> Option<i128> {
+        self.length_expr
+            .evaluate(format, &|field| match field.dtype {
+                Array::Primitive(_) => field.maybe_size_of().map(|nbytes| nbytes as i128),
+                Array::Dynamic(_) => match semantics.as_ref().get(&field.name)? {
+                    FieldSemantic::Payload => Some(payload_nbytes as i128),
+                    FieldSemantic::Padding(_) => Some(padding_nbytes as i128),
+                    semantic => semantic.value_nbytes().map(|nbytes| nbytes as i128),
+                },
+            })
+    }
+}
+
+/// The value of the LENGTH field, which by default is the size of everything
+/// after the segment the field is in, so that a receiver can read the rest of
+/// the message once it read that segment.
+fn resolve_length_expr(
+    format: &Format,
+    length_field_id: &Identifier,
+    expr: Option<&LengthExpr>,
+) -> Option<LengthExpr> {
+    if let Some(expr) = expr {
+        return expr.expand_ranges(format);
+    }
+
+    let segments = format.split_into_segments();
+    let length_segment_index = segments
+        .iter()
+        .position(|segment| segment.try_get_field_by_name(length_field_id).is_some())?;
+
+    let mut rest = segments[length_segment_index + 1..]
+        .iter()
+        .flat_map(|segment| &segment.fields);
+    match (rest.next(), rest.last()) {
+        (Some(first), last) => {
+            LengthExpr::SizeOfRange(first.name.clone(), last.unwrap_or(first).name.clone())
+                .expand_ranges(format)
+        }
+        (None, _) => Some(LengthExpr::Literal(0)),
+    }
+}
+
 fn generate_dynamic_payload_hints(
     format: &Format,
     semantics: &Semantics,
     crypto: Option<&CryptoSpec>,
 ) -> Option<HintsDynamicPayload> {
-    semantics.find_field_id(FieldSemantic::Payload)?;
-
     // Need to figure out if the payload field is encoded with a length
-    let payload_field_id = semantics.find_field_id(FieldSemantic::Payload).unwrap();
-    let payload_field = format.try_get_field_by_name(&payload_field_id).unwrap();
-
-    let (static_prefix, dynamic_suffix) = format.split_into_fixed_sized_prefix_dynamic_suffix();
-
-    let (_, dynamic_suffix_fixed_part) =
-        dynamic_suffix.split_into_dynamic_prefix_and_fixed_suffix();
-    let suffix_fixed_size = dynamic_suffix_fixed_part.fixed_fields_size();
-
-    let static_prefix_last_field: Identifier;
-    if let Some(last_field) = static_prefix.fields.last() {
-        static_prefix_last_field = last_field.name.clone();
-    } else {
+    let payload_field_id = semantics.find_field_id(FieldSemantic::Payload)?;
+    let payload_field = format.try_get_field_by_name(&payload_field_id)?;
+    let Array::Dynamic(_) = payload_field.dtype else {
         return None;
-    }
-
-    let len_field_max: usize;
-    let length_field_id: Identifier;
-    let length_field_nbytes: usize;
-
-    if let Array::Dynamic(d) = payload_field.dtype {
-        length_field_id = d.try_get_length_field().unwrap();
-        let length_field = format.try_get_field_by_name(&length_field_id).unwrap();
-
-        let len_field_type = TryInto::<NumericType>::try_into(
-            TryInto::<PrimitiveArray>::try_into(length_field.dtype).unwrap(),
-        )
-        .unwrap();
-
-        len_field_max = <u128 as TryInto<usize>>::try_into(len_field_type.bounds().1).unwrap()
-            - suffix_fixed_size;
+    };
 
-        length_field_nbytes = len_field_type.size_of();
-    } else {
+    let length_field_id = semantics.find_field_id(FieldSemantic::Length(None))?;
+    let length_field = format.try_get_field_by_name(&length_field_id)?;
+    let length_field_type = PrimitiveArray::try_from(length_field.dtype).ok()?.0;
+    let FieldSemantic::Length(ref expr) = semantics.as_ref()[&length_field_id] else {
         return None;
-    }
-
-    let hints_padding =
-        if let Some(padding_field_id) = semantics.find_field_id(FieldSemantic::Padding) {
-            let padding_field = format.try_get_field_by_name(&padding_field_id).unwrap();
-            assert!(matches!(padding_field.dtype, Array::Dynamic(_)));
-
-            let padding_length_field_id = semantics
-                .find_field_id(FieldSemantic::PaddingLength)
-                .unwrap();
-
-            let padding_length_field = format
-                .try_get_field_by_name(&padding_length_field_id)
-                .unwrap();
+    };
+    let length_expr = resolve_length_expr(format, &length_field_id, expr.as_ref())?;
 
-            let padding_len_field_type = TryInto::<NumericType>::try_into(
-                TryInto::<PrimitiveArray>::try_into(padding_length_field.dtype).unwrap(),
-            )
-            .unwrap();
+    let hints_padding = match semantics.find_field_id(FieldSemantic::Padding(Default::default())) {
+        Some(padding_field_id) => {
+            let FieldSemantic::Padding(ref policy) = semantics.as_ref()[&padding_field_id] else {
+                return None;
+            };
+            let padding_length_field_id = semantics.find_field_id(FieldSemantic::PaddingLength)?;
+            let padding_length_field = format.try_get_field_by_name(&padding_length_field_id)?;
 
             Some(HintsPadding {
                 field_id: padding_field_id,
                 length_field_id: padding_length_field_id,
-                length_field_nbytes: padding_len_field_type.size_of(),
+                length_field_nbytes: padding_length_field.maybe_size_of(),
+                policy: policy.clone(),
+                block_size_nbytes: crypto
+                    .and_then(|crypto| crypto.cipher.block_size_nbytes())
+                    .map(usize::from),
+                alignment: padding_alignment(format, semantics, &length_expr, &payload_field_id),
             })
-        } else {
-            None
-        };
-
-    let padding_space = if hints_padding.is_some() {
-        crypto
-            .as_ref()
-            .unwrap()
-            .cipher
-            .block_size_nbytes()
-            .unwrap_or_default()
-    } else {
-        0
+        }
+        None => None,
     };
 
-    Some(HintsDynamicPayload {
+    // The validator checks that we have a block size when we pad to it. We
+    // may add up to a divisor less one bytes to align the padding.
+    let padding_max_nbytes = hints_padding
+        .as_ref()
+        .and_then(|hints| {
+            let align_nbytes = hints.alignment.as_ref().map_or(0, |a| a.divisor - 1);
+            Some(hints.policy.max_nbytes(hints.block_size_nbytes)? + align_nbytes)
+        })
+        .unwrap_or_default();
+
+    let mut hints = HintsDynamicPayload {
         payload_field_name: payload_field_id,
         length_field_name: length_field_id,
-        length_field_max: len_field_max - (padding_space as usize),
-        length_field_nbytes,
-        static_prefix_last_field,
+        length_field_max: 0,
+        length_field_nbytes: length_field_type.maybe_size_of(),
+        length_expr,
+        padding_max_nbytes,
         hints_padding,
+    };
+
+    // The validator ensures the value grows with the payload, so we search for
+    // the largest payload whose value fits in the LENGTH field with any padding.
+    let bound = usize::try_from(length_field_type.bounds().1).ok()?;
+    let fits = |payload_nbytes: usize| {
+        [0, padding_max_nbytes].iter().all(|&padding_nbytes| {
+            hints
+                .static_length_value(format, semantics, payload_nbytes, padding_nbytes)
+                .is_some_and(|value| value <= bound as i128)
+        })
+    };
+
+    if fits(0) {
+        let (mut lo, mut hi) = (0, bound);
+        while lo < hi {
+            let mid = lo + (hi - lo).div_ceil(2);
+            if fits(mid) { lo = mid } else { hi = mid - 1 }
+        }
+        hints.length_field_max = lo;
+    }
+
+    Some(hints)
+}
+
+/// The divisions of the expression whose dividend or divisor counts the field.
+fn divisions_counting<'a>(
+    expr: &'a LengthExpr,
+    field_id: &Identifier,
+) -> Vec<(&'a LengthExpr, &'a LengthExpr)> {
+    let LengthExpr::Binary(lhs, op, rhs) = expr else {
+        return vec![];
+    };
+    let mut divisions = vec![];
+    if *op == ArithOp::Div && expr.field_ids().contains(&field_id) {
+        divisions.push((lhs.as_ref(), rhs.as_ref()));
+    }
+    divisions.extend(divisions_counting(lhs, field_id));
+    divisions.extend(divisions_counting(rhs, field_id));
+    divisions
+}
+
+/// The division of the LENGTH expression whose dividend counts the payload,
+/// which the padding aligns. The validator checks that the dividend counts the
+/// padding and that we know the divisor before we run the protocol.
+fn padding_alignment(
+    format: &Format,
+    semantics: &Semantics,
+    length_expr: &LengthExpr,
+    payload_field_id: &Identifier,
+) -> Option<PaddingAlignment> {
+    let [(dividend, divisor)] = divisions_counting(length_expr, payload_field_id)[..] else {
+        return None;
+    };
+    let divisor = divisor.evaluate(format, &|field| match field.dtype {
+        Array::Primitive(_) => field.maybe_size_of().map(|nbytes| nbytes as i128),
+        Array::Dynamic(_) => semantics
+            .as_ref()
+            .get(&field.name)?
+            .value_nbytes()
+            .map(|nbytes| nbytes as i128),
+    })?;
+    Some(PaddingAlignment {
+        dividend: dividend.clone(),
+        divisor: usize::try_from(divisor).ok().filter(|&n| n > 0)?,
     })
 }
 
+/// Solves `expr = target` for the size of the `unknown` field, which must
+/// occur once in `expr` and not be in a divisor. The sender aligns a dividend
+/// that counts the field, so we multiply the value back. The sizes of the
+/// other fields are given by `nbytes`.
+fn solve_for_size(
+    expr: &LengthExpr,
+    unknown: &Identifier,
+    target: HeapExpr,
+    nbytes: &impl Fn(&Identifier) -> Option<HeapExpr>,
+) -> Option<HeapExpr> {
+    match expr {
+        LengthExpr::SizeOf(id) if id == unknown => Some(target),
+        LengthExpr::Binary(lhs, op, rhs) => {
+            let unknown_in_lhs = lhs.field_ids().contains(&unknown);
+            let (unknown_side, known_side) = if unknown_in_lhs {
+                (lhs, rhs)
+            } else {
+                (rhs, lhs)
+            };
+            let known = to_heap_expr(known_side, nbytes)?;
+
+            let target = match (op, unknown_in_lhs) {
+                (ArithOp::Add, _) => HeapExpr::binary(target, ArithOp::Sub, known),
+                (ArithOp::Sub, true) => HeapExpr::binary(target, ArithOp::Add, known),
+                (ArithOp::Sub, false) => HeapExpr::binary(known, ArithOp::Sub, target),
+                (ArithOp::Mul, _) => HeapExpr::binary(target, ArithOp::Div, known),
+                (ArithOp::Div, true) => HeapExpr::binary(target, ArithOp::Mul, known),
+                (ArithOp::Div, false) => return None,
+            };
+            solve_for_size(unknown_side, unknown, target, nbytes)
+        }
+        _ => None,
+    }
+}
+
+fn to_heap_expr(
+    expr: &LengthExpr,
+    nbytes: &impl Fn(&Identifier) -> Option<HeapExpr>,
+) -> Option<HeapExpr> {
+    match expr {
+        LengthExpr::Literal(n) => Some(HeapExpr::Literal(*n as i128)),
+        LengthExpr::SizeOf(id) => nbytes(id),
+        LengthExpr::SizeOfRange(..) => None,
+        LengthExpr::Binary(lhs, op, rhs) => Some(HeapExpr::binary(
+            to_heap_expr(lhs, nbytes)?,
+            *op,
+            to_heap_expr(rhs, nbytes)?,
+        )),
+    }
+}
+
 #[derive(Debug)]
 struct HintsEncryption {
     starting_format: Identifier,
@@ -322,20 +615,106 @@ fn generate_encryption_hints(format: &Format, crypto_spec: &CryptoSpec) -> Optio
         0 => None,
         1 => {
             let d = &directive[0].1;
+
+            // The receiver decrypts one segment at a time, so we order the
+            // fields by the segment their ciphertext is in. The sort is stable
+            // and keeps the order of the directives within a segment.
+            let segments = format.split_into_segments();
+            let mut enc_field_dirs = d.enc_field_dirs.clone();
+            enc_field_dirs.sort_by_key(|field_dir| {
+                segments.iter().position(|segment| {
+                    segment
+                        .try_get_field_by_name(&field_dir.ctext_name)
+                        .is_some()
+                })
+            });
+
             Some(HintsEncryption {
                 starting_format: d.enc_fmt_bnd.from_format_name.clone(),
-                enc_field_dirs: d.enc_field_dirs.clone(),
+                enc_field_dirs,
             })
         }
         _ => panic!("Ambigious encryption field directives"),
     }
 }
 
+/// Where to split the write of a message, if the options ask us to split it:
+/// after the named field if the format has it, or otherwise after the LENGTH
+/// field. The validator ensures the split point is in the fixed-size prefix.
+fn split_write_nbytes(
+    format: &Format,
+    semantics: &Semantics,
+    options: Option<&Options>,
+) -> Option<usize> {
+    let options = options?;
+
+    let split_field_id = match options.split_write_after_setting {
+        Some(ref field_id) if format.try_get_field_by_name(field_id).is_some() => field_id.clone(),
+        _ if options.separate_length_field_setting => {
+            semantics.find_field_id(FieldSemantic::Length(None))?
+        }
+        _ => return None,
+    };
+
+    let (_, offset, nbytes) = format.try_get_field_type_offset_and_size(&split_field_id)?;
+    Some(offset + nbytes)
+}
+
 static CFORMAT_HEAP_NAME: &str = "cformat_on_heap";
 static MESSAGE_HEAP_NAME: &str = "message_on_heap";
-static LEN_FIELD_HEAP_NAME: &str = "length_value_on_heap";
+static COVER_HEAP_NAME: &str = "cover_on_heap";
+static APP_DATA_HEAP_NAME: &str = "app_data_on_heap";
+
+/// Where we keep the value of a field that a guard compares until we pick the
+/// transition of the message.
+fn guard_value_heap_id(field_id: &Identifier) -> Identifier {
+    (field_id.0.to_string() + "_guard_on_heap").as_str().id()
+}
+
+/// The fields of the format that guards of the sequence compare.
+fn guard_field_ids(format_id: &Identifier, psf: &Psf) -> Vec<Identifier> {
+    psf.sequence
+        .iter()
+        .filter(|seqspec| seqspec.format == *format_id)
+        .filter_map(|seqspec| seqspec.guard.as_ref())
+        .map(|guard| guard.field.clone())
+        .unique()
+        .collect()
+}
+
+/// Where we keep the value of a variable while we set a field to it.
+fn variable_heap_id(field_id: &Identifier) -> Identifier {
+    (field_id.0.to_string() + "_variable_on_heap").as_str().id()
+}
+
+/// The variable of a counter, which counts the messages of its format that
+/// the role sends.
+fn counter_var_id(role: Role, format_id: &Identifier, field_id: &Identifier) -> Identifier {
+    format!("{role:?}_{}_{}_counter", format_id.0, field_id.0)
+        .as_str()
+        .id()
+}
+
+/// The number of values of a counter field, after which the counter wraps
+/// around to 0.
+fn counter_modulus(format: &Format, field_id: &Identifier) -> Option<u128> {
+    let field = format.try_get_field_by_name(field_id)?;
+    let (_, max) = PrimitiveArray::try_from(field.dtype).ok()?.0.bounds();
+    max.checked_add(1)
+}
 
-fn compile_plaintext_commands_sender(format_id: &Identifier, psf: &Psf) -> Vec<InstructionV1> {
+/// Where we keep the value of a length field while we compute or parse it.
+fn length_value_heap_id(length_field_id: &Identifier) -> Identifier {
+    (length_field_id.0.to_string() + "_value_on_heap")
+        .as_str()
+        .id()
+}
+
+fn compile_plaintext_commands_sender(
+    format_id: &Identifier,
+    role: Role,
+    psf: &Psf,
+) -> Vec<InstructionV1> {
     let mut instrs: Vec<InstructionV1> = vec![];
 
     let afs = psf.formats.get(format_id).unwrap();
@@ -345,14 +724,15 @@ fn compile_plaintext_commands_sender(format_id: &Identifier, psf: &Psf) -> Vec<I
     let maybe_hints_dynamic_payload =
         generate_dynamic_payload_hints(format, semantics, psf.crypto_spec.as_ref());
 
-    let has_padding = maybe_hints_dynamic_payload.is_some()
-        && maybe_hints_dynamic_payload
-            .as_ref()
-            .unwrap()
-            .hints_padding
-            .is_some();
+    let maybe_hints_padding = maybe_hints_dynamic_payload
+        .as_ref()
+        .and_then(|hints| hints.hints_padding.as_ref());
 
-    // Handle dynamic length fields
+    // The validator checks that messages with a COVER field have a payload.
+    let maybe_cover_field_id = semantics.find_field_id(FieldSemantic::Cover);
+
+    // Dynamic fields whose bytes we store on the heap. The others have fixed
+    // values, which the message gets from its format.
     let mut dynamic_field_names = vec![];
 
     if let Some(ref hints_dynamic_payload) = maybe_hints_dynamic_payload {
@@ -360,6 +740,7 @@ fn compile_plaintext_commands_sender(format_id: &Identifier, psf: &Psf) -> Vec<I
             ReadAppArgs {
                 from_len: 1..hints_dynamic_payload.length_field_max,
                 to_heap_id: hints_dynamic_payload.payload_field_name.clone(),
+                to_cover_heap_id: maybe_cover_field_id.as_ref().map(|_| COVER_HEAP_NAME.id()),
             }
             .into(),
         );
@@ -367,40 +748,38 @@ fn compile_plaintext_commands_sender(format_id: &Identifier, psf: &Psf) -> Vec<I
         dynamic_field_names.push(hints_dynamic_payload.payload_field_name.clone());
     }
 
+    if let Some(hints_padding) = maybe_hints_padding {
+        dynamic_field_names.push(hints_padding.field_id.clone());
+    }
+
     instrs.push(
         ConcretizeFormatArgs {
             from_format: AbstractFormat {
                 format: format.clone(),
-                fixed_fields: afs.semantics.get_fixed_fields(),
+                fixed_fields: afs.semantics.get_fixed_fields(format),
             },
             to_heap_id: CFORMAT_HEAP_NAME.id(),
-            padding_field: if has_padding {
-                Some(
-                    maybe_hints_dynamic_payload
-                        .as_ref()
-                        .unwrap()
-                        .hints_padding
-                        .as_ref()
-                        .unwrap()
-                        .field_id
-                        .clone(),
-                )
-            } else {
-                None
-            },
-            block_size_nbytes: if has_padding {
-                Some(
-                    psf.crypto_spec
-                        .as_ref()
-                        .unwrap()
-                        .cipher
-                        .block_size_nbytes()
-                        .unwrap()
-                        .into(),
-                )
-            } else {
-                None
-            },
+            padding: maybe_hints_padding.map(|hints_padding| PaddingDescriptor {
+                field_id: hints_padding.field_id.clone(),
+                // Unwrap OK: we only pad messages with a payload.
+                payload_field_id: maybe_hints_dynamic_payload
+                    .as_ref()
+                    .unwrap()
+                    .payload_field_name
+                    .clone(),
+                policy: hints_padding.policy.clone(),
+                block_size_nbytes: hints_padding.block_size_nbytes,
+                alignment: hints_padding.alignment.clone(),
+            }),
+            timestamp_fields: afs
+                .semantics
+                .as_ref()
+                .iter()
+                .filter_map(|(field_id, semantic)| match semantic {
+                    FieldSemantic::Timestamp(unit, _) => Some((field_id.clone(), *unit)),
+                    _ => None,
+                })
+                .collect(),
         }
         .into(),
     );
@@ -424,53 +803,304 @@ fn compile_plaintext_commands_sender(format_id: &Identifier, psf: &Psf) -> Vec<I
         );
     }
 
-    // If there's a length field to set, set it here.
-
-    if let Some(ref hints_dynamic_payload) = maybe_hints_dynamic_payload {
-        instrs.push(
-            ComputeLengthArgs {
-                from_msg_heap_id: MESSAGE_HEAP_NAME.id(),
-                from_field_id: hints_dynamic_payload.static_prefix_last_field.clone(),
-                to_heap_id: LEN_FIELD_HEAP_NAME.id(),
-            }
-            .into(),
-        );
-
+    if let Some(to_field_id) = maybe_cover_field_id {
         instrs.push(
             SetNumericValueArgs {
-                from_heap_id: LEN_FIELD_HEAP_NAME.id(),
+                from_heap_id: COVER_HEAP_NAME.id(),
                 to_msg_heap_id: MESSAGE_HEAP_NAME.id(),
-                to_field_id: hints_dynamic_payload.length_field_name.clone(),
+                to_field_id,
             }
             .into(),
         );
+    }
 
-        if let Some(ref hints_padding) = hints_dynamic_payload.hints_padding {
-            instrs.push(
-                SetNumericValueArgs {
-                    // FIXME(rwails) MEGA HACK
-                    from_heap_id: "__padding_len_on_heap".id(),
-                    to_msg_heap_id: MESSAGE_HEAP_NAME.id(),
-                    to_field_id: hints_padding.length_field_id.clone(),
+    // Fields whose values the connection keeps for later messages, which
+    // have fixed sizes.
+    for (field_id, semantic) in semantics.as_ref().iter().sorted() {
+        match semantic {
+            FieldSemantic::Counter(start, step) => {
+                let var_id = counter_var_id(role, format_id, field_id);
+                instrs.push(
+                    IncrementCounterArgs {
+                        var_id: var_id.clone(),
+                        start: *start,
+                        step: *step,
+                        modulus: counter_modulus(format, field_id),
+                    }
+                    .into(),
+                );
+                instrs.push(
+                    LoadVariableArgs {
+                        from_var_id: var_id,
+                        to_heap_id: variable_heap_id(field_id),
+                    }
+                    .into(),
+                );
+                instrs.push(
+                    SetNumericValueArgs {
+                        from_heap_id: variable_heap_id(field_id),
+                        to_msg_heap_id: MESSAGE_HEAP_NAME.id(),
+                        to_field_id: field_id.clone(),
+                    }
+                    .into(),
+                );
+            }
+            FieldSemantic::Echo(name) => {
+                instrs.push(
+                    LoadVariableArgs {
+                        from_var_id: name.clone(),
+                        to_heap_id: variable_heap_id(field_id),
+                    }
+                    .into(),
+                );
+                instrs.push(
+                    SetArrayBytesArgs {
+                        from_heap_id: variable_heap_id(field_id),
+                        to_msg_heap_id: MESSAGE_HEAP_NAME.id(),
+                        to_field_id: field_id.clone(),
+                    }
+                    .into(),
+                );
+            }
+            FieldSemantic::SaveAs(name) => instrs.push(
+                StoreVariableArgs {
+                    from_msg_heap_id: MESSAGE_HEAP_NAME.id(),
+                    from_field_id: field_id.clone(),
+                    to_var_id: name.clone(),
                 }
                 .into(),
-            );
+            ),
+            _ => {}
+        }
+    }
 
-            instrs.push(
-                SetArrayBytesArgs {
-                    // FIXME(rwails) MEGA HACK
-                    from_heap_id: hints_padding.field_id.clone(),
-                    to_msg_heap_id: MESSAGE_HEAP_NAME.id(),
-                    to_field_id: hints_padding.field_id.clone(),
+    // Now that the message has its size, set the length fields. The LENGTH
+    // field holds the value of its expression, and the others hold the size
+    // of their own field. We set the LENGTH field last, since its expression
+    // may count the other length fields, and setting a varint changes its
+    // width.
+    let is_payload = |field: &&Field| {
+        maybe_hints_dynamic_payload
+            .as_ref()
+            .is_some_and(|hints| hints.payload_field_name == field.name)
+    };
+    for field in format.fields.iter().sorted_by_key(is_payload) {
+        let Array::Dynamic(ref darray) = field.dtype else {
+            continue;
+        };
+        let length_field_id = darray.try_get_length_field().unwrap();
+        let length_heap_id = length_value_heap_id(&length_field_id);
+
+        match maybe_hints_dynamic_payload {
+            Some(ref hints) if hints.payload_field_name == field.name => instrs.push(
+                ComputeLengthArgs {
+                    from_msg_heap_id: MESSAGE_HEAP_NAME.id(),
+                    from_expr: hints.length_expr.clone(),
+                    to_heap_id: length_heap_id.clone(),
                 }
                 .into(),
-            );
+            ),
+            _ => instrs.push(
+                GetArrayLengthArgs {
+                    from_msg_heap_id: MESSAGE_HEAP_NAME.id(),
+                    from_field_id: field.name.clone(),
+                    to_heap_id: length_heap_id.clone(),
+                }
+                .into(),
+            ),
         }
+
+        instrs.push(
+            SetNumericValueArgs {
+                from_heap_id: length_heap_id,
+                to_msg_heap_id: MESSAGE_HEAP_NAME.id(),
+                to_field_id: length_field_id,
+            }
+            .into(),
+        );
+    }
+
+    for field_id in guard_field_ids(format_id, psf) {
+        instrs.push(
+            GetNumericValueArgs {
+                from_msg_heap_id: MESSAGE_HEAP_NAME.id(),
+                from_field_id: field_id.clone(),
+                to_heap_id: guard_value_heap_id(&field_id),
+            }
+            .into(),
+        );
     }
 
     instrs
 }
 
+/// The CHECKSUM fields of the format with their algorithms and ranges, in an
+/// order in which we compute a checksum after those in its range.
+fn checksum_fields<'a>(
+    format: &Format,
+    semantics: &'a Semantics,
+) -> Vec<(
+    &'a Identifier,
+    ChecksumAlgorithm,
+    &'a Identifier,
+    &'a Identifier,
+)> {
+    let mut pending: Vec<_> = semantics
+        .as_ref()
+        .iter()
+        .filter_map(|(field_id, semantic)| match semantic {
+            FieldSemantic::Checksum(algorithm, from, to) => Some((field_id, *algorithm, from, to)),
+            _ => None,
+        })
+        .sorted()
+        .collect();
+
+    let position = |id: &Identifier| format.fields.iter().position(|f| f.name == *id);
+    let covers = |from: &Identifier, to: &Identifier, id: &Identifier| {
+        matches!(
+            (position(from), position(to), position(id)),
+            (Some(first), Some(last), Some(i)) if (first..=last).contains(&i)
+        )
+    };
+
+    let mut ordered = vec![];
+    while !pending.is_empty() {
+        // The validator rejects checksums that cover each other, but if some
+        // still do, we compute them in any order.
+        let i = pending
+            .iter()
+            .position(|(own_id, _, from, to)| {
+                !pending
+                    .iter()
+                    .any(|(field_id, ..)| field_id != own_id && covers(from, to, field_id))
+            })
+            .unwrap_or(0);
+        ordered.push(pending.remove(i));
+    }
+    ordered
+}
+
+/// Sets the CHECKSUM fields of the message once its other fields are set,
+/// which for an encrypted message is before we encrypt it.
+fn compile_checksums_sender(format: &Format, semantics: &Semantics) -> Vec<InstructionV1> {
+    checksum_fields(format, semantics)
+        .into_iter()
+        .map(|(field_id, algorithm, from, to)| {
+            ComputeChecksumArgs {
+                msg_heap_ids: vec![MESSAGE_HEAP_NAME.id()],
+                algorithm,
+                from_field_id: from.clone(),
+                to_field_id: to.clone(),
+                field_id: field_id.clone(),
+                verify: false,
+            }
+            .into()
+        })
+        .collect()
+}
+
+/// Lets the reads from the application return no bytes, so that we send
+/// HANDSHAKE messages without waiting for data.
+fn allow_empty_app_reads(ins: &mut [InstructionV1]) {
+    for i in ins {
+        match i {
+            InstructionV1::ReadApp(ReadAppArgs { from_len: x, .. })
+            | InstructionV1::SelectFormat(SelectFormatArgs {
+                read: ReadAppArgs { from_len: x, .. },
+                ..
+            }) => *x = 0..x.end,
+            InstructionV1::Flight(FlightArgs { messages, .. }) => {
+                messages.iter_mut().for_each(|m| allow_empty_app_reads(m))
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Compiles the formats a sender picks from into one instruction, which reads
+/// from the application once for all of them.
+fn compile_select_format(formats: Vec<(&SequenceSpecifier, Vec<InstructionV1>)>) -> InstructionV1 {
+    let mut read = ReadAppArgs {
+        from_len: 1..1,
+        to_heap_id: APP_DATA_HEAP_NAME.id(),
+        to_cover_heap_id: None,
+    };
+
+    let cases: Vec<SelectCase> = formats
+        .into_iter()
+        .map(|(seqspec, mut ins)| {
+            // The format's own read becomes part of the one we share.
+            let format_read = ins
+                .iter()
+                .position(|i| matches!(i, InstructionV1::ReadApp(_)))
+                .map(|pos| match ins.remove(pos) {
+                    InstructionV1::ReadApp(args) => args,
+                    _ => unreachable!(),
+                });
+            let (payload_heap_id, max_payload_nbytes) = match format_read {
+                Some(args) => {
+                    read.from_len.end = read.from_len.end.max(args.from_len.end);
+                    read.to_cover_heap_id = read.to_cover_heap_id.take().or(args.to_cover_heap_id);
+                    (Some(args.to_heap_id), args.from_len.end - 1)
+                }
+                None => (None, 0),
+            };
+            if seqspec.condition == Some(SendCondition::Cover) {
+                read.to_cover_heap_id = Some(COVER_HEAP_NAME.id());
+            }
+
+            SelectCase {
+                condition: seqspec.condition.clone(),
+                payload_heap_id,
+                max_payload_nbytes,
+                ins,
+            }
+        })
+        .collect();
+
+    // Without a payload to send, we need not wait for the application.
+    if cases.iter().all(|case| case.payload_heap_id.is_none()) {
+        read.from_len.start = 0;
+    }
+
+    SelectFormatArgs { read, cases }.into()
+}
+
+/// Compiles the formats a receiver may read into one instruction, which tells
+/// them apart by their tags.
+fn compile_dispatch_format(
+    formats: Vec<(&SequenceSpecifier, Vec<InstructionV1>)>,
+    psf: &Psf,
+) -> InstructionV1 {
+    let mut tag_range = 0..0;
+
+    let cases = formats
+        .into_iter()
+        .map(|(seqspec, ins)| {
+            let afs = psf.formats.get(&seqspec.format).unwrap();
+            let format = &afs.format.format;
+
+            // Unwraps OK: the validator checks that the formats have tags at
+            // the same bytes.
+            let tag_id = afs
+                .semantics
+                .find_field_id(FieldSemantic::TypeTag(0))
+                .unwrap();
+            tag_range = format.try_get_fixed_byte_range(&tag_id).unwrap();
+            let (_, tag) = afs
+                .semantics
+                .get_fixed_fields(format)
+                .into_iter()
+                .find(|(id, _)| *id == tag_id)
+                .unwrap();
+
+            DispatchCase { tag, ins }
+        })
+        .collect();
+
+    DispatchFormatArgs { tag_range, cases }.into()
+}
+
 fn compile_message_to_instrs(
     my_role: Role,
     edge_role: Role,
@@ -486,15 +1116,6 @@ fn compile_message_to_instrs(
     let mut has_pubkey: Option<Identifier> = None;
     let mut pubkey_enc: Option<PubkeyEncoding> = None;
 
-    let mut length_present_and_nbytes: (bool, usize) = (false, 0);
-
-    for field in &format.fields {
-        //field.name
-        if field.name.0 == "length" {
-            length_present_and_nbytes = (true, field.dtype.maybe_size_of().unwrap());
-        }
-    }
-
     for semantic in semantics.as_ref().iter().sorted() {
         let id = semantic.0;
         let fs = semantic.1;
@@ -507,6 +1128,8 @@ fn compile_message_to_instrs(
         }
     }
 
+    let has_salt = semantics.find_field_id(FieldSemantic::Salt);
+
     let is_sender = my_role == edge_role;
 
     let maybe_hints_dynamic_payload =
@@ -524,20 +1147,33 @@ fn compile_message_to_instrs(
                 // Set up the original message
                 instrs.extend(compile_plaintext_commands_sender(
                     &hints_encryption.starting_format,
+                    edge_role,
                     psf,
                 ));
 
                 if let Some(id) = has_pubkey {
                     instrs.push(
-                        SaveKeyArgs {
-                            from_msg_heap_id: MESSAGE_HEAP_NAME.id(),
-                            from_field_id: id.clone(),
+                        SetPubkeyArgs {
+                            to_msg_heap_id: MESSAGE_HEAP_NAME.id(),
+                            to_field_id: id.clone(),
                             pubkey_encoding: pubkey_enc.unwrap(),
                         }
                         .into(),
                     );
                 }
 
+                if let Some(ref id) = has_salt {
+                    instrs.push(
+                        SetSaltArgs {
+                            to_msg_heap_id: MESSAGE_HEAP_NAME.id(),
+                            to_field_id: id.clone(),
+                        }
+                        .into(),
+                    );
+                }
+
+                instrs.extend(compile_checksums_sender(format, semantics));
+
                 // Then encrypt whatever fields we need to encrypt
                 for field_dir in &hints_encryption.enc_field_dirs {
                     let ctext_heap_id =
@@ -582,41 +1218,49 @@ fn compile_message_to_instrs(
                     }
                 }
             } else {
-                instrs.extend(compile_plaintext_commands_sender(format_id, psf));
+                instrs.extend(compile_plaintext_commands_sender(format_id, edge_role, psf));
                 if let Some(id) = has_pubkey {
                     instrs.push(
-                        SaveKeyArgs {
-                            from_msg_heap_id: MESSAGE_HEAP_NAME.id(),
-                            from_field_id: id.clone(),
+                        SetPubkeyArgs {
+                            to_msg_heap_id: MESSAGE_HEAP_NAME.id(),
+                            to_field_id: id.clone(),
                             pubkey_encoding: pubkey_enc.unwrap(),
                         }
                         .into(),
                     );
                 }
+                if let Some(ref id) = has_salt {
+                    instrs.push(
+                        SetSaltArgs {
+                            to_msg_heap_id: MESSAGE_HEAP_NAME.id(),
+                            to_field_id: id.clone(),
+                        }
+                        .into(),
+                    );
+                }
+                instrs.extend(compile_checksums_sender(format, semantics));
             }
         } else {
-            instrs.extend(compile_plaintext_commands_sender(format_id, psf));
+            instrs.extend(compile_plaintext_commands_sender(format_id, edge_role, psf));
 
             if let Some(id) = has_pubkey {
                 instrs.push(
-                    SaveKeyArgs {
-                        from_msg_heap_id: MESSAGE_HEAP_NAME.id(),
-                        from_field_id: id.clone(),
+                    SetPubkeyArgs {
+                        to_msg_heap_id: MESSAGE_HEAP_NAME.id(),
+                        to_field_id: id.clone(),
                         pubkey_encoding: pubkey_enc.unwrap(),
                     }
                     .into(),
                 );
             }
+            instrs.extend(compile_checksums_sender(format, semantics));
         }
 
-        if psf.options.is_some()
-            && psf.options.as_ref().unwrap().separate_length_field_setting
-            && length_present_and_nbytes.0
-        {
+        if let Some(len_first_write) = split_write_nbytes(format, semantics, psf.options.as_ref()) {
             instrs.push(
                 WriteNetTwiceArgs {
                     from_msg_heap_id: MESSAGE_HEAP_NAME.id(),
-                    len_first_write: length_present_and_nbytes.1,
+                    len_first_write,
                 }
                 .into(),
             );
@@ -629,36 +1273,110 @@ fn compile_message_to_instrs(
             );
         }
     } else {
-        // Is receiver
-        let (prefix, suffix) = format.split_into_fixed_sized_prefix_dynamic_suffix();
+        // Is receiver. We read the message one segment at a time, because the
+        // length fields in a segment tell us how much to read for the next.
+        let maybe_hints_encryption = psf
+            .crypto_spec
+            .as_ref()
+            .and_then(|crypto_spec| generate_encryption_hints(format, crypto_spec));
 
-        let hints_padding = if let Some(payload_hints) =
-            generate_dynamic_payload_hints(format, semantics, psf.crypto_spec.as_ref())
-        {
-            payload_hints.hints_padding
-        } else {
-            None
+        if let Some(ref hints_encryption) = maybe_hints_encryption {
+            if hints_encryption.starting_format != format.name {
+                unimplemented!();
+            }
+        }
+
+        let length_field_ids: Vec<Identifier> = format
+            .fields
+            .iter()
+            .filter_map(|field| match field.dtype {
+                Array::Dynamic(ref darray) => darray.try_get_length_field(),
+                Array::Primitive(_) => None,
+            })
+            .collect();
+
+        // The sizes of the fields, which we know for the other fields by the
+        // time we read the payload.
+        let known_nbytes = |field_id: &Identifier| {
+            let field = format.try_get_field_by_name(field_id)?;
+            match field.dtype {
+                Array::Primitive(_) => Some(HeapExpr::Literal(field.maybe_size_of()? as i128)),
+                Array::Dynamic(ref darray) => Some(HeapExpr::Heap(length_value_heap_id(
+                    &darray.try_get_length_field()?,
+                ))),
+            }
         };
 
-        let has_prefix = !prefix.fields.is_empty();
-        let has_suffix = !suffix.fields.is_empty();
+        // The receiver does not use the values we pick for the fields with
+        // several allowed values, but it checks the others against their fixed
+        // values.
+        let fixed_fields = semantics.get_fixed_fields(format);
+
+        let maybe_cover_field_id = semantics.find_field_id(FieldSemantic::Cover);
 
-        const CFORMAT_PFX_HEAP_NAME: &str = "cformat_prefix_on_heap";
-        const CFORMAT_SFX_HEAP_NAME: &str = "cformat_suffix_on_heap";
+        let mut payload_msg_heap_id: Option<Identifier> = None;
+
+        // The checksums we verify once we have read their fields, and the
+        // segments and fields we have read so far.
+        let mut pending_checksums = checksum_fields(format, semantics);
+        let mut read_msg_heap_ids: Vec<Identifier> = vec![];
+        let mut read_field_ids: Vec<Identifier> = vec![];
+
+        for (i, segment) in format.split_into_segments().iter().enumerate() {
+            if segment.fields.is_empty() {
+                continue;
+            }
 
-        const MSG_PFX_HEAP_NAME: &str = "message_prefix_on_heap";
-        const MSG_SFX_HEAP_NAME: &str = "message_suffix_on_heap";
+            let cformat_heap_id = format!("cformat_segment{i}_on_heap").as_str().id();
+            let msg_heap_id = format!("message_segment{i}_on_heap").as_str().id();
 
-        const LENGTH_ON_HEAP_NAME: &str = "num_payload_bytes_on_heap";
+            for field in &segment.fields {
+                // We read a group of bitfields at once, into the heap under
+                // the name of the first.
+                let bit_group = segment.bit_group(&field.name).unwrap();
+                if bit_group[0].name != field.name {
+                    continue;
+                }
 
-        if has_prefix {
-            // Read the fixed-size elements
-            for field in &prefix.fields[..] {
-                let field_nbytes = field.maybe_size_of().unwrap();
+                let from_len = match field.dtype {
+                    // A varint's first bytes tell us its width.
+                    Array::Primitive(ref parray) => match parray.varint_type() {
+                        Some(t) => ReadNetLength::VarInt(t),
+                        None => {
+                            let field_nbytes = bit_group
+                                .iter()
+                                .map(|field| field.dtype.maybe_size_of_bits().unwrap())
+                                .sum::<usize>()
+                                .div_ceil(8);
+                            ReadNetLength::Range(field_nbytes..field_nbytes + 1)
+                        }
+                    },
+                    Array::Dynamic(ref darray) => match maybe_hints_dynamic_payload {
+                        // We solve the LENGTH expression for the size of
+                        // the payload.
+                        Some(ref hints) if hints.payload_field_name == field.name => {
+                            let length_value =
+                                HeapExpr::Heap(length_value_heap_id(&hints.length_field_name));
+                            // Unwrap OK: the validator checks we can solve it.
+                            ReadNetLength::Expr(
+                                solve_for_size(
+                                    &hints.length_expr,
+                                    &hints.payload_field_name,
+                                    length_value,
+                                    &known_nbytes,
+                                )
+                                .unwrap(),
+                            )
+                        }
+                        _ => ReadNetLength::Expr(HeapExpr::Heap(length_value_heap_id(
+                            &darray.try_get_length_field().unwrap(),
+                        ))),
+                    },
+                };
 
                 instrs.push(
                     ReadNetArgs {
-                        from_len: ReadNetLength::Range(field_nbytes..field_nbytes + 1),
+                        from_len,
                         to_heap_id: field.name.clone(),
                     }
                     .into(),
@@ -668,270 +1386,276 @@ fn compile_message_to_instrs(
             instrs.push(
                 ConcretizeFormatArgs {
                     from_format: AbstractFormat {
-                        format: prefix.clone(),
-                        fixed_fields: afs.semantics.get_fixed_fields(),
+                        format: segment.clone(),
+                        // We overwrite every field with the bytes we read, so we
+                        // need no values for the variable-length fields, which
+                        // may not fit the bytes the peer sent.
+                        fixed_fields: fixed_fields
+                            .iter()
+                            .filter(|(id, _)| {
+                                segment
+                                    .try_get_field_by_name(id)
+                                    .is_some_and(|field| field.maybe_size_of().is_some())
+                            })
+                            .cloned()
+                            .collect(),
                     },
-                    to_heap_id: CFORMAT_PFX_HEAP_NAME.id(),
-
-                    // FIXME(rwails)
-                    padding_field: None,
-                    block_size_nbytes: None,
+                    to_heap_id: cformat_heap_id.clone(),
+                    padding: None,
+                    timestamp_fields: vec![],
                 }
                 .into(),
             );
 
             instrs.push(
                 CreateMessageArgs {
-                    from_format_heap_id: CFORMAT_PFX_HEAP_NAME.id(),
-                    to_heap_id: MSG_PFX_HEAP_NAME.id(),
+                    from_format_heap_id: cformat_heap_id,
+                    to_heap_id: msg_heap_id.clone(),
                 }
                 .into(),
             );
 
-            for name in prefix
-                .fields
-                .iter()
-                .map(|e| e.name.clone())
-                .collect::<Vec<Identifier>>()
-            {
+            for field in &segment.fields {
                 instrs.push(
                     SetArrayBytesArgs {
-                        from_heap_id: name.clone(),
-                        to_msg_heap_id: MSG_PFX_HEAP_NAME.id(),
-                        to_field_id: name.clone(),
+                        from_heap_id: segment.bit_group(&field.name).unwrap()[0].name.clone(),
+                        to_msg_heap_id: msg_heap_id.clone(),
+                        to_field_id: field.name.clone(),
                     }
                     .into(),
                 );
             }
 
-            if let Some(id) = has_pubkey {
-                instrs.push(
-                    SaveKeyArgs {
-                        from_msg_heap_id: MSG_PFX_HEAP_NAME.id(),
-                        from_field_id: id.clone(),
-                        pubkey_encoding: pubkey_enc.unwrap(),
-                    }
-                    .into(),
-                );
+            if let Some(ref id) = has_pubkey {
+                if segment.try_get_field_by_name(id).is_some() {
+                    instrs.push(
+                        SaveKeyArgs {
+                            from_msg_heap_id: msg_heap_id.clone(),
+                            from_field_id: id.clone(),
+                            pubkey_encoding: pubkey_enc.unwrap(),
+                        }
+                        .into(),
+                    );
+                }
             }
 
-            // Now, if there's anything to decrypt in the prefix, we do it here.
-
-            if let Some(ref crypto_spec) = psf.crypto_spec {
-                let maybe_hints_encryption = generate_encryption_hints(format, crypto_spec);
-
-                if let Some(ref hints_encryption) = maybe_hints_encryption {
-                    if hints_encryption.starting_format != format.name {
-                        unimplemented!();
-                    }
-
-                    for field_dir in &hints_encryption.enc_field_dirs {
-                        let ctext_name = &field_dir.ctext_name;
-
-                        // If the field exists in the prefix:
-                        if prefix.try_get_field_by_name(ctext_name).is_some() {
-                            let ptext_heap_name = (field_dir.ptext_name.0.to_string()
-                                + "_dec_heap")
-                                .as_str()
-                                .id();
-                            // Decrypt it
-                            let from_mac_field_id = field_dir.mac_name.clone();
-
-                            instrs.push(
-                                DecryptFieldArgs {
-                                    from_msg_heap_id: MSG_PFX_HEAP_NAME.id(),
-                                    from_ciphertext_field_id: ctext_name.clone(),
-                                    from_mac_field_id,
-                                    to_plaintext_heap_id: ptext_heap_name.clone(),
-                                }
-                                .into(),
-                            );
-
-                            // Copy it back
-                            instrs.push(
-                                SetArrayBytesArgs {
-                                    from_heap_id: ptext_heap_name,
-                                    to_msg_heap_id: MSG_PFX_HEAP_NAME.id(),
-                                    to_field_id: field_dir.ptext_name.clone(),
-                                }
-                                .into(),
-                            );
+            if let Some(ref id) = has_salt {
+                if psf.crypto_spec.is_some() && segment.try_get_field_by_name(id).is_some() {
+                    instrs.push(
+                        SaveSaltArgs {
+                            from_msg_heap_id: msg_heap_id.clone(),
+                            from_field_id: id.clone(),
                         }
-                    }
+                        .into(),
+                    );
                 }
             }
-        } // has_prefix
-
-        // RSW - What I think I need to do is add a new instruction
-        // which happens after this point to set the key.
-
-        if has_suffix {
-            // Figure out how much stuff is the the fixed-sized tail on the suffix,
-            // which is covered by the length field.
-            let (_suffix_dynamic_head, suffix_fixed_tail) =
-                suffix.split_into_dynamic_prefix_and_fixed_suffix();
 
-            let fixed_tail_size = suffix_fixed_tail.fixed_fields_size();
+            // Now, if there's anything to decrypt in the segment, we do it here.
+            if let Some(ref hints_encryption) = maybe_hints_encryption {
+                for field_dir in &hints_encryption.enc_field_dirs {
+                    let ctext_name = &field_dir.ctext_name;
 
-            if let Some(ref hints_dynamic_payload) = maybe_hints_dynamic_payload {
-                // The length field must exist in the fixed-size prefix.
-                // Assumes there's only one payload...
-                instrs.push(
-                    GetNumericValueArgs {
-                        from_msg_heap_id: MSG_PFX_HEAP_NAME.id(),
-                        from_field_id: hints_dynamic_payload.length_field_name.clone(),
-                        to_heap_id: LENGTH_ON_HEAP_NAME.id(),
+                    if segment.try_get_field_by_name(ctext_name).is_none() {
+                        continue;
                     }
-                    .into(),
-                );
 
-                if let Some(ref hints_padding) = hints_padding {
+                    let ptext_heap_name = (field_dir.ptext_name.0.to_string() + "_dec_heap")
+                        .as_str()
+                        .id();
+
+                    // Decrypt it
                     instrs.push(
-                        GetNumericValueArgs {
-                            from_msg_heap_id: MSG_PFX_HEAP_NAME.id(),
-                            from_field_id: hints_padding.length_field_id.clone(),
-                            to_heap_id: "__padding_len_on_heap".id(),
+                        DecryptFieldArgs {
+                            from_msg_heap_id: msg_heap_id.clone(),
+                            from_ciphertext_field_id: ctext_name.clone(),
+                            from_mac_field_id: field_dir.mac_name.clone(),
+                            to_plaintext_heap_id: ptext_heap_name.clone(),
                         }
                         .into(),
                     );
 
+                    // Copy it back
                     instrs.push(
-                        ReadNetArgs {
-                            from_len: ReadNetLength::IdentifierMinusMinus((
-                                LENGTH_ON_HEAP_NAME.id(),
-                                "__padding_len_on_heap".id(),
-                                fixed_tail_size,
-                            )),
-                            to_heap_id: hints_dynamic_payload.payload_field_name.clone(),
+                        SetArrayBytesArgs {
+                            from_heap_id: ptext_heap_name,
+                            to_msg_heap_id: msg_heap_id.clone(),
+                            to_field_id: field_dir.ptext_name.clone(),
                         }
                         .into(),
                     );
+                }
+            }
 
+            // The length fields are only meaningful once decrypted.
+            for field in &segment.fields {
+                if length_field_ids.contains(&field.name) {
                     instrs.push(
-                        ReadNetArgs {
-                            from_len: ReadNetLength::Identifier("__padding_len_on_heap".id()),
-                            to_heap_id: hints_padding.field_id.clone(),
+                        GetNumericValueArgs {
+                            from_msg_heap_id: msg_heap_id.clone(),
+                            from_field_id: field.name.clone(),
+                            to_heap_id: length_value_heap_id(&field.name),
                         }
                         .into(),
                     );
-                } else {
-                    instrs.push(
-                        ReadNetArgs {
-                            from_len: ReadNetLength::IdentifierMinus((
-                                LENGTH_ON_HEAP_NAME.id(),
-                                fixed_tail_size,
-                            )),
-                            to_heap_id: hints_dynamic_payload.payload_field_name.clone(),
+                }
+            }
+
+            // A peer whose fields do not have the values we expect is not
+            // speaking our protocol. We compare values rather than bytes where
+            // a value may have several encodings or share bytes with others.
+            for field_id in segment.fields.iter().map(|field| &field.name) {
+                let Some((_, bytes)) = fixed_fields.iter().find(|(id, _)| id == field_id) else {
+                    continue;
+                };
+                match &semantics.as_ref()[field_id] {
+                    FieldSemantic::FixedBytes(_) | FieldSemantic::FixedString(_) => instrs.push(
+                        CheckFieldBytesArgs {
+                            from_msg_heap_id: msg_heap_id.clone(),
+                            from_field_id: field_id.clone(),
+                            expected: bytes.clone(),
                         }
                         .into(),
-                    );
+                    ),
+                    semantic @ (FieldSemantic::FixedValue(_)
+                    | FieldSemantic::OneOf(_)
+                    | FieldSemantic::Range(..)
+                    | FieldSemantic::TypeTag(_)
+                    | FieldSemantic::Timestamp(..)) => instrs.push(
+                        CheckFieldValueArgs {
+                            from_msg_heap_id: msg_heap_id.clone(),
+                            from_field_id: field_id.clone(),
+                            semantic: semantic.clone(),
+                        }
+                        .into(),
+                    ),
+                    _ => {}
                 }
+            }
 
-                for field in &suffix_fixed_tail.fields {
-                    let field_len = field.maybe_size_of().unwrap();
+            read_msg_heap_ids.push(msg_heap_id.clone());
+            read_field_ids.extend(segment.fields.iter().map(|field| field.name.clone()));
+            let (ready_checksums, still_pending) = pending_checksums
+                .into_iter()
+                .partition::<Vec<_>, _>(|(field_id, _, from, to)| {
+                    [field_id, from, to]
+                        .into_iter()
+                        .all(|id| read_field_ids.contains(id))
+                });
+            pending_checksums = still_pending;
+            for (field_id, algorithm, from, to) in ready_checksums {
+                instrs.push(
+                    ComputeChecksumArgs {
+                        msg_heap_ids: read_msg_heap_ids.clone(),
+                        algorithm,
+                        from_field_id: from.clone(),
+                        to_field_id: to.clone(),
+                        field_id: field_id.clone(),
+                        verify: true,
+                    }
+                    .into(),
+                );
+            }
 
+            // We learn whether the message is cover before we get to the
+            // payload, which we then do not forward.
+            if let Some(ref field_id) = maybe_cover_field_id {
+                if segment.try_get_field_by_name(field_id).is_some() {
+                    instrs.push(
+                        CheckFieldValueArgs {
+                            from_msg_heap_id: msg_heap_id.clone(),
+                            from_field_id: field_id.clone(),
+                            semantic: FieldSemantic::Cover,
+                        }
+                        .into(),
+                    );
                     instrs.push(
-                        ReadNetArgs {
-                            from_len: ReadNetLength::Range(field_len..field_len + 1),
-                            to_heap_id: field.name.clone(),
+                        GetNumericValueArgs {
+                            from_msg_heap_id: msg_heap_id.clone(),
+                            from_field_id: field_id.clone(),
+                            to_heap_id: COVER_HEAP_NAME.id(),
                         }
                         .into(),
                     );
                 }
+            }
 
-                instrs.push(
-                    ConcretizeFormatArgs {
-                        from_format: AbstractFormat {
-                            format: suffix.clone(),
-                            fixed_fields: afs.semantics.get_fixed_fields(),
-                        },
-                        to_heap_id: CFORMAT_SFX_HEAP_NAME.id(),
-
-                        // FIXME
-                        padding_field: None,
-                        block_size_nbytes: None,
+            // The fields whose values the connection keeps, which we check
+            // against what we kept or keep for later messages.
+            for field_id in segment.fields.iter().map(|field| &field.name) {
+                let var_id = match semantics.as_ref().get(field_id) {
+                    Some(FieldSemantic::Counter(start, step)) => {
+                        let var_id = counter_var_id(edge_role, format_id, field_id);
+                        instrs.push(
+                            IncrementCounterArgs {
+                                var_id: var_id.clone(),
+                                start: *start,
+                                step: *step,
+                                modulus: counter_modulus(format, field_id),
+                            }
+                            .into(),
+                        );
+                        var_id
                     }
-                    .into(),
-                );
-
+                    Some(FieldSemantic::Echo(name)) => name.clone(),
+                    Some(FieldSemantic::SaveAs(name)) => {
+                        instrs.push(
+                            StoreVariableArgs {
+                                from_msg_heap_id: msg_heap_id.clone(),
+                                from_field_id: field_id.clone(),
+                                to_var_id: name.clone(),
+                            }
+                            .into(),
+                        );
+                        continue;
+                    }
+                    _ => continue,
+                };
                 instrs.push(
-                    CreateMessageArgs {
-                        from_format_heap_id: CFORMAT_SFX_HEAP_NAME.id(),
-                        to_heap_id: MSG_SFX_HEAP_NAME.id(),
+                    CheckFieldVariableArgs {
+                        from_msg_heap_id: msg_heap_id.clone(),
+                        from_field_id: field_id.clone(),
+                        var_id,
                     }
                     .into(),
                 );
+            }
 
-                for name in suffix
-                    .fields
-                    .iter()
-                    .map(|e| e.name.clone())
-                    .collect::<Vec<Identifier>>()
-                {
+            for field_id in guard_field_ids(format_id, psf) {
+                if segment.try_get_field_by_name(&field_id).is_some() {
                     instrs.push(
-                        SetArrayBytesArgs {
-                            from_heap_id: name.clone(),
-                            to_msg_heap_id: MSG_SFX_HEAP_NAME.id(),
-                            to_field_id: name.clone(),
+                        GetNumericValueArgs {
+                            from_msg_heap_id: msg_heap_id.clone(),
+                            from_field_id: field_id.clone(),
+                            to_heap_id: guard_value_heap_id(&field_id),
                         }
                         .into(),
                     );
                 }
+            }
 
-                // And then we decrypt in the suffix
-                if let Some(ref crypto_spec) = psf.crypto_spec {
-                    let maybe_hints_encryption = generate_encryption_hints(format, crypto_spec);
-
-                    if let Some(ref hints_encryption) = maybe_hints_encryption {
-                        if hints_encryption.starting_format != format.name {
-                            unimplemented!();
-                        }
-
-                        for field_dir in &hints_encryption.enc_field_dirs {
-                            let ctext_name = &field_dir.ctext_name;
-
-                            // If the field exists in the prefix:
-                            if suffix.try_get_field_by_name(ctext_name).is_some() {
-                                let ptext_heap_name = (field_dir.ptext_name.0.to_string()
-                                    + "_dec_heap")
-                                    .as_str()
-                                    .id();
-
-                                let from_mac_field_id = field_dir.mac_name.clone();
-                                // Decrypt it
-                                instrs.push(
-                                    DecryptFieldArgs {
-                                        from_msg_heap_id: MSG_SFX_HEAP_NAME.id(),
-                                        from_ciphertext_field_id: ctext_name.clone(),
-                                        from_mac_field_id,
-                                        to_plaintext_heap_id: ptext_heap_name.clone(),
-                                    }
-                                    .into(),
-                                );
-
-                                // Copy it back
-                                instrs.push(
-                                    SetArrayBytesArgs {
-                                        from_heap_id: ptext_heap_name,
-                                        to_msg_heap_id: MSG_SFX_HEAP_NAME.id(),
-                                        to_field_id: field_dir.ptext_name.clone(),
-                                    }
-                                    .into(),
-                                );
-                            }
-                        }
-                    }
+            if let Some(ref hints) = maybe_hints_dynamic_payload {
+                if segment
+                    .try_get_field_by_name(&hints.payload_field_name)
+                    .is_some()
+                {
+                    payload_msg_heap_id = Some(msg_heap_id);
                 }
-
-                instrs.push(
-                    WriteAppArgs {
-                        from_msg_heap_id: MSG_SFX_HEAP_NAME.id(),
-                        from_field_id: hints_dynamic_payload.payload_field_name.clone(),
-                    }
-                    .into(),
-                );
             }
-        } // has_suffix
+        }
+
+        if let (Some(hints), Some(from_msg_heap_id)) =
+            (maybe_hints_dynamic_payload, payload_msg_heap_id)
+        {
+            instrs.push(
+                WriteAppArgs {
+                    from_msg_heap_id,
+                    from_field_id: hints.payload_field_name,
+                    cover_heap_id: maybe_cover_field_id.map(|_| COVER_HEAP_NAME.id()),
+                }
+                .into(),
+            );
+        }
     } // receiver
 
     instrs
@@ -947,11 +1671,7 @@ mod tests {
         let psf = parse_example_psf().unwrap();
         let graph = compile_task_graph(psf.sequence.iter());
 
-        let tg = TaskGraphImpl {
-            graph,
-            my_role: Role::Server,
-            psf,
-        };
+        let tg = TaskGraphImpl::new(graph, Role::Server, psf).unwrap();
 
         let mut task_completed: TaskID = Default::default();
 
@@ -974,16 +1694,174 @@ mod tests {
         }
     }
 
+    fn write_instruction(psf: &str, role: Role) -> InstructionV1 {
+        let psf = parser::parse_psf(psf).unwrap();
+        compile_message_to_instrs(role, role, &"Msg".id(), &psf)
+            .pop()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_split_write() {
+        let psf = r#"@SEGMENT.FORMATS
+            DEFINE Msg
+              { NAME: header  ; TYPE: [u8; 3] },
+              { NAME: hdr_len ; TYPE: u16 },
+              { NAME: nonce   ; TYPE: [u8; 8] },
+              { NAME: payload ; TYPE: [u8; hdr_len.size_of] };
+            @SEGMENT.SEMANTICS
+            { FORMAT: Msg; FIELD: hdr_len; SEMANTIC: LENGTH };
+            { FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+            @SEGMENT.SEQUENCE
+            { ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+            { ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+            @SEGMENT.OPTIONS
+        "#;
+
+        let split_at =
+            |options: &str| match write_instruction(&(psf.to_string() + options), Role::Client) {
+                InstructionV1::WriteNetTwice(args) => Some(args.len_first_write),
+                InstructionV1::WriteNet(_) => None,
+                _ => panic!("message does not end with a write"),
+            };
+
+        assert_eq!(split_at(""), None);
+        assert_eq!(split_at("SEPARATE_LENGTH_FIELD = false;"), None);
+        // The split is after the LENGTH field, whatever its name.
+        assert_eq!(split_at("SEPARATE_LENGTH_FIELD = true;"), Some(5));
+        assert_eq!(split_at("SPLIT_WRITE_AFTER = nonce;"), Some(13));
+        assert_eq!(
+            split_at("SEPARATE_LENGTH_FIELD = true; SPLIT_WRITE_AFTER = header;"),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn test_receive_segments() {
+        let psf = r#"@SEGMENT.FORMATS
+            DEFINE Msg
+              { NAME: header      ; TYPE: [u8; 3] },
+              { NAME: length      ; TYPE: u16 },
+              { NAME: session_len ; TYPE: u8 },
+              { NAME: session_id  ; TYPE: [u8; session_len.size_of] },
+              { NAME: ext_len     ; TYPE: u16 },
+              { NAME: extensions  ; TYPE: [u8; ext_len.size_of] },
+              { NAME: payload     ; TYPE: [u8; length.size_of] },
+              { NAME: mac         ; TYPE: [u8; 16] };
+            @SEGMENT.SEMANTICS
+            { FORMAT: Msg; FIELD: length;     SEMANTIC: LENGTH };
+            { FORMAT: Msg; FIELD: session_id; SEMANTIC: RANDOM(32) };
+            { FORMAT: Msg; FIELD: extensions; SEMANTIC: FIXED_BYTES(0x0017) };
+            { FORMAT: Msg; FIELD: payload;    SEMANTIC: PAYLOAD };
+            @SEGMENT.SEQUENCE
+            { ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+            { ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+        "#;
+        let psf = parser::parse_psf(psf).unwrap();
+
+        let reads: Vec<String> =
+            compile_message_to_instrs(Role::Server, Role::Client, &"Msg".id(), &psf)
+                .into_iter()
+                .filter_map(|ins| match ins {
+                    InstructionV1::ReadNet(args) => {
+                        Some(format!("{} {:?}", args.to_heap_id.0, args.from_len))
+                    }
+                    _ => None,
+                })
+                .collect();
+
+        // The LENGTH field counts everything after the first segment, so the
+        // payload is what the other fields do not take up.
+        assert_eq!(
+            reads,
+            [
+                "header Range(3..4)",
+                "length Range(2..3)",
+                "session_len Range(1..2)",
+                "session_id Expr(Heap(Identifier(\"session_len_value_on_heap\")))",
+                "ext_len Range(2..3)",
+                "extensions Expr(Heap(Identifier(\"ext_len_value_on_heap\")))",
+                "payload Expr(Binary(Binary(Heap(Identifier(\"length_value_on_heap\")), Sub, \
+                 Literal(16)), Sub, Binary(Binary(Heap(Identifier(\"session_len_value_on_heap\")), \
+                 Add, Literal(2)), Add, Heap(Identifier(\"ext_len_value_on_heap\")))))",
+                "mac Range(16..17)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_constrained_values() {
+        let psf = r#"@SEGMENT.FORMATS
+            DEFINE Msg
+              { NAME: version ; TYPE: u16 },
+              { NAME: epoch   ; TYPE: u8 },
+              { NAME: length  ; TYPE: u16 },
+              { NAME: payload ; TYPE: [u8; length.size_of] };
+            @SEGMENT.SEMANTICS
+            { FORMAT: Msg; FIELD: version; SEMANTIC: ONE_OF(0x0301, 0x0303) };
+            { FORMAT: Msg; FIELD: epoch;   SEMANTIC: RANGE(1, 12) };
+            { FORMAT: Msg; FIELD: length;  SEMANTIC: LENGTH };
+            { FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+            @SEGMENT.SEQUENCE
+            { ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+            { ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+        "#;
+        let psf = parser::parse_psf(psf).unwrap();
+
+        let checks = |my_role| -> Vec<String> {
+            compile_message_to_instrs(my_role, Role::Client, &"Msg".id(), &psf)
+                .into_iter()
+                .filter_map(|ins| match ins {
+                    InstructionV1::CheckFieldValue(args) => Some(args.from_field_id.0),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // Only the receiver checks the values it gets.
+        assert!(checks(Role::Client).is_empty());
+        assert_eq!(checks(Role::Server), ["version", "epoch"]);
+    }
+
+    #[test]
+    fn test_timestamp_taken_when_sending() {
+        let psf = r#"@SEGMENT.FORMATS
+            DEFINE Msg
+              { NAME: time    ; TYPE: u64 },
+              { NAME: length  ; TYPE: u16 },
+              { NAME: payload ; TYPE: [u8; length.size_of] };
+            @SEGMENT.SEMANTICS
+            { FORMAT: Msg; FIELD: time;    SEMANTIC: TIMESTAMP(u64_millis, 30) };
+            { FORMAT: Msg; FIELD: length;  SEMANTIC: LENGTH };
+            { FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+            @SEGMENT.SEQUENCE
+            { ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+            { ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+        "#;
+        let psf = parser::parse_psf(psf).unwrap();
+
+        let args = compile_message_to_instrs(Role::Client, Role::Client, &"Msg".id(), &psf)
+            .into_iter()
+            .find_map(|ins| match ins {
+                InstructionV1::ConcretizeFormat(args) => Some(args),
+                _ => None,
+            })
+            .unwrap();
+
+        // The time we pick now is stale by the time we send the message, so we
+        // leave it to the VM to take the time when it builds the message.
+        assert_eq!(
+            args.timestamp_fields,
+            [("time".id(), TimestampUnit::U64Millis)]
+        );
+    }
+
     #[test]
     fn test_compile_shadow_socks() {
         let psf = parse_shadowsocks_psf().unwrap();
         let graph = compile_task_graph(psf.sequence.iter());
 
-        let tg = TaskGraphImpl {
-            graph,
-            my_role: Role::Client,
-            psf,
-        };
+        let tg = TaskGraphImpl::new(graph, Role::Client, psf).unwrap();
 
         let mut task_completed: TaskID = Default::default();
 
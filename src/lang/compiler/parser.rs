@@ -4,12 +4,13 @@ use core::str::FromStr;
 use std::collections::hash_map::HashMap;
 use std::fmt::Debug;
 
-use anyhow::Result;
+use anyhow::{Result, anyhow, bail};
 use pest::Parser;
 use pest::iterators::{Pair, Pairs};
 use pest_derive::Parser;
 
 use crate::lang::Role;
+use crate::lang::compiler::validate::{Diagnostic, ValidationError, validate_psf};
 use crate::lang::types::*;
 
 #[derive(Parser)]
@@ -23,6 +24,11 @@ fn print_type_of<T>(_: &T) {
     println!("{}", std::any::type_name::<T>())
 }
 
+fn span_of(p: &RulePair) -> Span {
+    let (line, col) = p.as_span().start_pos().line_col();
+    Span { line, col }
+}
+
 fn parse_simple<T: FromStr>(p: &RulePair) -> Result<T>
 where
     <T as std::str::FromStr>::Err: Debug,
@@ -219,6 +225,86 @@ fn parse_fixed_bytes_semantic(p: &RulePair) -> Result<FieldSemantic> {
     Ok(FieldSemantic::FixedBytes(parse_hex_literal(&p).unwrap()))
 }
 
+fn parse_integer_literal(p: &RulePair) -> Result<i128> {
+    assert!(p.as_rule() == Rule::integer_literal);
+
+    // Unwraps OK: ITR
+    let p = p.clone().into_inner().next().unwrap();
+
+    match p.as_rule() {
+        Rule::hex_literal => Ok(i128::from_str_radix(&p.as_str()[2..], 16)?),
+        _ => Ok(p.as_str().parse()?),
+    }
+}
+
+fn parse_value_literal(p: &RulePair) -> Result<PrimitiveValue> {
+    assert!(p.as_rule() == Rule::value_literal);
+
+    // Unwraps OK: ITR
+    let p = p.clone().into_inner().next().unwrap();
+
+    let value = match p.as_rule() {
+        Rule::integer_literal => PrimitiveValue::Int(parse_integer_literal(&p)?),
+        Rule::boolean => PrimitiveValue::Bool(parse_simple::<BoolType>(&p)?.into()),
+        // Strip the quotes and the backslash of an escaped quote or backslash.
+        Rule::char_literal => PrimitiveValue::Char(p.as_str().chars().nth_back(1).unwrap()),
+        _ => unreachable!(),
+    };
+
+    Ok(value)
+}
+
+fn parse_fixed_value_semantic(p: &RulePair) -> Result<FieldSemantic> {
+    assert!(p.as_rule() == Rule::fixed_value_semantic);
+
+    // Unwraps OK: ITR
+    let p = p.clone().into_inner().next().unwrap();
+
+    Ok(FieldSemantic::FixedValue(parse_value_literal(&p)?))
+}
+
+// A value without a weight has weight 1.
+fn parse_one_of_semantic(p: &RulePair) -> Result<FieldSemantic> {
+    assert!(p.as_rule() == Rule::one_of_semantic);
+
+    let choices = p
+        .clone()
+        .into_inner()
+        .map(|weighted| {
+            // Unwraps OK: ITR
+            let mut p = weighted.into_inner();
+            let value = parse_value_literal(&p.next().unwrap())?;
+            let weight = match p.next() {
+                Some(weight) => weight.as_str().parse()?,
+                None => 1,
+            };
+            Ok((value, weight))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(FieldSemantic::OneOf(choices))
+}
+
+fn parse_range_semantic(p: &RulePair) -> Result<FieldSemantic> {
+    assert!(p.as_rule() == Rule::range_semantic);
+
+    // Unwraps OK: ITR
+    let mut p = p.clone().into_inner();
+    let lo = parse_integer_literal(&p.next().unwrap())?;
+    let hi = parse_integer_literal(&p.next().unwrap())?;
+
+    Ok(FieldSemantic::Range(lo, hi))
+}
+
+fn parse_type_tag_semantic(p: &RulePair) -> Result<FieldSemantic> {
+    assert!(p.as_rule() == Rule::type_tag_semantic);
+
+    // Unwraps OK: ITR
+    let p = p.clone().into_inner().next().unwrap();
+
+    Ok(FieldSemantic::TypeTag(parse_integer_literal(&p)?))
+}
+
 fn parse_randomness_semantic(p: &RulePair) -> Result<FieldSemantic> {
     assert!(p.as_rule() == Rule::randomness_semantic);
 
@@ -230,6 +316,200 @@ fn parse_randomness_semantic(p: &RulePair) -> Result<FieldSemantic> {
     ))
 }
 
+fn parse_counter_semantic(p: &RulePair) -> Result<FieldSemantic> {
+    assert!(p.as_rule() == Rule::counter_semantic);
+
+    // Unwraps OK: ITR
+    let mut p = p.clone().into_inner();
+    let start = parse_integer_literal(&p.next().unwrap())?;
+    let step = parse_integer_literal(&p.next().unwrap())?;
+
+    Ok(FieldSemantic::Counter(start.try_into()?, step.try_into()?))
+}
+
+fn parse_save_as_semantic(p: &RulePair) -> Result<FieldSemantic> {
+    assert!(p.as_rule() == Rule::save_as_semantic);
+
+    // Unwrap OK: ITR
+    let name = parse_identifier(&p.clone().into_inner().next().unwrap())?;
+
+    Ok(FieldSemantic::SaveAs(name))
+}
+
+fn parse_echo_semantic(p: &RulePair) -> Result<FieldSemantic> {
+    assert!(p.as_rule() == Rule::echo_semantic);
+
+    // Unwrap OK: ITR
+    let name = parse_identifier(&p.clone().into_inner().next().unwrap())?;
+
+    Ok(FieldSemantic::Echo(name))
+}
+
+fn parse_timestamp_semantic(p: &RulePair) -> Result<FieldSemantic> {
+    assert!(p.as_rule() == Rule::timestamp_semantic);
+
+    // Unwrap OK: ITR
+    let mut p = p.clone().into_inner();
+    let unit = parse_simple(&p.next().unwrap())?;
+    let max_skew_secs = match p.next() {
+        Some(p) => Some(parse_positive_numeric_literal(&p)? as u64),
+        None => None,
+    };
+
+    Ok(FieldSemantic::Timestamp(unit, max_skew_secs))
+}
+
+fn parse_checksum_semantic(p: &RulePair) -> Result<FieldSemantic> {
+    assert!(p.as_rule() == Rule::checksum_semantic);
+
+    // Unwraps OK: ITR
+    let mut p = p.clone().into_inner();
+    let algorithm = parse_simple(&p.next().unwrap())?;
+    let from = parse_identifier(&p.next().unwrap())?;
+    let to = parse_identifier(&p.next().unwrap())?;
+
+    Ok(FieldSemantic::Checksum(algorithm, from, to))
+}
+
+fn parse_padding_size(p: &RulePair) -> Result<PaddingSize> {
+    assert!(p.as_rule() == Rule::padding_size);
+
+    let Some(p) = p.clone().into_inner().next() else {
+        return Ok(PaddingSize::Block);
+    };
+    // Unwraps OK: ITR
+    let sizes = p.clone().into_inner();
+
+    match p.as_rule() {
+        Rule::uniform_padding => Ok(PaddingSize::Uniform(parse_positive_numeric_literal(
+            &sizes.peek().unwrap(),
+        )?)),
+        Rule::buckets_padding => Ok(PaddingSize::Buckets(
+            sizes
+                .map(|size| parse_positive_numeric_literal(&size))
+                .collect::<Result<_>>()?,
+        )),
+        // A size without a weight has weight 1.
+        Rule::distribution_padding => Ok(PaddingSize::Distribution(
+            sizes
+                .map(|weighted| {
+                    let mut p = weighted.into_inner();
+                    let size = parse_positive_numeric_literal(&p.next().unwrap())?;
+                    let weight = match p.next() {
+                        Some(weight) => weight.as_str().parse()?,
+                        None => 1,
+                    };
+                    Ok((size, weight))
+                })
+                .collect::<Result<_>>()?,
+        )),
+        _ => unreachable!(),
+    }
+}
+
+fn parse_padding_fill(p: &RulePair) -> Result<PaddingFill> {
+    assert!(p.as_rule() == Rule::padding_fill);
+
+    match p.clone().into_inner().next() {
+        Some(byte) => Ok(PaddingFill::Constant(u8::from_str_radix(
+            &byte.as_str()[2..],
+            16,
+        )?)),
+        None => Ok(PaddingFill::Random),
+    }
+}
+
+fn parse_padding_semantic(p: &RulePair) -> Result<FieldSemantic> {
+    assert!(p.as_rule() == Rule::padding_semantic);
+
+    let mut policy = PaddingPolicy::default();
+    for p in p.clone().into_inner() {
+        match p.as_rule() {
+            Rule::padding_size => policy.size = parse_padding_size(&p)?,
+            Rule::padding_fill => policy.fill = parse_padding_fill(&p)?,
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(FieldSemantic::Padding(policy))
+}
+
+fn parse_size_of_range_op(p: &RulePair) -> Result<LengthExpr> {
+    assert!(p.as_rule() == Rule::size_of_range_op);
+
+    // Unwraps OK: ITR
+    let mut p = p.clone().into_inner();
+    let first = parse_identifier(&p.next().unwrap())?;
+    let last = parse_identifier(&p.next().unwrap())?;
+
+    Ok(LengthExpr::SizeOfRange(first, last))
+}
+
+fn parse_length_factor(p: &RulePair) -> Result<LengthExpr> {
+    assert!(p.as_rule() == Rule::length_factor);
+
+    // Unwraps OK: ITR
+    let p = p.clone().into_inner().next().unwrap();
+
+    match p.as_rule() {
+        Rule::size_of_range_op => parse_size_of_range_op(&p),
+        Rule::size_of_op => {
+            let UnaryOp::SizeOf(id) = parse_sizeof_op(&p)?;
+            Ok(LengthExpr::SizeOf(id))
+        }
+        Rule::positive_numeric_literal => Ok(LengthExpr::Literal(p.as_str().parse()?)),
+        Rule::length_expr => parse_length_expr(&p),
+        _ => unreachable!(),
+    }
+}
+
+fn parse_arith_op(p: &RulePair) -> ArithOp {
+    match p.as_str() {
+        "+" => ArithOp::Add,
+        "-" => ArithOp::Sub,
+        "*" => ArithOp::Mul,
+        _ => ArithOp::Div,
+    }
+}
+
+// Operators of the same precedence associate to the left.
+fn parse_length_term(p: &RulePair) -> Result<LengthExpr> {
+    assert!(p.as_rule() == Rule::length_term);
+
+    // Unwraps OK: ITR
+    let mut p = p.clone().into_inner();
+    let mut expr = parse_length_factor(&p.next().unwrap())?;
+    while let (Some(op), Some(rhs)) = (p.next(), p.next()) {
+        expr = LengthExpr::binary(expr, parse_arith_op(&op), parse_length_factor(&rhs)?);
+    }
+
+    Ok(expr)
+}
+
+fn parse_length_expr(p: &RulePair) -> Result<LengthExpr> {
+    assert!(p.as_rule() == Rule::length_expr);
+
+    // Unwraps OK: ITR
+    let mut p = p.clone().into_inner();
+    let mut expr = parse_length_term(&p.next().unwrap())?;
+    while let (Some(op), Some(rhs)) = (p.next(), p.next()) {
+        expr = LengthExpr::binary(expr, parse_arith_op(&op), parse_length_term(&rhs)?);
+    }
+
+    Ok(expr)
+}
+
+fn parse_length_semantic(p: &RulePair) -> Result<FieldSemantic> {
+    assert!(p.as_rule() == Rule::length_semantic);
+
+    let expr = match p.clone().into_inner().next() {
+        Some(ref expr) => Some(parse_length_expr(expr)?),
+        None => None,
+    };
+
+    Ok(FieldSemantic::Length(expr))
+}
+
 fn parse_field_semantic(p: &RulePair) -> Result<FieldSemantic> {
     assert!(p.as_rule() == Rule::field_semantic);
 
@@ -239,8 +519,19 @@ fn parse_field_semantic(p: &RulePair) -> Result<FieldSemantic> {
         match inner_p.as_rule() {
             Rule::fixed_string_semantic => parse_fixed_string_semantic(inner_p),
             Rule::fixed_bytes_semantic => parse_fixed_bytes_semantic(inner_p),
+            Rule::fixed_value_semantic => parse_fixed_value_semantic(inner_p),
+            Rule::one_of_semantic => parse_one_of_semantic(inner_p),
+            Rule::range_semantic => parse_range_semantic(inner_p),
+            Rule::type_tag_semantic => parse_type_tag_semantic(inner_p),
             Rule::randomness_semantic => parse_randomness_semantic(inner_p),
             Rule::pubkey_semantic => parse_pubkey_semantic(inner_p),
+            Rule::length_semantic => parse_length_semantic(inner_p),
+            Rule::padding_semantic => parse_padding_semantic(inner_p),
+            Rule::counter_semantic => parse_counter_semantic(inner_p),
+            Rule::save_as_semantic => parse_save_as_semantic(inner_p),
+            Rule::echo_semantic => parse_echo_semantic(inner_p),
+            Rule::timestamp_semantic => parse_timestamp_semantic(inner_p),
+            Rule::checksum_semantic => parse_checksum_semantic(inner_p),
             _ => unimplemented!(),
         }
     } else {
@@ -292,20 +583,97 @@ fn parse_phase(p: &RulePair) -> Result<Phase> {
 fn parse_sequence_specifier(p: &RulePair) -> Result<SequenceSpecifier> {
     assert!(p.as_rule() == Rule::sequence_specifier);
 
-    let mut p = p.clone().into_inner();
+    let mut role = None;
+    let mut phase = None;
+    let mut format = None;
+    let mut condition = None;
+    let mut transition = None;
+    let mut guard = None;
+
+    for p in p.clone().into_inner() {
+        match p.as_rule() {
+            Rule::transition => transition = Some(parse_transition(&p)?),
+            Rule::role => role = Some(parse_role(&p)?),
+            Rule::phase => phase = Some(parse_phase(&p)?),
+            Rule::identifier => format = Some(parse_identifier(&p)?),
+            Rule::send_condition => condition = Some(parse_send_condition(&p)?),
+            Rule::guard => guard = Some(parse_guard(&p)?),
+            _ => unreachable!(),
+        }
+    }
 
-    // Unwraps OK: ITR
-    let role = parse_role(&p.next().unwrap())?;
-    let phase = parse_phase(&p.next().unwrap())?;
-    let format = parse_identifier(&p.next().unwrap())?;
+    // A transition back to its state repeats like a DATA message.
+    let phase = match (phase, &transition) {
+        (Some(phase), _) => phase,
+        (None, Some(Transition { from, to })) if from == to => Phase::Data,
+        (None, _) => Phase::Handshake,
+    };
 
     Ok(SequenceSpecifier {
-        role,
+        // Unwraps OK: ITR
+        role: role.unwrap(),
         phase,
-        format,
+        format: format.unwrap(),
+        condition,
+        transition,
+        guard,
     })
 }
 
+fn parse_transition(p: &RulePair) -> Result<Transition> {
+    assert!(p.as_rule() == Rule::transition);
+
+    // Unwraps OK: ITR
+    let mut p = p.clone().into_inner();
+    let from = parse_identifier(&p.next().unwrap())?;
+    let to = parse_identifier(&p.next().unwrap())?;
+
+    Ok(Transition { from, to })
+}
+
+fn parse_guard(p: &RulePair) -> Result<Guard> {
+    assert!(p.as_rule() == Rule::guard);
+
+    // Unwraps OK: ITR
+    let mut p = p.clone().into_inner();
+    let field = parse_identifier(&p.next().unwrap())?;
+    let op = match p.next().unwrap().as_str() {
+        "==" => CompareOp::Eq,
+        "!=" => CompareOp::Ne,
+        "<" => CompareOp::Lt,
+        "<=" => CompareOp::Le,
+        ">" => CompareOp::Gt,
+        _ => CompareOp::Ge,
+    };
+    let value = parse_integer_literal(&p.next().unwrap())?;
+
+    Ok(Guard { field, op, value })
+}
+
+// A comparison with the payload size becomes the range of sizes it holds for.
+fn parse_send_condition(p: &RulePair) -> Result<SendCondition> {
+    assert!(p.as_rule() == Rule::send_condition);
+
+    let Some(p) = p.clone().into_inner().next() else {
+        return Ok(SendCondition::Cover);
+    };
+
+    // Unwraps OK: ITR
+    let mut p = p.into_inner();
+    let comparison = p.next().unwrap().as_str();
+    let nbytes: usize = p.next().unwrap().as_str().parse()?;
+
+    let range = match comparison {
+        "<" => 0..nbytes,
+        "<=" => 0..nbytes.saturating_add(1),
+        ">" => nbytes.saturating_add(1)..usize::MAX,
+        ">=" => nbytes..usize::MAX,
+        _ => unreachable!(),
+    };
+
+    Ok(SendCondition::PayloadNbytes(range))
+}
+
 fn parse_password_assignment(p: &RulePair) -> Result<Password> {
     assert!(p.as_rule() == Rule::password_assignment);
 
@@ -322,6 +690,74 @@ fn parse_password_assignment(p: &RulePair) -> Result<Password> {
     Ok(Password(p.as_str().to_string()))
 }
 
+fn parse_kdf(p: &RulePair) -> Result<Kdf> {
+    assert!(p.as_rule() == Rule::kdf);
+
+    // Unwrap OK: ITR
+    let p = p.clone().into_inner().next().unwrap();
+
+    match p.as_rule() {
+        Rule::argon2id_kdf => {
+            let (mut m_cost, mut t_cost, mut p_cost) = (None, None, None);
+
+            for param in p.into_inner() {
+                // Unwraps OK: ITR
+                let mut param = param.into_inner();
+                let name = param.next().unwrap();
+                let value = u32::try_from(parse_positive_numeric_literal(&param.next().unwrap())?)?;
+
+                let cost = match name.as_str() {
+                    "m" => &mut m_cost,
+                    "t" => &mut t_cost,
+                    "p" => &mut p_cost,
+                    _ => unimplemented!(),
+                };
+                if cost.replace(value).is_some() {
+                    bail!("Duplicate ARGON2ID parameter '{}'", name.as_str());
+                }
+            }
+
+            Ok(Kdf::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+            })
+        }
+        Rule::hkdf_kdf => Ok(Kdf::Hkdf),
+        _ => unimplemented!(),
+    }
+}
+
+fn parse_kdf_assignment(p: &RulePair) -> Result<Kdf> {
+    assert!(p.as_rule() == Rule::kdf_assignment);
+    // Unwrap OK: ITR
+    let p = p.clone().into_inner().next().unwrap();
+    parse_kdf(&p)
+}
+
+fn parse_salt_assignment(p: &RulePair) -> Result<Vec<u8>> {
+    assert!(p.as_rule() == Rule::salt_assignment);
+    // Unwraps OK: ITR
+    let p = p.clone().into_inner().next().unwrap();
+    parse_hex_literal(&p)
+}
+
+fn parse_key_label_assignment(p: &RulePair) -> Result<String> {
+    assert!(p.as_rule() == Rule::key_label_assignment);
+
+    // Unwraps OK: ITR
+    let p = p
+        .clone()
+        .into_inner()
+        .next()
+        .unwrap()
+        .into_inner()
+        .next()
+        .unwrap();
+
+    Ok(p.as_str().to_string())
+}
+
 fn parse_cipher(p: &RulePair) -> Result<Cipher> {
     assert!(p.as_rule() == Rule::cipher);
     parse_simple(p)
@@ -400,6 +836,9 @@ fn parse_crypto_segment(p: &RulePair) -> Result<CryptoSpec> {
     assert!(p.as_rule() == Rule::crypto_segment);
 
     let mut password: Option<Password> = None;
+    let mut kdf = Kdf::default();
+    let mut salt: Option<Vec<u8>> = None;
+    let mut key_label: Option<String> = None;
     let mut cipher: Option<Cipher> = None;
     let mut encryption_directives = vec![];
 
@@ -408,6 +847,15 @@ fn parse_crypto_segment(p: &RulePair) -> Result<CryptoSpec> {
             Rule::password_assignment => {
                 password = Some(parse_password_assignment(&e)?);
             }
+            Rule::kdf_assignment => {
+                kdf = parse_kdf_assignment(&e)?;
+            }
+            Rule::salt_assignment => {
+                salt = Some(parse_salt_assignment(&e)?);
+            }
+            Rule::key_label_assignment => {
+                key_label = Some(parse_key_label_assignment(&e)?);
+            }
             Rule::cipher_assignment => {
                 cipher = Some(parse_cipher_assignment(&e)?);
             }
@@ -420,6 +868,9 @@ fn parse_crypto_segment(p: &RulePair) -> Result<CryptoSpec> {
 
     Ok(CryptoSpec::new(
         password,
+        kdf,
+        salt,
+        key_label,
         cipher.unwrap(),
         encryption_directives.iter(),
     ))
@@ -432,77 +883,282 @@ fn parse_separate_length_field_setting(p: &RulePair) -> Result<BoolType> {
     Ok(value)
 }
 
+fn parse_coalesce_flights_setting(p: &RulePair) -> Result<BoolType> {
+    assert!(p.as_rule() == Rule::coalesce_flights_setting);
+    // Unwrap OK: ITR
+    parse_simple(&p.clone().into_inner().next().unwrap())
+}
+
+fn parse_split_write_after_setting(p: &RulePair) -> Result<Identifier> {
+    assert!(p.as_rule() == Rule::split_write_after_setting);
+    // Unwrap OK: ITR
+    parse_identifier(&p.clone().into_inner().next().unwrap())
+}
+
 fn parse_options_segment(p: &RulePair) -> Result<Options> {
     assert!(p.as_rule() == Rule::options_segment);
 
     let mut separate_length_fields = false;
+    let mut split_write_after = None;
+    let mut coalesce_flights = false;
 
     for e in p.clone().into_inner() {
         match e.as_rule() {
             Rule::separate_length_field_setting => {
                 separate_length_fields = parse_separate_length_field_setting(&e).unwrap().into();
             }
+            Rule::split_write_after_setting => {
+                split_write_after = Some(parse_split_write_after_setting(&e)?);
+            }
+            Rule::coalesce_flights_setting => {
+                coalesce_flights = parse_coalesce_flights_setting(&e)?.into();
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    Ok(Options::new(
+        separate_length_fields,
+        split_write_after,
+        coalesce_flights,
+    ))
+}
+
+fn parse_histogram(p: &RulePair) -> Result<Histogram> {
+    assert!(p.as_rule() == Rule::histogram);
+
+    let mut bins = vec![];
+    for bin in p.clone().into_inner() {
+        // Unwraps OK: ITR
+        let mut p = bin.into_inner();
+        let low = p.next().unwrap().as_str().parse()?;
+        let (mut high, mut weight) = (low, 1);
+        for e in p {
+            let value = e.clone().into_inner().next().unwrap().as_str();
+            match e.as_rule() {
+                Rule::bin_high => high = value.parse()?,
+                Rule::bin_weight => weight = value.parse()?,
+                _ => unimplemented!(),
+            }
+        }
+        bins.push((low, high, weight));
+    }
+
+    Ok(Histogram { bins })
+}
+
+fn parse_timing_segment(p: &RulePair) -> Result<TimingSpec> {
+    assert!(p.as_rule() == Rule::timing_segment);
+
+    let mut timing = TimingSpec::default();
+
+    for e in p.clone().into_inner() {
+        // Unwrap OK: ITR
+        let histogram = Some(parse_histogram(&e.clone().into_inner().next().unwrap())?);
+        match e.as_rule() {
+            Rule::message_delay_setting => timing.message_delay = histogram,
+            Rule::burst_size_setting => timing.burst_size = histogram,
+            Rule::burst_delay_setting => timing.burst_delay = histogram,
+            Rule::cover_delay_setting => timing.cover_delay = histogram,
             _ => unimplemented!(),
         }
     }
 
-    Ok(Options::new(separate_length_fields))
+    Ok(timing)
 }
 
-fn parse_psf_impl(p: &RulePair) -> Result<Psf> {
+// Also returns diagnostics for definitions that conflict with earlier ones,
+// which we drop so that the rest of the PSF can still be validated.
+fn parse_psf_impl(p: &RulePair) -> Result<(Psf, Vec<Diagnostic>)> {
     assert!(p.as_rule() == Rule::psf);
 
     let mut formats: HashMap<Identifier, AbstractFormatAndSemantics> = Default::default();
     let mut sequence: Vec<SequenceSpecifier> = vec![];
     let mut crypto_spec: Option<CryptoSpec> = None;
     let mut options: Option<Options> = None;
+    let mut timing_spec: Option<TimingSpec> = None;
+    let mut source_map = SourceMap::default();
+    let mut diagnostics = vec![];
 
     let p = p.clone().into_inner();
 
     for x in p {
+        let span = span_of(&x);
+
         match x.as_rule() {
             Rule::format => {
-                let format: AbstractFormatAndSemantics =
-                    Into::<AbstractFormat>::into(parse_format(&x)?).into();
-                formats.insert(format.format.format.name.clone(), format);
+                let format = parse_format(&x)?;
+                let format_id = format.name.clone();
+
+                if source_map.formats.insert(format_id.clone(), span).is_some() {
+                    diagnostics.push(Diagnostic::new(
+                        Some(span),
+                        format!("format `{}` is already defined", format_id.0),
+                    ));
+                    continue;
+                }
+
+                // Unwraps OK: ITR
+                for f in x.into_inner().skip(1) {
+                    let field_id = parse_name_value(&f.clone().into_inner().next().unwrap())?;
+                    let key = (format_id.clone(), field_id.clone());
+                    if source_map.fields.insert(key, span_of(&f)).is_some() {
+                        diagnostics.push(Diagnostic::new(
+                            Some(span_of(&f)),
+                            format!(
+                                "field `{}` is already defined in format `{}`",
+                                field_id.0, format_id.0
+                            ),
+                        ));
+                    }
+                }
+
+                formats.insert(format_id, Into::<AbstractFormat>::into(format).into());
             }
             Rule::semantic_binding => {
                 let sem_binding = parse_semantic_binding(&x)?;
-                formats
-                    .get_mut(&sem_binding.format)
-                    .unwrap()
-                    .semantics
-                    .as_mut_ref()
-                    .insert(sem_binding.field.clone(), sem_binding.semantic);
+
+                let Some(afs) = formats.get_mut(&sem_binding.format) else {
+                    diagnostics.push(Diagnostic::new(
+                        Some(span),
+                        format!(
+                            "semantic bound to undefined format `{}`",
+                            sem_binding.format.0
+                        ),
+                    ));
+                    continue;
+                };
+
+                let semantics = afs.semantics.as_mut_ref();
+                match semantics.get(&sem_binding.field) {
+                    Some(semantic) if *semantic != sem_binding.semantic => {
+                        diagnostics.push(Diagnostic::new(
+                            Some(span),
+                            format!(
+                                "field `{}` of format `{}` is already bound to another semantic",
+                                sem_binding.field.0, sem_binding.format.0
+                            ),
+                        ));
+                    }
+                    Some(_) => {}
+                    None => {
+                        semantics.insert(sem_binding.field.clone(), sem_binding.semantic);
+                        source_map
+                            .semantics
+                            .insert((sem_binding.format, sem_binding.field), span);
+                    }
+                }
             }
             Rule::sequence_specifier => {
                 let seqspec = parse_sequence_specifier(&x)?;
                 sequence.push(seqspec);
+                source_map.sequence.push(span);
             }
             Rule::crypto_segment => {
                 crypto_spec = Some(parse_crypto_segment(&x)?);
+                source_map.crypto = Some(span);
+
+                for d in x
+                    .into_inner()
+                    .filter(|e| e.as_rule() == Rule::encryption_directives)
+                {
+                    let directives = parse_encryption_directives(&d)?;
+                    let format_id = directives.enc_fmt_bnd.to_format_name;
+
+                    if source_map
+                        .encryption_directives
+                        .insert(format_id.clone(), span_of(&d))
+                        .is_some()
+                    {
+                        diagnostics.push(Diagnostic::new(
+                            Some(span_of(&d)),
+                            format!("format `{}` is already encrypted", format_id.0),
+                        ));
+                        continue;
+                    }
+
+                    for (i, f) in d.into_inner().skip(1).enumerate() {
+                        source_map
+                            .encryption_field_directives
+                            .insert((format_id.clone(), i), span_of(&f));
+                    }
+                }
             }
             Rule::options_segment => {
                 options = Some(parse_options_segment(&x)?);
+                for e in x.into_inner() {
+                    let (name, setting) = match e.as_rule() {
+                        Rule::separate_length_field_setting => (
+                            "SEPARATE_LENGTH_FIELD",
+                            &mut source_map.separate_length_field,
+                        ),
+                        Rule::split_write_after_setting => {
+                            ("SPLIT_WRITE_AFTER", &mut source_map.split_write_after)
+                        }
+                        Rule::coalesce_flights_setting => {
+                            ("COALESCE_FLIGHTS", &mut source_map.coalesce_flights)
+                        }
+                        _ => unimplemented!(),
+                    };
+                    if setting.replace(span_of(&e)).is_some() {
+                        diagnostics.push(Diagnostic::new(
+                            Some(span_of(&e)),
+                            format!("option `{name}` is already set"),
+                        ));
+                    }
+                }
+            }
+            Rule::timing_segment => {
+                timing_spec = Some(parse_timing_segment(&x)?);
+                for e in x.into_inner() {
+                    let (name, setting) = match e.as_rule() {
+                        Rule::message_delay_setting => {
+                            ("MESSAGE_DELAY", &mut source_map.message_delay)
+                        }
+                        Rule::burst_size_setting => ("BURST_SIZE", &mut source_map.burst_size),
+                        Rule::burst_delay_setting => ("BURST_DELAY", &mut source_map.burst_delay),
+                        Rule::cover_delay_setting => ("COVER_DELAY", &mut source_map.cover_delay),
+                        _ => unimplemented!(),
+                    };
+                    if setting.replace(span_of(&e)).is_some() {
+                        diagnostics.push(Diagnostic::new(
+                            Some(span_of(&e)),
+                            format!("timing setting `{name}` is already set"),
+                        ));
+                    }
+                }
             }
             _ => {}
         }
     }
 
-    Ok(Psf {
+    let psf = Psf {
         formats,
         sequence,
         crypto_spec,
         options,
-    })
+        timing_spec,
+        source_map,
+    };
+    Ok((psf, diagnostics))
 }
 
+/// Parses and validates the PSF, failing with the location of every problem
+/// we found in it.
 pub fn parse_psf(psf_contents: &str) -> Result<Psf> {
     let rule = Rule::psf;
-    let mut p = ProteusLiteParser::parse(rule, psf_contents).expect("Unsuccessful parse");
+    let mut p = ProteusLiteParser::parse(rule, psf_contents)
+        .map_err(|e| anyhow!("Invalid protocol specification:\n{e}"))?;
+    // Unwrap OK: ITR
     let pair = p.next().unwrap();
-    let psf = parse_psf_impl(&pair)?;
-    assert!(psf.is_valid());
+
+    let (psf, mut diagnostics) = parse_psf_impl(&pair)?;
+    diagnostics.extend(validate_psf(&psf));
+
+    if !diagnostics.is_empty() {
+        diagnostics.sort_by_key(|d| d.span);
+        bail!(ValidationError(diagnostics));
+    }
     Ok(psf)
 }
 
@@ -548,6 +1204,12 @@ pub mod tests {
             ("i16", NumericType::I16),
             ("i32", NumericType::I32),
             ("i64", NumericType::I64),
+            ("u16le", NumericType::U16Le),
+            ("u32le", NumericType::U32Le),
+            ("u64le", NumericType::U64Le),
+            ("i16le", NumericType::I16Le),
+            ("i32le", NumericType::I32Le),
+            ("i64le", NumericType::I64Le),
         ];
 
         test_rule_pair(test_cases.iter(), Rule::numeric_type, parse_numeric_type);
@@ -557,6 +1219,11 @@ pub mod tests {
     fn test_parse_primitive_type() {
         let test_cases = [
             ("u8", NumericType::U8.into()),
+            ("u32le", NumericType::U32Le.into()),
+            ("quic_varint", PrimitiveType::VarInt(VarIntType::Quic, None)),
+            ("leb128", PrimitiveType::VarInt(VarIntType::Leb128, None)),
+            ("u1", PrimitiveType::Bits(1)),
+            ("u7", PrimitiveType::Bits(7)),
             ("bool", PrimitiveType::Bool),
             ("char", PrimitiveType::Char),
         ];
@@ -716,14 +1383,97 @@ pub mod tests {
     fn test_parse_field_semantic() {
         let test_cases = vec![
             ("PAYLOAD", FieldSemantic::Payload),
-            ("PADDING", FieldSemantic::Padding),
+            ("PADDING", FieldSemantic::Padding(PaddingPolicy::default())),
+            (
+                "PADDING(UNIFORM(64), RANDOM)",
+                FieldSemantic::Padding(PaddingPolicy {
+                    size: PaddingSize::Uniform(64),
+                    fill: PaddingFill::Random,
+                }),
+            ),
+            (
+                "PADDING(BUCKETS(256, 512, 1500))",
+                FieldSemantic::Padding(PaddingPolicy {
+                    size: PaddingSize::Buckets(vec![256, 512, 1500]),
+                    fill: PaddingFill::Constant(0xff),
+                }),
+            ),
+            (
+                "PADDING(DISTRIBUTION(100 : 3, 1500), 0x00)",
+                FieldSemantic::Padding(PaddingPolicy {
+                    size: PaddingSize::Distribution(vec![(100, 3), (1500, 1)]),
+                    fill: PaddingFill::Constant(0x00),
+                }),
+            ),
+            (
+                "PADDING(RANDOM)",
+                FieldSemantic::Padding(PaddingPolicy {
+                    size: PaddingSize::Block,
+                    fill: PaddingFill::Random,
+                }),
+            ),
             ("PADDING_LENGTH", FieldSemantic::PaddingLength),
-            ("LENGTH", FieldSemantic::Length),
+            ("COVER", FieldSemantic::Cover),
+            ("SALT", FieldSemantic::Salt),
+            ("TYPE_TAG(0x17)", FieldSemantic::TypeTag(0x17)),
+            ("COUNTER(0, 1)", FieldSemantic::Counter(0, 1)),
+            ("COUNTER(0x100, 2)", FieldSemantic::Counter(0x100, 2)),
+            (
+                "SAVE_AS(session_id)",
+                FieldSemantic::SaveAs("session_id".id()),
+            ),
+            ("ECHO(session_id)", FieldSemantic::Echo("session_id".id())),
+            (
+                "TIMESTAMP(u32_seconds)",
+                FieldSemantic::Timestamp(TimestampUnit::U32Seconds, None),
+            ),
+            (
+                "TIMESTAMP(u64_millis, 30)",
+                FieldSemantic::Timestamp(TimestampUnit::U64Millis, Some(30)),
+            ),
+            (
+                "CHECKSUM(CRC32, header..payload)",
+                FieldSemantic::Checksum(ChecksumAlgorithm::Crc32, "header".id(), "payload".id()),
+            ),
+            (
+                "CHECKSUM(HMAC_SHA256, a..a)",
+                FieldSemantic::Checksum(ChecksumAlgorithm::HmacSha256, "a".id(), "a".id()),
+            ),
+            ("LENGTH", FieldSemantic::Length(None)),
             (
                 "FIXED_STRING(\"foo\")",
                 FieldSemantic::FixedString("foo".to_string()),
             ),
             ("FIXED_BYTES(0x1)", FieldSemantic::FixedBytes([1].to_vec())),
+            (
+                "FIXED_VALUE(-3)",
+                FieldSemantic::FixedValue(PrimitiveValue::Int(-3)),
+            ),
+            (
+                "FIXED_VALUE(true)",
+                FieldSemantic::FixedValue(PrimitiveValue::Bool(true)),
+            ),
+            (
+                "FIXED_VALUE('a')",
+                FieldSemantic::FixedValue(PrimitiveValue::Char('a')),
+            ),
+            (
+                "FIXED_VALUE('\\'')",
+                FieldSemantic::FixedValue(PrimitiveValue::Char('\'')),
+            ),
+            (
+                "FIXED_VALUE(0x0303)",
+                FieldSemantic::FixedValue(PrimitiveValue::Int(0x0303)),
+            ),
+            (
+                "ONE_OF(0x0301, 0x0303 : 3, 'a')",
+                FieldSemantic::OneOf(vec![
+                    (PrimitiveValue::Int(0x0301), 1),
+                    (PrimitiveValue::Int(0x0303), 3),
+                    (PrimitiveValue::Char('a'), 1),
+                ]),
+            ),
+            ("RANGE(-1, 0x10)", FieldSemantic::Range(-1, 16)),
             ("RANDOM(1337)", FieldSemantic::Random(1337)),
             ("PUBKEY(RAW)", FieldSemantic::Pubkey(PubkeyEncoding::Raw)),
         ];
@@ -735,6 +1485,48 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_length_semantic() {
+        let size_of = |id: &str| LengthExpr::SizeOf(id.id());
+        let test_cases = [
+            ("LENGTH", FieldSemantic::Length(None)),
+            (
+                "LENGTH(payload.size_of + 5)",
+                FieldSemantic::Length(Some(LengthExpr::binary(
+                    size_of("payload"),
+                    ArithOp::Add,
+                    LengthExpr::Literal(5),
+                ))),
+            ),
+            (
+                "LENGTH((header..mac).size_of / 4)",
+                FieldSemantic::Length(Some(LengthExpr::binary(
+                    LengthExpr::SizeOfRange("header".id(), "mac".id()),
+                    ArithOp::Div,
+                    LengthExpr::Literal(4),
+                ))),
+            ),
+            (
+                "LENGTH(a.size_of - b.size_of - 2 * (c.size_of + 1))",
+                FieldSemantic::Length(Some(LengthExpr::binary(
+                    LengthExpr::binary(size_of("a"), ArithOp::Sub, size_of("b")),
+                    ArithOp::Sub,
+                    LengthExpr::binary(
+                        LengthExpr::Literal(2),
+                        ArithOp::Mul,
+                        LengthExpr::binary(size_of("c"), ArithOp::Add, LengthExpr::Literal(1)),
+                    ),
+                ))),
+            ),
+        ];
+
+        test_rule_pair(
+            test_cases.iter(),
+            Rule::length_semantic,
+            parse_length_semantic,
+        );
+    }
+
     #[test]
     fn test_parse_semantic_binding() {
         let s = "{ FORMAT: Foo; FIELD: Bar; SEMANTIC: PAYLOAD };";
@@ -800,9 +1592,72 @@ pub mod tests {
             role: Role::Client,
             phase: Phase::Handshake,
             format: "Foo".id(),
+            condition: None,
+            transition: None,
+            guard: None,
+        };
+
+        let data = |format: &str, condition| SequenceSpecifier {
+            role: Role::Server,
+            phase: Phase::Data,
+            format: format.id(),
+            condition: Some(condition),
+            transition: None,
+            guard: None,
+        };
+
+        let transition = |from: &str, to: &str, phase, guard| SequenceSpecifier {
+            role: Role::Server,
+            phase,
+            format: "Reply".id(),
+            condition: None,
+            transition: Some(Transition {
+                from: from.id(),
+                to: to.id(),
+            }),
+            guard,
         };
 
-        let test_cases = [(s, ss)];
+        let test_cases = [
+            (s, ss),
+            (
+                "{ ROLE: SERVER; PHASE: DATA; FORMAT: Idle; WHEN: COVER };",
+                data("Idle", SendCondition::Cover),
+            ),
+            (
+                "{ ROLE: SERVER; PHASE: DATA; FORMAT: Small; WHEN: PAYLOAD < 512 };",
+                data("Small", SendCondition::PayloadNbytes(0..512)),
+            ),
+            (
+                "{ ROLE: SERVER; PHASE: DATA; FORMAT: Small; WHEN: PAYLOAD <= 512 };",
+                data("Small", SendCondition::PayloadNbytes(0..513)),
+            ),
+            (
+                "{ ROLE: SERVER; PHASE: DATA; FORMAT: Large; WHEN: PAYLOAD > 512 };",
+                data("Large", SendCondition::PayloadNbytes(513..usize::MAX)),
+            ),
+            (
+                "{ ROLE: SERVER; PHASE: DATA; FORMAT: Large; WHEN: PAYLOAD >= 512 };",
+                data("Large", SendCondition::PayloadNbytes(512..usize::MAX)),
+            ),
+            (
+                "{ FROM: Hello; TO: Done; ROLE: SERVER; FORMAT: Reply };",
+                transition("Hello", "Done", Phase::Handshake, None),
+            ),
+            (
+                "{ FROM: Done; TO: Done; ROLE: SERVER; FORMAT: Reply; GUARD: status != 0x2 };",
+                transition(
+                    "Done",
+                    "Done",
+                    Phase::Data,
+                    Some(Guard {
+                        field: "status".id(),
+                        op: CompareOp::Ne,
+                        value: 2,
+                    }),
+                ),
+            ),
+        ];
 
         test_rule_pair(
             test_cases.iter(),
@@ -825,6 +1680,68 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_salt() {
+        let test_cases = [
+            (
+                "SALT = 0x0011223344556677;",
+                [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77].to_vec(),
+            ),
+            ("SALT = 0xabcdef;", [0xab, 0xcd, 0xef].to_vec()),
+        ];
+
+        test_rule_pair(
+            test_cases.iter(),
+            Rule::salt_assignment,
+            parse_salt_assignment,
+        );
+    }
+
+    #[test]
+    fn test_parse_kdf() {
+        let test_cases = [
+            ("KDF = ARGON2ID;", Kdf::default()),
+            (
+                "KDF = ARGON2ID(m=19456, t=2, p=1);",
+                Kdf::Argon2id {
+                    m_cost: Some(19456),
+                    t_cost: Some(2),
+                    p_cost: Some(1),
+                },
+            ),
+            (
+                "KDF = ARGON2ID(t=4);",
+                Kdf::Argon2id {
+                    m_cost: None,
+                    t_cost: Some(4),
+                    p_cost: None,
+                },
+            ),
+            ("KDF = HKDF;", Kdf::Hkdf),
+        ];
+
+        test_rule_pair(
+            test_cases.iter(),
+            Rule::kdf_assignment,
+            parse_kdf_assignment,
+        );
+
+        let mut pairs =
+            ProteusLiteParser::parse(Rule::kdf_assignment, "KDF = ARGON2ID(m=8, m=16);").unwrap();
+        assert!(parse_kdf_assignment(&pairs.next().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_parse_key_label() {
+        let test_cases = [("KEY_LABEL = \"example v1\";", "example v1".to_string())];
+
+        test_rule_pair(
+            test_cases.iter(),
+            Rule::key_label_assignment,
+            parse_key_label_assignment,
+        );
+    }
+
     #[test]
     fn test_parse_cipher_assignment() {
         let test_cases = [
@@ -931,6 +1848,9 @@ pub mod tests {
     fn test_parse_crypto_segment() {
         let input = "@SEGMENT.CRYPTO\
             PASSWORD = \"hunter2\";\
+            KDF      = ARGON2ID(m=65536, t=3);\
+            SALT     = 0x0011223344556677;\
+            KEY_LABEL = \"example v1\";\
             CIPHER   = CHACHA20-POLY1305;\
             ENCRYPT EncDataMsg FROM DataMsg\
             { PTEXT: length;  CTEXT: enc_length;  MAC: length_mac },\
@@ -962,7 +1882,16 @@ pub mod tests {
             enc_field_dirs,
         }];
 
-        let output = CryptoSpec::new(password, cipher, directives.iter());
+        let salt = Some([0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77].to_vec());
+
+        let kdf = Kdf::Argon2id {
+            m_cost: Some(65536),
+            t_cost: Some(3),
+            p_cost: None,
+        };
+        let key_label = Some("example v1".to_string());
+
+        let output = CryptoSpec::new(password, kdf, salt, key_label, cipher, directives.iter());
 
         let test_cases = [(input, output)];
 
@@ -987,16 +1916,58 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_coalesce_flights_setting() {
+        let test_cases = [
+            ("COALESCE_FLIGHTS = true;", BoolType::True),
+            ("COALESCE_FLIGHTS = false;", BoolType::False),
+        ];
+
+        test_rule_pair(
+            test_cases.iter(),
+            Rule::coalesce_flights_setting,
+            parse_coalesce_flights_setting,
+        );
+    }
+
+    #[test]
+    fn test_parse_split_write_after_setting() {
+        let test_cases = [("SPLIT_WRITE_AFTER = hdr_len;", "hdr_len".id())];
+
+        test_rule_pair(
+            test_cases.iter(),
+            Rule::split_write_after_setting,
+            parse_split_write_after_setting,
+        );
+    }
+
     #[test]
     fn test_parse_options_segment() {
         let test_cases = [
             (
                 "@SEGMENT.OPTIONS SEPARATE_LENGTH_FIELD = true;",
-                Options::new(true),
+                Options::new(true, None, false),
             ),
             (
                 "@SEGMENT.OPTIONS SEPARATE_LENGTH_FIELD = false;",
-                Options::new(false),
+                Options::new(false, None, false),
+            ),
+            (
+                "@SEGMENT.OPTIONS SPLIT_WRITE_AFTER = hdr_len;",
+                Options::new(false, Some("hdr_len".id()), false),
+            ),
+            (
+                "@SEGMENT.OPTIONS SEPARATE_LENGTH_FIELD = true; SPLIT_WRITE_AFTER = header;",
+                Options::new(true, Some("header".id()), false),
+            ),
+            (
+                "@SEGMENT.OPTIONS COALESCE_FLIGHTS = true;",
+                Options::new(false, None, true),
+            ),
+            (
+                "@SEGMENT.OPTIONS COALESCE_FLIGHTS = true; SPLIT_WRITE_AFTER = header; \
+                 SEPARATE_LENGTH_FIELD = true;",
+                Options::new(true, Some("header".id()), true),
             ),
         ];
 
@@ -1007,6 +1978,74 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_timing_segment() {
+        let test_cases = [
+            ("@SEGMENT.TIMING", TimingSpec::default()),
+            (
+                "@SEGMENT.TIMING MESSAGE_DELAY = HISTOGRAM(0..10 : 3, 50); BURST_SIZE = \
+                 HISTOGRAM(1400);",
+                TimingSpec {
+                    message_delay: Some(Histogram {
+                        bins: vec![(0, 10, 3), (50, 50, 1)],
+                    }),
+                    burst_size: Some(Histogram {
+                        bins: vec![(1400, 1400, 1)],
+                    }),
+                    burst_delay: None,
+                    cover_delay: None,
+                },
+            ),
+            (
+                "@SEGMENT.TIMING BURST_SIZE = HISTOGRAM(500..1500); BURST_DELAY = HISTOGRAM(1..5 \
+                 : 0, 20 : 2);",
+                TimingSpec {
+                    message_delay: None,
+                    burst_size: Some(Histogram {
+                        bins: vec![(500, 1500, 1)],
+                    }),
+                    burst_delay: Some(Histogram {
+                        bins: vec![(1, 5, 0), (20, 20, 2)],
+                    }),
+                    cover_delay: None,
+                },
+            ),
+            (
+                "@SEGMENT.TIMING COVER_DELAY = HISTOGRAM(100..1000);",
+                TimingSpec {
+                    cover_delay: Some(Histogram {
+                        bins: vec![(100, 1000, 1)],
+                    }),
+                    ..Default::default()
+                },
+            ),
+            (
+                "@SEGMENT.TIMING COVER_DELAY = HISTOGRAM(100); BURST_DELAY = HISTOGRAM(2); \
+                 BURST_SIZE = HISTOGRAM(1400); MESSAGE_DELAY = HISTOGRAM(5);",
+                TimingSpec {
+                    message_delay: Some(Histogram {
+                        bins: vec![(5, 5, 1)],
+                    }),
+                    burst_size: Some(Histogram {
+                        bins: vec![(1400, 1400, 1)],
+                    }),
+                    burst_delay: Some(Histogram {
+                        bins: vec![(2, 2, 1)],
+                    }),
+                    cover_delay: Some(Histogram {
+                        bins: vec![(100, 100, 1)],
+                    }),
+                },
+            ),
+        ];
+
+        test_rule_pair(
+            test_cases.iter(),
+            Rule::timing_segment,
+            parse_timing_segment,
+        );
+    }
+
     pub fn parse_example_psf() -> Result<Psf> {
         let filepath = "tests/fixtures/handshake_with_payload.psf";
         let input = fs::read_to_string(filepath).expect("cannot read example file");
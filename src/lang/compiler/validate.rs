@@ -0,0 +1,3361 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::mem::discriminant;
+use std::ops::Range;
+
+use itertools::Itertools;
+
+use crate::crypto::kdf;
+use crate::lang::Role;
+use crate::lang::types::*;
+
+/// A problem with a PSF, located at the definition it concerns.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub span: Option<Span>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(span: Option<Span>, message: String) -> Self {
+        Self { span, message }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{}: {}", span, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Every problem we found in a PSF, ordered by where they occur.
+#[derive(Debug)]
+pub struct ValidationError(pub Vec<Diagnostic>);
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid protocol specification:")?;
+        for diagnostic in &self.0 {
+            write!(f, "\n  {}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Checks the invariants the compiler relies on to generate instructions, and
+/// returns a diagnostic for every violation.
+pub fn validate_psf(psf: &Psf) -> Vec<Diagnostic> {
+    let mut validator = Validator {
+        psf,
+        diagnostics: vec![],
+    };
+
+    validator.check_formats();
+    validator.check_variables();
+    validator.check_sequence();
+    validator.check_crypto();
+    validator.check_options();
+    validator.check_timing();
+
+    validator.diagnostics
+}
+
+struct Validator<'a> {
+    psf: &'a Psf,
+    diagnostics: Vec<Diagnostic>,
+}
+
+fn semantic_name(semantic: &FieldSemantic) -> &'static str {
+    match semantic {
+        FieldSemantic::Payload => "PAYLOAD",
+        FieldSemantic::Padding(_) => "PADDING",
+        FieldSemantic::PaddingLength => "PADDING_LENGTH",
+        FieldSemantic::Length(_) => "LENGTH",
+        FieldSemantic::FixedString(_) => "FIXED_STRING",
+        FieldSemantic::FixedBytes(_) => "FIXED_BYTES",
+        FieldSemantic::FixedValue(_) => "FIXED_VALUE",
+        FieldSemantic::OneOf(_) => "ONE_OF",
+        FieldSemantic::Range(..) => "RANGE",
+        FieldSemantic::Random(_) => "RANDOM",
+        FieldSemantic::Pubkey(_) => "PUBKEY",
+        FieldSemantic::Salt => "SALT",
+        FieldSemantic::Cover => "COVER",
+        FieldSemantic::TypeTag(_) => "TYPE_TAG",
+        FieldSemantic::Counter(..) => "COUNTER",
+        FieldSemantic::SaveAs(_) => "SAVE_AS",
+        FieldSemantic::Echo(_) => "ECHO",
+        FieldSemantic::Timestamp(..) => "TIMESTAMP",
+        FieldSemantic::Checksum(..) => "CHECKSUM",
+    }
+}
+
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::Client => "CLIENT",
+        Role::Server => "SERVER",
+    }
+}
+
+// Length and cover fields are read and written as single unsigned integers,
+// which may be varints or bitfields.
+fn unsigned_integer(dtype: &Array) -> Option<PrimitiveType> {
+    match dtype {
+        Array::Primitive(PrimitiveArray(t @ PrimitiveType::Numeric(n), 1)) if n.is_unsigned() => {
+            Some(*t)
+        }
+        Array::Primitive(PrimitiveArray(
+            t @ (PrimitiveType::VarInt(..) | PrimitiveType::Bits(_)),
+            1,
+        )) => Some(*t),
+        _ => None,
+    }
+}
+
+fn is_varint(field: &Field) -> bool {
+    matches!(
+        field.dtype,
+        Array::Primitive(PrimitiveArray(PrimitiveType::VarInt(..), _))
+    )
+}
+
+// The fields the expression counts apart from the rest of their bit group,
+// either on their own or as the end of a range that splits the group.
+fn split_bitfields<'a>(format: &Format, expr: &'a LengthExpr) -> Vec<&'a Identifier> {
+    let group = |id: &Identifier| format.bit_group(id).filter(|group| group.len() > 1);
+    match expr {
+        LengthExpr::Literal(_) => vec![],
+        LengthExpr::SizeOf(id) => match group(id) {
+            Some(_) => vec![id],
+            None => vec![],
+        },
+        LengthExpr::SizeOfRange(first, last) => {
+            let mut ids = vec![];
+            if group(first).is_some_and(|group| group[0].name != *first) {
+                ids.push(first);
+            }
+            if group(last).is_some_and(|group| group[group.len() - 1].name != *last) {
+                ids.push(last);
+            }
+            ids
+        }
+        LengthExpr::Binary(lhs, _, rhs) => {
+            let mut ids = split_bitfields(format, lhs);
+            ids.extend(split_bitfields(format, rhs));
+            ids
+        }
+    }
+}
+
+// Whether the expression adds the size of the field once, so that each byte
+// of the field adds one to its value.
+fn adds_once(expr: &LengthExpr, field_id: &Identifier) -> bool {
+    let counts = |e: &LengthExpr| e.field_ids().contains(&field_id);
+    match expr {
+        LengthExpr::SizeOf(id) => id == field_id,
+        LengthExpr::Binary(lhs, ArithOp::Add, rhs) => match (counts(lhs), counts(rhs)) {
+            (true, false) => adds_once(lhs, field_id),
+            (false, true) => adds_once(rhs, field_id),
+            _ => false,
+        },
+        LengthExpr::Binary(lhs, ArithOp::Sub, rhs) => !counts(rhs) && adds_once(lhs, field_id),
+        _ => false,
+    }
+}
+
+// Collects the dividend and divisor of every division in the expression, if we
+// know them before we run the protocol.
+fn static_divisions(
+    format: &Format,
+    semantics: &Semantics,
+    expr: &LengthExpr,
+    divisions: &mut Vec<(Option<i128>, Option<i128>)>,
+) {
+    let LengthExpr::Binary(lhs, op, rhs) = expr else {
+        return;
+    };
+    if *op == ArithOp::Div {
+        let nbytes = |field: &Field| match field.dtype {
+            Array::Primitive(_) => field.maybe_size_of().map(|n| n as i128),
+            Array::Dynamic(_) => semantics
+                .as_ref()
+                .get(&field.name)
+                .and_then(|s| s.value_nbytes())
+                .map(|n| n as i128),
+        };
+        divisions.push((lhs.evaluate(format, &nbytes), rhs.evaluate(format, &nbytes)));
+    }
+    static_divisions(format, semantics, lhs, divisions);
+    static_divisions(format, semantics, rhs, divisions);
+}
+
+fn position(format: &Format, field_id: &Identifier) -> Option<usize> {
+    format.fields.iter().position(|f| f.name == *field_id)
+}
+
+// Whether the field comes before the first variable-length field, so that a
+// receiver reads it before it knows the size of the rest of the message.
+fn in_fixed_prefix(format: &Format, field_id: &Identifier) -> bool {
+    let (prefix, _) = format.split_into_fixed_sized_prefix_dynamic_suffix();
+    prefix.try_get_field_by_name(field_id).is_some()
+}
+
+impl Validator<'_> {
+    fn error(&mut self, span: Option<Span>, message: String) {
+        self.diagnostics.push(Diagnostic::new(span, message));
+    }
+
+    fn field_span(&self, format_id: &Identifier, field_id: &Identifier) -> Option<Span> {
+        let key = (format_id.clone(), field_id.clone());
+        self.psf.source_map.fields.get(&key).copied()
+    }
+
+    fn semantic_span(&self, format_id: &Identifier, field_id: &Identifier) -> Option<Span> {
+        let key = (format_id.clone(), field_id.clone());
+        self.psf.source_map.semantics.get(&key).copied()
+    }
+
+    // The fields bound to the same kind of semantic as `semantic`, in the
+    // order they were bound.
+    fn find_fields(
+        &self,
+        format_id: &Identifier,
+        semantics: &Semantics,
+        semantic: &FieldSemantic,
+    ) -> Vec<Identifier> {
+        semantics
+            .as_ref()
+            .iter()
+            .filter(|(_, s)| discriminant(*s) == discriminant(semantic))
+            .map(|(id, _)| id.clone())
+            .sorted_by_key(|id| self.semantic_span(format_id, id))
+            .collect()
+    }
+
+    fn check_formats(&mut self) {
+        let source_map = &self.psf.source_map;
+        let formats = self
+            .psf
+            .formats
+            .iter()
+            .sorted_by_key(|(id, _)| source_map.formats.get(*id));
+
+        for (format_id, afs) in formats {
+            let format = &afs.format.format;
+            let semantics = &afs.semantics;
+
+            self.check_layout(format, semantics);
+
+            let bindings = semantics
+                .as_ref()
+                .iter()
+                .sorted_by_key(|(id, _)| self.semantic_span(format_id, id));
+            for (field_id, semantic) in bindings {
+                self.check_semantic(format, field_id, semantic);
+            }
+
+            for semantic in [
+                FieldSemantic::Payload,
+                FieldSemantic::Length(None),
+                FieldSemantic::Padding(Default::default()),
+                FieldSemantic::PaddingLength,
+                FieldSemantic::Pubkey(PubkeyEncoding::Raw),
+                FieldSemantic::Salt,
+                FieldSemantic::Cover,
+                FieldSemantic::TypeTag(0),
+            ] {
+                let field_ids = self.find_fields(format_id, semantics, &semantic);
+                for field_id in field_ids.iter().skip(1) {
+                    self.error(
+                        self.semantic_span(format_id, field_id),
+                        format!(
+                            "format `{}` already has a {} field `{}`",
+                            format_id.0,
+                            semantic_name(&semantic),
+                            field_ids[0].0
+                        ),
+                    );
+                }
+            }
+
+            self.check_payload(format, semantics);
+        }
+    }
+
+    /// Checks that a receiver can find the size of each variable-length field
+    /// before reading it, and that the compiler knows how to fill it.
+    fn check_layout(&mut self, format: &Format, semantics: &Semantics) {
+        // The variable-length field each length field gives the size of.
+        let mut sized_fields: HashMap<Identifier, Identifier> = HashMap::new();
+
+        // A receiver reads whole bytes.
+        for group in format.bit_groups() {
+            let nbits: usize = group
+                .iter()
+                .filter_map(|field| field.dtype.maybe_size_of_bits())
+                .sum();
+            if nbits % 8 != 0 {
+                let last = &group[group.len() - 1];
+                self.error(
+                    self.field_span(&format.name, &last.name),
+                    format!(
+                        "bitfields `{}` to `{}` take {} bits, which is not a whole number of bytes",
+                        group[0].name.0, last.name.0, nbits
+                    ),
+                );
+            }
+        }
+
+        for (i, field) in format.fields.iter().enumerate() {
+            if let Array::Primitive(PrimitiveArray(t @ PrimitiveType::VarInt(..), n)) = field.dtype
+            {
+                if n != 1 {
+                    self.error(
+                        self.field_span(&format.name, &field.name),
+                        format!(
+                            "field `{}` cannot be an array of `{}`, since each value has its own \
+                             width",
+                            field.name.0, t
+                        ),
+                    );
+                }
+            }
+            if let Array::Primitive(PrimitiveArray(t @ PrimitiveType::Bits(_), n)) = field.dtype {
+                if n != 1 {
+                    self.error(
+                        self.field_span(&format.name, &field.name),
+                        format!(
+                            "field `{}` cannot be an array of `{}`; define a field for each value",
+                            field.name.0, t
+                        ),
+                    );
+                }
+            }
+            let Array::Dynamic(ref dynamic) = field.dtype else {
+                continue;
+            };
+            let span = self.field_span(&format.name, &field.name);
+
+            // Unwrap OK: a dynamic array always has a length field.
+            let length_field_id = dynamic.try_get_length_field().unwrap();
+            let length_type = match format.try_get_field_by_name(&length_field_id) {
+                None => {
+                    self.error(
+                        span,
+                        format!(
+                            "the size of field `{}` is given by undefined field `{}`",
+                            field.name.0, length_field_id.0
+                        ),
+                    );
+                    None
+                }
+                Some(length_field) => {
+                    let length_type = unsigned_integer(&length_field.dtype);
+                    if length_type.is_none() {
+                        self.error(
+                            span,
+                            format!(
+                                "the size of field `{}` is given by field `{}`, which must be an \
+                                 unsigned integer but has type `{}`",
+                                field.name.0, length_field_id.0, length_field.dtype
+                            ),
+                        );
+                    } else if position(format, &length_field_id) > Some(i) {
+                        self.error(
+                            span,
+                            format!(
+                                "the size of field `{}` is given by field `{}`, which must come \
+                                 before it",
+                                field.name.0, length_field_id.0
+                            ),
+                        );
+                    }
+                    length_type
+                }
+            };
+
+            if let Some(other) = sized_fields.insert(length_field_id.clone(), field.name.clone()) {
+                self.error(
+                    span,
+                    format!(
+                        "the size of field `{}` is given by field `{}`, which already gives the \
+                         size of field `{}`",
+                        field.name.0, length_field_id.0, other.0
+                    ),
+                );
+            }
+
+            // Other than the payload and padding, a variable-length field has a
+            // fixed value, and we set its length field from the size of it.
+            let value = match semantics.as_ref().get(&field.name) {
+                None => {
+                    self.error(
+                        span,
+                        format!(
+                            "variable-length field `{}` must have the PAYLOAD, PADDING, \
+                             FIXED_BYTES, FIXED_STRING, or RANDOM semantic",
+                            field.name.0
+                        ),
+                    );
+                    continue;
+                }
+                Some(semantic) => semantic.value_nbytes().map(|n| (semantic, n)),
+            };
+            let Some((semantic, value_nbytes)) = value else {
+                continue;
+            };
+
+            if let Some(length_semantic) = semantics.as_ref().get(&length_field_id) {
+                self.error(
+                    span,
+                    format!(
+                        "the size of field `{}` is given by field `{}`, which must not have the {} \
+                         semantic",
+                        field.name.0,
+                        length_field_id.0,
+                        semantic_name(length_semantic)
+                    ),
+                );
+            }
+            if let Some(length_type) = length_type {
+                if value_nbytes as u128 > length_type.bounds().1 {
+                    self.error(
+                        self.semantic_span(&format.name, &field.name),
+                        format!(
+                            "{} value is {} bytes long, but field `{}` of type `{}` counts at \
+                             most {} bytes",
+                            semantic_name(semantic),
+                            value_nbytes,
+                            length_field_id.0,
+                            length_type,
+                            length_type.bounds().1
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Checks that the semantic can be applied to the type of its field.
+    fn check_semantic(&mut self, format: &Format, field_id: &Identifier, semantic: &FieldSemantic) {
+        let span = self.semantic_span(&format.name, field_id);
+        let name = semantic_name(semantic);
+
+        let Some(field) = format.try_get_field_by_name(field_id) else {
+            self.error(
+                span,
+                format!("format `{}` has no field `{}`", format.name.0, field_id.0),
+            );
+            return;
+        };
+
+        let value_nbytes = match semantic {
+            FieldSemantic::Length(_) | FieldSemantic::PaddingLength | FieldSemantic::Cover => {
+                if unsigned_integer(&field.dtype).is_none() {
+                    self.error(
+                        span,
+                        format!(
+                            "{} field `{}` must be an unsigned integer, but has type `{}`",
+                            name, field_id.0, field.dtype
+                        ),
+                    );
+                }
+                return;
+            }
+            FieldSemantic::Counter(start, step) => {
+                match unsigned_integer(&field.dtype) {
+                    None => self.error(
+                        span,
+                        format!(
+                            "{} field `{}` must be an unsigned integer, but has type `{}`",
+                            name, field_id.0, field.dtype
+                        ),
+                    ),
+                    Some(t) if *start > t.bounds().1 => self.error(
+                        span,
+                        format!(
+                            "{} value {} does not fit field `{}` of type `{}`",
+                            name, start, field_id.0, field.dtype
+                        ),
+                    ),
+                    Some(_) => {}
+                }
+                if *step == 0 {
+                    self.error(
+                        span,
+                        format!(
+                            "{} field `{}` must have a step of at least 1",
+                            name, field_id.0
+                        ),
+                    );
+                }
+                return;
+            }
+            FieldSemantic::Timestamp(unit, _) => {
+                if !matches!(
+                    unsigned_integer(&field.dtype),
+                    Some(PrimitiveType::Numeric(t)) if t.size_of() == unit.nbytes()
+                ) {
+                    self.error(
+                        span,
+                        format!(
+                            "{} field `{}` must be an unsigned integer of {} bytes, but has type \
+                             `{}`",
+                            name,
+                            field_id.0,
+                            unit.nbytes(),
+                            field.dtype
+                        ),
+                    );
+                }
+                return;
+            }
+            // We keep, compare, or compute the bytes of the field.
+            FieldSemantic::SaveAs(_)
+            | FieldSemantic::Echo(_)
+            | FieldSemantic::Checksum(..)
+            | FieldSemantic::Salt
+                if is_varint(&field)
+                    || field.dtype.is_bitfield()
+                    || field.maybe_size_of().is_none() =>
+            {
+                self.error(
+                    span,
+                    format!(
+                        "{} field `{}` must take whole bytes of a fixed width, but has type `{}`",
+                        name, field_id.0, field.dtype
+                    ),
+                );
+                return;
+            }
+            FieldSemantic::SaveAs(_) | FieldSemantic::Echo(_) => return,
+            FieldSemantic::Salt => {
+                let nbytes = field.maybe_size_of().unwrap_or_default();
+                if nbytes < kdf::MIN_SALT_NBYTES {
+                    self.error(
+                        span,
+                        format!(
+                            "{} field `{}` must have at least {} bytes, but has type `{}`",
+                            name,
+                            field_id.0,
+                            kdf::MIN_SALT_NBYTES,
+                            field.dtype
+                        ),
+                    );
+                }
+                return;
+            }
+            FieldSemantic::Checksum(algorithm, from, to) => {
+                self.check_checksum(span, format, &field, *algorithm, from, to);
+                return;
+            }
+            FieldSemantic::Payload | FieldSemantic::Padding(_) => {
+                if !matches!(field.dtype, Array::Dynamic(_)) {
+                    self.error(
+                        span,
+                        format!(
+                            "{} field `{}` must be variable-length, but has type `{}`",
+                            name, field_id.0, field.dtype
+                        ),
+                    );
+                }
+                return;
+            }
+            FieldSemantic::Pubkey(encoding) => {
+                let key_nbytes = encoding.key_length_nbytes();
+                if field.maybe_size_of() != Some(key_nbytes) {
+                    self.error(
+                        span,
+                        format!(
+                            "{:?} public key field `{}` must have type `[u8; {}]`, but has type \
+                             `{}`",
+                            encoding, field_id.0, key_nbytes, field.dtype
+                        ),
+                    );
+                }
+                return;
+            }
+            FieldSemantic::FixedString(_)
+            | FieldSemantic::FixedBytes(_)
+            | FieldSemantic::Random(_)
+                if is_varint(&field) =>
+            {
+                self.error(
+                    span,
+                    format!(
+                        "{} field `{}` cannot have type `{}`, whose width depends on its value",
+                        name, field_id.0, field.dtype
+                    ),
+                );
+                return;
+            }
+            FieldSemantic::FixedString(_)
+            | FieldSemantic::FixedBytes(_)
+            | FieldSemantic::Random(_)
+                if field.dtype.is_bitfield() =>
+            {
+                self.error(
+                    span,
+                    format!(
+                        "{} field `{}` cannot have type `{}`, which is less than a byte; use \
+                         FIXED_VALUE",
+                        name, field_id.0, field.dtype
+                    ),
+                );
+                return;
+            }
+            // The receiver tells formats apart by the bytes of the tag.
+            FieldSemantic::TypeTag(_) if is_varint(&field) || field.dtype.is_bitfield() => {
+                self.error(
+                    span,
+                    format!(
+                        "{} field `{}` must take whole bytes of a fixed width, but has type `{}`",
+                        name, field_id.0, field.dtype
+                    ),
+                );
+                return;
+            }
+            FieldSemantic::FixedValue(_)
+            | FieldSemantic::OneOf(_)
+            | FieldSemantic::Range(..)
+            | FieldSemantic::TypeTag(_) => {
+                let values = match semantic {
+                    FieldSemantic::FixedValue(value) => vec![*value],
+                    FieldSemantic::TypeTag(tag) => vec![PrimitiveValue::Int(*tag)],
+                    FieldSemantic::OneOf(choices) => {
+                        if choices.iter().all(|(_, weight)| *weight == 0) {
+                            self.error(
+                                span,
+                                format!(
+                                    "{} field `{}` has no value with a weight",
+                                    name, field_id.0
+                                ),
+                            );
+                        }
+                        choices.iter().map(|(value, _)| *value).collect()
+                    }
+                    FieldSemantic::Range(lo, hi) => {
+                        if lo > hi {
+                            self.error(
+                                span,
+                                format!("{} of field `{}` is empty", name, field_id.0),
+                            );
+                        }
+                        vec![PrimitiveValue::Int(*lo), PrimitiveValue::Int(*hi)]
+                    }
+                    _ => unreachable!(),
+                };
+                for value in values {
+                    let fits = match field.dtype {
+                        Array::Primitive(PrimitiveArray(t, 1)) => t.encode_value(value).is_some(),
+                        _ => false,
+                    };
+                    if !fits {
+                        self.error(
+                            span,
+                            format!(
+                                "{} value {} does not fit field `{}` of type `{}`",
+                                name, value, field_id.0, field.dtype
+                            ),
+                        );
+                    }
+                }
+                return;
+            }
+            FieldSemantic::FixedString(s) if !s.is_ascii() => {
+                self.error(span, format!("{} value {:?} must be ASCII", name, s));
+                return;
+            }
+            FieldSemantic::FixedString(s) => s.chars().count(),
+            FieldSemantic::FixedBytes(b) => b.len(),
+            FieldSemantic::Random(n) => *n,
+        };
+
+        // We check the values of variable-length fields with their length
+        // fields.
+        match field.maybe_size_of() {
+            Some(field_nbytes) if value_nbytes > field_nbytes => self.error(
+                span,
+                format!(
+                    "{} value is {} bytes long, but field `{}` of type `{}` holds only {} bytes",
+                    name, value_nbytes, field_id.0, field.dtype, field_nbytes
+                ),
+            ),
+            _ => {}
+        }
+    }
+
+    /// Checks that each ECHO field has bytes to echo, of its own size, that a
+    /// SAVE_AS field keeps.
+    fn check_variables(&mut self) {
+        // The SAVE_AS and ECHO fields, with their formats and sizes.
+        let mut saved = vec![];
+        let mut echoed = vec![];
+        let formats = self
+            .psf
+            .formats
+            .values()
+            .sorted_by_key(|afs| self.psf.source_map.formats.get(&afs.format.format.name));
+        for afs in formats {
+            let format = &afs.format.format;
+            let bindings = afs
+                .semantics
+                .as_ref()
+                .iter()
+                .sorted_by_key(|(id, _)| self.semantic_span(&format.name, id));
+            for (field_id, semantic) in bindings {
+                let nbytes = format
+                    .try_get_field_by_name(field_id)
+                    .and_then(|field| field.maybe_size_of());
+                match semantic {
+                    FieldSemantic::SaveAs(name) => {
+                        saved.push((&format.name, field_id, name, nbytes))
+                    }
+                    FieldSemantic::Echo(name) => {
+                        echoed.push((&format.name, field_id, name, nbytes))
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        for (format_id, field_id, name, nbytes) in echoed {
+            let span = self.semantic_span(format_id, field_id);
+            let saves: Vec<_> = saved
+                .iter()
+                .filter(|(.., save_name, _)| *save_name == name)
+                .collect();
+            if saves.is_empty() {
+                self.error(
+                    span,
+                    format!(
+                        "ECHO field `{}` echoes `{}`, but no SAVE_AS field keeps it",
+                        field_id.0, name.0
+                    ),
+                );
+                continue;
+            }
+            for (save_format_id, save_field_id, _, save_nbytes) in saves {
+                if let (Some(nbytes), Some(save_nbytes)) = (nbytes, save_nbytes) {
+                    if nbytes != *save_nbytes {
+                        self.error(
+                            span,
+                            format!(
+                                "ECHO field `{}` has {} bytes, but SAVE_AS field `{}` of format \
+                                 `{}` keeps {} bytes as `{}`",
+                                field_id.0,
+                                nbytes,
+                                save_field_id.0,
+                                save_format_id.0,
+                                save_nbytes,
+                                name.0
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks that the length fields of the payload and padding are bound, and
+    /// that the payload fits in its length field.
+    fn check_payload(&mut self, format: &Format, semantics: &Semantics) {
+        let find = |semantic: FieldSemantic| {
+            semantics
+                .find_field_id(semantic)
+                .and_then(|id| format.try_get_field_by_name(&id))
+        };
+        let length_of = |field: &Field| match &field.dtype {
+            Array::Dynamic(d) => d
+                .try_get_length_field()
+                .and_then(|id| format.try_get_field_by_name(&id)),
+            Array::Primitive(_) => None,
+        };
+
+        let Some(payload) = find(FieldSemantic::Payload) else {
+            for semantic in [
+                FieldSemantic::Length(None),
+                FieldSemantic::Padding(Default::default()),
+                FieldSemantic::PaddingLength,
+                FieldSemantic::Cover,
+            ] {
+                if let Some(field_id) = semantics.find_field_id(semantic.clone()) {
+                    self.error(
+                        self.semantic_span(&format.name, &field_id),
+                        format!(
+                            "format `{}` has a {} field but no PAYLOAD field",
+                            format.name.0,
+                            semantic_name(&semantic)
+                        ),
+                    );
+                }
+            }
+            return;
+        };
+        let payload_span = self.semantic_span(&format.name, &payload.name);
+
+        let Some(length) = length_of(&payload) else {
+            return;
+        };
+        if !matches!(
+            semantics.as_ref().get(&length.name),
+            Some(FieldSemantic::Length(_))
+        ) {
+            self.error(
+                payload_span,
+                format!(
+                    "the size of PAYLOAD field `{}` is given by field `{}`, which must have the \
+                     LENGTH semantic",
+                    payload.name.0, length.name.0
+                ),
+            );
+        }
+
+        // The receiver must know the sizes of the variable-length fields the
+        // LENGTH field counts before it can read the payload.
+        for field in format
+            .fields
+            .iter()
+            .skip_while(|f| f.name != payload.name)
+            .skip(1)
+        {
+            if let Some(field_length) = length_of(field) {
+                if position(format, &field_length.name) > position(format, &payload.name) {
+                    self.error(
+                        self.field_span(&format.name, &field.name),
+                        format!(
+                            "the size of field `{}` after PAYLOAD field `{}` is given by field \
+                             `{}`, which must come before `{}`",
+                            field.name.0, payload.name.0, field_length.name.0, payload.name.0
+                        ),
+                    );
+                }
+            }
+        }
+
+        let padding = find(FieldSemantic::Padding(Default::default()));
+        if let Some(ref padding) = padding {
+            let padding_span = self.semantic_span(&format.name, &padding.name);
+            if let Some(padding_length) = length_of(padding) {
+                if semantics.as_ref().get(&padding_length.name)
+                    != Some(&FieldSemantic::PaddingLength)
+                {
+                    self.error(
+                        padding_span,
+                        format!(
+                            "the size of PADDING field `{}` is given by field `{}`, which must \
+                             have the PADDING_LENGTH semantic",
+                            padding.name.0, padding_length.name.0
+                        ),
+                    );
+                }
+            }
+            if let Some(FieldSemantic::Padding(policy)) = semantics.as_ref().get(&padding.name) {
+                self.check_padding_policy(format, semantics, padding, policy);
+            }
+        } else if let Some(field_id) = semantics.find_field_id(FieldSemantic::PaddingLength) {
+            self.error(
+                self.semantic_span(&format.name, &field_id),
+                format!(
+                    "format `{}` has a PADDING_LENGTH field but no PADDING field",
+                    format.name.0
+                ),
+            );
+        }
+
+        let Some(FieldSemantic::Length(expr)) = semantics.as_ref().get(&length.name) else {
+            return;
+        };
+        if let Some(expr) = expr {
+            if !self.check_length_expr(format, &length.name, expr) {
+                return;
+            }
+        }
+        // We report a LENGTH field after its payload elsewhere.
+        if unsigned_integer(&length.dtype).is_none()
+            || position(format, &length.name) > position(format, &payload.name)
+        {
+            return;
+        }
+        let Some(hints) =
+            super::generate_dynamic_payload_hints(format, semantics, self.psf.crypto_spec.as_ref())
+        else {
+            return;
+        };
+        self.check_length_value(format, semantics, &length, &payload, &hints);
+    }
+
+    /// Checks that the fields a LENGTH expression refers to exist.
+    fn check_length_expr(
+        &mut self,
+        format: &Format,
+        length_field_id: &Identifier,
+        expr: &LengthExpr,
+    ) -> bool {
+        let span = self.semantic_span(&format.name, length_field_id);
+        let mut valid = true;
+
+        for field_id in expr.field_ids() {
+            if format.try_get_field_by_name(field_id).is_none() {
+                self.error(
+                    span,
+                    format!(
+                        "LENGTH expression of field `{}` refers to undefined field `{}`",
+                        length_field_id.0, field_id.0
+                    ),
+                );
+                valid = false;
+            }
+        }
+        if !valid {
+            return false;
+        }
+
+        for field_id in split_bitfields(format, expr) {
+            self.error(
+                span,
+                format!(
+                    "LENGTH expression of field `{}` cannot count bitfield `{}` apart from the \
+                     bitfields it shares bytes with",
+                    length_field_id.0, field_id.0
+                ),
+            );
+            valid = false;
+        }
+        if valid && expr.expand_ranges(format).is_none() {
+            self.error(
+                span,
+                format!(
+                    "LENGTH expression of field `{}` has a range whose last field comes before \
+                     its first",
+                    length_field_id.0
+                ),
+            );
+            valid = false;
+        }
+
+        valid
+    }
+
+    /// Checks that we can pick the size of the padding, and that the
+    /// PADDING_LENGTH field can count it.
+    fn check_padding_policy(
+        &mut self,
+        format: &Format,
+        semantics: &Semantics,
+        padding: &Field,
+        policy: &PaddingPolicy,
+    ) {
+        let span = self.semantic_span(&format.name, &padding.name);
+        let cipher = self.psf.crypto_spec.as_ref().map(|crypto| crypto.cipher);
+        let block_nbytes = cipher
+            .and_then(|cipher| cipher.block_size_nbytes())
+            .map(usize::from);
+
+        match (&policy.size, cipher) {
+            (PaddingSize::Block, None) => self.error(
+                span,
+                format!(
+                    "PADDING field `{}` pads to the block size of the cipher, which requires a \
+                     CRYPTO segment",
+                    padding.name.0
+                ),
+            ),
+            (PaddingSize::Block, Some(cipher)) if block_nbytes.is_none() => self.error(
+                span,
+                format!(
+                    "PADDING field `{}` pads to the block size of the cipher, but {:?} has none",
+                    padding.name.0, cipher
+                ),
+            ),
+            (PaddingSize::Buckets(sizes), _) if sizes.contains(&0) => self.error(
+                span,
+                format!(
+                    "PADDING field `{}` pads to a size of 0 bytes",
+                    padding.name.0
+                ),
+            ),
+            (PaddingSize::Distribution(sizes), _)
+                if sizes.iter().all(|(_, weight)| *weight == 0) =>
+            {
+                self.error(
+                    span,
+                    format!(
+                        "PADDING field `{}` has no size with a weight",
+                        padding.name.0
+                    ),
+                )
+            }
+            _ => {}
+        }
+
+        // We size the message before we set the varints that we do not know
+        // the values of, whose width would change the size. Both values of a
+        // cover field have the same width.
+        if matches!(
+            policy.size,
+            PaddingSize::Buckets(_) | PaddingSize::Distribution(_)
+        ) {
+            for field in format.fields.iter().filter(|field| is_varint(field)) {
+                if !matches!(
+                    semantics.as_ref().get(&field.name),
+                    Some(
+                        FieldSemantic::FixedValue(_)
+                            | FieldSemantic::OneOf(_)
+                            | FieldSemantic::Range(..)
+                            | FieldSemantic::Cover
+                    )
+                ) {
+                    self.error(
+                        span,
+                        format!(
+                            "PADDING field `{}` pads the message to a size, which the width of \
+                             varint field `{}` would change",
+                            padding.name.0, field.name.0
+                        ),
+                    );
+                }
+            }
+        }
+
+        let padding_length = match padding.dtype {
+            Array::Dynamic(ref darray) => darray
+                .try_get_length_field()
+                .and_then(|id| format.try_get_field_by_name(&id)),
+            Array::Primitive(_) => None,
+        };
+        let (Some(padding_length), Some(max_nbytes)) =
+            (padding_length, policy.max_nbytes(block_nbytes))
+        else {
+            return;
+        };
+        // We may add up to a divisor less one bytes to align the padding.
+        let align_nbytes = super::generate_dynamic_payload_hints(format, semantics, None)
+            .and_then(|hints| hints.hints_padding?.alignment)
+            .map_or(0, |alignment| alignment.divisor - 1);
+        let max_nbytes = max_nbytes + align_nbytes;
+        if let Some(length_type) = unsigned_integer(&padding_length.dtype) {
+            if max_nbytes as u128 > length_type.bounds().1 {
+                self.error(
+                    span,
+                    format!(
+                        "PADDING field `{}` may take {} bytes, but field `{}` of type `{}` counts \
+                         at most {} bytes",
+                        padding.name.0,
+                        max_nbytes,
+                        padding_length.name.0,
+                        length_type,
+                        length_type.bounds().1
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Checks that a LENGTH expression divides the size of the payload at most
+    /// once, in a dividend that the padding can align to a divisor we know
+    /// before we run the protocol.
+    fn check_payload_division(
+        &mut self,
+        format: &Format,
+        semantics: &Semantics,
+        length: &Field,
+        payload: &Field,
+        expr: &LengthExpr,
+    ) -> bool {
+        let span = self.semantic_span(&format.name, &length.name);
+        let divisions = super::divisions_counting(expr, &payload.name);
+        let [(dividend, divisor)] = divisions[..] else {
+            if divisions.len() > 1 {
+                self.error(
+                    span,
+                    format!(
+                        "LENGTH expression of field `{}` cannot divide the size of PAYLOAD field \
+                         `{}` more than once",
+                        length.name.0, payload.name.0
+                    ),
+                );
+            }
+            return divisions.is_empty();
+        };
+
+        if divisor.field_ids().contains(&&payload.name) {
+            self.error(
+                span,
+                format!(
+                    "LENGTH expression of field `{}` cannot divide by the size of PAYLOAD field \
+                     `{}`",
+                    length.name.0, payload.name.0
+                ),
+            );
+            return false;
+        }
+        let padding_id = semantics.find_field_id(FieldSemantic::Padding(Default::default()));
+        let aligns =
+            |id: &Identifier| adds_once(dividend, id) && !divisor.field_ids().contains(&id);
+        match padding_id {
+            Some(ref padding_id) if aligns(&payload.name) && aligns(padding_id) => {}
+            _ => {
+                self.error(
+                    span,
+                    format!(
+                        "LENGTH expression of field `{}` divides the size of PAYLOAD field `{}`, \
+                         so the dividend must add it and the size of a PADDING field once each, \
+                         which we align to the divisor",
+                        length.name.0, payload.name.0
+                    ),
+                );
+                return false;
+            }
+        }
+        if super::padding_alignment(format, semantics, expr, &payload.name).is_none() {
+            self.error(
+                span,
+                format!(
+                    "LENGTH expression of field `{}` divides the size of PAYLOAD field `{}` by \
+                     `{}`, which must be positive and known before we run the protocol",
+                    length.name.0, payload.name.0, divisor
+                ),
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Checks that a receiver can solve the value of the LENGTH field for the
+    /// size of the payload, and that the payload fits in the field. We reserve
+    /// a block for the padding.
+    fn check_length_value(
+        &mut self,
+        format: &Format,
+        semantics: &Semantics,
+        length: &Field,
+        payload: &Field,
+        hints: &super::HintsDynamicPayload,
+    ) {
+        let span = self.semantic_span(&format.name, &length.name);
+        let expr = &hints.length_expr;
+
+        // When a receiver solves for the size of the payload, it knows the
+        // sizes of variable-length fields from their length fields, but not
+        // the widths of varints.
+        let varint_ids: Vec<&Identifier> = expr
+            .field_ids()
+            .into_iter()
+            .filter(|id| {
+                format
+                    .try_get_field_by_name(id)
+                    .is_some_and(|f| is_varint(&f))
+            })
+            .unique()
+            .collect();
+        for field_id in &varint_ids {
+            self.error(
+                span,
+                format!(
+                    "LENGTH expression of field `{}` cannot count varint field `{}`, whose width \
+                     depends on its value",
+                    length.name.0, field_id.0
+                ),
+            );
+        }
+        if !varint_ids.is_empty() {
+            return;
+        }
+
+        let payload_count = expr
+            .field_ids()
+            .into_iter()
+            .filter(|id| **id == payload.name)
+            .count();
+        if payload_count != 1 {
+            self.error(
+                span,
+                format!(
+                    "LENGTH expression of field `{}` must count the size of PAYLOAD field `{}` \
+                     exactly once",
+                    length.name.0, payload.name.0
+                ),
+            );
+            return;
+        }
+        if !self.check_payload_division(format, semantics, length, payload, expr) {
+            return;
+        }
+
+        let mut divisions = vec![];
+        static_divisions(format, semantics, expr, &mut divisions);
+        for (dividend, divisor) in divisions {
+            match (dividend, divisor) {
+                (_, Some(0)) => self.error(
+                    span,
+                    format!(
+                        "LENGTH expression of field `{}` divides by zero",
+                        length.name.0
+                    ),
+                ),
+                (Some(dividend), Some(divisor)) if dividend % divisor != 0 => self.error(
+                    span,
+                    format!(
+                        "LENGTH expression of field `{}` divides {} bytes by {}, which leaves a \
+                         remainder",
+                        length.name.0, dividend, divisor
+                    ),
+                ),
+                _ => {}
+            }
+        }
+
+        let value = |payload_nbytes, padding_nbytes| {
+            hints.static_length_value(format, semantics, payload_nbytes, padding_nbytes)
+        };
+        // A value that divides the payload grows once the payload fills a
+        // divisor.
+        let step_nbytes = hints
+            .hints_padding
+            .as_ref()
+            .and_then(|hints| hints.alignment.as_ref())
+            .map_or(1, |alignment| alignment.divisor);
+        if let (Some(empty), Some(step)) = (value(0, 0), value(step_nbytes, 0)) {
+            if step <= empty {
+                self.error(
+                    span,
+                    format!(
+                        "LENGTH expression of field `{}` must grow with the size of PAYLOAD field \
+                         `{}`",
+                        length.name.0, payload.name.0
+                    ),
+                );
+                return;
+            }
+        }
+        if [0, hints.padding_max_nbytes]
+            .iter()
+            .any(|&padding_nbytes| value(0, padding_nbytes).is_some_and(|v| v < 0))
+        {
+            self.error(
+                span,
+                format!(
+                    "LENGTH expression of field `{}` is negative for an empty payload",
+                    length.name.0
+                ),
+            );
+            return;
+        }
+
+        if hints.length_field_max <= 1 {
+            let overhead_nbytes = value(0, hints.padding_max_nbytes).unwrap_or_default();
+            self.error(
+                self.field_span(&format.name, &length.name),
+                format!(
+                    "LENGTH field `{}` of type `{}` is too small to count the payload and the {} \
+                     other bytes it counts",
+                    length.name.0, length.dtype, overhead_nbytes
+                ),
+            );
+        }
+    }
+
+    /// Checks that the sequence only names defined formats, and that at every
+    /// step of the task graph compiled from it, a role either sends one
+    /// message, or DATA messages that the receiver tells apart by their tags.
+    fn check_sequence(&mut self) {
+        let spans = &self.psf.source_map.sequence;
+        let span = |i: usize| spans.get(i).copied();
+
+        for (i, seqspec) in self.psf.sequence.iter().enumerate() {
+            // Transitions may branch on the message, whatever its phase.
+            if seqspec.condition.is_some()
+                && seqspec.phase == Phase::Handshake
+                && seqspec.transition.is_none()
+            {
+                self.error(
+                    span(i),
+                    "only DATA messages can have a WHEN condition".to_string(),
+                );
+            }
+
+            match self.psf.formats.get(&seqspec.format) {
+                None => self.error(span(i), format!("undefined format `{}`", seqspec.format.0)),
+                // A cover message has nothing to carry.
+                Some(afs)
+                    if seqspec.phase == Phase::Data
+                        && seqspec.condition != Some(SendCondition::Cover) =>
+                {
+                    if afs
+                        .semantics
+                        .find_field_id(FieldSemantic::Payload)
+                        .is_none()
+                    {
+                        self.error(
+                            span(i),
+                            format!(
+                                "format `{}` is sent in the DATA phase but has no PAYLOAD field",
+                                seqspec.format.0
+                            ),
+                        );
+                    }
+                }
+                Some(_) => {}
+            }
+
+            if let Some(ref guard) = seqspec.guard {
+                self.check_guard(i, guard);
+            }
+        }
+
+        let (transitions, steps): (Vec<usize>, Vec<usize>) =
+            (0..self.psf.sequence.len()).partition(|&i| self.psf.sequence[i].transition.is_some());
+        match (transitions.first(), steps.first()) {
+            (Some(&i), Some(&j)) => {
+                self.error(
+                    span(i.max(j)),
+                    "the sequence must either list steps with PHASE or transitions with FROM and \
+                     TO"
+                    .to_string(),
+                );
+                return;
+            }
+            (Some(_), None) => return self.check_transitions(),
+            _ => {}
+        }
+
+        // Mirrors `compile_task_graph`: each HANDSHAKE message leads to a new
+        // step, and DATA messages repeat at the step they are listed in.
+        let mut steps: Vec<Vec<usize>> = vec![vec![]];
+        for (i, seqspec) in self.psf.sequence.iter().enumerate() {
+            // Unwrap OK: there is always a current step.
+            steps.last_mut().unwrap().push(i);
+            if seqspec.phase == Phase::Handshake {
+                steps.push(vec![]);
+            }
+        }
+
+        for step in &steps {
+            if step.is_empty() {
+                self.error(
+                    span(self.psf.sequence.len().saturating_sub(1)),
+                    "the sequence must end with a DATA message".to_string(),
+                );
+            }
+            for role in [Role::Client, Role::Server] {
+                let sent: Vec<usize> = step
+                    .iter()
+                    .copied()
+                    .filter(|&i| self.psf.sequence[i].role == role)
+                    .collect();
+                // A HANDSHAKE message ends its step.
+                match sent[..] {
+                    [.., last] if self.psf.sequence[last].phase == Phase::Handshake => {
+                        if sent.len() > 1 {
+                            self.error(
+                                span(last),
+                                "a role can send several messages at the same step of the \
+                                 sequence only if they are all DATA messages"
+                                    .to_string(),
+                            );
+                        }
+                    }
+                    _ => self.check_data_formats(role, &sent),
+                }
+            }
+        }
+    }
+
+    /// Checks that the field a guard compares is an integer of its format.
+    fn check_guard(&mut self, i: usize, guard: &Guard) {
+        let span = self.psf.source_map.sequence.get(i).copied();
+        let seqspec = &self.psf.sequence[i];
+
+        if seqspec.transition.is_none() {
+            self.error(
+                span,
+                "only transitions with FROM and TO can have a GUARD".to_string(),
+            );
+            return;
+        }
+
+        let Some(afs) = self.psf.formats.get(&seqspec.format) else {
+            return;
+        };
+        match afs.format.format.try_get_field_by_name(&guard.field) {
+            None => self.error(
+                span,
+                format!(
+                    "format `{}` has no field `{}`",
+                    seqspec.format.0, guard.field.0
+                ),
+            ),
+            Some(field) if unsigned_integer(&field.dtype).is_none() => self.error(
+                span,
+                format!(
+                    "GUARD field `{}` must be an unsigned integer, but has type `{}`",
+                    guard.field.0, field.dtype
+                ),
+            ),
+            Some(_) => {}
+        }
+    }
+
+    /// Mirrors `compile_task_graph` for a sequence of transitions: each state
+    /// must lead somewhere, and the messages out of it must be told apart.
+    fn check_transitions(&mut self) {
+        let spans = &self.psf.source_map.sequence;
+        let span = |i: usize| spans.get(i).copied();
+        let sequence = &self.psf.sequence;
+        // Unwrap OK: we only get here for transitions.
+        let transition = |i: usize| sequence[i].transition.as_ref().unwrap();
+
+        // The states in order of appearance, with the transition that first
+        // mentions them.
+        let states: Vec<(&Identifier, usize)> = (0..sequence.len())
+            .flat_map(|i| [(&transition(i).from, i), (&transition(i).to, i)])
+            .unique_by(|(state, _)| *state)
+            .collect();
+
+        for (state, first) in states {
+            let out: Vec<usize> = (0..sequence.len())
+                .filter(|&i| transition(i).from == *state)
+                .collect();
+            if out.is_empty() {
+                self.error(
+                    span(first),
+                    format!("state `{}` has no transitions out of it", state.0),
+                );
+                continue;
+            }
+
+            // Whoever sends first picks the transition, so both roles may
+            // only send where the other's messages do not change the state.
+            if !out.iter().map(|&i| sequence[i].role).all_equal() {
+                if let Some(&i) = out.iter().find(|&&i| transition(i).to != *state) {
+                    self.error(
+                        span(i),
+                        format!(
+                            "both roles send in state `{}`, so its transitions must all lead \
+                             back to it",
+                            state.0
+                        ),
+                    );
+                }
+            }
+
+            for role in [Role::Client, Role::Server] {
+                let sent: Vec<usize> = out
+                    .iter()
+                    .copied()
+                    .filter(|&i| sequence[i].role == role)
+                    .collect();
+
+                // Transitions of the same format are one message, which the
+                // guards send on to a state.
+                let mut formats: Vec<Vec<usize>> = vec![];
+                for &i in &sent {
+                    match formats
+                        .iter_mut()
+                        .find(|f| sequence[f[0]].format == sequence[i].format)
+                    {
+                        Some(f) => f.push(i),
+                        None => formats.push(vec![i]),
+                    }
+                }
+
+                // A format without one condition cannot be told from the others.
+                let mut conditions_agree = true;
+                for f in &formats {
+                    conditions_agree &= self.check_guarded_transitions(state, f);
+                }
+                if conditions_agree {
+                    self.check_data_formats(role, &formats.iter().map(|f| f[0]).collect_vec());
+                }
+            }
+        }
+    }
+
+    /// Checks that the guards of the transitions of a message pick one of
+    /// them for every value, and returns whether they share a condition.
+    fn check_guarded_transitions(&mut self, state: &Identifier, transitions: &[usize]) -> bool {
+        let spans = &self.psf.source_map.sequence;
+        let span = |i: usize| spans.get(i).copied();
+        let sequence = &self.psf.sequence;
+        let format_id = &sequence[transitions[0]].format;
+
+        let mut conditions_agree = true;
+        for &i in &transitions[1..] {
+            if sequence[i].condition != sequence[transitions[0]].condition {
+                conditions_agree = false;
+                self.error(
+                    span(i),
+                    format!(
+                        "the transitions out of state `{}` with format `{}` must have the same \
+                         WHEN condition",
+                        state.0, format_id.0
+                    ),
+                );
+            }
+        }
+
+        match transitions
+            .iter()
+            .position(|&i| sequence[i].guard.is_none())
+        {
+            Some(pos) => {
+                for &i in &transitions[pos + 1..] {
+                    self.error(
+                        span(i),
+                        format!(
+                            "transition is never taken, since a transition out of state `{}` \
+                             with format `{}` comes before it without a GUARD",
+                            state.0, format_id.0
+                        ),
+                    );
+                }
+            }
+            None => {
+                // Unwrap OK: there is at least one transition.
+                let &last = transitions.last().unwrap();
+                self.error(
+                    span(last),
+                    format!(
+                        "the last transition out of state `{}` with format `{}` must have no \
+                         GUARD, so that every message has a transition",
+                        state.0, format_id.0
+                    ),
+                );
+            }
+        }
+
+        conditions_agree
+    }
+
+    /// Checks that the sender has a DATA format for every message, and that the
+    /// receiver can tell the formats apart by peeking at their tags.
+    fn check_data_formats(&mut self, role: Role, sent: &[usize]) {
+        let spans = &self.psf.source_map.sequence;
+        let span = |i: usize| spans.get(i).copied();
+        let sequence = &self.psf.sequence;
+
+        // The sender picks the first format whose condition holds.
+        match sent.iter().position(|&i| sequence[i].condition.is_none()) {
+            Some(pos) => {
+                for &i in &sent[pos + 1..] {
+                    self.error(
+                        span(i),
+                        format!(
+                            "format `{}` is never sent, since format `{}` comes before it without \
+                             a WHEN condition",
+                            sequence[i].format.0, sequence[sent[pos]].format.0
+                        ),
+                    );
+                }
+            }
+            None => {
+                if let Some(&last) = sent.last() {
+                    self.error(
+                        span(last),
+                        format!(
+                            "the last DATA format {} sends at a step of the sequence must have no \
+                             WHEN condition, so that every message has a format",
+                            role_name(role)
+                        ),
+                    );
+                }
+            }
+        }
+
+        if sent.len() < 2 {
+            return;
+        }
+
+        let mut first_tag: Option<(&Identifier, Identifier, Range<usize>, Array)> = None;
+        let mut tag_formats: HashMap<i128, &Identifier> = HashMap::new();
+        for &i in sent {
+            let Some(afs) = self.psf.formats.get(&sequence[i].format) else {
+                continue;
+            };
+            let format = &afs.format.format;
+            let Some(tag_id) = afs.semantics.find_field_id(FieldSemantic::TypeTag(0)) else {
+                self.error(
+                    span(i),
+                    format!(
+                        "format `{}` needs a TYPE_TAG field, since {} sends several DATA formats \
+                         at this step of the sequence",
+                        format.name.0,
+                        role_name(role)
+                    ),
+                );
+                continue;
+            };
+            let FieldSemantic::TypeTag(tag) = afs.semantics.as_ref()[&tag_id] else {
+                unreachable!();
+            };
+            let tag_span = self.semantic_span(&format.name, &tag_id);
+
+            // We check the type of the field with its semantic.
+            let Some(field) = format
+                .try_get_field_by_name(&tag_id)
+                .filter(|field| field.dtype.maybe_size_of_bits().is_some())
+            else {
+                continue;
+            };
+            let Some(range) = format.try_get_fixed_byte_range(&tag_id) else {
+                self.error(
+                    tag_span,
+                    format!(
+                        "TYPE_TAG field `{}` must come before the variable-length fields, so that \
+                         the receiver can peek at it",
+                        tag_id.0
+                    ),
+                );
+                continue;
+            };
+
+            match first_tag {
+                None => first_tag = Some((&format.name, tag_id.clone(), range, field.dtype)),
+                Some((first_format_id, ref first_id, ref first_range, ref first_dtype)) => {
+                    if range != *first_range || field.dtype != *first_dtype {
+                        self.error(
+                            tag_span,
+                            format!(
+                                "TYPE_TAG field `{}` must have the type and offset of TYPE_TAG \
+                                 field `{}` of format `{}`",
+                                tag_id.0, first_id.0, first_format_id.0
+                            ),
+                        );
+                    }
+                }
+            }
+
+            if let Some(other) = tag_formats.insert(tag, &format.name) {
+                self.error(
+                    tag_span,
+                    format!(
+                        "formats `{}` and `{}` have the same TYPE_TAG value {}",
+                        other.0, format.name.0, tag
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Checks that a checksum fits its field, and that its range is one we
+    /// know the bytes of when we compute it.
+    fn check_checksum(
+        &mut self,
+        span: Option<Span>,
+        format: &Format,
+        field: &Field,
+        algorithm: ChecksumAlgorithm,
+        from: &Identifier,
+        to: &Identifier,
+    ) {
+        // Unwrap OK: the caller checks the field has a fixed width.
+        let nbytes = field.maybe_size_of().unwrap();
+        if nbytes > algorithm.digest_nbytes() {
+            self.error(
+                span,
+                format!(
+                    "CHECKSUM field `{}` has {} bytes, but a {} checksum has only {}",
+                    field.name.0,
+                    nbytes,
+                    algorithm,
+                    algorithm.digest_nbytes()
+                ),
+            );
+        }
+
+        if algorithm.needs_key()
+            && self
+                .psf
+                .crypto_spec
+                .as_ref()
+                .is_none_or(|crypto| crypto.password.is_none())
+        {
+            self.error(
+                span,
+                format!("{algorithm} checksum requires a PASSWORD to derive its key from"),
+            );
+        }
+
+        let position = |id: &Identifier| format.fields.iter().position(|f| f.name == *id);
+        let mut ends = vec![];
+        for id in [from, to] {
+            match position(id) {
+                Some(i) => ends.push(i),
+                None => self.error(
+                    span,
+                    format!("format `{}` has no field `{}`", format.name.0, id.0),
+                ),
+            }
+        }
+        let [first, last] = ends[..] else {
+            return;
+        };
+        if first > last {
+            self.error(
+                span,
+                format!(
+                    "CHECKSUM range `{}..{}` is empty, since `{}` comes before `{}`",
+                    from.0, to.0, to.0, from.0
+                ),
+            );
+            return;
+        }
+
+        // We compute a checksum after those whose fields are in its range,
+        // which we cannot do for two that cover each other.
+        let in_range = |from: &Identifier, to: &Identifier, id: &Identifier| {
+            matches!(
+                (position(from), position(to), position(id)),
+                (Some(first), Some(last), Some(i)) if (first..=last).contains(&i)
+            )
+        };
+        let others = self
+            .psf
+            .formats
+            .get(&format.name)
+            .into_iter()
+            .flat_map(|afs| afs.semantics.as_ref().iter())
+            .filter_map(|(id, semantic)| match semantic {
+                FieldSemantic::Checksum(_, from, to) => Some((id, from, to)),
+                _ => None,
+            });
+        for (other_id, other_from, other_to) in others.sorted() {
+            // We report each pair once.
+            if field.name < *other_id
+                && in_range(from, to, other_id)
+                && in_range(other_from, other_to, &field.name)
+            {
+                self.error(
+                    span,
+                    format!(
+                        "CHECKSUM fields `{}` and `{}` cover each other",
+                        field.name.0, other_id.0
+                    ),
+                );
+            }
+        }
+
+        // We compute the checksum before we encrypt, and so before we set the
+        // MAC fields.
+        let mac_ids = self
+            .psf
+            .crypto_spec
+            .iter()
+            .flat_map(|crypto| crypto.directives.values())
+            .filter(|directive| directive.enc_fmt_bnd.to_format_name == format.name)
+            .flat_map(|directive| &directive.enc_field_dirs)
+            .filter_map(|field_dir| field_dir.mac_name.as_ref());
+        for mac_id in mac_ids.sorted_by_key(|id| position(id)) {
+            if position(mac_id).is_some_and(|i| (first..=last).contains(&i)) {
+                self.error(
+                    span,
+                    format!(
+                        "CHECKSUM range `{}..{}` covers MAC field `{}`, which is only set \
+                         once the message is encrypted",
+                        from.0, to.0, mac_id.0
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Checks that encryption directives refer to fields of the format they
+    /// encrypt, and that we have a key to encrypt with.
+    fn check_crypto(&mut self) {
+        let Some(ref crypto_spec) = self.psf.crypto_spec else {
+            self.check_salts_unused();
+            return;
+        };
+        let source_map = &self.psf.source_map;
+
+        // Keys agreed on with the peer are fresh for every connection, but keys
+        // from the password alone are fresh only for the salt we mix in.
+        let has_pubkey = self.psf.formats.values().any(|afs| {
+            afs.semantics
+                .as_ref()
+                .values()
+                .any(|s| matches!(s, FieldSemantic::Pubkey(_)))
+        });
+        let needs_salt = crypto_spec.password.is_some() && !has_pubkey;
+
+        let directives = crypto_spec.directives.values().sorted_by_key(|d| {
+            source_map
+                .encryption_directives
+                .get(&d.enc_fmt_bnd.to_format_name)
+        });
+
+        for directive in directives {
+            let format_id = &directive.enc_fmt_bnd.to_format_name;
+            let span = source_map.encryption_directives.get(format_id).copied();
+
+            let Some(afs) = self.psf.formats.get(format_id) else {
+                self.error(
+                    span,
+                    format!("cannot encrypt undefined format `{}`", format_id.0),
+                );
+                continue;
+            };
+            if directive.enc_fmt_bnd.from_format_name != *format_id {
+                self.error(
+                    span,
+                    format!(
+                        "cannot encrypt format `{}` from a different format `{}`",
+                        format_id.0, directive.enc_fmt_bnd.from_format_name.0
+                    ),
+                );
+            }
+
+            for (i, field_dir) in directive.enc_field_dirs.iter().enumerate() {
+                let key = (format_id.clone(), i);
+                let span = source_map
+                    .encryption_field_directives
+                    .get(&key)
+                    .copied()
+                    .or(span);
+                self.check_encryption_field_directive(&afs.format.format, field_dir, span);
+            }
+        }
+
+        self.check_salts(crypto_spec, needs_salt);
+
+        if !crypto_spec.directives.is_empty() && crypto_spec.password.is_none() && !has_pubkey {
+            self.error(
+                source_map.crypto,
+                "encryption requires a PASSWORD or a PUBKEY field to derive keys from".to_string(),
+            );
+        }
+    }
+
+    /// Checks that each role sends its salt once, in the first message it
+    /// sends, since a salt repeated in later messages would mark every message
+    /// of the connection. With keys from a password alone, a role that encrypts
+    /// must send a salt, ahead of the fields that a receiver decrypts with it.
+    fn check_salts(&mut self, crypto_spec: &CryptoSpec, needs_salt: bool) {
+        let psf = self.psf;
+        let span = |i: usize| psf.source_map.sequence.get(i).copied();
+        // We already reported formats with more than one SALT field.
+        let salt_ids: HashMap<&Identifier, Identifier> = psf
+            .formats
+            .iter()
+            .filter_map(|(format_id, afs)| {
+                self.find_fields(format_id, &afs.semantics, &FieldSemantic::Salt)
+                    .into_iter()
+                    .next()
+                    .map(|salt_id| (format_id, salt_id))
+            })
+            .collect();
+        let salt_id = |format_id: &Identifier| salt_ids.get(format_id).cloned();
+        let directive = |format_id: &Identifier| {
+            crypto_spec
+                .directives
+                .values()
+                .find(|d| d.enc_fmt_bnd.to_format_name == *format_id)
+        };
+
+        for role in [Role::Client, Role::Server] {
+            let sent: Vec<usize> = (0..psf.sequence.len())
+                .filter(|&i| psf.sequence[i].role == role)
+                .collect();
+            let Some((&first, later)) = sent.split_first() else {
+                continue;
+            };
+
+            for &i in later {
+                if salt_id(&psf.sequence[i].format).is_some() {
+                    self.error(
+                        span(i),
+                        format!(
+                            "format `{}` has a SALT field, but {} sends its salt only in the \
+                             first message it sends",
+                            psf.sequence[i].format.0,
+                            role_name(role)
+                        ),
+                    );
+                }
+            }
+
+            let format_id = &psf.sequence[first].format;
+            // We already reported undefined formats.
+            let Some(afs) = psf.formats.get(format_id) else {
+                continue;
+            };
+            let Some(salt_id) = salt_id(format_id) else {
+                let encrypts = sent
+                    .iter()
+                    .any(|&i| directive(&psf.sequence[i].format).is_some());
+                if needs_salt && encrypts {
+                    self.error(
+                        span(first),
+                        format!(
+                            "encryption with a PASSWORD requires a SALT field in format `{}`, the \
+                             first that {} sends",
+                            format_id.0,
+                            role_name(role)
+                        ),
+                    );
+                }
+                continue;
+            };
+
+            if psf.sequence[first].phase == Phase::Data {
+                self.error(
+                    span(first),
+                    format!(
+                        "format `{}` has a SALT field, so {} must send it in the HANDSHAKE phase, \
+                         which does not repeat it",
+                        format_id.0,
+                        role_name(role)
+                    ),
+                );
+            }
+
+            let Some(directive) = directive(format_id) else {
+                continue;
+            };
+            let position = |field_id: &Identifier| {
+                afs.format
+                    .format
+                    .fields
+                    .iter()
+                    .position(|field| field.name == *field_id)
+            };
+            let salt_position = position(&salt_id);
+            for field_dir in &directive.enc_field_dirs {
+                let ctext_id = &field_dir.ctext_name;
+                // We already reported fields that do not exist.
+                if position(ctext_id).is_some_and(|p| Some(p) <= salt_position) {
+                    self.error(
+                        psf.source_map.encryption_directives.get(format_id).copied(),
+                        format!(
+                            "SALT field `{}` must come before encrypted field `{}`",
+                            salt_id.0, ctext_id.0
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Checks that there are no SALT fields without a CRYPTO segment, which
+    /// would have no keys to mix them into.
+    fn check_salts_unused(&mut self) {
+        let source_map = &self.psf.source_map;
+        let formats = self
+            .psf
+            .formats
+            .iter()
+            .sorted_by_key(|(id, _)| source_map.formats.get(*id));
+
+        for (format_id, afs) in formats {
+            for field_id in self.find_fields(format_id, &afs.semantics, &FieldSemantic::Salt) {
+                self.error(
+                    self.semantic_span(format_id, &field_id),
+                    format!(
+                        "SALT field `{}` requires a CRYPTO segment to mix it into the keys",
+                        field_id.0
+                    ),
+                );
+            }
+        }
+    }
+
+    /// Checks that we can split writes at the named field or the LENGTH field,
+    /// which means it must be at a fixed offset in every format that has it.
+    fn check_options(&mut self) {
+        let separate_length_field = self
+            .psf
+            .options
+            .as_ref()
+            .is_some_and(|o| o.separate_length_field_setting);
+        if separate_length_field {
+            let formats = self
+                .psf
+                .formats
+                .values()
+                .sorted_by_key(|afs| self.psf.source_map.formats.get(&afs.format.format.name));
+            for afs in formats {
+                let format = &afs.format.format;
+                let Some(length_field_id) =
+                    afs.semantics.find_field_id(FieldSemantic::Length(None))
+                else {
+                    continue;
+                };
+                // A named split point takes precedence in formats that have it.
+                let split_after_named_field = self
+                    .psf
+                    .options
+                    .as_ref()
+                    .and_then(|o| o.split_write_after_setting.as_ref())
+                    .is_some_and(|id| format.try_get_field_by_name(id).is_some());
+                if !split_after_named_field
+                    && format.try_get_field_by_name(&length_field_id).is_some()
+                    && !in_fixed_prefix(format, &length_field_id)
+                {
+                    self.error(
+                        self.psf.source_map.separate_length_field,
+                        format!(
+                            "cannot split writes after LENGTH field `{}` of format `{}`, which \
+                             must come before the first variable-length field",
+                            length_field_id.0, format.name.0
+                        ),
+                    );
+                }
+            }
+        }
+
+        let Some(ref field_id) = self
+            .psf
+            .options
+            .as_ref()
+            .and_then(|o| o.split_write_after_setting.clone())
+        else {
+            return;
+        };
+        let span = self.psf.source_map.split_write_after;
+
+        let formats: Vec<&Format> = self
+            .psf
+            .formats
+            .values()
+            .map(|afs| &afs.format.format)
+            .filter(|format| format.try_get_field_by_name(field_id).is_some())
+            .sorted_by_key(|format| self.psf.source_map.formats.get(&format.name))
+            .collect();
+
+        if formats.is_empty() {
+            self.error(
+                span,
+                format!("cannot split writes after undefined field `{}`", field_id.0),
+            );
+        }
+        for format in formats {
+            if !in_fixed_prefix(format, field_id) {
+                self.error(
+                    span,
+                    format!(
+                        "cannot split writes after field `{}` of format `{}`, which must come \
+                         before the first variable-length field",
+                        field_id.0, format.name.0
+                    ),
+                );
+            }
+        }
+    }
+
+    fn check_timing(&mut self) {
+        let Some(ref timing) = self.psf.timing_spec else {
+            return;
+        };
+        let source_map = &self.psf.source_map;
+        let settings = [
+            (
+                "MESSAGE_DELAY",
+                &timing.message_delay,
+                source_map.message_delay,
+            ),
+            ("BURST_SIZE", &timing.burst_size, source_map.burst_size),
+            ("BURST_DELAY", &timing.burst_delay, source_map.burst_delay),
+            ("COVER_DELAY", &timing.cover_delay, source_map.cover_delay),
+        ];
+
+        for (name, histogram, span) in settings {
+            let Some(histogram) = histogram else {
+                continue;
+            };
+            for (low, high, _) in &histogram.bins {
+                if low > high {
+                    self.error(span, format!("{name} bin {low}..{high} is empty"));
+                }
+            }
+            if histogram.bins.iter().all(|(_, _, weight)| *weight == 0) {
+                self.error(span, format!("{name} has no bin with a weight"));
+            }
+        }
+
+        if let Some(ref burst_size) = timing.burst_size {
+            if burst_size.bins.iter().any(|(low, _, _)| *low == 0) {
+                self.error(
+                    source_map.burst_size,
+                    "BURST_SIZE must not pick bursts of 0 bytes".to_string(),
+                );
+            }
+        } else if timing.burst_delay.is_some() {
+            self.error(
+                source_map.burst_delay,
+                "BURST_DELAY requires BURST_SIZE to split messages into bursts".to_string(),
+            );
+        }
+
+        // We only wait for the application, and so only send cover, in the
+        // DATA phase.
+        let marks_cover =
+            |seqspec: &SequenceSpecifier| {
+                seqspec.condition == Some(SendCondition::Cover)
+                    || self.psf.formats.get(&seqspec.format).is_some_and(|afs| {
+                        afs.semantics.find_field_id(FieldSemantic::Cover).is_some()
+                    })
+            };
+        if timing.cover_delay.is_some()
+            && !self
+                .psf
+                .sequence
+                .iter()
+                .any(|seqspec| seqspec.phase == Phase::Data && marks_cover(seqspec))
+        {
+            self.error(
+                source_map.cover_delay,
+                "COVER_DELAY requires a DATA format with a COVER field or sent WHEN: COVER to mark \
+                 cover messages"
+                    .to_string(),
+            );
+        }
+    }
+
+    fn check_encryption_field_directive(
+        &mut self,
+        format: &Format,
+        field_dir: &EncryptionFieldDirective,
+        span: Option<Span>,
+    ) {
+        let mut lookup = |field_id: &Identifier| {
+            let field = format.try_get_field_by_name(field_id);
+            if field.is_none() {
+                self.error(
+                    span,
+                    format!("format `{}` has no field `{}`", format.name.0, field_id.0),
+                );
+            }
+            field
+        };
+
+        let ptext = lookup(&field_dir.ptext_name);
+        let ctext = lookup(&field_dir.ctext_name);
+        let mac = field_dir.mac_name.as_ref().and_then(&mut lookup);
+
+        // We encrypt whole bytes.
+        for field in [&ptext, &ctext, &mac]
+            .into_iter()
+            .flatten()
+            .unique_by(|field| &field.name)
+        {
+            if field.dtype.is_bitfield() {
+                self.error(
+                    span,
+                    format!(
+                        "cannot encrypt bitfield `{}`, which shares bytes with other fields",
+                        field.name.0
+                    ),
+                );
+            }
+        }
+
+        let Some(ctext) = ctext else {
+            return;
+        };
+        if is_varint(&ctext) {
+            self.error(
+                span,
+                format!(
+                    "CTEXT field `{}` cannot be a varint, since a receiver reads its width before \
+                     it decrypts it",
+                    ctext.name.0
+                ),
+            );
+            return;
+        }
+        let segments = format.split_into_segments();
+        let segment_of = |field_id: &Identifier| {
+            segments
+                .iter()
+                .position(|segment| segment.try_get_field_by_name(field_id).is_some())
+        };
+        // A receiver decrypts a field once it read the segment it is in.
+        let same_segment = |field: &Field| segment_of(&field.name) == segment_of(&ctext.name);
+
+        if let Some(ptext) = ptext {
+            if ptext.dtype != ctext.dtype {
+                self.error(
+                    span,
+                    format!(
+                        "PTEXT field `{}` has type `{}`, but CTEXT field `{}` has type `{}`",
+                        ptext.name.0, ptext.dtype, ctext.name.0, ctext.dtype
+                    ),
+                );
+            } else if !same_segment(&ptext) {
+                self.error(
+                    span,
+                    format!(
+                        "PTEXT field `{}` and CTEXT field `{}` must not be separated by the start \
+                         of a variable-length field",
+                        ptext.name.0, ctext.name.0
+                    ),
+                );
+            }
+        }
+
+        if let Some(mac) = mac {
+            // Unwrap OK: the cipher is always set when there are directives.
+            let mac_nbytes = self
+                .psf
+                .crypto_spec
+                .as_ref()
+                .unwrap()
+                .cipher
+                .mac_tag_nbytes();
+            if mac.maybe_size_of() != Some(mac_nbytes.into()) {
+                self.error(
+                    span,
+                    format!(
+                        "MAC field `{}` must have type `[u8; {}]`, but has type `{}`",
+                        mac.name.0, mac_nbytes, mac.dtype
+                    ),
+                );
+            } else if !same_segment(&mac) {
+                self.error(
+                    span,
+                    format!(
+                        "MAC field `{}` and CTEXT field `{}` must not be separated by the start of \
+                         a variable-length field",
+                        mac.name.0, ctext.name.0
+                    ),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lang::compiler::parser::parse_psf;
+
+    fn diagnostics(psf: &str) -> Vec<String> {
+        match parse_psf(psf) {
+            Ok(_) => vec![],
+            Err(e) => e
+                .downcast::<ValidationError>()
+                .unwrap()
+                .0
+                .iter()
+                .map(|d| d.to_string())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn valid_psf() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Salt
+  { NAME: salt    ; TYPE: [u8; 32] };
+DEFINE Msg
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] },
+  { NAME: mac     ; TYPE: [u8; 16] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Salt; FIELD: salt;    SEMANTIC: SALT };
+{ FORMAT: Msg;  FIELD: length;  SEMANTIC: LENGTH };
+{ FORMAT: Msg;  FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Msg;  FIELD: payload; SEMANTIC: PAYLOAD };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: HANDSHAKE; FORMAT: Salt };
+{ ROLE: SERVER; PHASE: HANDSHAKE; FORMAT: Salt };
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+@SEGMENT.CRYPTO
+PASSWORD = "hunter2";
+CIPHER = CHACHA20-POLY1305;
+ENCRYPT Msg FROM Msg
+  { PTEXT: payload; CTEXT: payload; MAC: mac };"#;
+
+        assert_eq!(diagnostics(psf), Vec::<String>::new());
+    }
+
+    #[test]
+    fn syntax_error() {
+        let err = parse_psf("@SEGMENT.FORMATS\nDEFINE Msg;").unwrap_err();
+        assert!(err.to_string().contains("2:11"), "{err}");
+    }
+
+    #[test]
+    fn semantic_types() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: length  ; TYPE: bool },
+  { NAME: header  ; TYPE: [u8; 2] },
+  { NAME: key     ; TYPE: [u8; 16] },
+  { NAME: payload ; TYPE: [u8; 4] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: length;  SEMANTIC: LENGTH };
+{ FORMAT: Msg; FIELD: header;  SEMANTIC: FIXED_BYTES(0x170303) };
+{ FORMAT: Msg; FIELD: key;     SEMANTIC: PUBKEY(RAW) };
+{ FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Msg; FIELD: missing; SEMANTIC: RANDOM(4) };
+{ FORMAT: Other; FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Msg; FIELD: header;  SEMANTIC: FIXED_STRING("ab") };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Msg };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "8:1: LENGTH field `length` must be an unsigned integer, but has type `bool`",
+                "9:1: FIXED_BYTES value is 3 bytes long, but field `header` of type `[u8; 2]` \
+                 holds only 2 bytes",
+                "10:1: Raw public key field `key` must have type `[u8; 32]`, but has type `[u8; \
+                 16]`",
+                "11:1: PAYLOAD field `payload` must be variable-length, but has type `[u8; 4]`",
+                "12:1: format `Msg` has no field `missing`",
+                "13:1: semantic bound to undefined format `Other`",
+                "14:1: field `header` of format `Msg` is already bound to another semantic",
+            ]
+        );
+    }
+
+    #[test]
+    fn payload_layout() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: payload ; TYPE: [u8; length.size_of] },
+  { NAME: length  ; TYPE: u16 },
+  { NAME: extra   ; TYPE: [u8; nowhere.size_of] },
+  { NAME: length  ; TYPE: u8 };
+DEFINE Small
+  { NAME: length  ; TYPE: u8 },
+  { NAME: payload ; TYPE: [u8; length.size_of] },
+  { NAME: trailer ; TYPE: [u8; 254] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: length;  SEMANTIC: LENGTH };
+{ FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Small; FIELD: length;  SEMANTIC: LENGTH };
+{ FORMAT: Small; FIELD: payload; SEMANTIC: PAYLOAD };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Small };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "3:3: the size of field `payload` is given by field `length`, which must come \
+                 before it",
+                "5:3: the size of field `extra` is given by undefined field `nowhere`",
+                "5:3: variable-length field `extra` must have the PAYLOAD, PADDING, FIXED_BYTES, \
+                 FIXED_STRING, or RANDOM semantic",
+                "6:3: field `length` is already defined in format `Msg`",
+                "8:3: LENGTH field `length` of type `u8` is too small to count the payload and \
+                 the 254 other bytes it counts",
+            ]
+        );
+    }
+
+    #[test]
+    fn length_expressions() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Words
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] };
+DEFINE Twice
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] };
+DEFINE Missing
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] };
+DEFINE Backwards
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] },
+  { NAME: mac     ; TYPE: [u8; 16] };
+DEFINE Shrinks
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] };
+DEFINE Negative
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] };
+DEFINE Remainder
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] },
+  { NAME: mac     ; TYPE: [u8; 15] };
+DEFINE Offset
+  { NAME: header  ; TYPE: [u8; 3] },
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] },
+  { NAME: mac     ; TYPE: [u8; 16] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Words;     FIELD: length;  SEMANTIC: LENGTH(payload.size_of / 4) };
+{ FORMAT: Words;     FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Twice;     FIELD: length;  SEMANTIC: LENGTH(payload.size_of * 2 + payload.size_of) };
+{ FORMAT: Twice;     FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Missing;   FIELD: length;  SEMANTIC: LENGTH(payload.size_of + trailer.size_of) };
+{ FORMAT: Missing;   FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Backwards; FIELD: length;  SEMANTIC: LENGTH((mac..payload).size_of) };
+{ FORMAT: Backwards; FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Shrinks;   FIELD: length;  SEMANTIC: LENGTH(100 - payload.size_of) };
+{ FORMAT: Shrinks;   FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Negative;  FIELD: length;  SEMANTIC: LENGTH(payload.size_of - 4) };
+{ FORMAT: Negative;  FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Remainder; FIELD: length;  SEMANTIC: LENGTH(payload.size_of * 4 + mac.size_of / 4) };
+{ FORMAT: Remainder; FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Offset;    FIELD: length;  SEMANTIC: LENGTH((header..mac).size_of + 5) };
+{ FORMAT: Offset;    FIELD: payload; SEMANTIC: PAYLOAD };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Words };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Offset };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "31:1: LENGTH expression of field `length` divides the size of PAYLOAD field \
+                 `payload`, so the dividend must add it and the size of a PADDING field once \
+                 each, which we align to the divisor",
+                "33:1: LENGTH expression of field `length` must count the size of PAYLOAD field \
+                 `payload` exactly once",
+                "35:1: LENGTH expression of field `length` refers to undefined field `trailer`",
+                "37:1: LENGTH expression of field `length` has a range whose last field comes \
+                 before its first",
+                "39:1: LENGTH expression of field `length` must grow with the size of PAYLOAD \
+                 field `payload`",
+                "41:1: LENGTH expression of field `length` is negative for an empty payload",
+                "43:1: LENGTH expression of field `length` divides 15 bytes by 4, which leaves a \
+                 remainder",
+            ]
+        );
+    }
+
+    #[test]
+    fn payload_divisions() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Words
+  { NAME: length         ; TYPE: u16 },
+  { NAME: padding_length ; TYPE: u8 },
+  { NAME: payload        ; TYPE: [u8; length.size_of] },
+  { NAME: padding        ; TYPE: [u8; padding_length.size_of] };
+DEFINE Halves
+  { NAME: length         ; TYPE: u16 },
+  { NAME: padding_length ; TYPE: u8 },
+  { NAME: payload        ; TYPE: [u8; length.size_of] },
+  { NAME: padding        ; TYPE: [u8; padding_length.size_of] };
+DEFINE Divisor
+  { NAME: length         ; TYPE: u16 },
+  { NAME: padding_length ; TYPE: u8 },
+  { NAME: payload        ; TYPE: [u8; length.size_of] },
+  { NAME: padding        ; TYPE: [u8; padding_length.size_of] };
+DEFINE Zero
+  { NAME: length         ; TYPE: u16 },
+  { NAME: padding_length ; TYPE: u8 },
+  { NAME: payload        ; TYPE: [u8; length.size_of] },
+  { NAME: padding        ; TYPE: [u8; padding_length.size_of] };
+DEFINE Overflow
+  { NAME: length         ; TYPE: u16 },
+  { NAME: padding_length ; TYPE: u8 },
+  { NAME: payload        ; TYPE: [u8; length.size_of] },
+  { NAME: padding        ; TYPE: [u8; padding_length.size_of] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Words;    FIELD: length;         SEMANTIC: LENGTH((payload..padding).size_of / 4) };
+{ FORMAT: Words;    FIELD: padding_length; SEMANTIC: PADDING_LENGTH };
+{ FORMAT: Words;    FIELD: payload;        SEMANTIC: PAYLOAD };
+{ FORMAT: Words;    FIELD: padding;        SEMANTIC: PADDING(UNIFORM(16)) };
+{ FORMAT: Halves;   FIELD: length;         SEMANTIC: LENGTH((payload..padding).size_of / 2 / 2) };
+{ FORMAT: Halves;   FIELD: padding_length; SEMANTIC: PADDING_LENGTH };
+{ FORMAT: Halves;   FIELD: payload;        SEMANTIC: PAYLOAD };
+{ FORMAT: Halves;   FIELD: padding;        SEMANTIC: PADDING(UNIFORM(16)) };
+{ FORMAT: Divisor;  FIELD: length;         SEMANTIC: LENGTH(padding.size_of / payload.size_of) };
+{ FORMAT: Divisor;  FIELD: padding_length; SEMANTIC: PADDING_LENGTH };
+{ FORMAT: Divisor;  FIELD: payload;        SEMANTIC: PAYLOAD };
+{ FORMAT: Divisor;  FIELD: padding;        SEMANTIC: PADDING(UNIFORM(16)) };
+{ FORMAT: Zero;     FIELD: length;         SEMANTIC: LENGTH((payload..padding).size_of / 0) };
+{ FORMAT: Zero;     FIELD: padding_length; SEMANTIC: PADDING_LENGTH };
+{ FORMAT: Zero;     FIELD: payload;        SEMANTIC: PAYLOAD };
+{ FORMAT: Zero;     FIELD: padding;        SEMANTIC: PADDING(UNIFORM(16)) };
+{ FORMAT: Overflow; FIELD: length;         SEMANTIC: LENGTH((payload..padding).size_of / 8) };
+{ FORMAT: Overflow; FIELD: padding_length; SEMANTIC: PADDING_LENGTH };
+{ FORMAT: Overflow; FIELD: payload;        SEMANTIC: PAYLOAD };
+{ FORMAT: Overflow; FIELD: padding;        SEMANTIC: PADDING(UNIFORM(250)) };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Words };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Words };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "32:1: LENGTH expression of field `length` cannot divide the size of PAYLOAD \
+                 field `payload` more than once",
+                "36:1: LENGTH expression of field `length` cannot divide by the size of PAYLOAD \
+                 field `payload`",
+                "40:1: LENGTH expression of field `length` divides the size of PAYLOAD field \
+                 `payload` by `0`, which must be positive and known before we run the protocol",
+                "47:1: PADDING field `padding` may take 257 bytes, but field `padding_length` of \
+                 type `u8` counts at most 255 bytes",
+            ]
+        );
+    }
+
+    #[test]
+    fn varints() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: length  ; TYPE: leb128 },
+  { NAME: flags   ; TYPE: quic_varint },
+  { NAME: ids     ; TYPE: [quic_varint; 2] },
+  { NAME: big     ; TYPE: u32le },
+  { NAME: signed  ; TYPE: i16le },
+  { NAME: extra   ; TYPE: [u8; signed.size_of] },
+  { NAME: payload ; TYPE: [u8; length.size_of] },
+  { NAME: mac     ; TYPE: [u8; 16] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: length;  SEMANTIC: LENGTH((flags..mac).size_of) };
+{ FORMAT: Msg; FIELD: flags;   SEMANTIC: FIXED_BYTES(0x01) };
+{ FORMAT: Msg; FIELD: extra;   SEMANTIC: RANDOM(4) };
+{ FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+@SEGMENT.CRYPTO
+PASSWORD = "hunter2";
+CIPHER = CHACHA20-POLY1305;
+ENCRYPT Msg FROM Msg
+  { PTEXT: length;  CTEXT: length;  MAC: NULL },
+  { PTEXT: payload; CTEXT: payload; MAC: mac };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "5:3: field `ids` cannot be an array of `quic_varint`, since each value has its \
+                 own width",
+                "8:3: the size of field `extra` is given by field `signed`, which must be an \
+                 unsigned integer but has type `i16le`",
+                "12:1: LENGTH expression of field `length` cannot count varint field `flags`, \
+                 whose width depends on its value",
+                "12:1: LENGTH expression of field `length` cannot count varint field `ids`, whose \
+                 width depends on its value",
+                "13:1: FIXED_BYTES field `flags` cannot have type `quic_varint`, whose width \
+                 depends on its value",
+                "17:1: encryption with a PASSWORD requires a SALT field in format `Msg`, the \
+                 first that CLIENT sends",
+                "18:1: encryption with a PASSWORD requires a SALT field in format `Msg`, the \
+                 first that SERVER sends",
+                "23:3: CTEXT field `length` cannot be a varint, since a receiver reads its width \
+                 before it decrypts it",
+            ]
+        );
+    }
+
+    #[test]
+    fn bitfields() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: fin     ; TYPE: u1 },
+  { NAME: opcode  ; TYPE: u4 },
+  { NAME: flags   ; TYPE: [u1; 3] },
+  { NAME: mask    ; TYPE: u2 },
+  { NAME: length  ; TYPE: u7 },
+  { NAME: kind    ; TYPE: u8 },
+  { NAME: odd     ; TYPE: u3 },
+  { NAME: payload ; TYPE: [u8; length.size_of] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: length;  SEMANTIC: LENGTH((mask..payload).size_of + opcode.size_of) };
+{ FORMAT: Msg; FIELD: opcode;  SEMANTIC: FIXED_VALUE(16) };
+{ FORMAT: Msg; FIELD: fin;     SEMANTIC: FIXED_BYTES(0x01) };
+{ FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+@SEGMENT.CRYPTO
+PASSWORD = "hunter2";
+CIPHER = CHACHA20-POLY1305;
+ENCRYPT Msg FROM Msg
+  { PTEXT: kind;  CTEXT: kind;  MAC: NULL },
+  { PTEXT: mask;  CTEXT: mask;  MAC: NULL };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "5:3: field `flags` cannot be an array of `u1`; define a field for each value",
+                "7:3: bitfields `fin` to `length` take 17 bits, which is not a whole number of \
+                 bytes",
+                "9:3: bitfields `odd` to `odd` take 3 bits, which is not a whole number of bytes",
+                "12:1: LENGTH expression of field `length` cannot count bitfield `mask` apart \
+                 from the bitfields it shares bytes with",
+                "12:1: LENGTH expression of field `length` cannot count bitfield `opcode` apart \
+                 from the bitfields it shares bytes with",
+                "13:1: FIXED_VALUE value 16 does not fit field `opcode` of type `u4`",
+                "14:1: FIXED_BYTES field `fin` cannot have type `u1`, which is less than a byte; \
+                 use FIXED_VALUE",
+                "17:1: encryption with a PASSWORD requires a SALT field in format `Msg`, the \
+                 first that CLIENT sends",
+                "18:1: encryption with a PASSWORD requires a SALT field in format `Msg`, the \
+                 first that SERVER sends",
+                "24:3: cannot encrypt bitfield `mask`, which shares bytes with other fields",
+            ]
+        );
+    }
+
+    #[test]
+    fn constrained_values() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: version ; TYPE: u16 },
+  { NAME: flag    ; TYPE: bool },
+  { NAME: small   ; TYPE: u8 },
+  { NAME: count   ; TYPE: u4 },
+  { NAME: kind    ; TYPE: u4 },
+  { NAME: word    ; TYPE: [u8; 2] },
+  { NAME: empty   ; TYPE: i8 };
+DEFINE Data
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: version; SEMANTIC: ONE_OF(0x0301, 0x0303 : 3) };
+{ FORMAT: Msg; FIELD: flag;    SEMANTIC: ONE_OF(true : 0, false : 0) };
+{ FORMAT: Msg; FIELD: small;   SEMANTIC: ONE_OF(1, 0x100, 'a') };
+{ FORMAT: Msg; FIELD: count;   SEMANTIC: RANGE(1, 15) };
+{ FORMAT: Msg; FIELD: kind;    SEMANTIC: RANGE(-1, 16) };
+{ FORMAT: Msg; FIELD: word;    SEMANTIC: RANGE(0, 1) };
+{ FORMAT: Msg; FIELD: empty;   SEMANTIC: RANGE(2, 1) };
+{ FORMAT: Data; FIELD: length;  SEMANTIC: LENGTH };
+{ FORMAT: Data; FIELD: payload; SEMANTIC: PAYLOAD };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: HANDSHAKE; FORMAT: Msg };
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Data };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "15:1: ONE_OF field `flag` has no value with a weight",
+                "16:1: ONE_OF value 256 does not fit field `small` of type `u8`",
+                "16:1: ONE_OF value 'a' does not fit field `small` of type `u8`",
+                "18:1: RANGE value -1 does not fit field `kind` of type `u4`",
+                "18:1: RANGE value 16 does not fit field `kind` of type `u4`",
+                "19:1: RANGE value 0 does not fit field `word` of type `[u8; 2]`",
+                "19:1: RANGE value 1 does not fit field `word` of type `[u8; 2]`",
+                "20:1: RANGE of field `empty` is empty",
+            ]
+        );
+    }
+
+    #[test]
+    fn fixed_values() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: version ; TYPE: i16le },
+  { NAME: flag    ; TYPE: bool },
+  { NAME: letter  ; TYPE: char },
+  { NAME: word    ; TYPE: [char; 4] },
+  { NAME: small   ; TYPE: u8 },
+  { NAME: letters ; TYPE: [char; 2] },
+  { NAME: name    ; TYPE: [char; 4] };
+DEFINE Data
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: version; SEMANTIC: FIXED_VALUE(-1) };
+{ FORMAT: Msg; FIELD: flag;    SEMANTIC: FIXED_VALUE(false) };
+{ FORMAT: Msg; FIELD: letter;  SEMANTIC: FIXED_VALUE(true) };
+{ FORMAT: Msg; FIELD: word;    SEMANTIC: FIXED_STRING("abcd") };
+{ FORMAT: Msg; FIELD: small;   SEMANTIC: FIXED_VALUE(256) };
+{ FORMAT: Msg; FIELD: letters; SEMANTIC: FIXED_VALUE('a') };
+{ FORMAT: Msg; FIELD: name;    SEMANTIC: FIXED_STRING("café") };
+{ FORMAT: Data; FIELD: length;  SEMANTIC: LENGTH };
+{ FORMAT: Data; FIELD: payload; SEMANTIC: PAYLOAD };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: HANDSHAKE; FORMAT: Msg };
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Data };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "16:1: FIXED_VALUE value true does not fit field `letter` of type `char`",
+                "18:1: FIXED_VALUE value 256 does not fit field `small` of type `u8`",
+                "19:1: FIXED_VALUE value 'a' does not fit field `letters` of type `[char; 2]`",
+                "20:1: FIXED_STRING value \"café\" must be ASCII",
+            ]
+        );
+    }
+
+    #[test]
+    fn dynamic_fields() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: length      ; TYPE: u16 },
+  { NAME: session_len ; TYPE: u8 },
+  { NAME: session_id  ; TYPE: [u8; session_len.size_of] },
+  { NAME: ext_len     ; TYPE: u16 },
+  { NAME: extensions  ; TYPE: [u8; ext_len.size_of] },
+  { NAME: payload     ; TYPE: [u8; length.size_of] },
+  { NAME: trailer     ; TYPE: [u8; ext_len.size_of] },
+  { NAME: cookie      ; TYPE: [u8; cookie_len.size_of] },
+  { NAME: cookie_len  ; TYPE: u8 };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: length;     SEMANTIC: LENGTH };
+{ FORMAT: Msg; FIELD: session_id; SEMANTIC: RANDOM(300) };
+{ FORMAT: Msg; FIELD: ext_len;    SEMANTIC: FIXED_BYTES(0x0004) };
+{ FORMAT: Msg; FIELD: extensions; SEMANTIC: FIXED_BYTES(0x00170000) };
+{ FORMAT: Msg; FIELD: payload;    SEMANTIC: PAYLOAD };
+{ FORMAT: Msg; FIELD: trailer;    SEMANTIC: RANDOM(4) };
+{ FORMAT: Msg; FIELD: cookie;     SEMANTIC: FIXED_STRING("yum") };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+@SEGMENT.CRYPTO
+PASSWORD = "hunter2";
+CIPHER = CHACHA20-POLY1305;
+ENCRYPT Msg FROM Msg
+  { PTEXT: length; CTEXT: length; MAC: NULL },
+  { PTEXT: session_len; CTEXT: session_len; MAC: NULL },
+  { PTEXT: ext_len; CTEXT: ext_len; MAC: NULL },
+  { PTEXT: length; CTEXT: ext_len; MAC: NULL };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "7:3: the size of field `extensions` is given by field `ext_len`, which must not \
+                 have the FIXED_BYTES semantic",
+                "9:3: the size of field `trailer` is given by field `ext_len`, which already \
+                 gives the size of field `extensions`",
+                "9:3: the size of field `trailer` is given by field `ext_len`, which must not \
+                 have the FIXED_BYTES semantic",
+                "10:3: the size of field `cookie` is given by field `cookie_len`, which must come \
+                 before it",
+                "10:3: the size of field `cookie` after PAYLOAD field `payload` is given by field \
+                 `cookie_len`, which must come before `payload`",
+                "14:1: RANDOM value is 300 bytes long, but field `session_len` of type `u8` \
+                 counts at most 255 bytes",
+                "21:1: encryption with a PASSWORD requires a SALT field in format `Msg`, the \
+                 first that CLIENT sends",
+                "22:1: encryption with a PASSWORD requires a SALT field in format `Msg`, the \
+                 first that SERVER sends",
+                "30:3: PTEXT field `length` and CTEXT field `ext_len` must not be separated by \
+                 the start of a variable-length field",
+            ]
+        );
+    }
+
+    #[test]
+    fn padding() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: length         ; TYPE: u16 },
+  { NAME: padding_length ; TYPE: u16 },
+  { NAME: padding        ; TYPE: [u8; length.size_of] },
+  { NAME: payload        ; TYPE: [u8; padding_length.size_of] };
+DEFINE Fixed
+  { NAME: padding_length ; TYPE: u16 };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: length;         SEMANTIC: LENGTH };
+{ FORMAT: Msg; FIELD: padding_length; SEMANTIC: PADDING_LENGTH };
+{ FORMAT: Msg; FIELD: payload;        SEMANTIC: PAYLOAD };
+{ FORMAT: Msg; FIELD: padding;        SEMANTIC: PADDING };
+{ FORMAT: Fixed; FIELD: padding_length; SEMANTIC: PADDING_LENGTH };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: HANDSHAKE; FORMAT: Fixed };
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Msg };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "12:1: the size of PAYLOAD field `payload` is given by field `padding_length`, \
+                 which must have the LENGTH semantic",
+                "13:1: the size of PADDING field `padding` is given by field `length`, which \
+                 must have the PADDING_LENGTH semantic",
+                "13:1: PADDING field `padding` pads to the block size of the cipher, which \
+                 requires a CRYPTO segment",
+                "14:1: format `Fixed` has a PADDING_LENGTH field but no PAYLOAD field",
+            ]
+        );
+    }
+
+    #[test]
+    fn padding_policies() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Block
+  { NAME: length         ; TYPE: u16 },
+  { NAME: padding_length ; TYPE: u16 },
+  { NAME: payload        ; TYPE: [u8; length.size_of] },
+  { NAME: padding        ; TYPE: [u8; padding_length.size_of] };
+DEFINE Buckets
+  { NAME: length         ; TYPE: quic_varint },
+  { NAME: padding_length ; TYPE: u8 },
+  { NAME: payload        ; TYPE: [u8; length.size_of] },
+  { NAME: padding        ; TYPE: [u8; padding_length.size_of] };
+DEFINE Distribution
+  { NAME: length         ; TYPE: u16 },
+  { NAME: padding_length ; TYPE: u16 },
+  { NAME: payload        ; TYPE: [u8; length.size_of] },
+  { NAME: padding        ; TYPE: [u8; padding_length.size_of] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Block; FIELD: length;                SEMANTIC: LENGTH };
+{ FORMAT: Block; FIELD: padding_length;        SEMANTIC: PADDING_LENGTH };
+{ FORMAT: Block; FIELD: payload;               SEMANTIC: PAYLOAD };
+{ FORMAT: Block; FIELD: padding;               SEMANTIC: PADDING };
+{ FORMAT: Buckets; FIELD: length;              SEMANTIC: LENGTH };
+{ FORMAT: Buckets; FIELD: padding_length;      SEMANTIC: PADDING_LENGTH };
+{ FORMAT: Buckets; FIELD: payload;             SEMANTIC: PAYLOAD };
+{ FORMAT: Buckets; FIELD: padding;             SEMANTIC: PADDING(BUCKETS(0, 1500)) };
+{ FORMAT: Distribution; FIELD: length;         SEMANTIC: LENGTH };
+{ FORMAT: Distribution; FIELD: padding_length; SEMANTIC: PADDING_LENGTH };
+{ FORMAT: Distribution; FIELD: payload;        SEMANTIC: PAYLOAD };
+{ FORMAT: Distribution; FIELD: padding;        SEMANTIC: PADDING(DISTRIBUTION(512 : 0)) };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: HANDSHAKE; FORMAT: Block };
+{ ROLE: CLIENT; PHASE: HANDSHAKE; FORMAT: Buckets };
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Distribution };
+@SEGMENT.CRYPTO
+PASSWORD = "hunter2";
+CIPHER = CHACHA20-POLY1305;"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "21:1: PADDING field `padding` pads to the block size of the cipher, but \
+                 ChaCha20Poly1305 has none",
+                "25:1: PADDING field `padding` pads to a size of 0 bytes",
+                "25:1: PADDING field `padding` pads the message to a size, which the width of \
+                 varint field `length` would change",
+                "25:1: PADDING field `padding` may take 1500 bytes, but field `padding_length` \
+                 of type `u8` counts at most 255 bytes",
+                "29:1: PADDING field `padding` has no size with a weight",
+            ]
+        );
+    }
+
+    #[test]
+    fn sequence() {
+        let formats = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] };
+DEFINE Hello
+  { NAME: hello   ; TYPE: [u8; 5] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: length;  SEMANTIC: LENGTH };
+{ FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+@SEGMENT.SEQUENCE
+"#;
+
+        let psf = formats.to_string()
+            + r#"{ ROLE: CLIENT; PHASE: HANDSHAKE; FORMAT: Goodbye };
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Hello };
+{ ROLE: CLIENT; PHASE: HANDSHAKE; FORMAT: Msg; WHEN: COVER };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Msg };"#;
+
+        assert_eq!(
+            diagnostics(&psf),
+            [
+                "11:1: undefined format `Goodbye`",
+                "12:1: format `Hello` is sent in the DATA phase but has no PAYLOAD field",
+                "13:1: only DATA messages can have a WHEN condition",
+                "13:1: a role can send several messages at the same step of the sequence only if \
+                 they are all DATA messages",
+            ]
+        );
+
+        let psf = formats.to_string()
+            + r#"{ ROLE: CLIENT; PHASE: HANDSHAKE; FORMAT: Hello };
+{ ROLE: SERVER; PHASE: HANDSHAKE; FORMAT: Hello };"#;
+
+        assert_eq!(
+            diagnostics(&psf),
+            ["12:1: the sequence must end with a DATA message"]
+        );
+    }
+
+    #[test]
+    fn transitions() {
+        let formats = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] };
+DEFINE Reply
+  { NAME: status  ; TYPE: u8 },
+  { NAME: flags   ; TYPE: [u8; 2] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: length;  SEMANTIC: LENGTH };
+{ FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+@SEGMENT.SEQUENCE
+"#;
+
+        let psf = formats.to_string()
+            + r#"{ FROM: Start; TO: Wait; ROLE: CLIENT; FORMAT: Reply };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Msg; GUARD: status == 0x1 };"#;
+
+        assert_eq!(
+            diagnostics(&psf),
+            [
+                "13:1: only transitions with FROM and TO can have a GUARD",
+                "13:1: the sequence must either list steps with PHASE or transitions with FROM \
+                 and TO",
+            ]
+        );
+
+        let psf = formats.to_string()
+            + r#"{ FROM: Start; TO: Wait; ROLE: CLIENT; FORMAT: Reply };
+{ FROM: Wait; TO: Start; ROLE: SERVER; FORMAT: Reply; GUARD: flags == 0x1 };
+{ FROM: Wait; TO: Open; ROLE: SERVER; FORMAT: Reply; GUARD: missing > 0x1 };
+{ FROM: Wait; TO: Open; ROLE: CLIENT; FORMAT: Reply };
+{ FROM: Open; TO: Closed; ROLE: CLIENT; FORMAT: Msg };"#;
+
+        assert_eq!(
+            diagnostics(&psf),
+            [
+                "13:1: GUARD field `flags` must be an unsigned integer, but has type `[u8; 2]`",
+                "13:1: both roles send in state `Wait`, so its transitions must all lead back to \
+                 it",
+                "14:1: format `Reply` has no field `missing`",
+                "14:1: the last transition out of state `Wait` with format `Reply` must have no \
+                 GUARD, so that every message has a transition",
+                "16:1: state `Closed` has no transitions out of it",
+            ]
+        );
+
+        let psf = formats.to_string()
+            + r#"{ FROM: Start; TO: Open; ROLE: CLIENT; FORMAT: Msg };
+{ FROM: Start; TO: Start; ROLE: CLIENT; FORMAT: Msg; GUARD: length < 0x2 };
+{ FROM: Open; TO: Open; ROLE: CLIENT; FORMAT: Msg; WHEN: PAYLOAD < 10; GUARD: length > 0x5 };
+{ FROM: Open; TO: Start; ROLE: CLIENT; FORMAT: Msg };"#;
+
+        assert_eq!(
+            diagnostics(&psf),
+            [
+                "13:1: transition is never taken, since a transition out of state `Start` with \
+                 format `Msg` comes before it without a GUARD",
+                "15:1: the transitions out of state `Open` with format `Msg` must have the same \
+                 WHEN condition",
+            ]
+        );
+    }
+
+    #[test]
+    fn split_write_after() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: len     ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; len.size_of] },
+  { NAME: trailer ; TYPE: [u8; 4] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: len;     SEMANTIC: LENGTH };
+{ FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+@SEGMENT.OPTIONS
+"#;
+
+        assert_eq!(
+            diagnostics(&(psf.to_string() + "SPLIT_WRITE_AFTER = len;")),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            diagnostics(&(psf.to_string() + "SPLIT_WRITE_AFTER = trailer;")),
+            [
+                "13:1: cannot split writes after field `trailer` of format `Msg`, which must come \
+              before the first variable-length field"
+            ]
+        );
+        assert_eq!(
+            diagnostics(&(psf.to_string() + "SPLIT_WRITE_AFTER = header;")),
+            ["13:1: cannot split writes after undefined field `header`"]
+        );
+        assert_eq!(
+            diagnostics(
+                &(psf.to_string()
+                    + "COALESCE_FLIGHTS = true;\nSPLIT_WRITE_AFTER = len;\nCOALESCE_FLIGHTS = \
+                       false;")
+            ),
+            ["15:1: option `COALESCE_FLIGHTS` is already set"]
+        );
+
+        let psf = psf.replace(
+            "{ NAME: len     ; TYPE: u16 },",
+            "{ NAME: pad_len ; TYPE: u8 },\n  { NAME: pad ; TYPE: [u8; pad_len.size_of] },\n  { \
+             NAME: len     ; TYPE: u16 },",
+        ) + "SEPARATE_LENGTH_FIELD = true;";
+        assert_eq!(
+            diagnostics(&psf),
+            [
+                "4:3: variable-length field `pad` must have the PAYLOAD, PADDING, FIXED_BYTES, \
+                 FIXED_STRING, or RANDOM semantic",
+                "15:1: cannot split writes after LENGTH field `len` of format `Msg`, which must \
+                 come before the first variable-length field",
+            ]
+        );
+    }
+
+    #[test]
+    fn timing() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: len     ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; len.size_of] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: len;     SEMANTIC: LENGTH };
+{ FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+@SEGMENT.TIMING
+"#;
+
+        assert_eq!(
+            diagnostics(
+                &(psf.to_string()
+                    + "MESSAGE_DELAY = HISTOGRAM(0..20 : 9, 100..500 : 1);\n\
+                       BURST_SIZE = HISTOGRAM(1..1400);\n\
+                       BURST_DELAY = HISTOGRAM(0);")
+            ),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            diagnostics(
+                &(psf.to_string()
+                    + "MESSAGE_DELAY = HISTOGRAM(20..10, 5 : 0);\n\
+                       BURST_SIZE = HISTOGRAM(0..1400 : 0);")
+            ),
+            [
+                "12:1: MESSAGE_DELAY bin 20..10 is empty",
+                "13:1: BURST_SIZE has no bin with a weight",
+                "13:1: BURST_SIZE must not pick bursts of 0 bytes",
+            ]
+        );
+        assert_eq!(
+            diagnostics(&(psf.to_string() + "BURST_DELAY = HISTOGRAM(10);")),
+            ["12:1: BURST_DELAY requires BURST_SIZE to split messages into bursts"]
+        );
+        assert_eq!(
+            diagnostics(
+                &(psf.to_string()
+                    + "BURST_SIZE = HISTOGRAM(1..1400);\n\
+                       MESSAGE_DELAY = HISTOGRAM(0..20);\n\
+                       BURST_SIZE = HISTOGRAM(100);")
+            ),
+            ["14:1: timing setting `BURST_SIZE` is already set"]
+        );
+        assert_eq!(
+            diagnostics(&(psf.to_string() + "COVER_DELAY = HISTOGRAM(100..1000);")),
+            [
+                "12:1: COVER_DELAY requires a DATA format with a COVER field or sent WHEN: COVER to \
+              mark cover messages"
+            ]
+        );
+    }
+
+    #[test]
+    fn cover() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: cover   ; TYPE: u1 },
+  { NAME: again   ; TYPE: u7 },
+  { NAME: len     ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; len.size_of] };
+DEFINE Hello
+  { NAME: cover   ; TYPE: [u8; 2] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: cover;   SEMANTIC: COVER };
+{ FORMAT: Msg; FIELD: again;   SEMANTIC: COVER };
+{ FORMAT: Msg; FIELD: len;     SEMANTIC: LENGTH };
+{ FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Hello; FIELD: cover; SEMANTIC: COVER };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: HANDSHAKE; FORMAT: Hello };
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+@SEGMENT.TIMING
+COVER_DELAY = HISTOGRAM(100..1000);"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "11:1: format `Msg` already has a COVER field `cover`",
+                "14:1: COVER field `cover` must be an unsigned integer, but has type `[u8; 2]`",
+                "14:1: format `Hello` has a COVER field but no PAYLOAD field",
+            ]
+        );
+    }
+
+    #[test]
+    fn variables() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Hello
+  { NAME: session ; TYPE: [u8; 8] },
+  { NAME: nonce   ; TYPE: leb128 };
+DEFINE Msg
+  { NAME: seq     ; TYPE: u8 },
+  { NAME: epoch   ; TYPE: [u8; 2] },
+  { NAME: session ; TYPE: [u8; 4] },
+  { NAME: token   ; TYPE: [u8; 4] },
+  { NAME: len     ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; len.size_of] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Hello; FIELD: session; SEMANTIC: SAVE_AS(session_id) };
+{ FORMAT: Hello; FIELD: nonce;   SEMANTIC: SAVE_AS(nonce) };
+{ FORMAT: Msg;   FIELD: seq;     SEMANTIC: COUNTER(0x100, 0) };
+{ FORMAT: Msg;   FIELD: epoch;   SEMANTIC: COUNTER(0, 1) };
+{ FORMAT: Msg;   FIELD: session; SEMANTIC: ECHO(session_id) };
+{ FORMAT: Msg;   FIELD: token;   SEMANTIC: ECHO(token) };
+{ FORMAT: Msg;   FIELD: len;     SEMANTIC: LENGTH };
+{ FORMAT: Msg;   FIELD: payload; SEMANTIC: PAYLOAD };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: HANDSHAKE; FORMAT: Hello };
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Msg };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "14:1: SAVE_AS field `nonce` must take whole bytes of a fixed width, but has \
+                 type `leb128`",
+                "15:1: COUNTER value 256 does not fit field `seq` of type `u8`",
+                "15:1: COUNTER field `seq` must have a step of at least 1",
+                "16:1: COUNTER field `epoch` must be an unsigned integer, but has type `[u8; 2]`",
+                "17:1: ECHO field `session` has 4 bytes, but SAVE_AS field `session` of format \
+                 `Hello` keeps 8 bytes as `session_id`",
+                "18:1: ECHO field `token` echoes `token`, but no SAVE_AS field keeps it",
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: secs    ; TYPE: u32 },
+  { NAME: millis  ; TYPE: u64le },
+  { NAME: short   ; TYPE: u32 },
+  { NAME: signed  ; TYPE: i32 },
+  { NAME: len     ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; len.size_of] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: secs;    SEMANTIC: TIMESTAMP(u32_seconds, 30) };
+{ FORMAT: Msg; FIELD: millis;  SEMANTIC: TIMESTAMP(u64_millis) };
+{ FORMAT: Msg; FIELD: short;   SEMANTIC: TIMESTAMP(u64_millis) };
+{ FORMAT: Msg; FIELD: signed;  SEMANTIC: TIMESTAMP(u32_seconds) };
+{ FORMAT: Msg; FIELD: len;     SEMANTIC: LENGTH };
+{ FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Msg };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "12:1: TIMESTAMP field `short` must be an unsigned integer of 8 bytes, but has \
+                 type `u32`",
+                "13:1: TIMESTAMP field `signed` must be an unsigned integer of 4 bytes, but has \
+                 type `i32`",
+            ]
+        );
+    }
+
+    #[test]
+    fn checksums() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: length  ; TYPE: u16 },
+  { NAME: mac     ; TYPE: [u8; 16] },
+  { NAME: a       ; TYPE: u16 },
+  { NAME: b       ; TYPE: u16 },
+  { NAME: crc     ; TYPE: [u8; 8] },
+  { NAME: var     ; TYPE: leb128 },
+  { NAME: hmac    ; TYPE: [u8; 8] },
+  { NAME: payload ; TYPE: [u8; length.size_of] },
+  { NAME: key     ; TYPE: [u8; 32] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: length;  SEMANTIC: LENGTH(payload.size_of) };
+{ FORMAT: Msg; FIELD: a;       SEMANTIC: CHECKSUM(INTERNET, a..b) };
+{ FORMAT: Msg; FIELD: b;       SEMANTIC: CHECKSUM(ADLER32, a..b) };
+{ FORMAT: Msg; FIELD: crc;     SEMANTIC: CHECKSUM(CRC32, payload..length) };
+{ FORMAT: Msg; FIELD: var;     SEMANTIC: CHECKSUM(CRC32, length..nope) };
+{ FORMAT: Msg; FIELD: hmac;    SEMANTIC: CHECKSUM(HMAC_SHA256, length..payload) };
+{ FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Msg; FIELD: key;     SEMANTIC: PUBKEY(RAW) };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+@SEGMENT.CRYPTO
+CIPHER = CHACHA20-POLY1305;
+ENCRYPT Msg FROM Msg
+  { PTEXT: length; CTEXT: length; MAC: mac };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "14:1: CHECKSUM fields `a` and `b` cover each other",
+                "16:1: CHECKSUM field `crc` has 8 bytes, but a CRC32 checksum has only 4",
+                "16:1: CHECKSUM range `payload..length` is empty, since `length` comes before \
+                 `payload`",
+                "17:1: CHECKSUM field `var` must take whole bytes of a fixed width, but has type \
+                 `leb128`",
+                "18:1: HMAC_SHA256 checksum requires a PASSWORD to derive its key from",
+                "18:1: CHECKSUM range `length..payload` covers MAC field `mac`, which is only set \
+                 once the message is encrypted",
+            ]
+        );
+    }
+
+    #[test]
+    fn salts() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Hello
+  { NAME: length  ; TYPE: u16 },
+  { NAME: salt    ; TYPE: [u8; 8] },
+  { NAME: again   ; TYPE: [u8; 16] },
+  { NAME: payload ; TYPE: [u8; length.size_of] };
+DEFINE Msg
+  { NAME: salt    ; TYPE: [u8; 16] },
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Hello; FIELD: length;  SEMANTIC: LENGTH };
+{ FORMAT: Hello; FIELD: salt;    SEMANTIC: SALT };
+{ FORMAT: Hello; FIELD: again;   SEMANTIC: SALT };
+{ FORMAT: Hello; FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Msg;   FIELD: salt;    SEMANTIC: SALT };
+{ FORMAT: Msg;   FIELD: length;  SEMANTIC: LENGTH };
+{ FORMAT: Msg;   FIELD: payload; SEMANTIC: PAYLOAD };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: HANDSHAKE; FORMAT: Hello };
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+@SEGMENT.CRYPTO
+PASSWORD = "hunter2";
+CIPHER = CHACHA20-POLY1305;
+ENCRYPT Hello FROM Hello
+  { PTEXT: length; CTEXT: length; MAC: NULL };
+ENCRYPT Msg FROM Msg
+  { PTEXT: payload; CTEXT: payload; MAC: NULL };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "13:1: SALT field `salt` must have at least 16 bytes, but has type `[u8; 8]`",
+                "14:1: format `Hello` already has a SALT field `salt`",
+                "21:1: format `Msg` has a SALT field, but CLIENT sends its salt only in the first \
+                 message it sends",
+                "22:1: format `Msg` has a SALT field, so SERVER must send it in the HANDSHAKE \
+                 phase, which does not repeat it",
+                "26:1: SALT field `salt` must come before encrypted field `length`",
+            ]
+        );
+
+        let unsalted = psf.replace("{ FORMAT: Msg;   FIELD: salt;    SEMANTIC: SALT };\n", "");
+        assert_eq!(
+            diagnostics(&unsalted),
+            [
+                "13:1: SALT field `salt` must have at least 16 bytes, but has type `[u8; 8]`",
+                "14:1: format `Hello` already has a SALT field `salt`",
+                "21:1: encryption with a PASSWORD requires a SALT field in format `Msg`, the \
+                 first that SERVER sends",
+                "25:1: SALT field `salt` must come before encrypted field `length`",
+            ]
+        );
+
+        let plaintext = &psf[..psf.find("@SEGMENT.CRYPTO").unwrap()];
+        assert_eq!(
+            diagnostics(plaintext),
+            [
+                "13:1: SALT field `salt` must have at least 16 bytes, but has type `[u8; 8]`",
+                "13:1: SALT field `salt` requires a CRYPTO segment to mix it into the keys",
+                "14:1: format `Hello` already has a SALT field `salt`",
+                "14:1: SALT field `again` requires a CRYPTO segment to mix it into the keys",
+                "16:1: SALT field `salt` requires a CRYPTO segment to mix it into the keys",
+            ]
+        );
+    }
+
+    #[test]
+    fn data_formats() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Short
+  { NAME: type    ; TYPE: u8 },
+  { NAME: length  ; TYPE: u8 },
+  { NAME: payload ; TYPE: [u8; length.size_of] };
+DEFINE Long
+  { NAME: length  ; TYPE: u16 },
+  { NAME: type    ; TYPE: u8 },
+  { NAME: payload ; TYPE: [u8; length.size_of] };
+DEFINE Late
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] },
+  { NAME: type    ; TYPE: u16 };
+DEFINE Idle
+  { NAME: type    ; TYPE: leb128 };
+@SEGMENT.SEMANTICS
+{ FORMAT: Short; FIELD: type;    SEMANTIC: TYPE_TAG(0x17) };
+{ FORMAT: Short; FIELD: length;  SEMANTIC: LENGTH };
+{ FORMAT: Short; FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Long;  FIELD: type;    SEMANTIC: TYPE_TAG(0x17) };
+{ FORMAT: Long;  FIELD: length;  SEMANTIC: LENGTH };
+{ FORMAT: Long;  FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Late;  FIELD: type;    SEMANTIC: TYPE_TAG(0x18) };
+{ FORMAT: Late;  FIELD: length;  SEMANTIC: LENGTH };
+{ FORMAT: Late;  FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Idle;  FIELD: type;    SEMANTIC: TYPE_TAG(0x19) };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Short; WHEN: PAYLOAD < 100 };
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Idle; WHEN: COVER };
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Long };
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Late };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Short; WHEN: COVER };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "20:1: TYPE_TAG field `type` must have the type and offset of TYPE_TAG field \
+                 `type` of format `Short`",
+                "20:1: formats `Short` and `Long` have the same TYPE_TAG value 23",
+                "23:1: TYPE_TAG field `type` must come before the variable-length fields, so that \
+                 the receiver can peek at it",
+                "26:1: TYPE_TAG field `type` must take whole bytes of a fixed width, but has type \
+                 `leb128`",
+                "31:1: format `Late` is never sent, since format `Long` comes before it without a \
+                 WHEN condition",
+                "32:1: the last DATA format SERVER sends at a step of the sequence must have no \
+                 WHEN condition, so that every message has a format",
+            ]
+        );
+    }
+
+    #[test]
+    fn encryption() {
+        let psf = r#"@SEGMENT.FORMATS
+DEFINE Msg
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] },
+  { NAME: mac     ; TYPE: [u8; 8] };
+DEFINE Other
+  { NAME: length  ; TYPE: u16 },
+  { NAME: payload ; TYPE: [u8; length.size_of] },
+  { NAME: mac     ; TYPE: [u8; 16] };
+@SEGMENT.SEMANTICS
+{ FORMAT: Msg; FIELD: length;  SEMANTIC: LENGTH };
+{ FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+{ FORMAT: Other; FIELD: length;  SEMANTIC: LENGTH };
+{ FORMAT: Other; FIELD: payload; SEMANTIC: PAYLOAD };
+@SEGMENT.SEQUENCE
+{ ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+{ ROLE: SERVER; PHASE: DATA; FORMAT: Other };
+@SEGMENT.CRYPTO
+CIPHER = AES128GCM;
+ENCRYPT Msg FROM Msg
+  { PTEXT: length; CTEXT: payload; MAC: NULL },
+  { PTEXT: payload; CTEXT: payload; MAC: mac };
+ENCRYPT Other FROM Msg
+  { PTEXT: length; CTEXT: length; MAC: mac };"#;
+
+        assert_eq!(
+            diagnostics(psf),
+            [
+                "18:1: encryption requires a PASSWORD or a PUBKEY field to derive keys from",
+                "21:3: PTEXT field `length` has type `u16`, but CTEXT field `payload` has type \
+                 `[u8; length.size_of]`",
+                "22:3: MAC field `mac` must have type `[u8; 16]`, but has type `[u8; 8]`",
+                "23:1: cannot encrypt format `Other` from a different format `Msg`",
+                "24:3: MAC field `mac` and CTEXT field `length` must not be separated by the \
+                 start of a variable-length field",
+            ]
+        );
+    }
+}
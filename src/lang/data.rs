@@ -17,6 +17,23 @@ pub enum Data {
     Number(u128),
 }
 
+/// A value the VM keeps for the rest of a connection, which it copies onto
+/// the heap as data.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Variable {
+    Bytes(Bytes),
+    Number(u128),
+}
+
+impl From<Variable> for Data {
+    fn from(value: Variable) -> Self {
+        match value {
+            Variable::Bytes(bytes) => Data::Bytes(bytes),
+            Variable::Number(num) => Data::Number(num),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum DataKind {
     Bytes,
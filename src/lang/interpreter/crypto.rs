@@ -1,12 +1,33 @@
 use std::sync::{Arc, Mutex};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
+use rand_core::{OsRng, RngCore};
 
-use crate::crypto::chacha::{Cipher, CipherKind, DecryptionCipher, EncryptionCipher};
+use crate::crypto::aead::{self, CipherKind, DecryptionCipher, EncryptionCipher};
+use crate::crypto::kdf::{self, SessionKeys};
+use crate::crypto::pubkey::{X25519KeyPair, X25519PubKey};
+use crate::lang::interpreter::vm::VmError;
+use crate::lang::types::Cipher;
 
 pub struct CryptoState {
     encryptor: Option<EncryptionCipher>,
     decryptor: Option<DecryptionCipher>,
+    // The password-derived key, which we mix into the agreed session keys.
+    master_key: Option<[u8; 32]>,
+    // The key length of the configured cipher.
+    key_nbytes: usize,
+    // The label that separates our session keys from other protocols'.
+    key_label: Vec<u8>,
+    // Our ephemeral key pair, which both directions must agree on.
+    keypair: Option<X25519KeyPair>,
+    // The public key the peer used in the key agreement.
+    peer_pubkey: Option<X25519PubKey>,
+    // Session keys from the key agreement. In the owned state, these are the
+    // keys we last applied to our owned ciphers.
+    session_keys: Option<SessionKeys>,
+    // In the owned state, the salt of our forwarding direction: the one we
+    // send if we encrypt, or the one the peer sent if we decrypt.
+    salt: Option<Vec<u8>>,
 }
 
 impl CryptoState {
@@ -14,8 +35,26 @@ impl CryptoState {
         Self {
             encryptor: None,
             decryptor: None,
+            master_key: None,
+            key_nbytes: 32,
+            key_label: kdf::DEFAULT_KEY_LABEL.to_vec(),
+            keypair: None,
+            peer_pubkey: None,
+            session_keys: None,
+            salt: None,
         }
     }
+
+    fn rekey(&mut self, keys: &SessionKeys) -> anyhow::Result<()> {
+        if let Some(enc) = self.encryptor.as_mut() {
+            enc.rekey(keys)?;
+        }
+        if let Some(dec) = self.decryptor.as_mut() {
+            dec.rekey(keys)?;
+        }
+        self.session_keys = Some(keys.clone());
+        Ok(())
+    }
 }
 
 /// Wrapper to allow us to safely share the internal ciphers across threads
@@ -55,9 +94,17 @@ impl CryptoStream {
         self.state_shared.clone()
     }
 
-    pub fn create_cipher(&mut self, secret_key: [u8; 32], kind: CipherKind) {
-        let cipher = Cipher::new(secret_key, kind);
-        let (enc, dec) = cipher.into_split();
+    pub fn create_cipher(
+        &mut self,
+        cipher: Cipher,
+        master_key: [u8; 32],
+        key_label: &[u8],
+        kind: CipherKind,
+    ) -> anyhow::Result<()> {
+        // Each direction gets its own key, at the length the cipher requires.
+        let key_nbytes = usize::from(cipher.key_length_nbytes());
+        let keys = kdf::derive_session_keys(&master_key, None, key_label, key_nbytes);
+        let (enc, dec) = aead::Cipher::new(cipher.into(), &keys, kind)?.into_split();
 
         // Store the ciphers in the _shared_ state so later each forwarding
         // direction can grab _only_ the enc or dec one they need.
@@ -66,19 +113,151 @@ impl CryptoStream {
             let mut crypt = self.state_shared.inner.lock().unwrap();
             crypt.encryptor = Some(enc);
             crypt.decryptor = Some(dec);
+            crypt.master_key = Some(master_key);
+            crypt.key_nbytes = key_nbytes;
+            crypt.key_label = key_label.to_vec();
+        }
+        Ok(())
+    }
+
+    /// Returns our ephemeral public key, generating the key pair on first use.
+    pub fn public_key(&mut self) -> anyhow::Result<X25519PubKey> {
+        // Take care not to panic in this scope while holding the lock.
+        let mut crypt = self
+            .state_shared
+            .inner
+            .lock()
+            .map_err(|e| anyhow!("Crypto mutex was poisoned: {}", e.to_string()))?;
+        Ok(crypt
+            .keypair
+            .get_or_insert_with(X25519KeyPair::new)
+            .public_key()
+            .clone())
+    }
+
+    /// Returns the key of keyed checksums, which requires a password.
+    pub fn checksum_key(&self) -> anyhow::Result<[u8; 32]> {
+        // Take care not to panic in this scope while holding the lock.
+        let crypt = self
+            .state_shared
+            .inner
+            .lock()
+            .map_err(|e| anyhow!("Crypto mutex was poisoned: {}", e.to_string()))?;
+        let master_key = crypt
+            .master_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("No password-derived key for a keyed checksum"))?;
+        Ok(kdf::derive_checksum_key(master_key, &crypt.key_label))
+    }
+
+    /// Agrees on session keys with the peer's public key and rekeys the
+    /// ciphers of both forwarding directions. A peer may repeat its public key
+    /// in later messages, but it may not change it.
+    pub fn agree_key(&mut self, peer: &X25519PubKey) -> anyhow::Result<()> {
+        let keys = {
+            // Take care not to panic in this scope while holding the lock.
+            let mut crypt = self
+                .state_shared
+                .inner
+                .lock()
+                .map_err(|e| anyhow!("Crypto mutex was poisoned: {}", e.to_string()))?;
+
+            if let Some(agreed) = crypt.peer_pubkey.as_ref() {
+                if agreed != peer {
+                    bail!("Peer changed its public key after key agreement");
+                }
+                return Ok(());
+            }
+
+            let shared_secret = crypt
+                .keypair
+                .get_or_insert_with(X25519KeyPair::new)
+                .diffie_hellman(peer)?;
+            let keys = kdf::derive_session_keys(
+                &shared_secret,
+                crypt.master_key.as_ref().map(|k| &k[..]),
+                &crypt.key_label,
+                crypt.key_nbytes,
+            );
+
+            // Ciphers not yet taken by a forwarding direction are rekeyed here,
+            // the others when their direction next loads them.
+            crypt.rekey(&keys)?;
+            crypt.peer_pubkey = Some(peer.clone());
+            keys
+        };
+        self.state_owned.rekey(&keys)
+    }
+
+    /// Picks the salt we send the peer at random and mixes it into the keys of
+    /// our encryptor. Every connection thus encrypts under its own keys, even
+    /// though the password is the same. We send the salt only once, since the
+    /// same bytes in every message would mark the connection.
+    pub fn salt(&mut self, nbytes: usize) -> anyhow::Result<Vec<u8>> {
+        if self.state_owned.salt.is_some() {
+            bail!("Salt was already sent");
         }
+
+        let mut salt = vec![0u8; nbytes];
+        OsRng.fill_bytes(&mut salt);
+
+        self.load_owned_encryptor()?;
+        if let Some(keys) = self.salted_session_keys(&salt)? {
+            self.load_owned_encryptor()?.rekey(&keys)?;
+        }
+        self.state_owned.salt = Some(salt.clone());
+        Ok(salt)
     }
 
-    pub fn init_key(&mut self, key: &[u8]) -> anyhow::Result<()> {
-        if let Some(enc) = self.state_owned.encryptor.as_mut() {
-            enc.init_key(key);
+    /// Mixes the salt the peer sent into the keys of our decryptor. The peer
+    /// sends its salt only once.
+    pub fn save_salt(&mut self, salt: &[u8]) -> anyhow::Result<()> {
+        if self.state_owned.salt.is_some() {
+            bail!("Peer sent its salt again");
         }
-        if let Some(dec) = self.state_owned.decryptor.as_mut() {
-            dec.init_key(key);
+
+        self.load_owned_decryptor()?;
+        if let Some(keys) = self.salted_session_keys(salt)? {
+            self.load_owned_decryptor()?.rekey(&keys)?;
         }
+        self.state_owned.salt = Some(salt.to_vec());
         Ok(())
     }
 
+    /// Derives session keys from the password-derived key and a salt, unless
+    /// our ciphers already use keys from a key agreement, which are fresh for
+    /// every connection.
+    fn salted_session_keys(&self, salt: &[u8]) -> anyhow::Result<Option<SessionKeys>> {
+        if self.state_owned.session_keys.is_some() {
+            return Ok(None);
+        }
+        // Take care not to panic in this scope while holding the lock.
+        let crypt = self
+            .state_shared
+            .inner
+            .lock()
+            .map_err(|e| anyhow!("Crypto mutex was poisoned: {}", e.to_string()))?;
+        Ok(crypt.master_key.as_ref().map(|master_key| {
+            kdf::derive_session_keys(master_key, Some(salt), &crypt.key_label, crypt.key_nbytes)
+        }))
+    }
+
+    /// Applies session keys that the other forwarding direction agreed on to
+    /// the ciphers we own.
+    fn sync_session_keys(&mut self) -> anyhow::Result<()> {
+        if self.state_owned.session_keys.is_some() {
+            return Ok(());
+        }
+        let keys = match self.state_shared.inner.lock() {
+            Ok(crypt) => crypt.session_keys.clone(),
+            Err(e) => bail!("Crypto mutex was poisoned: {}", e.to_string()),
+        };
+        match keys {
+            Some(keys) => self.state_owned.rekey(&keys),
+            None => Ok(()),
+        }
+    }
+
     fn take_shared_encryptor(&self) -> anyhow::Result<EncryptionCipher> {
         // Take care not to panic in this scope while holding the lock.
         let mut crypt = self.state_shared.inner.lock().unwrap();
@@ -94,6 +273,7 @@ impl CryptoStream {
             // Move an existing cipher from shared to local state.
             self.state_owned.encryptor = Some(self.take_shared_encryptor()?);
         }
+        self.sync_session_keys()?;
         self.state_owned
             .encryptor
             .as_mut()
@@ -101,7 +281,7 @@ impl CryptoStream {
     }
 
     pub fn encrypt(&mut self, plaintext: &[u8]) -> anyhow::Result<(Vec<u8>, [u8; 16])> {
-        Ok(self.load_owned_encryptor()?.encrypt(plaintext))
+        self.load_owned_encryptor()?.encrypt(plaintext)
     }
 
     pub fn encrypt_unauth(&mut self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
@@ -123,6 +303,7 @@ impl CryptoStream {
             // Move an existing cipher from shared to local state.
             self.state_owned.decryptor = Some(self.take_shared_decryptor()?);
         }
+        self.sync_session_keys()?;
         self.state_owned
             .decryptor
             .as_mut()
@@ -130,7 +311,9 @@ impl CryptoStream {
     }
 
     pub fn decrypt(&mut self, ciphertext: &[u8], mac: &[u8; 16]) -> anyhow::Result<Vec<u8>> {
-        Ok(self.load_owned_decryptor()?.decrypt(ciphertext, mac))
+        self.load_owned_decryptor()?
+            .decrypt(ciphertext, mac)
+            .map_err(|_| VmError::Authentication.into())
     }
 
     pub fn decrypt_unauth(&mut self, ciphertext: &[u8]) -> anyhow::Result<Vec<u8>> {
@@ -0,0 +1,157 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use anyhow::{Context, anyhow};
+use bytes::Bytes;
+use tokio::net::TcpStream;
+
+use super::replay::ReplayFilter;
+use crate::net::{self, Connection, Reader, Writer};
+
+/// The most bytes we read at once while draining a connection.
+const DRAIN_NBYTES: usize = 2usize.pow(14u32); // 16 KiB
+
+/// What a server does with a connection once its peer fails to authenticate,
+/// replays a message, or sends a message that does not match the protocol. A
+/// probing censor must not be able to distinguish us from a benign service by
+/// how or when we close the connection.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Fallback {
+    /// Close the connection immediately.
+    Close,
+    /// Keep reading and discarding data until the peer closes the connection.
+    #[default]
+    Tarpit,
+    /// Splice the connection to a decoy service, which receives all data the
+    /// peer sent so far.
+    Decoy(SocketAddr),
+}
+
+impl FromStr for Fallback {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("decoy", addr)) => {
+                Ok(Fallback::Decoy(addr.parse().with_context(|| {
+                    format!("invalid decoy address '{addr}'")
+                })?))
+            }
+            _ => match s {
+                "close" => Ok(Fallback::Close),
+                "tarpit" => Ok(Fallback::Tarpit),
+                _ => Err(anyhow!(
+                    "invalid fallback '{s}', expected 'close', 'tarpit', or 'decoy:<ADDR>'"
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Fallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fallback::Close => write!(f, "close"),
+            Fallback::Tarpit => write!(f, "tarpit"),
+            Fallback::Decoy(addr) => write!(f, "decoy:{addr}"),
+        }
+    }
+}
+
+/// How a server defends itself against active probing.
+#[derive(Clone, Default)]
+pub struct ProbeDefense {
+    /// Shared by all connections, so we detect messages replayed across them.
+    pub replay_filter: ReplayFilter,
+    pub fallback: Fallback,
+}
+
+impl ProbeDefense {
+    pub fn new(fallback: Fallback) -> Self {
+        Self {
+            replay_filter: ReplayFilter::default(),
+            fallback,
+        }
+    }
+}
+
+impl Fallback {
+    /// Handles the connection after we stopped running the protocol on it.
+    /// The `received` bytes are those the peer sent so far, if we have them,
+    /// and the `net_dst` is missing if we already closed it.
+    pub async fn apply<R: Reader, W: Writer>(
+        self,
+        mut net_src: R,
+        received: Option<Bytes>,
+        net_dst: Option<W>,
+    ) {
+        match (self, received, net_dst) {
+            (Fallback::Close, _, _) => {}
+            (Fallback::Decoy(addr), Some(received), Some(net_dst)) => {
+                if let Err(e) = splice_to_decoy(addr, received, net_src, net_dst).await {
+                    log::debug!("Failed to splice connection to decoy: {}", e);
+                }
+            }
+            (Fallback::Decoy(_), _, net_dst) => {
+                log::debug!("Cannot splice connection to decoy, tarpitting instead");
+                drain(&mut net_src).await;
+                drop(net_dst);
+            }
+            (Fallback::Tarpit, _, net_dst) => {
+                drain(&mut net_src).await;
+                drop(net_dst);
+            }
+        }
+    }
+}
+
+/// Reads and discards everything from `src` until the peer closes it, which
+/// looks the same to the peer as a service still waiting for a complete
+/// request.
+async fn drain<R: Reader>(src: &mut R) {
+    while src.read_bytes(1..DRAIN_NBYTES).await.is_ok() {}
+}
+
+/// Connects to the decoy, sends it the data the peer sent us so far, and then
+/// forwards data between the peer and the decoy until either closes.
+async fn splice_to_decoy<R: Reader, W: Writer>(
+    decoy_addr: SocketAddr,
+    received: Bytes,
+    net_src: R,
+    net_dst: W,
+) -> anyhow::Result<()> {
+    let decoy_stream = TcpStream::connect(decoy_addr)
+        .await
+        .with_context(|| format!("connecting to decoy {}", decoy_addr))?;
+    let (decoy_src, mut decoy_dst) = Connection::from(decoy_stream).into_split();
+    decoy_dst.write_bytes(&received).await?;
+
+    let (_, _) = tokio::join!(
+        net::forward(net_src, decoy_dst),
+        net::forward(decoy_src, net_dst)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fallback() {
+        assert_eq!("close".parse::<Fallback>().unwrap(), Fallback::Close);
+        assert_eq!("tarpit".parse::<Fallback>().unwrap(), Fallback::Tarpit);
+        assert_eq!(
+            "decoy:127.0.0.1:8080".parse::<Fallback>().unwrap(),
+            Fallback::Decoy("127.0.0.1:8080".parse().unwrap())
+        );
+        assert!("decoy".parse::<Fallback>().is_err());
+        assert!("decoy:localhost".parse::<Fallback>().is_err());
+        assert!("drop".parse::<Fallback>().is_err());
+
+        for fallback in [Fallback::Close, Fallback::Tarpit, Fallback::default()] {
+            assert_eq!(fallback.to_string().parse::<Fallback>().unwrap(), fallback);
+        }
+    }
+}
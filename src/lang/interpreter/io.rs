@@ -1,15 +1,24 @@
 use std::ops::Range;
 
 use anyhow::bail;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 
+use crate::lang::interpreter::shaper::Shaper;
 use crate::net::{Reader, Writer};
 
+/// The most bytes we record from the src before giving up on the recording.
+const MAX_RECORDING_NBYTES: usize = 2usize.pow(16u32); // 64 KiB
+
 pub struct IoStream<R: Reader, W: Writer> {
     src: R,
     n_recv_src: usize,
     dst: W,
     n_sent_dst: usize,
+    // Bytes received from src while we are recording.
+    recording: Option<BytesMut>,
+    shaper: Option<Shaper>,
+    // Bytes we send to dst while we hold our writes.
+    held: Option<BytesMut>,
 }
 
 impl<R: Reader, W: Writer> IoStream<R, W> {
@@ -19,10 +28,74 @@ impl<R: Reader, W: Writer> IoStream<R, W> {
             n_recv_src: 0,
             dst,
             n_sent_dst: 0,
+            recording: None,
+            shaper: None,
+            held: None,
+        }
+    }
+
+    /// Shapes the bytes we send to dst from now on.
+    pub fn shape(&mut self, shaper: Shaper) {
+        self.shaper = Some(shaper);
+    }
+
+    /// Records all bytes we receive from now on, up to a limit.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(BytesMut::new());
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.recording = None;
+    }
+
+    /// Returns the recorded bytes, unless we stopped recording or exceeded the
+    /// limit.
+    pub fn take_recording(&mut self) -> Option<Bytes> {
+        self.recording.take().map(|r| r.freeze())
+    }
+
+    pub fn into_src(self) -> R {
+        self.src
+    }
+
+    /// Holds the bytes we send to dst from now on, until we release them.
+    pub fn hold(&mut self) {
+        self.held.get_or_insert_with(BytesMut::new);
+    }
+
+    /// Sends the bytes we held as one write, and stops holding.
+    pub async fn release(&mut self) -> anyhow::Result<usize> {
+        match self.held.take() {
+            Some(held) if !held.is_empty() => self.send(held.freeze()).await,
+            _ => Ok(0),
+        }
+    }
+
+    pub async fn send(&mut self, mut bytes: Bytes) -> anyhow::Result<usize> {
+        if let Some(ref mut held) = self.held {
+            held.extend_from_slice(&bytes);
+            return Ok(bytes.len());
         }
+
+        let Some(ref shaper) = self.shaper else {
+            return self.send_now(bytes).await;
+        };
+
+        let mut num_written = 0;
+        for (delay, nbytes) in shaper.schedule(bytes.len()) {
+            if !delay.is_zero() {
+                // Make sure the bytes we wrote so far go out before we wait.
+                self.flush().await?;
+                log::trace!("waiting {delay:?} before sending {nbytes} bytes to dst");
+                tokio::time::sleep(delay).await;
+            }
+            num_written += self.send_now(bytes.split_to(nbytes)).await?;
+        }
+
+        Ok(num_written)
     }
 
-    pub async fn send(&mut self, bytes: Bytes) -> anyhow::Result<usize> {
+    async fn send_now(&mut self, bytes: Bytes) -> anyhow::Result<usize> {
         log::trace!("trying to send {} bytes to dst", bytes.len());
 
         let num_written = match self.dst.write_bytes(&bytes).await {
@@ -40,6 +113,31 @@ impl<R: Reader, W: Writer> IoStream<R, W> {
         self.dst.flush().await
     }
 
+    /// Receives like `recv`, unless src stays idle for longer than the shaper
+    /// allows, in which case we return None so that we can send cover.
+    pub async fn recv_or_idle(&mut self, len: Range<usize>) -> anyhow::Result<Option<Bytes>> {
+        let Some(timeout) = self.shaper.as_ref().and_then(Shaper::idle_timeout) else {
+            return self.recv(len).await.map(Some);
+        };
+
+        // Reading is cancel-safe: bytes we read before the timeout stay
+        // buffered in src.
+        match tokio::time::timeout(timeout, self.recv(len)).await {
+            Ok(result) => result.map(Some),
+            Err(_) => {
+                log::trace!("src was idle for {timeout:?}");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Returns the next `len` bytes from src, which we then receive again with
+    /// `recv`, so we do not record them now.
+    pub async fn peek(&mut self, len: usize) -> anyhow::Result<Bytes> {
+        log::trace!("Trying to peek at {len} bytes from src");
+        self.src.peek_bytes(len).await
+    }
+
     pub async fn recv(&mut self, len: Range<usize>) -> anyhow::Result<Bytes> {
         log::trace!("Trying to receive {len:?} bytes from src",);
 
@@ -58,6 +156,14 @@ impl<R: Reader, W: Writer> IoStream<R, W> {
 
         let n_bytes = data.len();
         self.n_recv_src += n_bytes;
+
+        if let Some(recording) = self.recording.as_mut() {
+            if recording.len() + n_bytes > MAX_RECORDING_NBYTES {
+                self.recording = None;
+            } else {
+                recording.extend_from_slice(&data);
+            }
+        }
         log::trace!("Received {n_bytes} bytes from src");
 
         Ok(data)
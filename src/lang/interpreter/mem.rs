@@ -1,8 +1,10 @@
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
 use anyhow::{anyhow, bail};
 
 use crate::lang::Data;
+use crate::lang::data::Variable;
 use crate::lang::types::Identifier;
 
 pub struct Heap {
@@ -47,3 +49,29 @@ impl Heap {
         self.mem.clear();
     }
 }
+
+/// Values that outlive the task that sets them, which both forwarding
+/// directions of a connection share.
+#[derive(Clone, Default)]
+pub struct Variables {
+    mem: Arc<Mutex<HashMap<Identifier, Variable>>>,
+}
+
+impl Variables {
+    pub fn get(&self, name: &Identifier) -> anyhow::Result<Option<Variable>> {
+        let mem = self
+            .mem
+            .lock()
+            .map_err(|e| anyhow!("Variables mutex was poisoned: {}", e.to_string()))?;
+        Ok(mem.get(name).cloned())
+    }
+
+    pub fn insert(&self, name: Identifier, value: Variable) -> anyhow::Result<()> {
+        let mut mem = self
+            .mem
+            .lock()
+            .map_err(|e| anyhow!("Variables mutex was poisoned: {}", e.to_string()))?;
+        mem.insert(name, value);
+        Ok(())
+    }
+}
@@ -1,16 +1,21 @@
 use std::collections::HashMap;
 
+pub use fallback::{Fallback, ProbeDefense};
 use loader::Loader;
 use vm::VirtualMachine;
+pub use vm::VmError;
 
 use crate::lang::ir::bridge::TaskProvider;
-use crate::net::{Connection, Reader, Writer};
+use crate::net::{Connection, Reader, SharedWriter, Writer};
 
 mod crypto;
+mod fallback;
 mod io;
 mod loader;
 mod mem;
 pub mod program;
+mod replay;
+mod shaper;
 mod vm;
 
 #[derive(Clone, Copy, Debug)]
@@ -24,15 +29,23 @@ pub struct Interpreter {}
 impl Interpreter {
     /// Run the configured proteus protocol instance to completion. This returns
     /// when the proteus protocol terminates and all connections can be closed.
-    pub async fn run<R, W, T>(
-        net_conn: Connection<R, W>,
-        app_conn: Connection<R, W>,
+    ///
+    /// Servers should pass a `defense` shared by all their connections. Then,
+    /// if a peer fails to authenticate or replays a message, we stop running
+    /// the protocol and handle the connection as the fallback says, rather than
+    /// revealing ourselves by closing the connection right away.
+    pub async fn run<NR, NW, AR, AW, T>(
+        net_conn: Connection<NR, NW>,
+        app_conn: Connection<AR, AW>,
         protospec: T,
         _options: HashMap<String, String>,
+        defense: Option<ProbeDefense>,
     ) -> anyhow::Result<()>
     where
-        R: Reader,
-        W: Writer,
+        NR: Reader,
+        NW: Writer + Send,
+        AR: Reader,
+        AW: Writer,
         T: TaskProvider + Clone + Send,
     {
         // Get the source and sink ends so that we can forward data in both
@@ -40,6 +53,14 @@ impl Interpreter {
         let (net_src, net_dst) = net_conn.into_split();
         let (app_src, app_dst) = app_conn.into_split();
 
+        // If we may need to fall back, we keep our own handle to the net dst
+        // so it stays open after we stop running the protocol.
+        let net_dst = SharedWriter::new(net_dst);
+        let (fallback, mut fallback_dst) = match defense {
+            Some(ref defense) => (Some(defense.fallback), Some(net_dst.clone())),
+            None => (None, None),
+        };
+
         // Buffers for data we are proxying. The inner src is unobfuscated data
         // maybe read from a local process over a localhost connection, while
         // the inner dst is to a proteus process typically running on a remote
@@ -50,23 +71,80 @@ impl Interpreter {
         // process typically running on a remote host, while the inner dst is
         // is unobfuscated data maybe written to a local process over a localhost
         // connection. The data read from the src was network-observable.
-        let net_to_app = VirtualMachine::new(net_src, app_dst, Some(app_to_net.share()));
+        let mut net_to_app = VirtualMachine::new(net_src, app_dst, Some(app_to_net.share()));
+        if let Some(defense) = defense {
+            net_to_app = net_to_app.with_replay_filter(defense.replay_filter);
+        }
 
         // Creates programs out of tasks from the protocol specification.
         let loader = Loader::new(protospec);
 
         // Execute both forwarding directions concurrently.
-        let (_, _) = tokio::join!(
-            Interpreter::execute(loader.clone(), app_to_net, ForwardingDirection::AppToNet),
-            Interpreter::execute(loader, net_to_app, ForwardingDirection::NetToApp),
-        );
-        Ok(())
+        let mut app_to_net = Box::pin(Interpreter::execute(
+            loader.clone(),
+            app_to_net,
+            ForwardingDirection::AppToNet,
+        ));
+        let mut net_to_app = Box::pin(Interpreter::execute(
+            loader,
+            net_to_app,
+            ForwardingDirection::NetToApp,
+        ));
+
+        let mut app_to_net_done = false;
+        let (net_to_app_result, net_to_app_vm) = tokio::select! {
+            result = &mut net_to_app => result,
+            app_to_net_output = &mut app_to_net => {
+                app_to_net_done = true;
+                // Closing the net dst tells the peer that we are done sending.
+                drop(app_to_net_output);
+                fallback_dst = None;
+                net_to_app.await
+            }
+        };
+
+        match (fallback, net_to_app_result) {
+            (Some(fallback), Err(e)) if e.downcast_ref::<VmError>().is_some() => {
+                // Make sure the peer receives nothing more from the protocol.
+                drop(app_to_net);
+                log::debug!("Falling back to {} after protocol failure: {}", fallback, e);
+
+                let (net_src, received) = net_to_app_vm.into_src_and_recording();
+                fallback.apply(net_src, received, fallback_dst).await;
+                Err(e)
+            }
+            _ => {
+                // Closes the app dst, in case the app is waiting for that.
+                drop(net_to_app_vm);
+                drop(fallback_dst);
+                if !app_to_net_done {
+                    let _ = app_to_net.await;
+                }
+                Ok(())
+            }
+        }
     }
 
+    /// Executes programs for `direction` until one fails, and returns the
+    /// result along with the VM.
     async fn execute<R, W, T>(
-        mut loader: Loader<T>,
+        loader: Loader<T>,
         mut vm: VirtualMachine<R, W>,
         direction: ForwardingDirection,
+    ) -> (anyhow::Result<()>, VirtualMachine<R, W>)
+    where
+        R: Reader,
+        W: Writer,
+        T: TaskProvider + Clone + Send,
+    {
+        let result = Interpreter::execute_programs(loader, &mut vm, direction).await;
+        (result, vm)
+    }
+
+    async fn execute_programs<R, W, T>(
+        mut loader: Loader<T>,
+        vm: &mut VirtualMachine<R, W>,
+        direction: ForwardingDirection,
     ) -> anyhow::Result<()>
     where
         R: Reader,
@@ -77,7 +155,7 @@ impl Interpreter {
             // Load a program for our direction, once one becomes available.
             let mut program = loader.load(direction).await?;
             // Runs the program by executing its sequence of instructions.
-            let exe_result = program.execute(&mut vm).await;
+            let exe_result = program.execute(vm).await;
             // The loader needs to know that this program finished, even on error.
             let unload_result = loader.unload(program);
 
@@ -92,10 +170,26 @@ impl Interpreter {
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
+    use bytes::{Bytes, BytesMut};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    use super::vm::VirtualMachine;
+    use super::{Fallback, Interpreter, ProbeDefense, VmError};
     use crate::common::mock;
-    use crate::lang::Role;
+    use crate::crypto::checksum;
+    use crate::lang::compiler::{Compiler, parser};
+    use crate::lang::ir::bridge::{OldCompile, TaskProvider};
     use crate::lang::ir::test::basic::LengthPayloadSpec;
     use crate::lang::ir::test::basic_enc::EncryptedLengthPayloadSpec;
+    use crate::lang::ir::v1::ConcretizeFormatArgs;
+    use crate::lang::types::{ConcreteFormat, TimestampUnit, ToIdentifier};
+    use crate::lang::{Execute, Role, Runtime};
+    use crate::net::{BufReader, Reader};
+
+    const BUF_NBYTES: usize = 1024;
 
     #[tokio::test]
     async fn length_payload_unencrypted() {
@@ -114,4 +208,684 @@ mod tests {
         )
         .await
     }
+
+    /// Runs a client that sends `payload` and returns the message it sent.
+    async fn record_client_message(payload: &'static [u8]) -> Bytes {
+        let (net, peer_net) = mock::connection_pair(BUF_NBYTES);
+        let (app, peer_app) = mock::connection_pair(BUF_NBYTES);
+        let (mut net_r, mut net_w) = peer_net.into_split();
+        let (_app_r, mut app_w) = peer_app.into_split();
+
+        let send = async move {
+            app_w.write_all(payload).await.unwrap();
+            app_w.shutdown().await.unwrap();
+        };
+        // The client sends its salt, and once it has the server's, a message
+        // with a length field and a payload, each with a MAC.
+        let message_len = 32 + 2 + 16 + payload.len() + 16;
+        let record = async move {
+            net_w.write_all(&[7; 32]).await.unwrap();
+            let message = net_r.read_bytes(message_len..message_len + 1).await;
+            // Closing the net connection lets the client terminate.
+            drop(net_w);
+            message.unwrap()
+        };
+
+        let (_, _, message) = tokio::join!(
+            Interpreter::run(
+                net,
+                app,
+                EncryptedLengthPayloadSpec::new(Role::Client),
+                HashMap::new(),
+                None
+            ),
+            send,
+            record,
+        );
+        message
+    }
+
+    /// Runs a server of `spec` that receives `message`, and returns the error
+    /// that made it fall back, if any, and what it forwarded to the app.
+    async fn run_server<T: TaskProvider + Clone + Send>(
+        spec: T,
+        message: Bytes,
+        defense: ProbeDefense,
+    ) -> (Option<VmError>, Bytes) {
+        let (net, peer_net) = mock::connection_pair(BUF_NBYTES);
+        let (app, peer_app) = mock::connection_pair(BUF_NBYTES);
+        let (_net_r, mut net_w) = peer_net.into_split();
+        let (mut app_r, mut app_w) = peer_app.into_split();
+
+        let send = async move {
+            net_w.write_all(&message).await.unwrap();
+            net_w.shutdown().await.unwrap();
+        };
+        // The app only closes after the server closed its end, so that the
+        // server does not close the net connection before it falls back.
+        let receive = async move {
+            let mut received = BytesMut::new();
+            while let Ok(bytes) = app_r.read_bytes(1..BUF_NBYTES).await {
+                received.extend(bytes);
+            }
+            app_w.shutdown().await.unwrap();
+            received.freeze()
+        };
+
+        let (result, _, received) = tokio::join!(
+            Interpreter::run(net, app, spec, HashMap::new(), Some(defense)),
+            send,
+            receive,
+        );
+        let error = result.err().map(|e| e.downcast::<VmError>().unwrap());
+        (error, received)
+    }
+
+    /// Runs a client of `spec` whose peer sends `reply` and whose app sends
+    /// `payload`. Both close once the client sent at least `nbytes`, so that
+    /// an idle client may send cover first. Returns each read of what the
+    /// client sent, which splits where the client paused between writes.
+    async fn run_client<T: TaskProvider + Clone + Send>(
+        spec: T,
+        reply: &[u8],
+        payload: &[u8],
+        nbytes: usize,
+    ) -> Vec<Bytes> {
+        let (net, peer_net) = mock::connection_pair(BUF_NBYTES);
+        let (app, peer_app) = mock::connection_pair(BUF_NBYTES);
+        let (mut net_r, mut net_w) = peer_net.into_split();
+        let (_app_r, mut app_w) = peer_app.into_split();
+        let (reply, payload) = (
+            Bytes::copy_from_slice(reply),
+            Bytes::copy_from_slice(payload),
+        );
+
+        let peer = async move {
+            net_w.write_all(&reply).await.unwrap();
+            app_w.write_all(&payload).await.unwrap();
+            let mut reads = vec![];
+            let mut nread = 0;
+            while nread < nbytes {
+                let bytes = net_r.read_bytes(1..BUF_NBYTES).await.unwrap();
+                nread += bytes.len();
+                reads.push(bytes);
+            }
+            app_w.shutdown().await.unwrap();
+            drop(net_w);
+            while let Ok(bytes) = net_r.read_bytes(1..BUF_NBYTES).await {
+                reads.push(bytes);
+            }
+            reads
+        };
+
+        let (_, reads) = tokio::join!(Interpreter::run(net, app, spec, HashMap::new(), None), peer);
+        reads
+    }
+
+    fn tamper(message: &Bytes) -> Bytes {
+        let mut tampered = BytesMut::from(&message[..]);
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        tampered.freeze()
+    }
+
+    #[tokio::test]
+    async fn clients_encrypt_under_fresh_keys() {
+        let first = record_client_message(b"hello").await;
+        let second = record_client_message(b"hello").await;
+
+        // Each client sends its own salt, and so encrypts under its own keys.
+        assert_ne!(first[..32], second[..32]);
+        assert_ne!(first[32..], second[32..]);
+    }
+
+    #[tokio::test]
+    async fn replayed_message_is_dropped() {
+        let defense = ProbeDefense::default();
+        let message = record_client_message(b"hello").await;
+
+        let (error, received) = run_server(
+            EncryptedLengthPayloadSpec::new(Role::Server),
+            message.clone(),
+            defense.clone(),
+        )
+        .await;
+        assert_eq!(error, None);
+        assert_eq!(&received[..], b"hello");
+
+        let (error, received) = run_server(
+            EncryptedLengthPayloadSpec::new(Role::Server),
+            message.clone(),
+            defense.clone(),
+        )
+        .await;
+        assert_eq!(error, Some(VmError::Replay));
+        assert!(received.is_empty());
+
+        let (error, received) = run_server(
+            EncryptedLengthPayloadSpec::new(Role::Server),
+            tamper(&message),
+            ProbeDefense::default(),
+        )
+        .await;
+        assert_eq!(error, Some(VmError::Authentication));
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn fresh_clients_with_same_message_are_accepted() {
+        let defense = ProbeDefense::default();
+
+        for _ in 0..2 {
+            let message = record_client_message(b"hello").await;
+            let (error, received) = run_server(
+                EncryptedLengthPayloadSpec::new(Role::Server),
+                message,
+                defense.clone(),
+            )
+            .await;
+            assert_eq!(error, None);
+            assert_eq!(&received[..], b"hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_message_is_spliced_to_decoy() {
+        let decoy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let defense = ProbeDefense::new(Fallback::Decoy(decoy.local_addr().unwrap()));
+        let message = tamper(&record_client_message(b"hello").await);
+
+        let run_decoy = async {
+            let (stream, _) = decoy.accept().await.unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut received = BytesMut::new();
+            while let Ok(bytes) = reader.read_bytes(1..BUF_NBYTES).await {
+                received.extend(bytes);
+            }
+            received.freeze()
+        };
+
+        let ((error, received), decoy_received) = tokio::join!(
+            run_server(
+                EncryptedLengthPayloadSpec::new(Role::Server),
+                message.clone(),
+                defense
+            ),
+            run_decoy
+        );
+        assert_eq!(error, Some(VmError::Authentication));
+        assert!(received.is_empty());
+        assert_eq!(decoy_received, message);
+    }
+
+    #[tokio::test]
+    async fn mismatched_message_is_dropped() {
+        let psf = r#"@SEGMENT.FORMATS
+            DEFINE Msg
+              { NAME: magic   ; TYPE: [char; 4] },
+              { NAME: version ; TYPE: u8 },
+              { NAME: length  ; TYPE: u16 },
+              { NAME: payload ; TYPE: [u8; length.size_of] };
+            @SEGMENT.SEMANTICS
+            { FORMAT: Msg; FIELD: magic;   SEMANTIC: FIXED_STRING("PRTS") };
+            { FORMAT: Msg; FIELD: version; SEMANTIC: ONE_OF(1, 2) };
+            { FORMAT: Msg; FIELD: length;  SEMANTIC: LENGTH };
+            { FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+            @SEGMENT.SEQUENCE
+            { ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+            { ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+        "#;
+        let spec = Compiler::parse_content(psf, Role::Server).unwrap();
+
+        for (message, expected) in [
+            (&b"PRTS\x01\x00\x05hello"[..], None),
+            (
+                &b"HTTP\x01\x00\x05hello"[..],
+                Some(VmError::ProtocolMismatch),
+            ),
+            (
+                &b"PRTS\x03\x00\x05hello"[..],
+                Some(VmError::ProtocolMismatch),
+            ),
+        ] {
+            let message = Bytes::from_static(message);
+            let (error, received) =
+                run_server(spec.clone(), message, ProbeDefense::default()).await;
+            assert_eq!(error, expected);
+            if expected.is_none() {
+                assert_eq!(&received[..], b"hello");
+            } else {
+                assert!(received.is_empty());
+            }
+        }
+    }
+
+    const COVER_PSF: &str = r#"@SEGMENT.FORMATS
+        DEFINE Msg
+          { NAME: cover   ; TYPE: u8 },
+          { NAME: length  ; TYPE: u16 },
+          { NAME: payload ; TYPE: [u8; length.size_of] };
+        @SEGMENT.SEMANTICS
+        { FORMAT: Msg; FIELD: cover;   SEMANTIC: COVER };
+        { FORMAT: Msg; FIELD: length;  SEMANTIC: LENGTH };
+        { FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+        @SEGMENT.SEQUENCE
+        { ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+        { ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+        @SEGMENT.TIMING
+        COVER_DELAY = HISTOGRAM(10);
+    "#;
+
+    #[tokio::test]
+    async fn idle_client_sends_cover() {
+        let spec = Compiler::parse_content(COVER_PSF, Role::Client).unwrap();
+
+        // The app stays idle once it sent its payload, until the client sent
+        // cover.
+        let sent = run_client(spec, b"", b"hello", 8 + 3).await.concat();
+        let (mut ncovers, mut payloads) = (0, vec![]);
+        let mut rest = &sent[..];
+        while let [cover, length0, length1, tail @ ..] = rest {
+            let length = u16::from_be_bytes([*length0, *length1]) as usize;
+            match cover {
+                0 => payloads.push(&tail[..length]),
+                _ => ncovers += 1,
+            }
+            rest = &tail[length..];
+        }
+        assert!(ncovers > 0);
+        assert_eq!(payloads, [b"hello"]);
+    }
+
+    #[tokio::test]
+    async fn cover_message_is_discarded() {
+        let spec = Compiler::parse_content(COVER_PSF, Role::Server).unwrap();
+
+        for (message, expected, forwarded) in [
+            (&b"\x01\x00\x00\x00\x00\x05hello"[..], None, &b"hello"[..]),
+            (
+                &b"\x02\x00\x00"[..],
+                Some(VmError::ProtocolMismatch),
+                &b""[..],
+            ),
+        ] {
+            let message = Bytes::from_static(message);
+            let (error, received) =
+                run_server(spec.clone(), message, ProbeDefense::default()).await;
+            assert_eq!(error, expected);
+            assert_eq!(&received[..], forwarded);
+        }
+    }
+
+    const DATA_FORMATS_PSF: &str = r#"@SEGMENT.FORMATS
+        DEFINE Short
+          { NAME: type    ; TYPE: u8 },
+          { NAME: length  ; TYPE: u8 },
+          { NAME: payload ; TYPE: [u8; length.size_of] };
+        DEFINE Idle
+          { NAME: type    ; TYPE: u8 };
+        DEFINE Long
+          { NAME: type    ; TYPE: u8 },
+          { NAME: length  ; TYPE: u16 },
+          { NAME: payload ; TYPE: [u8; length.size_of] };
+        @SEGMENT.SEMANTICS
+        { FORMAT: Short; FIELD: type;    SEMANTIC: TYPE_TAG(0x17) };
+        { FORMAT: Short; FIELD: length;  SEMANTIC: LENGTH };
+        { FORMAT: Short; FIELD: payload; SEMANTIC: PAYLOAD };
+        { FORMAT: Idle;  FIELD: type;    SEMANTIC: TYPE_TAG(0x18) };
+        { FORMAT: Long;  FIELD: type;    SEMANTIC: TYPE_TAG(0x19) };
+        { FORMAT: Long;  FIELD: length;  SEMANTIC: LENGTH };
+        { FORMAT: Long;  FIELD: payload; SEMANTIC: PAYLOAD };
+        @SEGMENT.SEQUENCE
+        { ROLE: CLIENT; PHASE: DATA; FORMAT: Short; WHEN: PAYLOAD < 100 };
+        { ROLE: CLIENT; PHASE: DATA; FORMAT: Idle;  WHEN: COVER };
+        { ROLE: CLIENT; PHASE: DATA; FORMAT: Long };
+        { ROLE: SERVER; PHASE: DATA; FORMAT: Long };
+        @SEGMENT.TIMING
+        COVER_DELAY = HISTOGRAM(10);
+    "#;
+
+    #[tokio::test]
+    async fn client_picks_data_format() {
+        let spec = Compiler::parse_content(DATA_FORMATS_PSF, Role::Client).unwrap();
+
+        // The number of covers the client sent, and the tags of the other
+        // messages with their payloads.
+        let tags = |sent: &[u8]| {
+            let (mut ncovers, mut tags) = (0, vec![]);
+            let mut rest = sent;
+            while let [tag, tail @ ..] = rest {
+                let (length, tail) = match tag {
+                    0x17 => (tail[0] as usize, &tail[1..]),
+                    0x19 => (u16::from_be_bytes([tail[0], tail[1]]) as usize, &tail[2..]),
+                    _ => (0, tail),
+                };
+                match tag {
+                    0x18 => ncovers += 1,
+                    _ => tags.push((*tag, Bytes::copy_from_slice(&tail[..length]))),
+                }
+                rest = &tail[length..];
+            }
+            (ncovers, tags)
+        };
+
+        // The client stays idle once it sent a short payload.
+        let sent = run_client(spec.clone(), b"", b"hello", 7 + 1)
+            .await
+            .concat();
+        let (ncovers, sent) = tags(&sent);
+        assert!(ncovers > 0);
+        assert_eq!(sent, [(0x17, Bytes::from_static(b"hello"))]);
+
+        let sent = run_client(spec, b"", &[0x55; 200], 3 + 200).await.concat();
+        let (_, sent) = tags(&sent);
+        assert_eq!(sent, [(0x19, Bytes::from_static(&[0x55; 200]))]);
+    }
+
+    #[tokio::test]
+    async fn server_dispatches_on_type_tag() {
+        let spec = Compiler::parse_content(DATA_FORMATS_PSF, Role::Server).unwrap();
+
+        for (message, expected, forwarded) in [
+            (&b"\x17\x05hello"[..], None, &b"hello"[..]),
+            (&b"\x19\x00\x05hello"[..], None, &b"hello"[..]),
+            (&b"\x18\x17\x05hello"[..], None, &b"hello"[..]),
+            (
+                &b"\x20\x05hello"[..],
+                Some(VmError::ProtocolMismatch),
+                &b""[..],
+            ),
+        ] {
+            let message = Bytes::from_static(message);
+            let (error, received) =
+                run_server(spec.clone(), message, ProbeDefense::default()).await;
+            assert_eq!(error, expected);
+            assert_eq!(&received[..], forwarded);
+        }
+    }
+
+    const STATE_MACHINE_PSF: &str = r#"@SEGMENT.FORMATS
+        DEFINE Hello
+          { NAME: hello   ; TYPE: u8 };
+        DEFINE Reply
+          { NAME: status  ; TYPE: u8 };
+        DEFINE Msg
+          { NAME: length  ; TYPE: u8 },
+          { NAME: payload ; TYPE: [u8; length.size_of] };
+        @SEGMENT.SEMANTICS
+        { FORMAT: Hello; FIELD: hello;   SEMANTIC: FIXED_VALUE(0x1) };
+        { FORMAT: Reply; FIELD: status;  SEMANTIC: ONE_OF(0x0, 0x1) };
+        { FORMAT: Msg;   FIELD: length;  SEMANTIC: LENGTH };
+        { FORMAT: Msg;   FIELD: payload; SEMANTIC: PAYLOAD };
+        @SEGMENT.SEQUENCE
+        { FROM: Start;   TO: Greeted; ROLE: CLIENT; FORMAT: Hello };
+        { FROM: Greeted; TO: Start;   ROLE: SERVER; FORMAT: Reply; GUARD: status == 0x0 };
+        { FROM: Greeted; TO: Open;    ROLE: SERVER; FORMAT: Reply };
+        { FROM: Open;    TO: Open;    ROLE: CLIENT; FORMAT: Msg };
+        { FROM: Open;    TO: Open;    ROLE: SERVER; FORMAT: Msg };
+    "#;
+
+    #[tokio::test]
+    async fn client_follows_guarded_transitions() {
+        let spec = Compiler::parse_content(STATE_MACHINE_PSF, Role::Client).unwrap();
+
+        // The server asks for the hello again once, then accepts it.
+        let sent = run_client(spec, b"\x00\x01", b"hello", 2 + 6)
+            .await
+            .concat();
+        assert_eq!(&sent[..], b"\x01\x01\x05hello");
+    }
+
+    const FLIGHT_PSF: &str = r#"@SEGMENT.FORMATS
+        DEFINE Hello
+          { NAME: hello   ; TYPE: u8 };
+        DEFINE Finished
+          { NAME: done    ; TYPE: u8 };
+        DEFINE Msg
+          { NAME: length  ; TYPE: u8 },
+          { NAME: payload ; TYPE: [u8; length.size_of] };
+        @SEGMENT.SEMANTICS
+        { FORMAT: Hello;    FIELD: hello;   SEMANTIC: FIXED_VALUE(0x1) };
+        { FORMAT: Finished; FIELD: done;    SEMANTIC: FIXED_VALUE(0x2) };
+        { FORMAT: Msg;      FIELD: length;  SEMANTIC: LENGTH };
+        { FORMAT: Msg;      FIELD: payload; SEMANTIC: PAYLOAD };
+        @SEGMENT.SEQUENCE
+        { ROLE: CLIENT; PHASE: HANDSHAKE; FORMAT: Hello };
+        { ROLE: CLIENT; PHASE: HANDSHAKE; FORMAT: Finished };
+        { ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+        { ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+        @SEGMENT.OPTIONS
+        COALESCE_FLIGHTS = {};
+        @SEGMENT.TIMING
+        MESSAGE_DELAY = HISTOGRAM(100);
+    "#;
+
+    #[tokio::test]
+    async fn client_coalesces_flight() {
+        // Each write waits for the message delay, so we read the second
+        // message of the flight with the first only if they go out together.
+        for (coalesce, first_read) in [("true", &b"\x01\x02"[..]), ("false", &b"\x01"[..])] {
+            let psf = FLIGHT_PSF.replace("{}", coalesce);
+            let spec = Compiler::parse_content(&psf, Role::Client).unwrap();
+            let reads = run_client(spec, b"", b"hi", 2 + 3).await;
+            assert_eq!(&reads[0][..], first_read);
+            assert_eq!(&reads.concat()[..], b"\x01\x02\x02hi");
+        }
+    }
+
+    #[tokio::test]
+    async fn server_reads_flight() {
+        let psf = FLIGHT_PSF.replace("{}", "true");
+        let spec = Compiler::parse_content(&psf, Role::Server).unwrap();
+
+        for (message, expected, forwarded) in [
+            (&b"\x01\x02\x05hello"[..], None, &b"hello"[..]),
+            (
+                &b"\x01\x01\x05hello"[..],
+                Some(VmError::ProtocolMismatch),
+                &b""[..],
+            ),
+        ] {
+            let message = Bytes::from_static(message);
+            let (error, received) =
+                run_server(spec.clone(), message, ProbeDefense::default()).await;
+            assert_eq!(error, expected);
+            assert_eq!(&received[..], forwarded);
+        }
+    }
+
+    const SESSION_PSF: &str = r#"@SEGMENT.FORMATS
+        DEFINE Hello
+          { NAME: session ; TYPE: [u8; 8] };
+        DEFINE Msg
+          { NAME: seq     ; TYPE: u8 },
+          { NAME: session ; TYPE: [u8; 8] },
+          { NAME: length  ; TYPE: u8 },
+          { NAME: payload ; TYPE: [u8; length.size_of] };
+        @SEGMENT.SEMANTICS
+        { FORMAT: Hello; FIELD: session; SEMANTIC: SAVE_AS(session_id) };
+        { FORMAT: Msg;   FIELD: seq;     SEMANTIC: COUNTER(0xfe, 1) };
+        { FORMAT: Msg;   FIELD: session; SEMANTIC: ECHO(session_id) };
+        { FORMAT: Msg;   FIELD: length;  SEMANTIC: LENGTH };
+        { FORMAT: Msg;   FIELD: payload; SEMANTIC: PAYLOAD };
+        @SEGMENT.SEQUENCE
+        { ROLE: CLIENT; PHASE: HANDSHAKE; FORMAT: Hello };
+        { ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+        { ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+    "#;
+
+    #[tokio::test]
+    async fn client_counts_and_echoes() {
+        let spec = Compiler::parse_content(SESSION_PSF, Role::Client).unwrap();
+
+        // The payload takes three messages, whose length field counts at most
+        // 255 bytes.
+        let sent = run_client(spec, b"", &[0x55; 600], 8 + 3 * 10 + 600)
+            .await
+            .concat();
+        let (session, mut rest) = sent.split_at(8);
+        let mut headers = vec![];
+        while let [seq, tail @ ..] = rest {
+            let length = tail[8] as usize;
+            headers.push((*seq, &tail[..8]));
+            rest = &tail[9 + length..];
+        }
+        // The counter wraps around at the width of its field.
+        assert_eq!(headers, [(0xfe, session), (0xff, session), (0x00, session)]);
+    }
+
+    #[tokio::test]
+    async fn server_checks_counter_and_echo() {
+        let spec = Compiler::parse_content(SESSION_PSF, Role::Server).unwrap();
+
+        for (message, expected, forwarded) in [
+            (
+                &b"sessionX\xfesessionX\x02hi\xffsessionX\x02yo"[..],
+                None,
+                &b"hiyo"[..],
+            ),
+            (
+                &b"sessionX\xfesessionX\x02hi\xfesessionX\x02yo"[..],
+                Some(VmError::ProtocolMismatch),
+                &b"hi"[..],
+            ),
+            (
+                &b"sessionX\xfesessionY\x02hi"[..],
+                Some(VmError::ProtocolMismatch),
+                &b""[..],
+            ),
+        ] {
+            let message = Bytes::from_static(message);
+            let (error, received) =
+                run_server(spec.clone(), message, ProbeDefense::default()).await;
+            assert_eq!(error, expected);
+            assert_eq!(&received[..], forwarded);
+        }
+    }
+
+    const TIMESTAMP_PSF: &str = r#"@SEGMENT.FORMATS
+        DEFINE Msg
+          { NAME: time    ; TYPE: u32 },
+          { NAME: length  ; TYPE: u8 },
+          { NAME: payload ; TYPE: [u8; length.size_of] };
+        @SEGMENT.SEMANTICS
+        { FORMAT: Msg; FIELD: time;    SEMANTIC: TIMESTAMP(u32_seconds, 30) };
+        { FORMAT: Msg; FIELD: length;  SEMANTIC: LENGTH };
+        { FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+        @SEGMENT.SEQUENCE
+        { ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+        { ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+    "#;
+
+    #[tokio::test]
+    async fn concretize_takes_time_of_message() {
+        let psf = parser::parse_psf(TIMESTAMP_PSF).unwrap();
+        let mut from_format = psf.formats[&"Msg".id()].format.clone();
+        // A time picked long before we build the message.
+        from_format.fixed_fields = vec![("time".id(), vec![0; 4])];
+
+        let (net, _peer_net) = mock::connection_pair(BUF_NBYTES);
+        let (src, dst) = net.into_split();
+        let mut vm = VirtualMachine::new(src, dst, None);
+        vm.store("payload".id(), Bytes::from_static(b"hi")).unwrap();
+
+        let before = TimestampUnit::U32Seconds.now();
+        ConcretizeFormatArgs {
+            from_format,
+            to_heap_id: "cformat".id(),
+            padding: None,
+            timestamp_fields: vec![("time".id(), TimestampUnit::U32Seconds)],
+        }
+        .execute(&mut vm)
+        .await
+        .unwrap();
+        let after = TimestampUnit::U32Seconds.now();
+
+        let cformat: &ConcreteFormat = vm.load(&"cformat".id()).unwrap();
+        let (_, time) = cformat
+            .fixed_fields
+            .iter()
+            .find(|(id, _)| *id == "time".id())
+            .unwrap();
+        let time = u32::from_be_bytes(time[..].try_into().unwrap()) as i128;
+        assert!((before..=after).contains(&time));
+    }
+
+    #[tokio::test]
+    async fn server_rejects_stale_time() {
+        let spec = Compiler::parse_content(TIMESTAMP_PSF, Role::Server).unwrap();
+        let now = TimestampUnit::U32Seconds.now() as u32;
+
+        for (time, expected, forwarded) in [
+            (now, None, &b"hi"[..]),
+            (now - 20, None, &b"hi"[..]),
+            (now + 20, None, &b"hi"[..]),
+            (now - 120, Some(VmError::ProtocolMismatch), &b""[..]),
+            (now + 120, Some(VmError::ProtocolMismatch), &b""[..]),
+        ] {
+            let mut message = time.to_be_bytes().to_vec();
+            message.extend_from_slice(b"\x02hi");
+            let (error, received) =
+                run_server(spec.clone(), Bytes::from(message), ProbeDefense::default()).await;
+            assert_eq!(error, expected);
+            assert_eq!(&received[..], forwarded);
+        }
+    }
+
+    const CHECKSUM_PSF: &str = r#"@SEGMENT.FORMATS
+        DEFINE Msg
+          { NAME: length  ; TYPE: u16 },
+          { NAME: sum     ; TYPE: u16 },
+          { NAME: crc     ; TYPE: u32 },
+          { NAME: payload ; TYPE: [u8; length.size_of] };
+        @SEGMENT.SEMANTICS
+        { FORMAT: Msg; FIELD: length;  SEMANTIC: LENGTH };
+        { FORMAT: Msg; FIELD: sum;     SEMANTIC: CHECKSUM(INTERNET, length..sum) };
+        { FORMAT: Msg; FIELD: crc;     SEMANTIC: CHECKSUM(CRC32, length..payload) };
+        { FORMAT: Msg; FIELD: payload; SEMANTIC: PAYLOAD };
+        @SEGMENT.SEQUENCE
+        { ROLE: CLIENT; PHASE: DATA; FORMAT: Msg };
+        { ROLE: SERVER; PHASE: DATA; FORMAT: Msg };
+    "#;
+
+    // A message of the checksum protocol, whose CRC covers the header sum.
+    fn checksummed_message(payload: &[u8]) -> Vec<u8> {
+        let mut message = (payload.len() as u16).to_be_bytes().to_vec();
+        message.extend_from_slice(&[0; 6]);
+        let sum = checksum::internet_checksum(&message[..4]);
+        message[2..4].copy_from_slice(&sum.to_be_bytes());
+        message.extend_from_slice(payload);
+        let crc = checksum::crc32(&message);
+        message[4..8].copy_from_slice(&crc.to_be_bytes());
+        message
+    }
+
+    #[tokio::test]
+    async fn client_sets_checksums() {
+        let spec = Compiler::parse_content(CHECKSUM_PSF, Role::Client).unwrap();
+        let sent = run_client(spec, b"", b"hello", 13).await.concat();
+        assert_eq!(sent, checksummed_message(b"hello"));
+    }
+
+    #[tokio::test]
+    async fn server_verifies_checksums() {
+        let spec = Compiler::parse_content(CHECKSUM_PSF, Role::Server).unwrap();
+
+        let valid = checksummed_message(b"hi");
+        let mut bad_payload = valid.clone();
+        bad_payload[9] ^= 1;
+        let mut bad_sum = valid.clone();
+        bad_sum[3] ^= 1;
+
+        for (message, expected, forwarded) in [
+            (valid, None, &b"hi"[..]),
+            (bad_payload, Some(VmError::ProtocolMismatch), &b""[..]),
+            (bad_sum, Some(VmError::ProtocolMismatch), &b""[..]),
+        ] {
+            let (error, received) =
+                run_server(spec.clone(), Bytes::from(message), ProbeDefense::default()).await;
+            assert_eq!(error, expected);
+            assert_eq!(&received[..], forwarded);
+        }
+    }
 }
@@ -1,6 +1,6 @@
 use super::vm::VirtualMachine;
-use crate::lang::Execute;
 use crate::lang::ir::bridge::{Task, TaskID};
+use crate::lang::{Execute, Runtime};
 use crate::net::{Reader, Writer};
 
 pub struct Program {
@@ -28,7 +28,11 @@ impl Program {
             self.task.ins[self.next_ins_index].execute(vm).await?;
             self.next_ins_index += 1;
         }
+        vm.check_replay()?;
         vm.clear_heap();
+        if let Some(id) = vm.take_next_task_id() {
+            self.task.id = id;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,195 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use sha2::{Digest, Sha256};
+
+use super::vm::VmError;
+
+/// How long we remember the first message of a connection by default. A
+/// recorded message replayed after the window has passed is not detected.
+const DEFAULT_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+type Fingerprint = [u8; 32];
+
+/// Remembers the first authenticated message of every connection a server
+/// accepts within a time window, so that a censor cannot replay a recorded
+/// client message to probe how the server responds. Clones share the same
+/// set of messages, so one filter should be shared across all connections.
+#[derive(Clone)]
+pub struct ReplayFilter {
+    window: Duration,
+    state_shared: Arc<Mutex<ReplayFilterState>>,
+}
+
+#[derive(Default)]
+struct ReplayFilterState {
+    seen: HashSet<Fingerprint>,
+    // Fingerprints in the order we saw them, so we can expire them cheaply.
+    expiry: VecDeque<(Instant, Fingerprint)>,
+}
+
+impl ReplayFilter {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            state_shared: Default::default(),
+        }
+    }
+
+    /// Records the fingerprint of a message. Returns false if we already saw
+    /// the same message within the window, i.e., the message is a replay.
+    fn insert(&self, fingerprint: Fingerprint, now: Instant) -> anyhow::Result<bool> {
+        let mut state = match self.state_shared.lock() {
+            Ok(state) => state,
+            Err(e) => bail!("Replay filter mutex was poisoned: {}", e.to_string()),
+        };
+
+        while let Some((seen_at, expired)) = state.expiry.front().copied() {
+            if now.duration_since(seen_at) < self.window {
+                break;
+            }
+            state.seen.remove(&expired);
+            state.expiry.pop_front();
+        }
+
+        if !state.seen.insert(fingerprint) {
+            return Ok(false);
+        }
+        state.expiry.push_back((now, fingerprint));
+        Ok(true)
+    }
+}
+
+impl Default for ReplayFilter {
+    fn default() -> Self {
+        Self::new(DEFAULT_WINDOW)
+    }
+}
+
+/// Checks the first authenticated message a connection receives against the
+/// replay filter. Every connection mixes its own random salt into its keys, so
+/// we fingerprint the message by the salt the peer sent: honest clients that
+/// send the same first message still differ in their salts, while a replayed
+/// message repeats one. Connections whose keys come from a key agreement have
+/// no salt to check, but a replayed message fails to authenticate under the
+/// fresh keys anyway.
+pub struct ReplayGuard {
+    filter: ReplayFilter,
+    // The hash of the first salt the peer sent, if any.
+    salt: Option<Fingerprint>,
+    // Whether we verified a MAC, and thus know the salt is the peer's.
+    authenticated: bool,
+    checked: bool,
+}
+
+impl ReplayGuard {
+    pub fn new(filter: ReplayFilter) -> Self {
+        Self {
+            filter,
+            salt: None,
+            authenticated: false,
+            checked: false,
+        }
+    }
+
+    /// Whether we checked the first authenticated message.
+    pub fn is_checked(&self) -> bool {
+        self.checked
+    }
+
+    pub fn record_salt(&mut self, salt: &[u8]) {
+        if self.salt.is_none() {
+            self.salt = Some(Sha256::digest(salt).into());
+        }
+    }
+
+    pub fn record_authenticated(&mut self) {
+        self.authenticated = true;
+    }
+
+    /// Fails if the salt we recorded was already used by another connection.
+    /// We check only once per connection, the first time we are called after
+    /// verifying a MAC.
+    pub fn check(&mut self) -> anyhow::Result<()> {
+        if !self.authenticated || self.checked {
+            return Ok(());
+        }
+        self.checked = true;
+
+        if let Some(salt) = self.salt {
+            if !self.filter.insert(salt, Instant::now())? {
+                bail!(VmError::Replay);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_window() {
+        let filter = ReplayFilter::new(Duration::from_secs(10));
+        let start = Instant::now();
+
+        assert!(filter.insert([1u8; 32], start).unwrap());
+        assert!(filter.insert([2u8; 32], start).unwrap());
+        assert!(
+            !filter
+                .insert([1u8; 32], start + Duration::from_secs(5))
+                .unwrap()
+        );
+
+        // Clones share the messages we saw.
+        assert!(!filter.clone().insert([2u8; 32], start).unwrap());
+
+        // After the window, the message is forgotten.
+        assert!(
+            filter
+                .insert([1u8; 32], start + Duration::from_secs(10))
+                .unwrap()
+        );
+        assert!(
+            !filter
+                .insert([1u8; 32], start + Duration::from_secs(11))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn guard_detects_replay() {
+        let filter = ReplayFilter::default();
+
+        let mut first = ReplayGuard::new(filter.clone());
+        first.record_salt(&[1u8; 32]);
+        first.check().unwrap();
+        assert!(!first.is_checked());
+        first.record_authenticated();
+        first.check().unwrap();
+        assert!(first.is_checked());
+        // Later messages on the same connection are not checked.
+        first.record_salt(&[2u8; 32]);
+        first.check().unwrap();
+
+        let mut different = ReplayGuard::new(filter.clone());
+        different.record_salt(&[2u8; 32]);
+        different.record_authenticated();
+        different.check().unwrap();
+
+        // Without a salt, there is nothing to check.
+        let mut unsalted = ReplayGuard::new(filter.clone());
+        unsalted.record_authenticated();
+        unsalted.check().unwrap();
+        assert!(unsalted.is_checked());
+
+        let mut replayed = ReplayGuard::new(filter);
+        replayed.record_salt(&[1u8; 32]);
+        replayed.record_authenticated();
+        let err = replayed.check().unwrap_err();
+        assert_eq!(err.downcast_ref::<VmError>(), Some(&VmError::Replay));
+    }
+}
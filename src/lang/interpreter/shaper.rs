@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use crate::lang::types::{Histogram, TimingSpec};
+
+/// Decides when we write the messages we send to the network, and in how many
+/// bursts, following the timing of the protocol.
+pub struct Shaper {
+    timing: TimingSpec,
+}
+
+impl Shaper {
+    pub fn new(timing: TimingSpec) -> Self {
+        Self { timing }
+    }
+
+    /// How long we wait for data to send before we send cover, if we do.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        let millis = self.timing.cover_delay.as_ref()?.sample()?;
+        Some(Duration::from_millis(millis))
+    }
+
+    /// Splits a message of `nbytes` into bursts, each with the time we wait
+    /// before writing it.
+    pub fn schedule(&self, nbytes: usize) -> Vec<(Duration, usize)> {
+        let mut bursts = vec![];
+        let mut delay = sample_millis(&self.timing.message_delay);
+        let mut remaining = nbytes;
+
+        loop {
+            // The validator checks that bursts are not empty.
+            let burst_nbytes = match self.timing.burst_size.as_ref().and_then(Histogram::sample) {
+                Some(size) => usize::try_from(size).unwrap_or(usize::MAX).min(remaining),
+                None => remaining,
+            };
+            bursts.push((delay, burst_nbytes));
+
+            remaining -= burst_nbytes;
+            if remaining == 0 {
+                return bursts;
+            }
+            delay = sample_millis(&self.timing.burst_delay);
+        }
+    }
+}
+
+fn sample_millis(histogram: &Option<Histogram>) -> Duration {
+    let millis = histogram.as_ref().and_then(Histogram::sample);
+    Duration::from_millis(millis.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedule() {
+        let histogram = |low, high| {
+            Some(Histogram {
+                bins: vec![(low, high, 1)],
+            })
+        };
+
+        let shaper = Shaper::new(TimingSpec::default());
+        assert_eq!(shaper.schedule(1000), [(Duration::ZERO, 1000)]);
+        assert_eq!(shaper.schedule(0), [(Duration::ZERO, 0)]);
+
+        let shaper = Shaper::new(TimingSpec {
+            message_delay: histogram(20, 20),
+            burst_size: histogram(300, 400),
+            burst_delay: histogram(1, 5),
+            cover_delay: None,
+        });
+        for _ in 0..16 {
+            let bursts = shaper.schedule(1000);
+            assert_eq!(bursts.iter().map(|(_, nbytes)| nbytes).sum::<usize>(), 1000);
+            assert_eq!(bursts[0].0, Duration::from_millis(20));
+            for (delay, nbytes) in &bursts[1..] {
+                assert!((1..=5).contains(&delay.as_millis()), "{delay:?}");
+                assert!(*nbytes <= 400);
+            }
+            // Only the last burst may be smaller than the burst size.
+            for (_, nbytes) in &bursts[..bursts.len() - 1] {
+                assert!(*nbytes >= 300);
+            }
+        }
+        assert_eq!(shaper.schedule(0), [(Duration::from_millis(20), 0)]);
+        assert_eq!(shaper.idle_timeout(), None);
+
+        let shaper = Shaper::new(TimingSpec {
+            cover_delay: histogram(100, 100),
+            ..Default::default()
+        });
+        assert_eq!(shaper.idle_timeout(), Some(Duration::from_millis(100)));
+    }
+}
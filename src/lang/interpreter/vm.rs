@@ -1,49 +1,122 @@
+use std::fmt;
 use std::ops::Range;
 
 use anyhow::anyhow;
 use bytes::{BufMut, Bytes, BytesMut};
+use subtle::ConstantTimeEq;
 
-use crate::crypto::chacha::CipherKind;
-use crate::crypto::kdf;
-use crate::lang::data::Data;
+use crate::crypto::aead::CipherKind;
+use crate::crypto::pubkey::X25519PubKey;
+use crate::lang::data::{Data, Variable};
 use crate::lang::interpreter::crypto::{CryptoStream, SharedCryptoState};
 use crate::lang::interpreter::io::IoStream;
-use crate::lang::interpreter::mem::Heap;
+use crate::lang::interpreter::mem::{Heap, Variables};
+use crate::lang::interpreter::replay::{ReplayFilter, ReplayGuard};
+use crate::lang::interpreter::shaper::Shaper;
 use crate::lang::ir::Instruction;
+use crate::lang::ir::bridge::TaskID;
 use crate::lang::ir::v1::*;
-use crate::lang::message::Message;
-use crate::lang::types::{Identifier, PubkeyEncoding};
+use crate::lang::message::{GetFieldError, Message};
+use crate::lang::types::{
+    AbstractFormat, Cipher, ConcreteFormat, Identifier, MaybeSized, PrimitiveArray, PrimitiveValue,
+    PubkeyEncoding, StaticallySized, TimingSpec, VarIntType,
+};
 use crate::lang::{Execute, Role, Runtime};
 use crate::net::{Reader, Writer};
 
+/// Errors indicating that the peer is not a legitimate proteus peer, e.g.,
+/// because it is a censor probing us.
+#[derive(Debug, PartialEq, Eq)]
+pub enum VmError {
+    /// A message failed authentication.
+    Authentication,
+    /// A message was a replay of one we received before.
+    Replay,
+    /// A field did not have a value the protocol allows, e.g., because the
+    /// peer speaks another protocol.
+    ProtocolMismatch,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::Authentication => write!(f, "Message authentication failed"),
+            VmError::Replay => write!(f, "Detected a replayed message"),
+            VmError::ProtocolMismatch => write!(f, "Message does not match the protocol"),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
 pub struct VirtualMachine<R: Reader, W: Writer> {
     heap: Heap,
     io: IoStream<R, W>,
     crypto: CryptoStream,
+    variables: Variables,
+    replay_guard: Option<ReplayGuard>,
+    // The task to continue with, if not the one the current task leads to.
+    next_task_id: Option<TaskID>,
 }
 
 #[derive(Clone)]
 pub struct SharedVmState {
     crypto_state: SharedCryptoState,
+    variables: Variables,
 }
 
 impl<R: Reader, W: Writer> VirtualMachine<R, W> {
     pub fn new(src: R, dst: W, state: Option<SharedVmState>) -> Self {
+        let (crypto_state, variables) = match state {
+            Some(state) => (Some(state.crypto_state), state.variables),
+            None => (None, Variables::default()),
+        };
         Self {
             heap: Heap::new(),
             io: IoStream::new(src, dst),
-            crypto: CryptoStream::new(state.map(|x| x.crypto_state)),
+            crypto: CryptoStream::new(crypto_state),
+            variables,
+            replay_guard: None,
+            next_task_id: None,
         }
     }
 
+    /// Checks the first authenticated message we receive against the filter.
+    /// Until it passes the check, we record the bytes we receive, so that we
+    /// could hand them over to a decoy service.
+    pub fn with_replay_filter(mut self, filter: ReplayFilter) -> Self {
+        self.replay_guard = Some(ReplayGuard::new(filter));
+        self.io.start_recording();
+        self
+    }
+
+    /// Fails if the authenticated message we received is a replay.
+    pub fn check_replay(&mut self) -> anyhow::Result<()> {
+        if let Some(guard) = self.replay_guard.as_mut() {
+            guard.check()?;
+            if guard.is_checked() {
+                self.io.stop_recording();
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns our source and the bytes we received from it while recording.
+    pub fn into_src_and_recording(mut self) -> (R, Option<Bytes>) {
+        let recording = self.io.take_recording();
+        (self.io.into_src(), recording)
+    }
+
     pub fn share(&self) -> SharedVmState {
         SharedVmState {
             crypto_state: self.crypto.share(),
+            variables: self.variables.clone(),
         }
     }
 
-    pub fn clear_heap(&mut self) {
-        self.heap.clear();
+    /// Returns the task to continue with that the current task picked, if any.
+    pub fn take_next_task_id(&mut self) -> Option<TaskID> {
+        self.next_task_id.take()
     }
 }
 
@@ -60,12 +133,59 @@ impl<R: Reader, W: Writer> Runtime for VirtualMachine<R, W> {
         self.heap.remove(addr)
     }
 
-    fn init_key(&mut self, key: &[u8]) -> anyhow::Result<()> {
-        self.crypto.init_key(key)
+    fn clear_heap(&mut self) {
+        self.heap.clear();
+    }
+
+    fn load_variable(&self, name: &Identifier) -> anyhow::Result<Option<Variable>> {
+        self.variables.get(name)
+    }
+
+    fn store_variable(&mut self, name: Identifier, value: Variable) -> anyhow::Result<()> {
+        self.variables.insert(name, value)
+    }
+
+    fn public_key(&mut self) -> anyhow::Result<X25519PubKey> {
+        self.crypto.public_key()
+    }
+
+    fn agree_key(&mut self, peer: &X25519PubKey) -> anyhow::Result<()> {
+        self.crypto.agree_key(peer)
+    }
+
+    fn salt(&mut self, nbytes: usize) -> anyhow::Result<Vec<u8>> {
+        self.crypto.salt(nbytes)
+    }
+
+    fn save_salt(&mut self, salt: &[u8]) -> anyhow::Result<()> {
+        self.crypto.save_salt(salt)?;
+        if let Some(guard) = self.replay_guard.as_mut() {
+            guard.record_salt(salt);
+        }
+        Ok(())
+    }
+
+    fn checksum_key(&self) -> anyhow::Result<[u8; 32]> {
+        self.crypto.checksum_key()
     }
 
-    fn create_cipher(&mut self, secret_key: [u8; 32], kind: CipherKind) {
-        self.crypto.create_cipher(secret_key, kind);
+    fn create_cipher(
+        &mut self,
+        cipher: Cipher,
+        master_key: [u8; 32],
+        key_label: &[u8],
+        kind: CipherKind,
+    ) -> anyhow::Result<()> {
+        self.crypto
+            .create_cipher(cipher, master_key, key_label, kind)
+    }
+
+    fn init_shaper(&mut self, timing: TimingSpec) {
+        self.io.shape(Shaper::new(timing));
+    }
+
+    fn goto(&mut self, task_id: TaskID) {
+        self.next_task_id = Some(task_id);
     }
 
     fn encrypt(&mut self, plaintext: &[u8]) -> anyhow::Result<(Vec<u8>, [u8; 16])> {
@@ -77,7 +197,11 @@ impl<R: Reader, W: Writer> Runtime for VirtualMachine<R, W> {
     }
 
     fn decrypt(&mut self, ciphertext: &[u8], mac: &[u8; 16]) -> anyhow::Result<Vec<u8>> {
-        self.crypto.decrypt(ciphertext, mac)
+        let plaintext = self.crypto.decrypt(ciphertext, mac)?;
+        if let Some(guard) = self.replay_guard.as_mut() {
+            guard.record_authenticated();
+        }
+        Ok(plaintext)
     }
 
     fn decrypt_unauth(&mut self, ciphertext: &[u8]) -> anyhow::Result<Vec<u8>> {
@@ -88,13 +212,31 @@ impl<R: Reader, W: Writer> Runtime for VirtualMachine<R, W> {
         self.io.recv(len).await
     }
 
+    async fn recv_or_idle(&mut self, len: Range<usize>) -> anyhow::Result<Option<Bytes>> {
+        self.io.recv_or_idle(len).await
+    }
+
+    async fn peek(&mut self, len: usize) -> anyhow::Result<Bytes> {
+        self.io.peek(len).await
+    }
+
     async fn send(&mut self, bytes: Bytes) -> anyhow::Result<usize> {
+        // Never release data from a replayed message.
+        self.check_replay()?;
         self.io.send(bytes).await
     }
 
     async fn flush(&mut self) -> anyhow::Result<()> {
         self.io.flush().await
     }
+
+    fn hold_writes(&mut self) {
+        self.io.hold();
+    }
+
+    async fn release_writes(&mut self) -> anyhow::Result<usize> {
+        self.io.release().await
+    }
 }
 
 impl Execute for Instruction {
@@ -108,96 +250,276 @@ impl Execute for Instruction {
 impl Execute for InstructionV1 {
     async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
         match &self {
+            InstructionV1::CheckFieldBytes(ins) => ins.execute(runtime).await,
+            InstructionV1::CheckFieldValue(ins) => ins.execute(runtime).await,
+            InstructionV1::CheckFieldVariable(ins) => ins.execute(runtime).await,
+            InstructionV1::ComputeChecksum(ins) => ins.execute(runtime).await,
             InstructionV1::ComputeLength(ins) => ins.execute(runtime).await,
             InstructionV1::ConcretizeFormat(ins) => ins.execute(runtime).await,
             InstructionV1::CreateMessage(ins) => ins.execute(runtime).await,
             InstructionV1::DecryptField(ins) => ins.execute(runtime).await,
+            InstructionV1::DispatchFormat(ins) => ins.execute(runtime).await,
             InstructionV1::EncryptField(ins) => ins.execute(runtime).await,
+            InstructionV1::Flight(ins) => ins.execute(runtime).await,
             InstructionV1::GetArrayBytes(ins) => ins.execute(runtime).await,
+            InstructionV1::GetArrayLength(ins) => ins.execute(runtime).await,
             InstructionV1::GetNumericValue(ins) => ins.execute(runtime).await,
+            InstructionV1::Goto(ins) => ins.execute(runtime).await,
+            InstructionV1::IncrementCounter(ins) => ins.execute(runtime).await,
             InstructionV1::InitFixedSharedKey(ins) => ins.execute(runtime).await,
+            InstructionV1::InitShaper(ins) => ins.execute(runtime).await,
+            InstructionV1::LoadVariable(ins) => ins.execute(runtime).await,
             InstructionV1::ReadApp(ins) => ins.execute(runtime).await,
             InstructionV1::ReadNet(ins) => ins.execute(runtime).await,
+            InstructionV1::SelectFormat(ins) => ins.execute(runtime).await,
             InstructionV1::SetArrayBytes(ins) => ins.execute(runtime).await,
             InstructionV1::SetNumericValue(ins) => ins.execute(runtime).await,
+            InstructionV1::SetPubkey(ins) => ins.execute(runtime).await,
+            InstructionV1::SetSalt(ins) => ins.execute(runtime).await,
+            InstructionV1::StoreVariable(ins) => ins.execute(runtime).await,
             InstructionV1::WriteApp(ins) => ins.execute(runtime).await,
             InstructionV1::WriteNet(ins) => ins.execute(runtime).await,
             InstructionV1::WriteNetTwice(ins) => ins.execute(runtime).await,
             InstructionV1::SaveKey(ins) => ins.execute(runtime).await,
+            InstructionV1::SaveSalt(ins) => ins.execute(runtime).await,
         }
     }
 }
 
-impl Execute for ComputeLengthArgs {
+/// Executes the instructions of a case of another instruction, which we box
+/// since the case may contain that kind of instruction again.
+async fn execute_all(ins: &[InstructionV1], runtime: &mut impl Runtime) -> anyhow::Result<()> {
+    for ins in ins {
+        Box::pin(ins.execute(runtime)).await?;
+    }
+    Ok(())
+}
+
+/// Evaluates the expression with checked arithmetic, since the numbers on the
+/// heap may come from the network.
+fn evaluate(expr: &HeapExpr, runtime: &impl Runtime) -> anyhow::Result<i128> {
+    match expr {
+        HeapExpr::Literal(n) => Ok(*n),
+        HeapExpr::Heap(id) => {
+            let num: &u128 = runtime.load(id)?;
+            i128::try_from(*num).map_err(|_| anyhow!("Heap value {num} is too large"))
+        }
+        HeapExpr::Binary(lhs, op, rhs) => {
+            let (lhs, rhs) = (evaluate(lhs, runtime)?, evaluate(rhs, runtime)?);
+            op.apply(lhs, rhs)
+                .ok_or_else(|| anyhow!("Cannot evaluate {lhs} {op} {rhs}"))
+        }
+    }
+}
+
+impl Execute for CheckFieldBytesArgs {
     async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
         let msg: &Message = runtime.load(&self.from_msg_heap_id)?;
-        let len = msg.len_suffix(&self.from_field_id);
-        runtime.store(self.to_heap_id.clone(), len as u128)?;
+        let bytes = msg
+            .get_field_bytes(&self.from_field_id)
+            .map_err(|_| anyhow!("No field bytes"))?;
+        if bytes[..] != self.expected[..] {
+            return Err(VmError::ProtocolMismatch.into());
+        }
         Ok(())
     }
 }
 
-impl Execute for ConcretizeFormatArgs {
+impl Execute for CheckFieldValueArgs {
     async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
-        let aformat = self.from_format.clone();
-
-        // The following block is ryans hack to support padding.
-        if let Some(padding_field_id) = &self.padding_field {
-            let block_size = self.block_size_nbytes.unwrap();
-
-            // Crummy hack. Infer the length of the payload field...
-            let darrays = aformat.get_dynamic_arrays();
-            assert!(darrays.len() == 2);
-
-            let payload_id = darrays.iter().find(|&id| id != padding_field_id).unwrap();
+        let msg: &Message = runtime.load(&self.from_msg_heap_id)?;
+        match msg.get_field_value(&self.from_field_id) {
+            Ok(value) if self.semantic.allows(value) => Ok(()),
+            // Bytes that are not a value of the type are unexpected too.
+            Ok(_) | Err(GetFieldError::TypeError) => Err(VmError::ProtocolMismatch.into()),
+            Err(GetFieldError::NotDefined) => Err(anyhow!("No field value")),
+        }
+    }
+}
 
-            // We know the payload bytes are there...
-            let payload_bytes: &Bytes = runtime.load(payload_id)?;
+impl Execute for CheckFieldVariableArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
+        let msg: &Message = runtime.load(&self.from_msg_heap_id)?;
+        let holds = match runtime.load_variable(&self.var_id)? {
+            Some(Variable::Number(num)) => msg
+                .get_field_unsigned_numeric(&self.from_field_id)
+                .map_err(|_| anyhow!("No field num"))?
+                .eq(&num),
+            Some(Variable::Bytes(bytes)) => msg
+                .get_field_bytes(&self.from_field_id)
+                .map_err(|_| anyhow!("No field bytes"))?
+                .eq(&bytes),
+            // The peer refers to something we never agreed on.
+            None => false,
+        };
+        if !holds {
+            return Err(VmError::ProtocolMismatch.into());
+        }
+        Ok(())
+    }
+}
 
-            let payload_nbytes = payload_bytes.len();
+impl Execute for ComputeChecksumArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
+        // A receiver reads a message one segment at a time, so we join the
+        // segments, noting where the fields are in the joined bytes.
+        let mut bytes = BytesMut::new();
+        let (mut start, mut end, mut field) = (None, None, None);
+        for msg_heap_id in &self.msg_heap_ids {
+            let msg: &Message = runtime.load(msg_heap_id)?;
+            let offset = bytes.len();
+            let range = |field_id| {
+                msg.try_get_field_byte_range(field_id)
+                    .map(|range| range.start + offset..range.end + offset)
+            };
+            start = start.or_else(|| range(&self.from_field_id).map(|range| range.start));
+            end = end.or_else(|| range(&self.to_field_id).map(|range| range.end));
+            field = field.or_else(|| range(&self.field_id).map(|range| (msg_heap_id, range)));
+            bytes.put_slice(msg.as_bytes());
+        }
+        let (Some(start), Some(end), Some((field_msg_heap_id, field_range))) = (start, end, field)
+        else {
+            return Err(anyhow!("No checksum fields"));
+        };
+        if start > end {
+            return Err(anyhow!("Checksum range is empty"));
+        }
 
-            let padding_nbytes = crate::lang::padding_nbytes(payload_nbytes, block_size);
+        let field_bytes = Bytes::copy_from_slice(&bytes[field_range.clone()]);
+        bytes[field_range.clone()].fill(0);
+        let key = match self.algorithm.needs_key() {
+            true => runtime.checksum_key()?.to_vec(),
+            false => vec![],
+        };
+        let mut checksum = self.algorithm.digest(&bytes[start..end], &key);
+        checksum.truncate(field_range.len());
+
+        if self.verify {
+            // A keyed checksum is a MAC, which a comparison that stops at the
+            // first differing byte would let a prober guess byte by byte.
+            if !bool::from(checksum[..].ct_eq(&field_bytes[..])) {
+                return Err(VmError::ProtocolMismatch.into());
+            }
+        } else {
+            let mut msg: Message = runtime.drop(field_msg_heap_id)?;
+            msg.set_field_bytes(&self.field_id, &Bytes::from(checksum))
+                .map_err(|_| anyhow!("No field bytes"))?;
+            runtime.store(field_msg_heap_id.clone(), msg)?;
+        }
 
-            let mut padding: Vec<u8> = vec![];
-            padding.resize_with(padding_nbytes, || 255);
-            let padding = bytes::Bytes::from(padding);
+        Ok(())
+    }
+}
 
-            runtime.store(padding_field_id.clone(), padding)?;
+impl Execute for ComputeLengthArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
+        let msg: &Message = runtime.load(&self.from_msg_heap_id)?;
+        let len = msg
+            .evaluate_length(&self.from_expr)
+            .and_then(|len| u128::try_from(len).ok())
+            .ok_or_else(|| anyhow!("Cannot compute length {}", self.from_expr))?;
+        runtime.store(self.to_heap_id.clone(), len)?;
+        Ok(())
+    }
+}
 
-            use crate::lang::types::ToIdentifier;
-            // FIXME(rwails) MEGA HACK
-            runtime.store("__padding_len_on_heap".id(), padding_nbytes as u128)?;
+impl Execute for ConcretizeFormatArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
+        let mut aformat = self.from_format.clone();
+
+        // The time is the one at which we build the message, which may be long
+        // after we compiled it while we waited for data.
+        for (field_id, unit) in &self.timestamp_fields {
+            let bytes = aformat
+                .format
+                .try_get_field_by_name(field_id)
+                .and_then(|field| PrimitiveArray::try_from(field.dtype).ok())
+                .and_then(|array| array.0.encode_value(PrimitiveValue::Int(unit.now())))
+                .ok_or_else(|| anyhow!("Timestamp does not fit field {}", field_id.0))?;
+            aformat.fixed_fields.retain(|(id, _)| id != field_id);
+            aformat.fixed_fields.push((field_id.clone(), bytes));
         }
 
-        // Get the fields that have dynamic lengths, and compute what the lengths
-        // will be now that we should have the data for each field on the heap.
-        let concrete_bytes: Vec<(Identifier, anyhow::Result<&Bytes>)> = aformat
-            .get_dynamic_arrays()
-            .iter()
-            .map(|id| {
-                (
-                    id.clone(),
-                    // self.bytes_heap.get(&id).unwrap().len(),
-                    runtime.load(id),
+        // Store the padding, so that we can size the padding field like the
+        // other fields. Its size may depend on the size of the rest of the
+        // message, which we get by sizing the message without it.
+        if let Some(ref padding) = self.padding {
+            runtime.store(padding.field_id.clone(), Bytes::new())?;
+            let message_nbytes = concretize(&aformat, runtime)?.size_of();
+            let payload_bytes: &Bytes = runtime.load(&padding.payload_field_id)?;
+            let mut padding_nbytes = padding
+                .policy
+                .pick_nbytes(
+                    payload_bytes.len(),
+                    message_nbytes,
+                    padding.block_size_nbytes,
                 )
-            })
-            .collect();
-
-        let mut concrete_sizes: Vec<(Identifier, usize)> = vec![];
-        for (id, bytes_res) in concrete_bytes {
-            concrete_sizes.push((id, bytes_res?.len()))
+                .ok_or_else(|| anyhow!("Padding requires a block size"))?;
+            runtime.drop::<Bytes>(&padding.field_id)?;
+            runtime.store(
+                padding.field_id.clone(),
+                Bytes::from(padding.policy.fill(padding_nbytes)),
+            )?;
+
+            // The dividend counts the padding once, so each byte we add to it
+            // moves the dividend one byte closer to a multiple of the divisor.
+            if let Some(ref alignment) = padding.alignment {
+                let format = concretize(&aformat, runtime)?.format;
+                let dividend = alignment
+                    .dividend
+                    .evaluate(&format, &|field| field.maybe_size_of().map(|n| n as i128))
+                    .ok_or_else(|| anyhow!("Cannot compute length {}", alignment.dividend))?;
+                let divisor = alignment.divisor as i128;
+                let align_nbytes = (divisor - dividend.rem_euclid(divisor)) % divisor;
+                if align_nbytes > 0 {
+                    padding_nbytes += align_nbytes as usize;
+                    runtime.drop::<Bytes>(&padding.field_id)?;
+                    runtime.store(
+                        padding.field_id.clone(),
+                        Bytes::from(padding.policy.fill(padding_nbytes)),
+                    )?;
+                }
+            }
         }
 
-        // Now that we know the total size, we can allocate the full format block.
-        let cformat = aformat.concretize(&concrete_sizes);
-
         // Store it for use by later instructions.
+        let cformat = concretize(&aformat, runtime)?;
         runtime.store(self.to_heap_id.clone(), cformat)?;
 
         Ok(())
     }
 }
 
+/// Sizes the format with the bytes on the heap.
+fn concretize(aformat: &AbstractFormat, runtime: &impl Runtime) -> anyhow::Result<ConcreteFormat> {
+    // Get the fields that have dynamic lengths, and compute what the lengths
+    // will be now that we should have the data for each field on the heap.
+    // Fields whose value is fixed by the format take the size of the value.
+    let mut concrete_sizes: Vec<(Identifier, usize)> = vec![];
+    for id in aformat.get_dynamic_arrays() {
+        let fixed_value = aformat
+            .fixed_fields
+            .iter()
+            .find(|(fixed_id, _)| fixed_id == &id);
+        let varint_type = aformat
+            .format
+            .try_get_field_by_name(&id)
+            .and_then(|field| PrimitiveArray::try_from(field.dtype).ok())
+            .and_then(|array| array.varint_type());
+        let size = match (runtime.load::<&Bytes>(&id), fixed_value, varint_type) {
+            (Ok(bytes), _, _) => bytes.len(),
+            (Err(_), Some((_, value)), _) => value.len(),
+            // A varint we have yet to set holds zero.
+            (Err(_), None, Some(t)) => t.encode(0).unwrap().len(),
+            (Err(e), None, None) => return Err(e),
+        };
+        concrete_sizes.push((id, size));
+    }
+
+    // Now that we know the total size, we can allocate the full format block.
+    Ok(aformat.clone().concretize(&concrete_sizes))
+}
+
 impl Execute for CreateMessageArgs {
     async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
         // Create a message with an existing concrete format.
@@ -229,10 +551,10 @@ impl Execute for DecryptFieldArgs {
             let mut mac_fixed = [0u8; 16];
             mac_fixed.copy_from_slice(&mac);
 
-            runtime.decrypt(&ciphertext, &mac_fixed).unwrap()
+            runtime.decrypt(&ciphertext, &mac_fixed)?
         } else {
             // We are doing unauthenticated encryption.
-            runtime.decrypt_unauth(&ciphertext).unwrap()
+            runtime.decrypt_unauth(&ciphertext)?
         };
 
         let mut buf = BytesMut::with_capacity(plaintext.len());
@@ -243,6 +565,22 @@ impl Execute for DecryptFieldArgs {
     }
 }
 
+impl Execute for DispatchFormatArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
+        let prefix = runtime
+            .peek(self.tag_range.end)
+            .await
+            .map_err(|e| anyhow!("DispatchFormat error {e}"))?;
+        let tag = &prefix[self.tag_range.clone()];
+        let case = self
+            .cases
+            .iter()
+            .find(|case| case.tag[..] == tag[..])
+            .ok_or(VmError::ProtocolMismatch)?;
+        execute_all(&case.ins, runtime).await
+    }
+}
+
 impl Execute for EncryptFieldArgs {
     async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
         let msg: &Message = runtime.load(&self.from_msg_heap_id)?;
@@ -253,7 +591,7 @@ impl Execute for EncryptFieldArgs {
         // TODO Should auth and unauth encrypt be separate instructions?
         if self.to_mac_heap_id.is_some() {
             // We are doing authenticated encryption.
-            let (ciphertext, mac) = runtime.encrypt(&plaintext).unwrap();
+            let (ciphertext, mac) = runtime.encrypt(&plaintext)?;
 
             let mut buf = BytesMut::with_capacity(ciphertext.len());
             buf.put_slice(&ciphertext);
@@ -264,7 +602,7 @@ impl Execute for EncryptFieldArgs {
             runtime.store(self.to_mac_heap_id.as_ref().unwrap().clone(), buf.freeze())?;
         } else {
             // We are doing unauthenticated encryption.
-            let ciphertext = runtime.encrypt_unauth(&plaintext).unwrap();
+            let ciphertext = runtime.encrypt_unauth(&plaintext)?;
             let mut buf = BytesMut::with_capacity(ciphertext.len());
             buf.put_slice(&ciphertext);
             runtime.store(self.to_ciphertext_heap_id.clone(), buf.freeze())?;
@@ -286,6 +624,18 @@ impl Execute for GetArrayBytesArgs {
     }
 }
 
+impl Execute for GetArrayLengthArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
+        let msg: &Message = runtime.load(&self.from_msg_heap_id)?;
+        let nbytes = msg
+            .get_field_nbytes(&self.from_field_id)
+            .map_err(|_| anyhow!("No field bytes"))?;
+        runtime.store(self.to_heap_id.clone(), nbytes as u128)?;
+
+        Ok(())
+    }
+}
+
 impl Execute for GetNumericValueArgs {
     async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
         let msg: &Message = runtime.load(&self.from_msg_heap_id)?;
@@ -298,28 +648,98 @@ impl Execute for GetNumericValueArgs {
     }
 }
 
-impl Execute for InitFixedSharedKeyArgs {
+impl Execute for FlightArgs {
     async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
-        let salt = "stupid stupid stupid";
-        let skey = kdf::derive_key_256(self.password.as_str(), salt);
+        if self.coalesce {
+            runtime.hold_writes();
+        }
+        for (i, ins) in self.messages.iter().enumerate() {
+            // Each message uses the heap ids of its format afresh.
+            if i > 0 {
+                runtime.clear_heap();
+            }
+            execute_all(ins, runtime).await?;
+        }
+        if self.coalesce {
+            runtime
+                .release_writes()
+                .await
+                .map_err(|e| anyhow!("Flight error on coalesced write {e}"))?;
+        }
+        Ok(())
+    }
+}
+
+impl Execute for GotoArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
+        for case in &self.cases {
+            let holds = match case.guard {
+                Some((ref heap_id, op, value)) => {
+                    op.apply(evaluate(&HeapExpr::Heap(heap_id.clone()), runtime)?, value)
+                }
+                None => true,
+            };
+            if holds {
+                runtime.goto(case.to_task_id);
+                return Ok(());
+            }
+        }
+        Err(anyhow!("No transition for the message"))
+    }
+}
+
+impl Execute for IncrementCounterArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
+        let value = match runtime.load_variable(&self.var_id)? {
+            Some(Variable::Number(num)) => {
+                let value = num
+                    .checked_add(self.step)
+                    .ok_or_else(|| anyhow!("Counter '{:?}' overflowed", self.var_id))?;
+                self.modulus.map_or(value, |modulus| value % modulus)
+            }
+            Some(Variable::Bytes(_)) => {
+                return Err(anyhow!("Counter '{:?}' is not a number", self.var_id));
+            }
+            None => self.start,
+        };
+        runtime.store_variable(self.var_id.clone(), Variable::Number(value))
+    }
+}
 
+impl Execute for InitFixedSharedKeyArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
         let kind = match self.role {
             Role::Client => CipherKind::Sender,
             Role::Server => CipherKind::Receiver,
         };
 
-        runtime.create_cipher(skey, kind);
+        runtime.create_cipher(self.cipher, self.secret_key, &self.key_label, kind)
+    }
+}
 
+impl Execute for InitShaperArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
+        runtime.init_shaper(self.timing.clone());
         Ok(())
     }
 }
 
 impl Execute for ReadAppArgs {
     async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
-        let data = runtime
-            .recv(self.from_len.clone())
-            .await
-            .map_err(|e| anyhow!("ReadApp error {e}"))?;
+        let data = match self.to_cover_heap_id {
+            Some(ref cover_heap_id) => {
+                let data = runtime
+                    .recv_or_idle(self.from_len.clone())
+                    .await
+                    .map_err(|e| anyhow!("ReadApp error {e}"))?;
+                runtime.store(cover_heap_id.clone(), u128::from(data.is_none()))?;
+                data.unwrap_or_default()
+            }
+            None => runtime
+                .recv(self.from_len.clone())
+                .await
+                .map_err(|e| anyhow!("ReadApp error {e}"))?,
+        };
         runtime.store(self.to_heap_id.clone(), data)?;
 
         Ok(())
@@ -329,32 +749,20 @@ impl Execute for ReadAppArgs {
 impl Execute for ReadNetArgs {
     async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
         let len = match &self.from_len {
-            ReadNetLength::Identifier(id) => {
-                let num: &u128 = runtime.load(id)?;
-                let val = *num as usize;
-                Range {
-                    start: val,
-                    end: val + 1,
-                }
-            }
-            ReadNetLength::IdentifierMinus((id, sub)) => {
-                let num: &u128 = runtime.load(id)?;
-                let val = (*num as usize) - sub;
-                Range {
-                    start: val,
-                    end: val + 1,
-                }
-            }
-            ReadNetLength::IdentifierMinusMinus((id, id_sub, sub)) => {
-                let num: &u128 = runtime.load(id)?;
-                let num2: &u128 = runtime.load(id_sub)?;
-                let val = (*num as usize) - (*num2 as usize) - sub;
+            ReadNetLength::Expr(expr) => {
+                let val = usize::try_from(evaluate(expr, runtime)?)
+                    .map_err(|_| anyhow!("ReadNet length is negative"))?;
                 Range {
                     start: val,
                     end: val + 1,
                 }
             }
             ReadNetLength::Range(r) => r.clone(),
+            ReadNetLength::VarInt(t) => {
+                let data = read_varint(*t, runtime).await?;
+                runtime.store(self.to_heap_id.clone(), data)?;
+                return Ok(());
+            }
         };
         let data = runtime
             .recv(len)
@@ -366,6 +774,62 @@ impl Execute for ReadNetArgs {
     }
 }
 
+/// Reads a varint from the network, a few bytes at a time, since we only know
+/// its width once we read its first bytes.
+async fn read_varint(t: VarIntType, runtime: &mut impl Runtime) -> anyhow::Result<Bytes> {
+    let mut buf = BytesMut::with_capacity(t.max_nbytes());
+    let mut nbytes = 1;
+    while nbytes > 0 {
+        let data = runtime
+            .recv(nbytes..nbytes + 1)
+            .await
+            .map_err(|e| anyhow!("ReadNet error {e}"))?;
+        buf.put_slice(&data);
+        nbytes = t
+            .remaining_nbytes(&buf)
+            .ok_or_else(|| anyhow!("ReadNet got an invalid {t}"))?;
+    }
+    Ok(buf.freeze())
+}
+
+impl Execute for SelectFormatArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
+        self.read.execute(runtime).await?;
+        let data: Bytes = runtime.drop(&self.read.to_heap_id)?;
+        let is_cover = match self.read.to_cover_heap_id {
+            Some(ref cover_heap_id) => *runtime.load::<&u128>(cover_heap_id)? != 0,
+            None => false,
+        };
+
+        let case = self
+            .cases
+            .iter()
+            .find(|case| {
+                case.max_payload_nbytes >= data.len()
+                    && case
+                        .condition
+                        .as_ref()
+                        .is_none_or(|condition| condition.matches(data.len(), is_cover))
+            })
+            .ok_or_else(|| anyhow!("No format to send {} bytes with", data.len()))?;
+        if let Some(ref payload_heap_id) = case.payload_heap_id {
+            runtime.store(payload_heap_id.clone(), data)?;
+        }
+        execute_all(&case.ins, runtime).await
+    }
+}
+
+impl Execute for LoadVariableArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
+        let value = runtime
+            .load_variable(&self.from_var_id)?
+            .ok_or_else(|| anyhow!("No variable '{:?}'", self.from_var_id))?;
+        runtime.store(self.to_heap_id.clone(), value)?;
+
+        Ok(())
+    }
+}
+
 impl Execute for SetArrayBytesArgs {
     async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
         let mut msg: Message = runtime.drop(&self.to_msg_heap_id)?;
@@ -390,16 +854,36 @@ impl Execute for SetNumericValueArgs {
     }
 }
 
+impl Execute for StoreVariableArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
+        let msg: &Message = runtime.load(&self.from_msg_heap_id)?;
+        let bytes = msg
+            .get_field_bytes(&self.from_field_id)
+            .map_err(|_| anyhow!("No field bytes"))?;
+        runtime.store_variable(self.to_var_id.clone(), Variable::Bytes(bytes))
+    }
+}
+
 impl Execute for WriteAppArgs {
     async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
         let msg: Message = runtime.drop(&self.from_msg_heap_id)?;
+        if let Some(ref cover_heap_id) = self.cover_heap_id {
+            let cover: &u128 = runtime.load(cover_heap_id)?;
+            if *cover != 0 {
+                return Ok(());
+            }
+        }
         let data = msg
             .into_inner_field(&self.from_field_id)
             .ok_or(anyhow!("No msg to bytes"))?;
         runtime
             .send(data)
             .await
-            .map_err(|e| anyhow!("WriteApp error {e}"))?;
+            .map_err(|e| match e.is::<VmError>() {
+                // Keep the error type so we know to fall back.
+                true => e,
+                false => anyhow!("WriteApp error {e}"),
+            })?;
 
         Ok(())
     }
@@ -457,12 +941,58 @@ impl Execute for SaveKeyArgs {
             .map_err(|_| anyhow!("No field bytes"))?;
 
         let decoded_key = match self.pubkey_encoding {
-            PubkeyEncoding::Raw => crate::crypto::pubkey::X25519PubKey::from_bytes(&bytes),
-            PubkeyEncoding::Pem => crate::crypto::pubkey::X25519PubKey::from_pem(bytes.to_vec()),
-            PubkeyEncoding::Der => crate::crypto::pubkey::X25519PubKey::from_der(bytes.to_vec()),
+            PubkeyEncoding::Raw => X25519PubKey::from_bytes(&bytes),
+            PubkeyEncoding::Pem => X25519PubKey::from_pem(bytes.to_vec()),
+            PubkeyEncoding::Der => X25519PubKey::from_der(bytes.to_vec()),
         };
 
-        runtime.init_key(decoded_key.as_bytes())?;
+        runtime.agree_key(&decoded_key)?;
+
+        Ok(())
+    }
+}
+
+impl Execute for SaveSaltArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
+        let msg: &Message = runtime.load(&self.from_msg_heap_id)?;
+
+        let bytes = msg
+            .get_field_bytes(&self.from_field_id)
+            .map_err(|_| anyhow!("No field bytes"))?;
+
+        runtime.save_salt(&bytes)
+    }
+}
+
+impl Execute for SetPubkeyArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
+        let key = runtime.public_key()?;
+        let bytes = match self.pubkey_encoding {
+            PubkeyEncoding::Raw => <[u8; 32]>::from(key).to_vec(),
+            PubkeyEncoding::Pem => key.into_pem(),
+            PubkeyEncoding::Der => key.into_der(),
+        };
+
+        let mut msg: Message = runtime.drop(&self.to_msg_heap_id)?;
+        msg.set_field_bytes(&self.to_field_id, &Bytes::from(bytes))
+            .map_err(|_| anyhow!("No field bytes"))?;
+        runtime.store(self.to_msg_heap_id.clone(), msg)?;
+
+        Ok(())
+    }
+}
+
+impl Execute for SetSaltArgs {
+    async fn execute(&self, runtime: &mut impl Runtime) -> anyhow::Result<()> {
+        let mut msg: Message = runtime.drop(&self.to_msg_heap_id)?;
+        let nbytes = msg
+            .get_field_nbytes(&self.to_field_id)
+            .map_err(|_| anyhow!("No field bytes"))?;
+
+        let salt = runtime.salt(nbytes)?;
+        msg.set_field_bytes(&self.to_field_id, &Bytes::from(salt))
+            .map_err(|_| anyhow!("No field bytes"))?;
+        runtime.store(self.to_msg_heap_id.clone(), msg)?;
 
         Ok(())
     }
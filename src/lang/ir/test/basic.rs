@@ -70,13 +70,14 @@ impl TaskProvider for LengthPayloadSpec {
                 ReadAppArgs {
                     from_len: 1..u16::MAX as usize,
                     to_heap_id: "payload".id(),
+                    to_cover_heap_id: None,
                 }
                 .into(),
                 ConcretizeFormatArgs {
                     from_format: self.abs_format_out.clone(),
                     to_heap_id: "cformat".id(),
-                    padding_field: None,
-                    block_size_nbytes: None,
+                    padding: None,
+                    timestamp_fields: vec![],
                 }
                 .into(),
                 CreateMessageArgs {
@@ -92,7 +93,7 @@ impl TaskProvider for LengthPayloadSpec {
                 .into(),
                 ComputeLengthArgs {
                     from_msg_heap_id: "message".id(),
-                    from_field_id: "length".id(),
+                    from_expr: LengthExpr::SizeOf("payload".id()),
                     to_heap_id: "length_value_on_heap".id(),
                 }
                 .into(),
@@ -121,8 +122,8 @@ impl TaskProvider for LengthPayloadSpec {
                 ConcretizeFormatArgs {
                     from_format: self.abs_format_in1.clone(),
                     to_heap_id: "cformat1".id(),
-                    padding_field: None,
-                    block_size_nbytes: None,
+                    padding: None,
+                    timestamp_fields: vec![],
                 }
                 .into(),
                 CreateMessageArgs {
@@ -143,15 +144,15 @@ impl TaskProvider for LengthPayloadSpec {
                 }
                 .into(),
                 ReadNetArgs {
-                    from_len: ReadNetLength::Identifier("payload_len_value".id()),
+                    from_len: ReadNetLength::Expr(HeapExpr::Heap("payload_len_value".id())),
                     to_heap_id: "payload".id(),
                 }
                 .into(),
                 ConcretizeFormatArgs {
                     from_format: self.abs_format_in2.clone(),
                     to_heap_id: "cformat2".id(),
-                    padding_field: None,
-                    block_size_nbytes: None,
+                    padding: None,
+                    timestamp_fields: vec![],
                 }
                 .into(),
                 CreateMessageArgs {
@@ -168,6 +169,7 @@ impl TaskProvider for LengthPayloadSpec {
                 WriteAppArgs {
                     from_msg_heap_id: "message_payload_part".id(),
                     from_field_id: "payload".id(),
+                    cover_heap_id: None,
                 }
                 .into(),
             ],
@@ -1,11 +1,19 @@
+use crate::crypto::kdf;
 use crate::lang::Role;
 use crate::lang::ir::bridge::*;
 use crate::lang::ir::v1::*;
 use crate::lang::types::*;
 
+// Each side sends its salt once, and then data messages.
+const SEND_SALT_TASK_ID: usize = 1;
+const RECEIVE_SALT_TASK_ID: usize = 2;
+const DATA_TASK_ID: usize = 3;
+
 #[derive(Clone)]
 pub struct EncryptedLengthPayloadSpec {
     role: Role,
+    secret_key: [u8; 32],
+    abs_format_salt: AbstractFormat,
     abs_format_out: AbstractFormat,
     abs_format_in1: AbstractFormat,
     abs_format_in2: AbstractFormat,
@@ -13,6 +21,15 @@ pub struct EncryptedLengthPayloadSpec {
 
 impl EncryptedLengthPayloadSpec {
     pub fn new(role: Role) -> Self {
+        let abs_format_salt: AbstractFormat = Format {
+            name: "SaltMessage".id(),
+            fields: vec![Field {
+                name: "salt".id(),
+                dtype: PrimitiveArray(NumericType::U8.into(), 32).into(),
+            }],
+        }
+        .into();
+
         let abs_format_out: AbstractFormat = Format {
             name: "DataMessageOut".id(),
             fields: vec![
@@ -66,8 +83,13 @@ impl EncryptedLengthPayloadSpec {
         }
         .into();
 
+        let secret_key =
+            kdf::derive_key_256("hunter2", kdf::DEFAULT_SALT, Default::default()).unwrap();
+
         Self {
             role,
+            secret_key,
+            abs_format_salt,
             abs_format_out,
             abs_format_in1,
             abs_format_in2,
@@ -77,34 +99,46 @@ impl EncryptedLengthPayloadSpec {
 
 impl TaskProvider for EncryptedLengthPayloadSpec {
     fn get_init_task(&self) -> Task {
-        let password = "hunter2";
-
         Task {
             id: Default::default(),
             ins: vec![
                 InitFixedSharedKeyArgs {
-                    password: password.to_string(),
+                    secret_key: self.secret_key,
+                    key_label: kdf::DEFAULT_KEY_LABEL.to_vec(),
                     role: self.role,
+                    cipher: Cipher::ChaCha20Poly1305,
                 }
                 .into(),
             ],
         }
     }
 
-    fn get_next_tasks(&self, _last_task: &TaskID) -> TaskSet {
+    fn get_next_tasks(&self, last_task: &TaskID) -> TaskSet {
+        // The client sends its salt first, and the server once it received it.
+        match (self.role, last_task.into_inner()) {
+            (Role::Client, 0) | (Role::Server, RECEIVE_SALT_TASK_ID) => {
+                return TaskSet::OutTask(self.send_salt_task());
+            }
+            (Role::Client, SEND_SALT_TASK_ID) | (Role::Server, 0) => {
+                return TaskSet::InTask(self.receive_salt_task());
+            }
+            _ => {}
+        }
+
         // Outgoing data forwarding direction.
         let out_task = Task {
             ins: vec![
                 ReadAppArgs {
                     from_len: 1..(u16::MAX - 32) as usize,
                     to_heap_id: "payload".id(),
+                    to_cover_heap_id: None,
                 }
                 .into(),
                 ConcretizeFormatArgs {
                     from_format: self.abs_format_out.clone(),
                     to_heap_id: "cformat".id(),
-                    padding_field: None,
-                    block_size_nbytes: None,
+                    padding: None,
+                    timestamp_fields: vec![],
                 }
                 .into(),
                 CreateMessageArgs {
@@ -120,7 +154,7 @@ impl TaskProvider for EncryptedLengthPayloadSpec {
                 .into(),
                 ComputeLengthArgs {
                     from_msg_heap_id: "message".id(),
-                    from_field_id: "length_mac".id(),
+                    from_expr: LengthExpr::SizeOfRange("payload".id(), "payload_mac".id()),
                     to_heap_id: "length_value_on_heap".id(),
                 }
                 .into(),
@@ -173,7 +207,7 @@ impl TaskProvider for EncryptedLengthPayloadSpec {
                 }
                 .into(),
             ],
-            id: TaskID::default(),
+            id: DATA_TASK_ID.into(),
         };
 
         // Incoming data forwarding direction.
@@ -192,8 +226,8 @@ impl TaskProvider for EncryptedLengthPayloadSpec {
                 ConcretizeFormatArgs {
                     from_format: self.abs_format_in1.clone(),
                     to_heap_id: "cformat1".id(),
-                    padding_field: None,
-                    block_size_nbytes: None,
+                    padding: None,
+                    timestamp_fields: vec![],
                 }
                 .into(),
                 CreateMessageArgs {
@@ -233,7 +267,11 @@ impl TaskProvider for EncryptedLengthPayloadSpec {
                 }
                 .into(),
                 ReadNetArgs {
-                    from_len: ReadNetLength::IdentifierMinus(("payload_len_value_heap".id(), 16)),
+                    from_len: ReadNetLength::Expr(HeapExpr::binary(
+                        HeapExpr::Heap("payload_len_value_heap".id()),
+                        ArithOp::Sub,
+                        HeapExpr::Literal(16),
+                    )),
                     to_heap_id: "payload".id(),
                 }
                 .into(),
@@ -245,8 +283,8 @@ impl TaskProvider for EncryptedLengthPayloadSpec {
                 ConcretizeFormatArgs {
                     from_format: self.abs_format_in2.clone(),
                     to_heap_id: "cformat2".id(),
-                    padding_field: None,
-                    block_size_nbytes: None,
+                    padding: None,
+                    timestamp_fields: vec![],
                 }
                 .into(),
                 CreateMessageArgs {
@@ -282,13 +320,81 @@ impl TaskProvider for EncryptedLengthPayloadSpec {
                 WriteAppArgs {
                     from_msg_heap_id: "message_payload_part".id(),
                     from_field_id: "payload".id(),
+                    cover_heap_id: None,
                 }
                 .into(),
             ],
-            id: TaskID::default(),
+            id: DATA_TASK_ID.into(),
         };
 
         // Concurrently execute tasks for both data forwarding directions.
         TaskSet::InAndOutTasks(TaskPair { out_task, in_task })
     }
 }
+
+impl EncryptedLengthPayloadSpec {
+    fn send_salt_task(&self) -> Task {
+        Task {
+            ins: vec![
+                ConcretizeFormatArgs {
+                    from_format: self.abs_format_salt.clone(),
+                    to_heap_id: "cformat".id(),
+                    padding: None,
+                    timestamp_fields: vec![],
+                }
+                .into(),
+                CreateMessageArgs {
+                    from_format_heap_id: "cformat".id(),
+                    to_heap_id: "message".id(),
+                }
+                .into(),
+                SetSaltArgs {
+                    to_msg_heap_id: "message".id(),
+                    to_field_id: "salt".id(),
+                }
+                .into(),
+                WriteNetArgs {
+                    from_msg_heap_id: "message".id(),
+                }
+                .into(),
+            ],
+            id: SEND_SALT_TASK_ID.into(),
+        }
+    }
+
+    fn receive_salt_task(&self) -> Task {
+        Task {
+            ins: vec![
+                ReadNetArgs {
+                    from_len: ReadNetLength::Range(32..33_usize),
+                    to_heap_id: "salt".id(),
+                }
+                .into(),
+                ConcretizeFormatArgs {
+                    from_format: self.abs_format_salt.clone(),
+                    to_heap_id: "cformat".id(),
+                    padding: None,
+                    timestamp_fields: vec![],
+                }
+                .into(),
+                CreateMessageArgs {
+                    from_format_heap_id: "cformat".id(),
+                    to_heap_id: "message".id(),
+                }
+                .into(),
+                SetArrayBytesArgs {
+                    from_heap_id: "salt".id(),
+                    to_msg_heap_id: "message".id(),
+                    to_field_id: "salt".id(),
+                }
+                .into(),
+                SaveSaltArgs {
+                    from_msg_heap_id: "message".id(),
+                    from_field_id: "salt".id(),
+                }
+                .into(),
+            ],
+            id: RECEIVE_SALT_TASK_ID.into(),
+        }
+    }
+}
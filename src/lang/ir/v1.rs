@@ -1,18 +1,35 @@
 use std::ops::Range;
 
 use crate::lang::Role;
-use crate::lang::types::{AbstractFormat, Identifier, PubkeyEncoding};
+use crate::lang::ir::bridge::TaskID;
+use crate::lang::types::{
+    AbstractFormat, ArithOp, ChecksumAlgorithm, Cipher, CompareOp, FieldSemantic, Identifier,
+    LengthExpr, PaddingPolicy, PubkeyEncoding, SendCondition, TimestampUnit, TimingSpec,
+    VarIntType,
+};
+
+/// An arithmetic expression over numbers on the heap.
+#[derive(Clone, Debug)]
+pub enum HeapExpr {
+    Literal(i128),
+    Heap(Identifier),
+    Binary(Box<HeapExpr>, ArithOp, Box<HeapExpr>),
+}
+
+impl HeapExpr {
+    pub fn binary(lhs: HeapExpr, op: ArithOp, rhs: HeapExpr) -> Self {
+        HeapExpr::Binary(Box::new(lhs), op, Box::new(rhs))
+    }
+}
 
 #[derive(Debug)]
 pub enum ReadNetLength {
-    /// Amount to read specified in this heap variable.
-    Identifier(Identifier),
-    /// Amount to read specified in this heap variable minus the given value.
-    IdentifierMinus((Identifier, usize)),
-    /// Subtract the id and the usize from the read amount.
-    IdentifierMinusMinus((Identifier, Identifier, usize)),
+    /// Amount to read given by the value of this expression.
+    Expr(HeapExpr),
     /// Amount to read specified by this range.
     Range(Range<usize>),
+    /// Read a varint, whose first bytes give its width.
+    VarInt(VarIntType),
 }
 
 // Auto-generates `From` implementations like
@@ -21,43 +38,129 @@ pub enum ReadNetLength {
 #[enum_from::enum_from]
 #[derive(Debug)]
 pub enum InstructionV1 {
+    CheckFieldBytes(CheckFieldBytesArgs),
+    CheckFieldValue(CheckFieldValueArgs),
+    CheckFieldVariable(CheckFieldVariableArgs),
+    ComputeChecksum(ComputeChecksumArgs),
     ComputeLength(ComputeLengthArgs),
     ConcretizeFormat(ConcretizeFormatArgs),
     CreateMessage(CreateMessageArgs),
     DecryptField(DecryptFieldArgs),
+    DispatchFormat(DispatchFormatArgs),
     EncryptField(EncryptFieldArgs),
+    Flight(FlightArgs),
     GetArrayBytes(GetArrayBytesArgs),
+    GetArrayLength(GetArrayLengthArgs),
     GetNumericValue(GetNumericValueArgs),
+    Goto(GotoArgs),
+    IncrementCounter(IncrementCounterArgs),
     InitFixedSharedKey(InitFixedSharedKeyArgs),
+    InitShaper(InitShaperArgs),
+    LoadVariable(LoadVariableArgs),
     ReadApp(ReadAppArgs),
     ReadNet(ReadNetArgs),
+    SelectFormat(SelectFormatArgs),
     SetArrayBytes(SetArrayBytesArgs),
     SetNumericValue(SetNumericValueArgs),
+    SetPubkey(SetPubkeyArgs),
+    SetSalt(SetSaltArgs),
+    StoreVariable(StoreVariableArgs),
     WriteApp(WriteAppArgs),
     WriteNet(WriteNetArgs),
     WriteNetTwice(WriteNetTwiceArgs),
     SaveKey(SaveKeyArgs),
+    SaveSalt(SaveSaltArgs),
 }
 
-/// Compute the length of all `from_msg_id` fields that are ordered after
-/// `from_field_id`, and store the length in `to_heap_id`.
+/// Check that the bytes of the field `from_field_id` inside the message stored
+/// on the heap at `from_msg_heap_id` are the `expected` bytes, and fail with a
+/// protocol mismatch if not.
 #[derive(Debug)]
-pub struct ComputeLengthArgs {
+pub struct CheckFieldBytesArgs {
+    pub from_msg_heap_id: Identifier,
+    pub from_field_id: Identifier,
+    pub expected: Vec<u8>,
+}
+
+/// Check that the value of the field `from_field_id` inside the message stored
+/// on the heap at `from_msg_heap_id` is one that `semantic` allows, and fail
+/// with a protocol mismatch if not.
+#[derive(Debug)]
+pub struct CheckFieldValueArgs {
     pub from_msg_heap_id: Identifier,
     pub from_field_id: Identifier,
+    pub semantic: FieldSemantic,
+}
+
+/// Check that the field `from_field_id` inside the message stored on the heap
+/// at `from_msg_heap_id` holds the variable `var_id`, comparing the value of a
+/// number and the bytes otherwise, and fail with a protocol mismatch if not.
+#[derive(Debug)]
+pub struct CheckFieldVariableArgs {
+    pub from_msg_heap_id: Identifier,
+    pub from_field_id: Identifier,
+    pub var_id: Identifier,
+}
+
+/// Compute the `algorithm` checksum of the bytes from the field `from_field_id`
+/// through the field `to_field_id` of the messages stored on the heap at
+/// `msg_heap_ids`, taken as one message, with the bytes of the field
+/// `field_id` as zeros. Then set that field to the checksum truncated to its
+/// width, or if `verify`, fail with a protocol mismatch if it holds another.
+#[derive(Debug)]
+pub struct ComputeChecksumArgs {
+    pub msg_heap_ids: Vec<Identifier>,
+    pub algorithm: ChecksumAlgorithm,
+    pub from_field_id: Identifier,
+    pub to_field_id: Identifier,
+    pub field_id: Identifier,
+    pub verify: bool,
+}
+
+/// Evaluate `from_expr` with the sizes of the fields of the message stored on
+/// the heap at `from_msg_heap_id`, and store the length in `to_heap_id`.
+#[derive(Debug)]
+pub struct ComputeLengthArgs {
+    pub from_msg_heap_id: Identifier,
+    pub from_expr: LengthExpr,
     pub to_heap_id: Identifier,
 }
 
 /// Instantiates a `ConcreteFormat` from the given `from_format` and stores the
 /// result in `to_heap_id`. All fields of type `DynamicArray` must already
 /// contain a bytes object with an identical id on the heap when using this
-/// instruction, or else it will fail.
+/// instruction, or have a fixed value in the format, or else it will fail. If
+/// there is `padding`, we first store the padding for its field on the heap.
+/// The fields in `timestamp_fields` take the time at which we run this
+/// instruction as their fixed value.
 #[derive(Debug)]
 pub struct ConcretizeFormatArgs {
     pub from_format: AbstractFormat,
     pub to_heap_id: Identifier,
-    pub padding_field: Option<Identifier>,
+    pub padding: Option<PaddingDescriptor>,
+    pub timestamp_fields: Vec<(Identifier, TimestampUnit)>,
+}
+
+/// The padding we add to the field `field_id`, whose size the `policy` picks
+/// from the size of the message and of the payload in `payload_field_id`, and
+/// for padding to the block size, `block_size_nbytes`. If there is an
+/// `alignment`, we add to the padding until it divides exactly.
+#[derive(Debug)]
+pub struct PaddingDescriptor {
+    pub field_id: Identifier,
+    pub payload_field_id: Identifier,
+    pub policy: PaddingPolicy,
     pub block_size_nbytes: Option<usize>,
+    pub alignment: Option<PaddingAlignment>,
+}
+
+/// A division of the LENGTH expression that counts the payload, whose
+/// `dividend` the padding makes a multiple of the `divisor`, so that a
+/// receiver can multiply the value back into a size.
+#[derive(Clone, Debug)]
+pub struct PaddingAlignment {
+    pub dividend: LengthExpr,
+    pub divisor: usize,
 }
 
 /// Creates an allocated message from the `ConcreteFormat` on the heap given by
@@ -77,6 +180,21 @@ pub struct DecryptFieldArgs {
     pub to_plaintext_heap_id: Identifier,
 }
 
+/// Peek at the first bytes of the next message from the network, and execute
+/// the instructions of the case whose tag is the bytes at `tag_range`, or fail
+/// with a protocol mismatch if there is none.
+#[derive(Debug)]
+pub struct DispatchFormatArgs {
+    pub tag_range: Range<usize>,
+    pub cases: Vec<DispatchCase>,
+}
+
+#[derive(Debug)]
+pub struct DispatchCase {
+    pub tag: Vec<u8>,
+    pub ins: Vec<InstructionV1>,
+}
+
 /// TODO
 #[derive(Debug)]
 pub struct EncryptFieldArgs {
@@ -86,6 +204,15 @@ pub struct EncryptFieldArgs {
     pub to_mac_heap_id: Option<Identifier>,
 }
 
+/// Send or receive the `messages` of a flight one after the other, each with
+/// the heap to itself. With `coalesce`, hold the writes of the messages and
+/// write them to the network at once after the last one.
+#[derive(Debug)]
+pub struct FlightArgs {
+    pub messages: Vec<Vec<InstructionV1>>,
+    pub coalesce: bool,
+}
+
 /// Get the bytes data from the field given by `from_field_id` inside of the
 /// message stored on the heap at `from_msg_heap_id`, and store the bytes on the
 /// heap in `to_heap_id`.
@@ -96,6 +223,16 @@ pub struct GetArrayBytesArgs {
     pub to_heap_id: Identifier,
 }
 
+/// Get the number of bytes in the field given by `from_field_id` inside of the
+/// message stored on the heap at `from_msg_heap_id`, and store the number on
+/// the heap in `to_heap_id`.
+#[derive(Debug)]
+pub struct GetArrayLengthArgs {
+    pub from_msg_heap_id: Identifier,
+    pub from_field_id: Identifier,
+    pub to_heap_id: Identifier,
+}
+
 /// Get the numeric value from the field given by `from_field_id` inside of the
 /// message stored on the heap at `from_msg_heap_id`, and store the value on the
 /// heap in `to_heap_id`.
@@ -106,19 +243,67 @@ pub struct GetNumericValueArgs {
     pub to_heap_id: Identifier,
 }
 
-/// TODO
+/// Continue with the task of the first of the `cases` whose guard holds once
+/// this task completes, rather than with the task this one leads to.
+#[derive(Debug)]
+pub struct GotoArgs {
+    pub cases: Vec<GotoCase>,
+}
+
+#[derive(Debug)]
+pub struct GotoCase {
+    /// The number on the heap to compare, and what to compare it with.
+    pub guard: Option<(Identifier, CompareOp, i128)>,
+    pub to_task_id: TaskID,
+}
+
+/// Set the counter in the variable `var_id` to `start` if it has no value
+/// yet, or else add `step` to it, wrapping around at `modulus` if given.
+#[derive(Debug)]
+pub struct IncrementCounterArgs {
+    pub var_id: Identifier,
+    pub start: u128,
+    pub step: u128,
+    pub modulus: Option<u128>,
+}
+
+/// Create the `cipher` that both forwarding directions will use to encrypt and
+/// decrypt fields in our `role`, with per-direction keys expanded from the
+/// password-derived `secret_key` under the protocol's `key_label`.
 #[derive(Debug)]
 pub struct InitFixedSharedKeyArgs {
-    pub password: String,
+    pub secret_key: [u8; 32],
+    pub key_label: Vec<u8>,
     pub role: Role,
+    pub cipher: Cipher,
+}
+
+/// Delay and split the writes to the network of the forwarding direction that
+/// runs the init task, which is the one that sends messages, as the `timing`
+/// says.
+#[derive(Debug)]
+pub struct InitShaperArgs {
+    pub timing: TimingSpec,
+}
+
+/// Copy the variable `from_var_id`, which outlives the task, onto the heap at
+/// `to_heap_id`.
+#[derive(Debug)]
+pub struct LoadVariableArgs {
+    pub from_var_id: Identifier,
+    pub to_heap_id: Identifier,
 }
 
 /// Read a number of bytes given by the `from_len` range from the application
-/// and store the result on the heap in `to_heap_id`.
+/// and store the result on the heap in `to_heap_id`. If there is a
+/// `to_cover_heap_id`, we stop waiting once the application was idle for as
+/// long as the shaper allows, store no bytes, and store 1 there to mark the
+/// message as cover, or else 0.
 #[derive(Debug)]
 pub struct ReadAppArgs {
     pub from_len: Range<usize>,
     pub to_heap_id: Identifier,
+    pub to_cover_heap_id: Option<Identifier>,
 }
 
 /// Read a number of bytes given by `from_len` from the network and store the
@@ -129,6 +314,25 @@ pub struct ReadNetArgs {
     pub to_heap_id: Identifier,
 }
 
+/// Read from the application as `read` says, and then execute the
+/// instructions of the first of the `cases` whose condition the data meets and
+/// whose format fits it, with the data stored on the heap at its
+/// `payload_heap_id`.
+#[derive(Debug)]
+pub struct SelectFormatArgs {
+    pub read: ReadAppArgs,
+    pub cases: Vec<SelectCase>,
+}
+
+#[derive(Debug)]
+pub struct SelectCase {
+    pub condition: Option<SendCondition>,
+    /// None for a format without a payload, which only fits no data.
+    pub payload_heap_id: Option<Identifier>,
+    pub max_payload_nbytes: usize,
+    pub ins: Vec<InstructionV1>,
+}
+
 /// Set the bytes stored on the heap at `from_heap_id` in the field
 /// `to_field_id` inside the message stored on the heap at `to_msg_heap_id`.
 #[derive(Debug)]
@@ -147,12 +351,42 @@ pub struct SetNumericValueArgs {
     pub to_field_id: Identifier,
 }
 
+/// Set our ephemeral public key in the field `to_field_id` inside the message
+/// stored on the heap at `to_msg_heap_id`.
+#[derive(Debug)]
+pub struct SetPubkeyArgs {
+    pub to_msg_heap_id: Identifier,
+    pub to_field_id: Identifier,
+    pub pubkey_encoding: PubkeyEncoding,
+}
+
+/// Set our salt in the field `to_field_id` inside the message stored on the
+/// heap at `to_msg_heap_id`, which fixes the keys we encrypt with for the rest
+/// of the connection.
+#[derive(Debug)]
+pub struct SetSaltArgs {
+    pub to_msg_heap_id: Identifier,
+    pub to_field_id: Identifier,
+}
+
+/// Keep the bytes of the field `from_field_id` inside the message stored on the
+/// heap at `from_msg_heap_id` in the variable `to_var_id`, for the rest of the
+/// connection.
+#[derive(Debug)]
+pub struct StoreVariableArgs {
+    pub from_msg_heap_id: Identifier,
+    pub from_field_id: Identifier,
+    pub to_var_id: Identifier,
+}
+
 /// Write the bytes from the field `from_field_id` inside of the message stored
-/// at `from_msg_heap_id` on the heap to the application.
+/// at `from_msg_heap_id` on the heap to the application, unless the number on
+/// the heap at `cover_heap_id` marks the message as cover.
 #[derive(Debug)]
 pub struct WriteAppArgs {
     pub from_msg_heap_id: Identifier,
     pub from_field_id: Identifier, // usually payload field
+    pub cover_heap_id: Option<Identifier>,
 }
 
 /// Write the bytes from the message stored on the heap at `from_msg_heap_id` to
@@ -171,9 +405,21 @@ pub struct WriteNetTwiceArgs {
     pub len_first_write: usize,
 }
 
+/// Agree on session keys with the peer's public key, which is stored in the
+/// field `from_field_id` inside of the message stored on the heap at
+/// `from_msg_heap_id`.
 #[derive(Debug)]
 pub struct SaveKeyArgs {
     pub from_msg_heap_id: Identifier,
     pub from_field_id: Identifier, // usually payload field
     pub pubkey_encoding: PubkeyEncoding,
 }
+
+/// Derive the keys we decrypt with from the peer's salt, which is stored in
+/// the field `from_field_id` inside of the message stored on the heap at
+/// `from_msg_heap_id`.
+#[derive(Debug)]
+pub struct SaveSaltArgs {
+    pub from_msg_heap_id: Identifier,
+    pub from_field_id: Identifier,
+}
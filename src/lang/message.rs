@@ -1,6 +1,8 @@
 #![allow(dead_code)]
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::ops::Range;
+
+use bytes::{BufMut, Bytes, BytesMut};
 
 use crate::lang::types::*;
 
@@ -35,7 +37,9 @@ impl Message {
             .unwrap();
 
         for (field_name, field_value) in &format.fixed_fields {
-            if let Some(mut bytes) = msg.try_get_field_slice_mut(field_name) {
+            if let Some((_, bit_offset, nbits)) = msg.try_get_bitfield(field_name) {
+                write_bits(&mut msg.data, bit_offset, nbits, field_value[0]);
+            } else if let Some(mut bytes) = msg.try_get_field_slice_mut(field_name) {
                 bytes.put_slice(&field_value[..]);
             }
         }
@@ -67,67 +71,26 @@ impl Message {
             .map(|(_, offset, size)| self.get_field_slice_mut(offset, size))
     }
 
+    /// The type, offset, and size in bits of a bitfield, or None if the field
+    /// is not a bitfield.
+    fn try_get_bitfield(&self, field_name: &Identifier) -> Option<(Array, usize, usize)> {
+        self.format
+            .format
+            .try_get_field_type_bit_offset_and_size(field_name)
+            .filter(|(dtype, ..)| dtype.is_bitfield())
+    }
+
     pub fn set_field_unsigned_numeric(
         &mut self,
         field_name: &Identifier,
         value: u128,
     ) -> Result<(), SetFieldError> {
-        if let Some((dtype, offset, size)) = self
-            .format
-            .format
-            .try_get_field_type_offset_and_size(field_name)
-        {
-            let mut field_bytes = self.get_field_slice_mut(offset, size);
-
-            match dtype {
-                Array::Primitive(PrimitiveArray(x, n)) => {
-                    if n > 1 {
-                        return Err(SetFieldError::TypeError);
-                    }
-                    match x {
-                        PrimitiveType::Char => Err(SetFieldError::TypeError),
-                        PrimitiveType::Bool => Err(SetFieldError::TypeError),
-                        PrimitiveType::Numeric(y) => match y {
-                            NumericType::U8 => {
-                                if let Ok(z) = u8::try_from(value) {
-                                    field_bytes.put_u8(z);
-                                    Ok(())
-                                } else {
-                                    Err(SetFieldError::DowncastError)
-                                }
-                            }
-                            NumericType::U16 => {
-                                if let Ok(z) = u16::try_from(value) {
-                                    field_bytes.put_u16(z);
-                                    Ok(())
-                                } else {
-                                    Err(SetFieldError::DowncastError)
-                                }
-                            }
-                            NumericType::U32 => {
-                                if let Ok(z) = u32::try_from(value) {
-                                    field_bytes.put_u32(z);
-                                    Ok(())
-                                } else {
-                                    Err(SetFieldError::DowncastError)
-                                }
-                            }
-                            NumericType::U64 => {
-                                if let Ok(z) = u64::try_from(value) {
-                                    field_bytes.put_u64(z);
-                                    Ok(())
-                                } else {
-                                    Err(SetFieldError::DowncastError)
-                                }
-                            }
-                            _ => Err(SetFieldError::TypeError),
-                        },
-                    }
-                }
-                _ => panic!(),
+        match self.try_get_primitive_type(field_name) {
+            Some(PrimitiveType::Numeric(t)) if !t.is_unsigned() => Err(SetFieldError::TypeError),
+            _ => {
+                let value = i128::try_from(value).map_err(|_| SetFieldError::DowncastError)?;
+                self.set_field_value(field_name, PrimitiveValue::Int(value))
             }
-        } else {
-            Err(SetFieldError::NotDefined)
         }
     }
 
@@ -135,34 +98,133 @@ impl Message {
         &self,
         field_name: &Identifier,
     ) -> Result<u128, GetFieldError> {
-        if let Some((dtype, offset, size)) = self
+        match self.try_get_primitive_type(field_name) {
+            Some(PrimitiveType::Numeric(t)) if !t.is_unsigned() => Err(GetFieldError::TypeError),
+            _ => match self.get_field_value(field_name)? {
+                PrimitiveValue::Int(n) => u128::try_from(n).map_err(|_| GetFieldError::TypeError),
+                _ => Err(GetFieldError::TypeError),
+            },
+        }
+    }
+
+    pub fn set_field_signed_numeric(
+        &mut self,
+        field_name: &Identifier,
+        value: i128,
+    ) -> Result<(), SetFieldError> {
+        self.set_field_value(field_name, PrimitiveValue::Int(value))
+    }
+
+    pub fn get_field_signed_numeric(&self, field_name: &Identifier) -> Result<i128, GetFieldError> {
+        match self.get_field_value(field_name)? {
+            PrimitiveValue::Int(n) => Ok(n),
+            _ => Err(GetFieldError::TypeError),
+        }
+    }
+
+    pub fn set_field_bool(
+        &mut self,
+        field_name: &Identifier,
+        value: bool,
+    ) -> Result<(), SetFieldError> {
+        self.set_field_value(field_name, PrimitiveValue::Bool(value))
+    }
+
+    pub fn get_field_bool(&self, field_name: &Identifier) -> Result<bool, GetFieldError> {
+        match self.get_field_value(field_name)? {
+            PrimitiveValue::Bool(b) => Ok(b),
+            _ => Err(GetFieldError::TypeError),
+        }
+    }
+
+    /// Chars are ASCII, so setting any other char fails.
+    pub fn set_field_char(
+        &mut self,
+        field_name: &Identifier,
+        value: char,
+    ) -> Result<(), SetFieldError> {
+        self.set_field_value(field_name, PrimitiveValue::Char(value))
+    }
+
+    pub fn get_field_char(&self, field_name: &Identifier) -> Result<char, GetFieldError> {
+        match self.get_field_value(field_name)? {
+            PrimitiveValue::Char(c) => Ok(c),
+            _ => Err(GetFieldError::TypeError),
+        }
+    }
+
+    /// The type of a field that holds a single bool, char, or integer.
+    fn try_get_primitive_type(&self, field_name: &Identifier) -> Option<PrimitiveType> {
+        match self.format.format.try_get_field_by_name(field_name)?.dtype {
+            Array::Primitive(PrimitiveArray(t, 1)) => Some(t),
+            _ => None,
+        }
+    }
+
+    pub fn set_field_value(
+        &mut self,
+        field_name: &Identifier,
+        value: PrimitiveValue,
+    ) -> Result<(), SetFieldError> {
+        let (_, offset, size) = self
             .format
             .format
             .try_get_field_type_offset_and_size(field_name)
-        {
-            let mut field_bytes = self.get_field_slice(offset, size);
-
-            match dtype {
-                Array::Primitive(PrimitiveArray(x, n)) => {
-                    if n > 1 {
-                        return Err(GetFieldError::TypeError);
-                    }
-                    match x {
-                        PrimitiveType::Char => Err(GetFieldError::TypeError),
-                        PrimitiveType::Bool => Err(GetFieldError::TypeError),
-                        PrimitiveType::Numeric(y) => match y {
-                            NumericType::U8 => Ok(field_bytes.get_u8() as u128),
-                            NumericType::U16 => Ok(field_bytes.get_u16() as u128),
-                            NumericType::U32 => Ok(field_bytes.get_u32() as u128),
-                            NumericType::U64 => Ok(field_bytes.get_u64() as u128),
-                            _ => Err(GetFieldError::TypeError),
-                        },
-                    }
+            .ok_or(SetFieldError::NotDefined)?;
+        let t = self
+            .try_get_primitive_type(field_name)
+            .filter(|t| t.accepts(value))
+            .ok_or(SetFieldError::TypeError)?;
+        let bytes = t.encode_value(value).ok_or(SetFieldError::DowncastError)?;
+
+        if let Some((_, bit_offset, nbits)) = self.try_get_bitfield(field_name) {
+            write_bits(&mut self.data, bit_offset, nbits, bytes[0]);
+            return Ok(());
+        }
+
+        // Setting a varint may change its width.
+        self.resize_field(field_name, offset, size, bytes.len());
+        self.get_field_slice_mut(offset, bytes.len())
+            .copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    pub fn get_field_value(
+        &self,
+        field_name: &Identifier,
+    ) -> Result<PrimitiveValue, GetFieldError> {
+        let field_bytes = match self.try_get_bitfield(field_name) {
+            Some((_, bit_offset, nbits)) => &[read_bits(&self.data, bit_offset, nbits)],
+            None => self
+                .try_get_field_slice(field_name)
+                .ok_or(GetFieldError::NotDefined)?,
+        };
+        self.try_get_primitive_type(field_name)
+            .and_then(|t| t.decode_value(field_bytes))
+            .ok_or(GetFieldError::TypeError)
+    }
+
+    /// Gives a varint field a new width, moving the fields after it. The
+    /// field keeps as many of its bytes as fit, and is zero-filled if it grows.
+    fn resize_field(&mut self, field_name: &Identifier, offset: usize, size: usize, nbytes: usize) {
+        if size == nbytes {
+            return;
+        }
+
+        let suffix = self.data.split_off(offset + size);
+        self.data.resize(offset + nbytes, 0);
+        self.data.unsplit(suffix);
+
+        for field in self.format.format.fields.iter_mut() {
+            if &field.name == field_name {
+                if let Array::Primitive(PrimitiveArray(
+                    PrimitiveType::VarInt(_, ref mut width),
+                    _,
+                )) = field.dtype
+                {
+                    *width = Some(nbytes);
                 }
-                _ => panic!(),
             }
-        } else {
-            Err(GetFieldError::NotDefined)
         }
     }
 
@@ -180,18 +242,19 @@ impl Message {
             .expect("payload length too large for length field");
     }
 
-    /// Computes the sum of the length of all fields after the given field.
-    pub fn len_suffix(&self, field_name: &Identifier) -> usize {
-        let mut nbytes: usize = 0;
-        let mut do_sum = false;
-        for field in &self.format.format.fields {
-            if do_sum {
-                nbytes += field.maybe_size_of().unwrap();
-            } else if field.name.eq(field_name) {
-                do_sum = true;
-            }
-        }
-        nbytes
+    /// Evaluates the length expression with the sizes of our fields.
+    pub fn evaluate_length(&self, expr: &LengthExpr) -> Option<i128> {
+        expr.evaluate(&self.format.format, &|field| {
+            field.maybe_size_of().map(|nbytes| nbytes as i128)
+        })
+    }
+
+    pub fn get_field_nbytes(&self, field_name: &Identifier) -> Result<usize, GetFieldError> {
+        self.format
+            .format
+            .try_get_field_type_offset_and_size(field_name)
+            .map(|(_, _, size)| size)
+            .ok_or(GetFieldError::NotDefined)
     }
 
     pub fn get_field_bytes(&self, field_name: &Identifier) -> Result<Bytes, GetFieldError> {
@@ -205,11 +268,28 @@ impl Message {
         }
     }
 
+    /// Sets the bytes of a field. Since a bitfield shares bytes with the
+    /// rest of its bit group, it takes the bytes of the whole group, but only
+    /// its own bits are set.
     pub fn set_field_bytes(
         &mut self,
         field_name: &Identifier,
         bytes: &Bytes,
     ) -> Result<(), SetFieldError> {
+        if let Some((_, bit_offset, nbits)) = self.try_get_bitfield(field_name) {
+            let (_, offset, size) = self
+                .format
+                .format
+                .try_get_field_type_offset_and_size(field_name)
+                .ok_or(SetFieldError::NotDefined)?;
+            if bytes.len() != size {
+                return Err(SetFieldError::TypeError);
+            }
+            let value = read_bits(bytes, bit_offset - offset * 8, nbits);
+            write_bits(&mut self.data, bit_offset, nbits, value);
+            return Ok(());
+        }
+
         match self.try_get_field_slice_mut(field_name) {
             Some(slice) => {
                 if slice.len() == bytes.len() {
@@ -223,6 +303,18 @@ impl Message {
         }
     }
 
+    /// The bytes of a field, or of its bit group if it is a bitfield.
+    pub fn try_get_field_byte_range(&self, field_name: &Identifier) -> Option<Range<usize>> {
+        self.format
+            .format
+            .try_get_field_type_offset_and_size(field_name)
+            .map(|(_, offset, size)| offset..offset + size)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
     pub fn into_inner(self) -> Bytes {
         self.data.freeze()
     }
@@ -235,6 +327,24 @@ impl Message {
     }
 }
 
+// Reads `nbits` bits starting `bit_offset` bits into the bytes, most
+// significant bit first, into the low bits of a byte.
+fn read_bits(bytes: &[u8], bit_offset: usize, nbits: usize) -> u8 {
+    (bit_offset..bit_offset + nbits).fold(0, |value, i| {
+        (value << 1) | ((bytes[i / 8] >> (7 - i % 8)) & 1)
+    })
+}
+
+// Writes the low `nbits` bits of the value `bit_offset` bits into the bytes,
+// most significant bit first.
+fn write_bits(bytes: &mut [u8], bit_offset: usize, nbits: usize, value: u8) {
+    for (j, i) in (bit_offset..bit_offset + nbits).enumerate() {
+        let bit = (value >> (nbits - 1 - j)) & 1;
+        let mask = 1 << (7 - i % 8);
+        bytes[i / 8] = (bytes[i / 8] & !mask) | (bit << (7 - i % 8));
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -257,6 +367,164 @@ mod tests {
         println!("{:?}", message.into_inner());
     }
 
+    #[test]
+    fn test_message_numeric_encodings() {
+        let format: ConcreteFormat = Format {
+            name: "Handshake".parse().unwrap(),
+            fields: vec![
+                Field {
+                    name: "little".parse().unwrap(),
+                    dtype: PrimitiveArray(NumericType::U16Le.into(), 1).into(),
+                },
+                Field {
+                    name: "varint".parse().unwrap(),
+                    dtype: PrimitiveArray(PrimitiveType::VarInt(VarIntType::Quic, Some(1)), 1)
+                        .into(),
+                },
+                Field {
+                    name: "trailer".parse().unwrap(),
+                    dtype: PrimitiveArray(NumericType::U8.into(), 1).into(),
+                },
+            ],
+        }
+        .try_into()
+        .unwrap();
+
+        let mut message = Message::new(format);
+        message
+            .set_field_unsigned_numeric(&"little".id(), 0x0102)
+            .unwrap();
+        message
+            .set_field_unsigned_numeric(&"trailer".id(), 0xff)
+            .unwrap();
+        // The varint grows and moves the trailer.
+        message
+            .set_field_unsigned_numeric(&"varint".id(), 15293)
+            .unwrap();
+
+        assert_eq!(message.get_field_nbytes(&"varint".id()).unwrap(), 2);
+        assert_eq!(
+            message.get_field_unsigned_numeric(&"varint".id()).unwrap(),
+            15293
+        );
+        assert_eq!(
+            message.get_field_unsigned_numeric(&"little".id()).unwrap(),
+            0x0102
+        );
+        assert_eq!(
+            message.into_inner().as_ref(),
+            [0x02, 0x01, 0x7b, 0xbd, 0xff]
+        );
+    }
+
+    #[test]
+    fn test_message_signed_bool_char() {
+        let field = |name: &str, t: PrimitiveType| Field {
+            name: name.id(),
+            dtype: PrimitiveArray(t, 1).into(),
+        };
+        let format: ConcreteFormat = Format {
+            name: "Handshake".parse().unwrap(),
+            fields: vec![
+                field("big", NumericType::I16.into()),
+                field("little", NumericType::I32Le.into()),
+                field("flag", PrimitiveType::Bool),
+                field("letter", PrimitiveType::Char),
+            ],
+        }
+        .try_into()
+        .unwrap();
+
+        let mut message = Message::new(format);
+        message.set_field_signed_numeric(&"big".id(), -2).unwrap();
+        message
+            .set_field_signed_numeric(&"little".id(), -3)
+            .unwrap();
+        message.set_field_bool(&"flag".id(), true).unwrap();
+        message.set_field_char(&"letter".id(), 'p').unwrap();
+
+        assert!(matches!(
+            message.set_field_signed_numeric(&"big".id(), 1 << 15),
+            Err(SetFieldError::DowncastError)
+        ));
+        assert!(matches!(
+            message.set_field_char(&"letter".id(), 'π'),
+            Err(SetFieldError::DowncastError)
+        ));
+        assert!(matches!(
+            message.set_field_unsigned_numeric(&"big".id(), 2),
+            Err(SetFieldError::TypeError)
+        ));
+
+        assert_eq!(message.get_field_signed_numeric(&"big".id()).unwrap(), -2);
+        assert_eq!(
+            message.get_field_signed_numeric(&"little".id()).unwrap(),
+            -3
+        );
+        assert!(message.get_field_bool(&"flag".id()).unwrap());
+        assert_eq!(message.get_field_char(&"letter".id()).unwrap(), 'p');
+        assert_eq!(
+            message.into_inner().as_ref(),
+            [0xff, 0xfe, 0xfd, 0xff, 0xff, 0xff, 0x01, b'p']
+        );
+    }
+
+    #[test]
+    fn test_message_bitfields() {
+        let field = |name: &str, t: PrimitiveType| Field {
+            name: name.id(),
+            dtype: PrimitiveArray(t, 1).into(),
+        };
+        let format = Format {
+            name: "Frame".parse().unwrap(),
+            fields: vec![
+                field("fin", PrimitiveType::Bits(1)),
+                field("rsv", PrimitiveType::Bits(3)),
+                field("opcode", PrimitiveType::Bits(4)),
+                field("mask", PrimitiveType::Bits(1)),
+                field("len", PrimitiveType::Bits(7)),
+                field("trailer", NumericType::U8.into()),
+            ],
+        };
+        assert_eq!(format.maybe_size_of(), Some(3));
+        let mut format: ConcreteFormat = format.try_into().unwrap();
+        format.fixed_fields = vec![("opcode".id(), vec![0x2])];
+
+        let mut message = Message::new(format);
+        message.set_field_unsigned_numeric(&"fin".id(), 1).unwrap();
+        message
+            .set_field_unsigned_numeric(&"len".id(), 0x55)
+            .unwrap();
+        message
+            .set_field_unsigned_numeric(&"trailer".id(), 0xab)
+            .unwrap();
+
+        assert!(matches!(
+            message.set_field_unsigned_numeric(&"rsv".id(), 8),
+            Err(SetFieldError::DowncastError)
+        ));
+        assert_eq!(message.get_field_unsigned_numeric(&"fin".id()).unwrap(), 1);
+        assert_eq!(message.get_field_unsigned_numeric(&"rsv".id()).unwrap(), 0);
+        assert_eq!(
+            message.get_field_unsigned_numeric(&"opcode".id()).unwrap(),
+            2
+        );
+        assert_eq!(
+            message.get_field_unsigned_numeric(&"len".id()).unwrap(),
+            0x55
+        );
+        assert_eq!(
+            message.get_field_bytes(&"mask".id()).unwrap().as_ref(),
+            [0x82, 0x55]
+        );
+
+        // Setting a bitfield from its group's bytes leaves the others alone.
+        message
+            .set_field_bytes(&"mask".id(), &Bytes::from_static(&[0x00, 0x80]))
+            .unwrap();
+        assert_eq!(message.into_inner().as_ref(), [0x82, 0xd5, 0xab]);
+    }
+
     #[test]
     fn test_message_set_length() {
         let format: ConcreteFormat = Format {
@@ -1,10 +1,12 @@
 use std::ops::Range;
 
 use bytes::Bytes;
-use types::Identifier;
+use types::{Cipher, Identifier, TimingSpec};
 
-use crate::crypto::chacha::CipherKind;
-use crate::lang::data::Data;
+use crate::crypto::aead::CipherKind;
+use crate::crypto::pubkey::X25519PubKey;
+use crate::lang::data::{Data, Variable};
+use crate::lang::ir::bridge::TaskID;
 
 pub mod compiler;
 mod data;
@@ -23,15 +25,34 @@ trait Runtime {
     fn store<T: Into<Data>>(&mut self, addr: Identifier, data: T) -> anyhow::Result<()>;
     fn load<'a, T: TryFrom<&'a Data>>(&'a self, addr: &Identifier) -> anyhow::Result<T>;
     fn drop<T: TryFrom<Data>>(&mut self, addr: &Identifier) -> anyhow::Result<T>;
-    fn init_key(&mut self, key: &[u8]) -> anyhow::Result<()>;
-    fn create_cipher(&mut self, secret_key: [u8; 32], kind: CipherKind);
+    fn clear_heap(&mut self);
+    fn load_variable(&self, name: &Identifier) -> anyhow::Result<Option<Variable>>;
+    fn store_variable(&mut self, name: Identifier, value: Variable) -> anyhow::Result<()>;
+    fn public_key(&mut self) -> anyhow::Result<X25519PubKey>;
+    fn agree_key(&mut self, peer: &X25519PubKey) -> anyhow::Result<()>;
+    fn salt(&mut self, nbytes: usize) -> anyhow::Result<Vec<u8>>;
+    fn save_salt(&mut self, salt: &[u8]) -> anyhow::Result<()>;
+    fn checksum_key(&self) -> anyhow::Result<[u8; 32]>;
+    fn create_cipher(
+        &mut self,
+        cipher: Cipher,
+        master_key: [u8; 32],
+        key_label: &[u8],
+        kind: CipherKind,
+    ) -> anyhow::Result<()>;
+    fn init_shaper(&mut self, timing: TimingSpec);
+    fn goto(&mut self, task_id: TaskID);
     fn encrypt(&mut self, plaintext: &[u8]) -> anyhow::Result<(Vec<u8>, [u8; 16])>;
     fn encrypt_unauth(&mut self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>>;
     fn decrypt(&mut self, ciphertext: &[u8], mac: &[u8; 16]) -> anyhow::Result<Vec<u8>>;
     fn decrypt_unauth(&mut self, ciphertext: &[u8]) -> anyhow::Result<Vec<u8>>;
     async fn recv(&mut self, len: Range<usize>) -> anyhow::Result<Bytes>;
+    async fn recv_or_idle(&mut self, len: Range<usize>) -> anyhow::Result<Option<Bytes>>;
+    async fn peek(&mut self, len: usize) -> anyhow::Result<Bytes>;
     async fn send(&mut self, bytes: Bytes) -> anyhow::Result<usize>;
     async fn flush(&mut self) -> anyhow::Result<()>;
+    fn hold_writes(&mut self);
+    async fn release_writes(&mut self) -> anyhow::Result<usize>;
 }
 
 trait Execute {
@@ -2,8 +2,14 @@
 
 use std::collections::hash_map::HashMap;
 use std::convert::{From, TryFrom};
+use std::fmt;
+use std::mem::discriminant;
+use std::ops::Range;
 use std::str::FromStr;
 
+use crate::crypto::aead::CipherAlgorithm;
+use crate::crypto::checksum;
+use crate::crypto::kdf::PasswordKdf;
 use crate::lang::Role;
 
 pub trait StaticallySized {
@@ -136,6 +142,7 @@ impl std::convert::From<BoolType> for bool {
     }
 }
 
+/// Integers are big-endian unless their type ends in `le`.
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum NumericType {
     U8,
@@ -146,19 +153,58 @@ pub enum NumericType {
     I16,
     I32,
     I64,
+    U16Le,
+    U32Le,
+    U64Le,
+    I16Le,
+    I32Le,
+    I64Le,
+}
+
+impl NumericType {
+    pub fn is_little_endian(&self) -> bool {
+        matches!(
+            self,
+            NumericType::U16Le
+                | NumericType::U32Le
+                | NumericType::U64Le
+                | NumericType::I16Le
+                | NumericType::I32Le
+                | NumericType::I64Le
+        )
+    }
+
+    pub fn is_unsigned(&self) -> bool {
+        matches!(
+            self,
+            NumericType::U8
+                | NumericType::U16
+                | NumericType::U32
+                | NumericType::U64
+                | NumericType::U16Le
+                | NumericType::U32Le
+                | NumericType::U64Le
+        )
+    }
 }
 
 impl NumericallyBounded for NumericType {
     fn bounds(&self) -> (i128, u128) {
         match self {
             NumericType::U8 => (u8::MIN.into(), u8::MAX.into()),
-            NumericType::U16 => (u16::MIN.into(), u16::MAX.into()),
-            NumericType::U32 => (u32::MIN.into(), u32::MAX.into()),
-            NumericType::U64 => (u64::MIN.into(), u64::MAX.into()),
+            NumericType::U16 | NumericType::U16Le => (u16::MIN.into(), u16::MAX.into()),
+            NumericType::U32 | NumericType::U32Le => (u32::MIN.into(), u32::MAX.into()),
+            NumericType::U64 | NumericType::U64Le => (u64::MIN.into(), u64::MAX.into()),
             NumericType::I8 => (i8::MIN.into(), i8::MAX.try_into().unwrap()),
-            NumericType::I16 => (i16::MIN.into(), i16::MAX.try_into().unwrap()),
-            NumericType::I32 => (i32::MIN.into(), i32::MAX.try_into().unwrap()),
-            NumericType::I64 => (i64::MIN.into(), i64::MAX.try_into().unwrap()),
+            NumericType::I16 | NumericType::I16Le => {
+                (i16::MIN.into(), i16::MAX.try_into().unwrap())
+            }
+            NumericType::I32 | NumericType::I32Le => {
+                (i32::MIN.into(), i32::MAX.try_into().unwrap())
+            }
+            NumericType::I64 | NumericType::I64Le => {
+                (i64::MIN.into(), i64::MAX.try_into().unwrap())
+            }
         }
     }
 }
@@ -167,13 +213,13 @@ impl StaticallySized for NumericType {
     fn size_of(&self) -> usize {
         match self {
             NumericType::U8 => std::mem::size_of::<u8>(),
-            NumericType::U16 => std::mem::size_of::<u16>(),
-            NumericType::U32 => std::mem::size_of::<u32>(),
-            NumericType::U64 => std::mem::size_of::<u64>(),
+            NumericType::U16 | NumericType::U16Le => std::mem::size_of::<u16>(),
+            NumericType::U32 | NumericType::U32Le => std::mem::size_of::<u32>(),
+            NumericType::U64 | NumericType::U64Le => std::mem::size_of::<u64>(),
             NumericType::I8 => std::mem::size_of::<i8>(),
-            NumericType::I16 => std::mem::size_of::<i16>(),
-            NumericType::I32 => std::mem::size_of::<i32>(),
-            NumericType::I64 => std::mem::size_of::<i64>(),
+            NumericType::I16 | NumericType::I16Le => std::mem::size_of::<i16>(),
+            NumericType::I32 | NumericType::I32Le => std::mem::size_of::<i32>(),
+            NumericType::I64 | NumericType::I64Le => std::mem::size_of::<i64>(),
         }
     }
 }
@@ -191,24 +237,283 @@ impl FromStr for NumericType {
             "i16" => Ok(NumericType::I16),
             "i32" => Ok(NumericType::I32),
             "i64" => Ok(NumericType::I64),
+            "u16le" => Ok(NumericType::U16Le),
+            "u32le" => Ok(NumericType::U32Le),
+            "u64le" => Ok(NumericType::U64Le),
+            "i16le" => Ok(NumericType::I16Le),
+            "i32le" => Ok(NumericType::I32Le),
+            "i64le" => Ok(NumericType::I64Le),
+            _ => Err(ParseError {}),
+        }
+    }
+}
+
+impl fmt::Display for NumericType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            NumericType::U8 => "u8",
+            NumericType::U16 => "u16",
+            NumericType::U32 => "u32",
+            NumericType::U64 => "u64",
+            NumericType::I8 => "i8",
+            NumericType::I16 => "i16",
+            NumericType::I32 => "i32",
+            NumericType::I64 => "i64",
+            NumericType::U16Le => "u16le",
+            NumericType::U32Le => "u32le",
+            NumericType::U64Le => "u64le",
+            NumericType::I16Le => "i16le",
+            NumericType::I32Le => "i32le",
+            NumericType::I64Le => "i64le",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// An unsigned integer whose width depends on its value.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VarIntType {
+    /// The QUIC encoding (RFC 9000, section 16), where the two high bits of
+    /// the first byte give the width: 1, 2, 4, or 8 bytes.
+    Quic,
+    /// Unsigned LEB128, with 7 bits per byte, least significant first, and
+    /// the high bit set on every byte but the last.
+    Leb128,
+}
+
+impl VarIntType {
+    pub fn max_nbytes(&self) -> usize {
+        match self {
+            VarIntType::Quic => 8,
+            VarIntType::Leb128 => 10,
+        }
+    }
+
+    /// Encodes the value in as few bytes as we can, or returns None if it is
+    /// out of bounds.
+    pub fn encode(&self, value: u128) -> Option<Vec<u8>> {
+        if value > self.bounds().1 {
+            return None;
+        }
+        match self {
+            VarIntType::Quic => {
+                let (nbytes, prefix) = match value {
+                    0..=0x3f => (1, 0x00),
+                    0x40..=0x3fff => (2, 0x40),
+                    0x4000..=0x3fff_ffff => (4, 0x80),
+                    _ => (8, 0xc0),
+                };
+                let mut bytes = (value as u64).to_be_bytes()[8 - nbytes..].to_vec();
+                bytes[0] |= prefix;
+                Some(bytes)
+            }
+            VarIntType::Leb128 => {
+                let mut bytes = vec![];
+                let mut rest = value;
+                loop {
+                    let byte = (rest & 0x7f) as u8;
+                    rest >>= 7;
+                    if rest == 0 {
+                        bytes.push(byte);
+                        return Some(bytes);
+                    }
+                    bytes.push(byte | 0x80);
+                }
+            }
+        }
+    }
+
+    /// Decodes a value that takes exactly all of the bytes, or returns None if
+    /// the bytes are not one valid encoding.
+    pub fn decode(&self, bytes: &[u8]) -> Option<u128> {
+        if bytes.is_empty() || self.remaining_nbytes(bytes) != Some(0) {
+            return None;
+        }
+        match self {
+            VarIntType::Quic => Some(
+                bytes[1..]
+                    .iter()
+                    .fold((bytes[0] & 0x3f) as u128, |value, byte| {
+                        (value << 8) | *byte as u128
+                    }),
+            ),
+            VarIntType::Leb128 => {
+                let value = bytes
+                    .iter()
+                    .rev()
+                    .fold(0u128, |value, byte| (value << 7) | (byte & 0x7f) as u128);
+                (value <= self.bounds().1).then_some(value)
+            }
+        }
+    }
+
+    /// How many more bytes we need to read after the first bytes of a value,
+    /// or None if they are not the start of a valid encoding.
+    pub fn remaining_nbytes(&self, prefix: &[u8]) -> Option<usize> {
+        match self {
+            VarIntType::Quic => {
+                let nbytes = 1usize << (prefix.first()? >> 6);
+                nbytes.checked_sub(prefix.len())
+            }
+            VarIntType::Leb128 => match prefix.iter().position(|byte| byte & 0x80 == 0) {
+                Some(last) if last + 1 == prefix.len() => Some(0),
+                Some(_) => None,
+                None if prefix.len() < self.max_nbytes() => Some(1),
+                None => None,
+            },
+        }
+    }
+}
+
+impl NumericallyBounded for VarIntType {
+    fn bounds(&self) -> (i128, u128) {
+        match self {
+            VarIntType::Quic => (0, (1 << 62) - 1),
+            VarIntType::Leb128 => (0, u64::MAX.into()),
+        }
+    }
+}
+
+impl FromStr for VarIntType {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        match s {
+            "quic_varint" => Ok(VarIntType::Quic),
+            "leb128" => Ok(VarIntType::Leb128),
             _ => Err(ParseError {}),
         }
     }
 }
 
+impl fmt::Display for VarIntType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VarIntType::Quic => write!(f, "quic_varint"),
+            VarIntType::Leb128 => write!(f, "leb128"),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum PrimitiveType {
     Numeric(NumericType),
+    /// A varint and, once we know its value, its width in bytes.
+    VarInt(VarIntType, Option<usize>),
+    /// An unsigned integer of 1 to 7 bits. Consecutive bitfields share bytes,
+    /// the first taking the most significant bits.
+    Bits(u8),
     Bool,
     Char,
 }
 
-impl StaticallySized for PrimitiveType {
-    fn size_of(&self) -> usize {
+impl MaybeSized for PrimitiveType {
+    fn maybe_size_of(&self) -> Option<usize> {
+        match self {
+            PrimitiveType::Numeric(t) => Some(t.size_of()),
+            PrimitiveType::VarInt(_, nbytes) => *nbytes,
+            // The byte the bitfield is in, which it may share with others.
+            PrimitiveType::Bits(_) => Some(1),
+            PrimitiveType::Bool => Some(std::mem::size_of::<bool>()),
+            // On the wire, a char is a single ASCII byte.
+            PrimitiveType::Char => Some(std::mem::size_of::<u8>()),
+        }
+    }
+}
+
+impl PrimitiveType {
+    /// Whether a field of this type can hold values of the kind of `value`,
+    /// whether or not it can hold `value` itself.
+    pub fn accepts(&self, value: PrimitiveValue) -> bool {
+        matches!(
+            (self, value),
+            (
+                PrimitiveType::Numeric(_) | PrimitiveType::VarInt(..) | PrimitiveType::Bits(_),
+                PrimitiveValue::Int(_)
+            ) | (PrimitiveType::Bool, PrimitiveValue::Bool(_))
+                | (PrimitiveType::Char, PrimitiveValue::Char(_))
+        )
+    }
+
+    /// Encodes a single value of this type, or returns None if the type does
+    /// not hold the value.
+    pub fn encode_value(&self, value: PrimitiveValue) -> Option<Vec<u8>> {
+        match (self, value) {
+            (PrimitiveType::Numeric(t), PrimitiveValue::Int(n)) => {
+                let (min, max) = t.bounds();
+                if n < min || (n >= 0 && n as u128 > max) {
+                    return None;
+                }
+                // Two's complement, truncated to the width of the type.
+                let mut bytes = n.to_be_bytes()[16 - t.size_of()..].to_vec();
+                if t.is_little_endian() {
+                    bytes.reverse();
+                }
+                Some(bytes)
+            }
+            (PrimitiveType::VarInt(t, _), PrimitiveValue::Int(n)) => {
+                t.encode(u128::try_from(n).ok()?)
+            }
+            // Right-aligned in a byte.
+            (PrimitiveType::Bits(_), PrimitiveValue::Int(n)) => {
+                let n = u128::try_from(n).ok().filter(|n| *n <= self.bounds().1)?;
+                Some(vec![n as u8])
+            }
+            (PrimitiveType::Bool, PrimitiveValue::Bool(b)) => Some(vec![b as u8]),
+            (PrimitiveType::Char, PrimitiveValue::Char(c)) if c.is_ascii() => Some(vec![c as u8]),
+            _ => None,
+        }
+    }
+
+    /// Decodes a single value of this type, or returns None if the bytes are
+    /// not a valid value.
+    pub fn decode_value(&self, bytes: &[u8]) -> Option<PrimitiveValue> {
+        match self {
+            PrimitiveType::Numeric(t) => {
+                if bytes.len() != t.size_of() {
+                    return None;
+                }
+                let mut bytes = bytes.to_vec();
+                if t.is_little_endian() {
+                    bytes.reverse();
+                }
+                // Sign-extend signed values.
+                let fill = match t.is_unsigned() || bytes[0] & 0x80 == 0 {
+                    true => 0x00,
+                    false => 0xff,
+                };
+                let mut buf = [fill; 16];
+                buf[16 - bytes.len()..].copy_from_slice(&bytes);
+                Some(PrimitiveValue::Int(i128::from_be_bytes(buf)))
+            }
+            PrimitiveType::VarInt(t, _) => i128::try_from(t.decode(bytes)?)
+                .ok()
+                .map(PrimitiveValue::Int),
+            PrimitiveType::Bits(_) => match bytes {
+                [n] if *n as u128 <= self.bounds().1 => Some(PrimitiveValue::Int(*n as i128)),
+                _ => None,
+            },
+            PrimitiveType::Bool => match bytes {
+                [0] => Some(PrimitiveValue::Bool(false)),
+                [1] => Some(PrimitiveValue::Bool(true)),
+                _ => None,
+            },
+            PrimitiveType::Char => match bytes {
+                [c] if c.is_ascii() => Some(PrimitiveValue::Char(*c as char)),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl NumericallyBounded for PrimitiveType {
+    fn bounds(&self) -> (i128, u128) {
         match self {
-            PrimitiveType::Numeric(t) => t.size_of(),
-            PrimitiveType::Bool => std::mem::size_of::<bool>(),
-            PrimitiveType::Char => std::mem::size_of::<char>(),
+            PrimitiveType::Numeric(t) => t.bounds(),
+            PrimitiveType::VarInt(t, _) => t.bounds(),
+            PrimitiveType::Bits(n) => (0, (1 << n) - 1),
+            PrimitiveType::Bool => (0, 1),
+            PrimitiveType::Char => (0, char::MAX.into()),
         }
     }
 }
@@ -220,9 +525,12 @@ impl FromStr for PrimitiveType {
         match s {
             "bool" => Ok(PrimitiveType::Bool),
             "char" => Ok(PrimitiveType::Char),
-            _ => match s.parse::<NumericType>() {
-                Ok(n) => Ok(n.into()),
-                Err(e) => Err(e),
+            _ => match s.parse::<VarIntType>() {
+                Ok(t) => Ok(PrimitiveType::VarInt(t, None)),
+                Err(_) => match s.strip_prefix('u').and_then(|n| n.parse::<u8>().ok()) {
+                    Some(n @ 1..=7) => Ok(PrimitiveType::Bits(n)),
+                    _ => s.parse::<NumericType>().map(Into::into),
+                },
             },
         }
     }
@@ -234,6 +542,36 @@ impl From<NumericType> for PrimitiveType {
     }
 }
 
+impl fmt::Display for PrimitiveType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PrimitiveType::Numeric(t) => write!(f, "{t}"),
+            PrimitiveType::VarInt(t, _) => write!(f, "{t}"),
+            PrimitiveType::Bits(n) => write!(f, "u{n}"),
+            PrimitiveType::Bool => write!(f, "bool"),
+            PrimitiveType::Char => write!(f, "char"),
+        }
+    }
+}
+
+/// The value of a single bool, char, or integer field.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PrimitiveValue {
+    Int(i128),
+    Bool(bool),
+    Char(char),
+}
+
+impl fmt::Display for PrimitiveValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PrimitiveValue::Int(n) => write!(f, "{n}"),
+            PrimitiveValue::Bool(b) => write!(f, "{b}"),
+            PrimitiveValue::Char(c) => write!(f, "{c:?}"),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum CompoundType {
     // Non-primitive types needed for the interpreter
@@ -273,6 +611,189 @@ pub enum UnaryOp {
     SizeOf(Identifier),
 }
 
+/// An arithmetic operator in a length expression.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl ArithOp {
+    /// Applies the operator, or returns None on overflow or division by zero.
+    /// Division rounds down.
+    pub fn apply(self, lhs: i128, rhs: i128) -> Option<i128> {
+        match self {
+            ArithOp::Add => lhs.checked_add(rhs),
+            ArithOp::Sub => lhs.checked_sub(rhs),
+            ArithOp::Mul => lhs.checked_mul(rhs),
+            ArithOp::Div => lhs.checked_div_euclid(rhs),
+        }
+    }
+}
+
+impl fmt::Display for ArithOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArithOp::Add => write!(f, "+"),
+            ArithOp::Sub => write!(f, "-"),
+            ArithOp::Mul => write!(f, "*"),
+            ArithOp::Div => write!(f, "/"),
+        }
+    }
+}
+
+/// A comparison of the value of a field in a guard.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    pub fn apply(self, lhs: i128, rhs: i128) -> bool {
+        match self {
+            CompareOp::Eq => lhs == rhs,
+            CompareOp::Ne => lhs != rhs,
+            CompareOp::Lt => lhs < rhs,
+            CompareOp::Le => lhs <= rhs,
+            CompareOp::Gt => lhs > rhs,
+            CompareOp::Ge => lhs >= rhs,
+        }
+    }
+}
+
+impl fmt::Display for CompareOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompareOp::Eq => write!(f, "=="),
+            CompareOp::Ne => write!(f, "!="),
+            CompareOp::Lt => write!(f, "<"),
+            CompareOp::Le => write!(f, "<="),
+            CompareOp::Gt => write!(f, ">"),
+            CompareOp::Ge => write!(f, ">="),
+        }
+    }
+}
+
+/// An arithmetic expression over the sizes of fields in bytes, which gives the
+/// value of a LENGTH field.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LengthExpr {
+    Literal(u64),
+    SizeOf(Identifier),
+    /// The total size of the fields from the first to the last, inclusive.
+    SizeOfRange(Identifier, Identifier),
+    Binary(Box<LengthExpr>, ArithOp, Box<LengthExpr>),
+}
+
+impl LengthExpr {
+    pub fn binary(lhs: LengthExpr, op: ArithOp, rhs: LengthExpr) -> Self {
+        LengthExpr::Binary(Box::new(lhs), op, Box::new(rhs))
+    }
+
+    /// The fields from `first` to `last`, if the format has both in that order.
+    pub fn range<'a>(
+        format: &'a Format,
+        first: &Identifier,
+        last: &Identifier,
+    ) -> Option<&'a [Field]> {
+        let start = format.fields.iter().position(|f| f.name == *first)?;
+        let end = format.fields.iter().position(|f| f.name == *last)?;
+        format.fields.get(start..=end)
+    }
+
+    /// Replaces each range with the sum of the sizes of the fields in it, or
+    /// returns None if the format does not have a range. Bitfields that share
+    /// bytes count as the number of bytes they take.
+    pub fn expand_ranges(&self, format: &Format) -> Option<LengthExpr> {
+        match self {
+            LengthExpr::SizeOfRange(first, last) => Self::range(format, first, last)?
+                .chunk_by(|a, b| a.dtype.is_bitfield() && b.dtype.is_bitfield())
+                .map(|group| match group {
+                    [field] if !field.dtype.is_bitfield() => LengthExpr::SizeOf(field.name.clone()),
+                    _ => LengthExpr::Literal(bitfields_nbytes(group) as u64),
+                })
+                .reduce(|lhs, rhs| LengthExpr::binary(lhs, ArithOp::Add, rhs)),
+            LengthExpr::Binary(lhs, op, rhs) => Some(LengthExpr::binary(
+                lhs.expand_ranges(format)?,
+                *op,
+                rhs.expand_ranges(format)?,
+            )),
+            _ => Some(self.clone()),
+        }
+    }
+
+    /// Evaluates the expression with the size of each field, or returns None
+    /// if a size is unknown or the arithmetic overflows.
+    pub fn evaluate(
+        &self,
+        format: &Format,
+        nbytes: &impl Fn(&Field) -> Option<i128>,
+    ) -> Option<i128> {
+        match self {
+            LengthExpr::Literal(n) => Some(*n as i128),
+            LengthExpr::SizeOf(id) => nbytes(&format.try_get_field_by_name(id)?),
+            LengthExpr::SizeOfRange(first, last) => Self::range(format, first, last)?
+                .chunk_by(|a, b| a.dtype.is_bitfield() && b.dtype.is_bitfield())
+                .try_fold(0i128, |sum, group| match group {
+                    [field] if !field.dtype.is_bitfield() => sum.checked_add(nbytes(field)?),
+                    _ => sum.checked_add(bitfields_nbytes(group) as i128),
+                }),
+            LengthExpr::Binary(lhs, op, rhs) => {
+                op.apply(lhs.evaluate(format, nbytes)?, rhs.evaluate(format, nbytes)?)
+            }
+        }
+    }
+
+    /// The fields the expression refers to, which are the ends of ranges.
+    pub fn field_ids(&self) -> Vec<&Identifier> {
+        match self {
+            LengthExpr::Literal(_) => vec![],
+            LengthExpr::SizeOf(id) => vec![id],
+            LengthExpr::SizeOfRange(first, last) => vec![first, last],
+            LengthExpr::Binary(lhs, _, rhs) => {
+                let mut ids = lhs.field_ids();
+                ids.extend(rhs.field_ids());
+                ids
+            }
+        }
+    }
+}
+
+// The number of bytes a run of consecutive bitfields takes.
+fn bitfields_nbytes(fields: &[Field]) -> usize {
+    fields
+        .iter()
+        .filter_map(|field| field.dtype.maybe_size_of_bits())
+        .sum::<usize>()
+        .div_ceil(8)
+}
+
+impl fmt::Display for LengthExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LengthExpr::Literal(n) => write!(f, "{}", n),
+            LengthExpr::SizeOf(id) => write!(f, "{}.size_of", id.0),
+            LengthExpr::SizeOfRange(first, last) => write!(f, "({}..{}).size_of", first.0, last.0),
+            LengthExpr::Binary(lhs, op, rhs) => {
+                let operand = |f: &mut fmt::Formatter, e: &LengthExpr| match e {
+                    LengthExpr::Binary(..) => write!(f, "({})", e),
+                    _ => write!(f, "{}", e),
+                };
+                operand(f, lhs)?;
+                write!(f, " {} ", op)?;
+                operand(f, rhs)
+            }
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct PrimitiveArray(pub PrimitiveType, pub usize);
 
@@ -288,9 +809,20 @@ impl TryFrom<PrimitiveArray> for NumericType {
     }
 }
 
-impl StaticallySized for PrimitiveArray {
-    fn size_of(&self) -> usize {
-        self.0.size_of() * self.1
+impl PrimitiveArray {
+    /// The type of a single varint, whose width we only know once we know its
+    /// value.
+    pub fn varint_type(&self) -> Option<VarIntType> {
+        match self {
+            PrimitiveArray(PrimitiveType::VarInt(t, _), 1) => Some(*t),
+            _ => None,
+        }
+    }
+}
+
+impl MaybeSized for PrimitiveArray {
+    fn maybe_size_of(&self) -> Option<usize> {
+        Some(self.0.maybe_size_of()? * self.1)
     }
 }
 
@@ -344,6 +876,25 @@ impl TryFrom<Array> for DynamicArray {
     }
 }
 
+impl Array {
+    /// The size in bits, which is less than a byte for a bitfield.
+    pub fn maybe_size_of_bits(&self) -> Option<usize> {
+        match self {
+            Array::Primitive(PrimitiveArray(PrimitiveType::Bits(n), count)) => {
+                Some(*n as usize * count)
+            }
+            _ => Some(self.maybe_size_of()? * 8),
+        }
+    }
+
+    pub fn is_bitfield(&self) -> bool {
+        matches!(
+            self,
+            Array::Primitive(PrimitiveArray(PrimitiveType::Bits(_), _))
+        )
+    }
+}
+
 impl MaybeSized for Array {
     fn maybe_size_of(&self) -> Option<usize> {
         match *self {
@@ -365,6 +916,19 @@ impl From<DynamicArray> for Array {
     }
 }
 
+// Formats the type as it is written in a PSF.
+impl fmt::Display for Array {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Array::Primitive(PrimitiveArray(t, 1)) => write!(f, "{t}"),
+            Array::Primitive(PrimitiveArray(t, n)) => write!(f, "[{t}; {n}]"),
+            Array::Dynamic(DynamicArray(UnaryOp::SizeOf(id))) => {
+                write!(f, "[u8; {}.size_of]", id.0)
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Field {
     pub name: Identifier,
@@ -384,14 +948,46 @@ pub struct Format {
 }
 
 impl Format {
+    /// The type, offset, and size in bytes of the field. A bitfield has the
+    /// offset and size of the bytes it shares with its bit group.
     pub fn try_get_field_type_offset_and_size(
         &self,
         field_name: &Identifier,
+    ) -> Option<(Array, usize, usize)> {
+        let group = self.bit_group(field_name)?;
+        let (_, bit_offset, _) = self.try_get_field_type_bit_offset_and_size(&group[0].name)?;
+        let nbits = group
+            .iter()
+            .map(|field| field.dtype.maybe_size_of_bits().unwrap())
+            .sum::<usize>();
+        let dtype = self.try_get_field_by_name(field_name)?.dtype;
+
+        Some((dtype, bit_offset / 8, nbits.div_ceil(8)))
+    }
+
+    /// The bytes of the field, if all fields up to it have fixed sizes, so
+    /// that they are at the same place in every message.
+    pub fn try_get_fixed_byte_range(&self, field_name: &Identifier) -> Option<Range<usize>> {
+        let i = self.fields.iter().position(|f| &f.name == field_name)?;
+        if self.fields[..=i]
+            .iter()
+            .any(|f| f.dtype.maybe_size_of_bits().is_none())
+        {
+            return None;
+        }
+        let (_, offset, nbytes) = self.try_get_field_type_offset_and_size(field_name)?;
+        Some(offset..offset + nbytes)
+    }
+
+    /// The type, offset, and size in bits of the field.
+    pub fn try_get_field_type_bit_offset_and_size(
+        &self,
+        field_name: &Identifier,
     ) -> Option<(Array, usize, usize)> {
         let mut acc: usize = 0;
 
         for field in &self.fields {
-            let size = field.dtype.maybe_size_of().unwrap();
+            let size = field.dtype.maybe_size_of_bits().unwrap();
 
             if &field.name == field_name {
                 return Some((field.dtype.clone(), acc, size));
@@ -403,6 +999,33 @@ impl Format {
         None
     }
 
+    /// The run of consecutive bitfields the field is in, or just the field if
+    /// it is not a bitfield.
+    pub fn bit_group(&self, field_name: &Identifier) -> Option<&[Field]> {
+        let i = self.fields.iter().position(|f| &f.name == field_name)?;
+        if !self.fields[i].dtype.is_bitfield() {
+            return Some(&self.fields[i..=i]);
+        }
+
+        let start = self.fields[..i]
+            .iter()
+            .rposition(|f| !f.dtype.is_bitfield())
+            .map_or(0, |j| j + 1);
+        let end = self.fields[i..]
+            .iter()
+            .position(|f| !f.dtype.is_bitfield())
+            .map_or(self.fields.len(), |j| i + j);
+        Some(&self.fields[start..end])
+    }
+
+    /// The runs of consecutive bitfields in the format.
+    pub fn bit_groups(&self) -> Vec<&[Field]> {
+        self.fields
+            .chunk_by(|a, b| a.dtype.is_bitfield() && b.dtype.is_bitfield())
+            .filter(|group| group[0].dtype.is_bitfield())
+            .collect()
+    }
+
     pub fn try_get_field_by_name(&self, field_name: &Identifier) -> Option<Field> {
         self.fields
             .iter()
@@ -413,8 +1036,9 @@ impl Format {
     pub fn fixed_fields_size(&self) -> usize {
         self.fields
             .iter()
-            .map(|x| x.maybe_size_of().unwrap_or_default())
-            .sum()
+            .map(|x| x.dtype.maybe_size_of_bits().unwrap_or_default())
+            .sum::<usize>()
+            .div_ceil(8)
     }
 
     pub fn split_into_fixed_sized_prefix_dynamic_suffix(&self) -> (Format, Format) {
@@ -444,6 +1068,30 @@ impl Format {
         )
     }
 
+    /// Splits the format before each variable-length field, so that a
+    /// receiver knows the size of each segment once it read the ones before.
+    /// The first segment is the fixed-size prefix, which may be empty.
+    pub fn split_into_segments(&self) -> Vec<Format> {
+        let mut segments: Vec<Vec<Field>> = vec![vec![]];
+
+        for field in &self.fields[..] {
+            if field.maybe_size_of().is_none() {
+                segments.push(vec![]);
+            }
+            // Unwrap OK: there is always at least one segment.
+            segments.last_mut().unwrap().push(field.clone());
+        }
+
+        segments
+            .into_iter()
+            .enumerate()
+            .map(|(i, fields)| Format {
+                name: format!("{}_segment{}", self.name.0, i).parse().unwrap(),
+                fields,
+            })
+            .collect()
+    }
+
     pub fn split_into_dynamic_prefix_and_fixed_suffix(&self) -> (Format, Format) {
         let mut dynamic_fields = vec![];
         let mut fixed_sized_fields = vec![];
@@ -490,13 +1138,22 @@ impl AbstractFormat {
 
     /// Sizes is a vector of (id, size) pairs where the size is given in bytes.
     /// This function will calculate the number of elements required to equal that number of bytes.
+    /// The size of a varint is its width.
     pub fn concretize(mut self, sizes: &Vec<(Identifier, usize)>) -> ConcreteFormat {
         for (id, size) in sizes {
             for field in self.format.fields.iter_mut() {
                 if id == &field.name {
-                    if let Array::Dynamic(_) = &field.dtype {
-                        field.dtype =
-                            PrimitiveArray(PrimitiveType::Numeric(NumericType::U8), *size).into()
+                    match &field.dtype {
+                        Array::Dynamic(_) => {
+                            field.dtype =
+                                PrimitiveArray(PrimitiveType::Numeric(NumericType::U8), *size)
+                                    .into()
+                        }
+                        Array::Primitive(PrimitiveArray(PrimitiveType::VarInt(t, _), n)) => {
+                            field.dtype =
+                                PrimitiveArray(PrimitiveType::VarInt(*t, Some(*size)), *n).into()
+                        }
+                        Array::Primitive(_) => {}
                     }
                 }
             }
@@ -572,26 +1229,219 @@ impl TryFrom<Format> for ConcreteFormat {
 
 impl MaybeSized for Format {
     fn maybe_size_of(&self) -> Option<usize> {
-        self.fields.iter().try_fold(0usize, |acc, field| {
-            if let Some(x) = field.maybe_size_of() {
-                acc.checked_add(x)
-            } else {
-                None
+        self.fields
+            .iter()
+            .try_fold(0usize, |acc, field| {
+                if let Some(x) = field.dtype.maybe_size_of_bits() {
+                    acc.checked_add(x)
+                } else {
+                    None
+                }
+            })
+            .map(|nbits| nbits.div_ceil(8))
+    }
+}
+
+/// How many bytes of padding a sender adds to a message.
+#[derive(Clone, Debug, Default, PartialEq, PartialOrd, Eq, Ord)]
+pub enum PaddingSize {
+    /// Pads the payload to a multiple of the block size of the cipher.
+    #[default]
+    Block,
+    /// A uniformly random number of bytes, up to the given number.
+    Uniform(usize),
+    /// Pads the message to the smallest of the sizes it fits in, or to a
+    /// multiple of the largest size if it fits in none.
+    Buckets(Vec<usize>),
+    /// Pads the message to one of the sizes it fits in, each picked with a
+    /// chance proportional to its weight. A message that fits in none of the
+    /// sizes is not padded.
+    Distribution(Vec<(usize, u32)>),
+}
+
+/// The bytes a sender pads a message with.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd, Eq, Ord)]
+pub enum PaddingFill {
+    Constant(u8),
+    Random,
+}
+
+impl Default for PaddingFill {
+    fn default() -> Self {
+        PaddingFill::Constant(0xff)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, PartialOrd, Eq, Ord)]
+pub struct PaddingPolicy {
+    pub size: PaddingSize,
+    pub fill: PaddingFill,
+}
+
+impl PaddingPolicy {
+    /// The most bytes of padding we add, or None if we pad to the block size
+    /// and the cipher has none.
+    pub fn max_nbytes(&self, block_nbytes: Option<usize>) -> Option<usize> {
+        match &self.size {
+            PaddingSize::Block => block_nbytes,
+            PaddingSize::Uniform(n) => Some(*n),
+            PaddingSize::Buckets(sizes) => sizes.iter().max().copied(),
+            PaddingSize::Distribution(sizes) => sizes.iter().map(|(size, _)| *size).max(),
+        }
+    }
+
+    /// Picks the number of bytes of padding for a payload and a message of the
+    /// given sizes, where the message does not have the padding yet.
+    pub fn pick_nbytes(
+        &self,
+        payload_nbytes: usize,
+        message_nbytes: usize,
+        block_nbytes: Option<usize>,
+    ) -> Option<usize> {
+        use rand::Rng;
+        use rand::seq::SliceRandom;
+
+        match &self.size {
+            PaddingSize::Block => Some(crate::lang::padding_nbytes(payload_nbytes, block_nbytes?)),
+            PaddingSize::Uniform(n) => Some(rand::thread_rng().gen_range(0..=*n)),
+            PaddingSize::Buckets(sizes) => {
+                let target = match sizes.iter().filter(|&&size| size >= message_nbytes).min() {
+                    Some(size) => *size,
+                    None => {
+                        let largest = *sizes.iter().max().filter(|&&size| size > 0)?;
+                        message_nbytes.div_ceil(largest) * largest
+                    }
+                };
+                Some(target - message_nbytes)
+            }
+            PaddingSize::Distribution(sizes) => {
+                let fitting: Vec<_> = sizes
+                    .iter()
+                    .filter(|(size, _)| *size >= message_nbytes)
+                    .collect();
+                match fitting.choose_weighted(&mut rand::thread_rng(), |(_, weight)| *weight) {
+                    Ok((size, _)) => Some(size - message_nbytes),
+                    Err(_) => Some(0),
+                }
+            }
+        }
+    }
+
+    /// The given number of bytes of padding.
+    pub fn fill(&self, nbytes: usize) -> Vec<u8> {
+        match self.fill {
+            PaddingFill::Constant(byte) => vec![byte; nbytes],
+            PaddingFill::Random => {
+                use rand_core::{OsRng, RngCore};
+                let mut bytes = vec![0; nbytes];
+                OsRng.fill_bytes(&mut bytes);
+                bytes
             }
-        })
+        }
     }
 }
 
 #[derive(Clone, Debug, PartialEq, PartialOrd, Eq, Ord)]
 pub enum FieldSemantic {
     Payload,
-    Padding,
+    Padding(PaddingPolicy),
     PaddingLength,
-    Length,
+    /// The value is the given expression, or by default the number of bytes
+    /// after the segment the field is in. An expression that divides the size
+    /// of the payload, such as a count of words, has the sender pad the
+    /// dividend to a multiple of the divisor.
+    Length(Option<LengthExpr>),
     FixedString(String),
     FixedBytes(Vec<u8>),
+    /// A value encoded with the type of its field.
+    FixedValue(PrimitiveValue),
+    /// One of the values, each picked with a chance proportional to its
+    /// weight.
+    OneOf(Vec<(PrimitiveValue, u32)>),
+    /// An integer from the first to the last, inclusive.
+    Range(i128, i128),
     Random(usize),
     Pubkey(PubkeyEncoding),
+    /// Random bytes that the sender picks once per connection and mixes into
+    /// the keys it encrypts with, so that no two connections use the same keys
+    /// and nonces. Only the first message a role sends carries its salt.
+    Salt,
+    /// The value is 1 for a cover message, which has an empty payload that
+    /// the receiver discards, and 0 otherwise.
+    Cover,
+    /// An integer that tells the DATA formats of a role apart, so that the
+    /// receiver knows which one it reads.
+    TypeTag(i128),
+    /// An integer that is the first number in the first message of the format
+    /// that a role sends, and grows by the second number in each one after.
+    Counter(u128, u128),
+    /// Random bytes that we keep under the name for the rest of the
+    /// connection, whichever role sends them.
+    SaveAs(Identifier),
+    /// The bytes we keep under the name.
+    Echo(Identifier),
+    /// The time when we build the message, in the unit. A receiver given a
+    /// number of seconds rejects times further than that from its own clock.
+    Timestamp(TimestampUnit, Option<u64>),
+    /// The checksum of the bytes from the first field through the second,
+    /// with the bytes of this field taken as zeros, and truncated to the width
+    /// of this field.
+    Checksum(ChecksumAlgorithm, Identifier, Identifier),
+}
+
+impl FieldSemantic {
+    /// The number of bytes of the value this semantic sets its field to, if
+    /// the semantic sets a value.
+    pub fn value_nbytes(&self) -> Option<usize> {
+        match self {
+            FieldSemantic::FixedString(s) => Some(s.chars().count()),
+            FieldSemantic::FixedBytes(b) => Some(b.len()),
+            FieldSemantic::Random(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Picks a value for a field with this semantic, if the semantic sets it
+    /// to a single value of its type.
+    pub fn pick_value(&self) -> Option<PrimitiveValue> {
+        use rand::Rng;
+        use rand::seq::SliceRandom;
+
+        match self {
+            FieldSemantic::FixedValue(value) => Some(*value),
+            FieldSemantic::TypeTag(tag) => Some(PrimitiveValue::Int(*tag)),
+            FieldSemantic::OneOf(choices) => choices
+                .choose_weighted(&mut rand::thread_rng(), |(_, weight)| *weight)
+                .ok()
+                .map(|(value, _)| *value),
+            FieldSemantic::Range(lo, hi) if lo <= hi => {
+                Some(PrimitiveValue::Int(rand::thread_rng().gen_range(*lo..=*hi)))
+            }
+            FieldSemantic::Timestamp(unit, _) => Some(PrimitiveValue::Int(unit.now())),
+            _ => None,
+        }
+    }
+
+    /// Whether a receiver accepts the value in a field with this semantic.
+    /// Semantics that do not constrain the value accept any value.
+    pub fn allows(&self, value: PrimitiveValue) -> bool {
+        match self {
+            FieldSemantic::FixedValue(fixed) => *fixed == value,
+            FieldSemantic::OneOf(choices) => choices.iter().any(|(choice, _)| *choice == value),
+            FieldSemantic::Range(lo, hi) => {
+                matches!(value, PrimitiveValue::Int(n) if (*lo..=*hi).contains(&n))
+            }
+            FieldSemantic::Cover => matches!(value, PrimitiveValue::Int(0 | 1)),
+            FieldSemantic::TypeTag(tag) => value == PrimitiveValue::Int(*tag),
+            FieldSemantic::Timestamp(unit, max_skew_secs) => match value {
+                PrimitiveValue::Int(time) => max_skew_secs.is_none_or(|secs| {
+                    time.abs_diff(unit.now()) <= secs as u128 * unit.per_second()
+                }),
+                _ => false,
+            },
+            _ => true,
+        }
+    }
 }
 
 impl TryFrom<FieldSemantic> for String {
@@ -612,9 +1462,10 @@ impl FromStr for FieldSemantic {
     fn from_str(s: &str) -> Result<Self, ParseError> {
         match s {
             "PAYLOAD" => Ok(FieldSemantic::Payload),
-            "PADDING" => Ok(FieldSemantic::Padding),
             "PADDING_LENGTH" => Ok(FieldSemantic::PaddingLength),
-            "LENGTH" => Ok(FieldSemantic::Length),
+            "COVER" => Ok(FieldSemantic::Cover),
+            "SALT" => Ok(FieldSemantic::Salt),
+            "LENGTH" => Ok(FieldSemantic::Length(None)),
             _ => Err(ParseError {}),
         }
     }
@@ -675,61 +1526,52 @@ impl Semantics {
         &self.semantics
     }
 
+    /// Finds a field bound to the same kind of semantic, whatever its
+    /// arguments.
     pub fn find_field_id(&self, semantic: FieldSemantic) -> Option<Identifier> {
         self.semantics
             .iter()
-            .find(|&e| *e.1 == semantic)
+            .find(|&e| discriminant(e.1) == discriminant(&semantic))
             .map(|e| e.0.clone())
     }
 
-    pub fn get_fixed_fields(&self) -> Vec<(Identifier, Vec<u8>)> {
-        let criterion = |e: (&Identifier, &FieldSemantic)| {
-            matches!(
-                e.1,
-                FieldSemantic::FixedString(_)
-                    | FieldSemantic::FixedBytes(_)
-                    | FieldSemantic::Random(_)
-                    | FieldSemantic::Pubkey(_)
-            )
-        };
-
+    /// The values of the fields whose semantics fix them, encoded with the
+    /// types of the fields in the format. We pick a new value for each call
+    /// for fields whose semantics allow several.
+    pub fn get_fixed_fields(&self, format: &Format) -> Vec<(Identifier, Vec<u8>)> {
         let extract = |e: (&Identifier, &FieldSemantic)| {
-            match e.1 {
-                FieldSemantic::FixedString(s) => (
-                    e.0.clone(),
-                    String::from(s).as_str().chars().map(|e| e as u8).collect(),
-                ),
-                FieldSemantic::FixedBytes(b) => (e.0.clone(), b.clone()),
-                FieldSemantic::Random(n) => {
-                    use rand_core::{OsRng, RngCore};
-                    let mut bytes = vec![0; *n];
-                    OsRng.fill_bytes(&mut bytes);
-                    (e.0.clone(), bytes)
+            let bytes = match e.1 {
+                FieldSemantic::FixedString(s) => {
+                    String::from(s).as_str().chars().map(|e| e as u8).collect()
                 }
-                FieldSemantic::Pubkey(encoding) => {
-                    let key = crate::crypto::pubkey::X25519PubKey::new();
-
-                    let bytes = match encoding {
-                        PubkeyEncoding::Raw => {
-                            <crate::crypto::pubkey::X25519PubKey as Into<[u8; 32]>>::into(key)
-                                .to_vec()
-                        }
-                        PubkeyEncoding::Der => key.into_der(),
-                        PubkeyEncoding::Pem => key.into_pem(),
+                FieldSemantic::FixedBytes(b) => b.clone(),
+                FieldSemantic::FixedValue(_)
+                | FieldSemantic::OneOf(_)
+                | FieldSemantic::Range(..)
+                | FieldSemantic::TypeTag(_)
+                | FieldSemantic::Timestamp(..) => {
+                    let field = format.try_get_field_by_name(e.0)?;
+                    PrimitiveArray::try_from(field.dtype)
+                        .ok()?
+                        .0
+                        .encode_value(e.1.pick_value()?)?
+                }
+                FieldSemantic::Random(_) | FieldSemantic::SaveAs(_) => {
+                    use rand_core::{OsRng, RngCore};
+                    let nbytes = match e.1 {
+                        FieldSemantic::Random(n) => *n,
+                        _ => format.try_get_field_by_name(e.0)?.maybe_size_of()?,
                     };
-
-                    (e.0.clone(), bytes)
+                    let mut bytes = vec![0; nbytes];
+                    OsRng.fill_bytes(&mut bytes);
+                    bytes
                 }
-                _ => unimplemented!(), // FieldSemantic::FixedBytes(_) => true,
-                                       //_ => false,
-            }
+                _ => return None,
+            };
+            Some((e.0.clone(), bytes))
         };
 
-        self.semantics
-            .iter()
-            .filter(|&e| criterion(e))
-            .map(extract)
-            .collect()
+        self.semantics.iter().filter_map(extract).collect()
     }
 }
 
@@ -754,11 +1596,55 @@ impl From<AbstractFormat> for AbstractFormatAndSemantics {
     }
 }
 
+/// When a sender picks a DATA format over the other DATA formats of its role.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SendCondition {
+    /// A cover message, which has no payload.
+    Cover,
+    /// A payload whose size is in the range.
+    PayloadNbytes(Range<usize>),
+}
+
+impl SendCondition {
+    pub fn matches(&self, payload_nbytes: usize, is_cover: bool) -> bool {
+        match self {
+            SendCondition::Cover => is_cover,
+            SendCondition::PayloadNbytes(range) => !is_cover && range.contains(&payload_nbytes),
+        }
+    }
+}
+
+/// The states of a sequence that a message leads from and to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transition {
+    pub from: Identifier,
+    pub to: Identifier,
+}
+
+/// A comparison of the value of a field with a number, which must hold for a
+/// message to take its transition.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Guard {
+    pub field: Identifier,
+    pub op: CompareOp,
+    pub value: i128,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct SequenceSpecifier {
     pub role: Role,
+    /// A transition that leads back to its state is in the DATA phase.
     pub phase: Phase,
     pub format: Identifier,
+    /// The sender sends the first DATA format of its role whose condition
+    /// holds, where a format without one always qualifies.
+    pub condition: Option<SendCondition>,
+    /// None for a sequence of steps, in which each HANDSHAKE message leads to
+    /// the next step and DATA messages repeat.
+    pub transition: Option<Transition>,
+    /// A message takes the first of the transitions for its format whose
+    /// guard holds, where a transition without one always qualifies.
+    pub guard: Option<Guard>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -772,32 +1658,54 @@ impl FromStr for Password {
     }
 }
 
+/// A line and column in the PSF source, both starting at 1.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// Where the parts of a PSF are defined in its source, so that we can point
+/// at them when the PSF is invalid.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceMap {
+    pub formats: HashMap<Identifier, Span>,
+    // Fields and semantics are keyed by format and field name.
+    pub fields: HashMap<(Identifier, Identifier), Span>,
+    pub semantics: HashMap<(Identifier, Identifier), Span>,
+    // In the same order as the sequence.
+    pub sequence: Vec<Span>,
+    pub crypto: Option<Span>,
+    pub separate_length_field: Option<Span>,
+    pub split_write_after: Option<Span>,
+    pub coalesce_flights: Option<Span>,
+    pub message_delay: Option<Span>,
+    pub burst_size: Option<Span>,
+    pub burst_delay: Option<Span>,
+    pub cover_delay: Option<Span>,
+    // Encryption directives are keyed by the name of the encrypted format, and
+    // field directives additionally by their position in the directive.
+    pub encryption_directives: HashMap<Identifier, Span>,
+    pub encryption_field_directives: HashMap<(Identifier, usize), Span>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Psf {
     pub formats: HashMap<Identifier, AbstractFormatAndSemantics>,
     pub sequence: Vec<SequenceSpecifier>,
     pub crypto_spec: Option<CryptoSpec>,
     pub options: Option<Options>,
+    pub timing_spec: Option<TimingSpec>,
+    pub source_map: SourceMap,
 }
 
-impl Psf {
-    fn validate_seqs(&self) -> bool {
-        for s in &self.sequence[..] {
-            if !self.formats.contains_key(&s.format) {
-                return false;
-            }
-        }
-
-        true
-    }
-
-    /// Run checks to ensure that the PSF is semantically valid
-    pub fn is_valid(&self) -> bool {
-        self.validate_seqs()
-    }
-}
-
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Cipher {
     ChaCha20Poly1305,
     Aes128Gcm,
@@ -817,6 +1725,16 @@ impl FromStr for Cipher {
     }
 }
 
+impl From<Cipher> for CipherAlgorithm {
+    fn from(value: Cipher) -> Self {
+        match value {
+            Cipher::ChaCha20Poly1305 => CipherAlgorithm::ChaCha20Poly1305,
+            Cipher::Aes128Gcm => CipherAlgorithm::Aes128Gcm,
+            Cipher::Aes256Gcm => CipherAlgorithm::Aes256Gcm,
+        }
+    }
+}
+
 impl Cipher {
     pub fn block_size_nbytes(&self) -> Option<u8> {
         match self {
@@ -864,9 +1782,49 @@ pub struct EncryptionDirectives {
     pub enc_field_dirs: Vec<EncryptionFieldDirective>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kdf {
+    Argon2id {
+        m_cost: Option<u32>,
+        t_cost: Option<u32>,
+        p_cost: Option<u32>,
+    },
+    Hkdf,
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Kdf::Argon2id {
+            m_cost: None,
+            t_cost: None,
+            p_cost: None,
+        }
+    }
+}
+
+impl From<Kdf> for PasswordKdf {
+    fn from(value: Kdf) -> Self {
+        match value {
+            Kdf::Argon2id {
+                m_cost,
+                t_cost,
+                p_cost,
+            } => PasswordKdf::Argon2id {
+                m_cost: m_cost.unwrap_or(PasswordKdf::DEFAULT_M_COST),
+                t_cost: t_cost.unwrap_or(PasswordKdf::DEFAULT_T_COST),
+                p_cost: p_cost.unwrap_or(PasswordKdf::DEFAULT_P_COST),
+            },
+            Kdf::Hkdf => PasswordKdf::Hkdf,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct CryptoSpec {
     pub password: Option<Password>,
+    pub kdf: Kdf,
+    pub salt: Option<Vec<u8>>,
+    pub key_label: Option<String>,
     pub cipher: Cipher,
     pub directives: HashMap<EncryptionFormatBinding, EncryptionDirectives>,
 }
@@ -874,17 +1832,138 @@ pub struct CryptoSpec {
 impl CryptoSpec {
     pub fn new<'a, T: Iterator<Item = &'a EncryptionDirectives>>(
         password: Option<Password>,
+        kdf: Kdf,
+        salt: Option<Vec<u8>>,
+        key_label: Option<String>,
         cipher: Cipher,
         itr: T,
     ) -> CryptoSpec {
         CryptoSpec {
             password,
+            kdf,
+            salt,
+            key_label,
             cipher,
             directives: HashMap::from_iter(itr.map(|e| (e.enc_fmt_bnd.clone(), e.clone()))),
         }
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd, Eq, Ord)]
+pub enum ChecksumAlgorithm {
+    Crc32,
+    Adler32,
+    /// The ones'-complement checksum of IP.
+    Internet,
+    Sha256,
+    /// HMAC-SHA256 with a key derived from the password.
+    HmacSha256,
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        match s {
+            "CRC32" => Ok(ChecksumAlgorithm::Crc32),
+            "ADLER32" => Ok(ChecksumAlgorithm::Adler32),
+            "INTERNET" => Ok(ChecksumAlgorithm::Internet),
+            "SHA256" => Ok(ChecksumAlgorithm::Sha256),
+            "HMAC_SHA256" => Ok(ChecksumAlgorithm::HmacSha256),
+            _ => Err(ParseError {}),
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ChecksumAlgorithm::Crc32 => "CRC32",
+            ChecksumAlgorithm::Adler32 => "ADLER32",
+            ChecksumAlgorithm::Internet => "INTERNET",
+            ChecksumAlgorithm::Sha256 => "SHA256",
+            ChecksumAlgorithm::HmacSha256 => "HMAC_SHA256",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl ChecksumAlgorithm {
+    pub fn digest_nbytes(self) -> usize {
+        match self {
+            ChecksumAlgorithm::Crc32 | ChecksumAlgorithm::Adler32 => 4,
+            ChecksumAlgorithm::Internet => 2,
+            ChecksumAlgorithm::Sha256 | ChecksumAlgorithm::HmacSha256 => 32,
+        }
+    }
+
+    pub fn needs_key(self) -> bool {
+        self == ChecksumAlgorithm::HmacSha256
+    }
+
+    /// The checksum of the bytes, with integers in network byte order. Only
+    /// keyed checksums use the key.
+    pub fn digest(self, bytes: &[u8], key: &[u8]) -> Vec<u8> {
+        match self {
+            ChecksumAlgorithm::Crc32 => checksum::crc32(bytes).to_be_bytes().to_vec(),
+            ChecksumAlgorithm::Adler32 => checksum::adler32(bytes).to_be_bytes().to_vec(),
+            ChecksumAlgorithm::Internet => {
+                checksum::internet_checksum(bytes).to_be_bytes().to_vec()
+            }
+            ChecksumAlgorithm::Sha256 => checksum::sha256(bytes).to_vec(),
+            ChecksumAlgorithm::HmacSha256 => checksum::hmac_sha256(key, bytes).to_vec(),
+        }
+    }
+}
+
+/// The unit and width of a `TIMESTAMP` field, counted from the Unix epoch.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd, Eq, Ord)]
+pub enum TimestampUnit {
+    U32Seconds,
+    U64Millis,
+}
+
+impl FromStr for TimestampUnit {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        match s {
+            "u32_seconds" => Ok(TimestampUnit::U32Seconds),
+            "u64_millis" => Ok(TimestampUnit::U64Millis),
+            _ => Err(ParseError {}),
+        }
+    }
+}
+
+impl TimestampUnit {
+    /// The width of the unsigned integer that holds the time.
+    pub fn nbytes(self) -> usize {
+        match self {
+            TimestampUnit::U32Seconds => 4,
+            TimestampUnit::U64Millis => 8,
+        }
+    }
+
+    pub fn per_second(self) -> u128 {
+        match self {
+            TimestampUnit::U32Seconds => 1,
+            TimestampUnit::U64Millis => 1000,
+        }
+    }
+
+    /// The current time in this unit.
+    pub fn now(self) -> i128 {
+        // A clock before the epoch is broken, so we send the epoch.
+        let elapsed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        match self {
+            TimestampUnit::U32Seconds => elapsed.as_secs() as i128,
+            TimestampUnit::U64Millis => elapsed.as_millis() as i128,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd, Eq, Ord)]
 pub enum PubkeyEncoding {
     Raw,
@@ -917,17 +1996,67 @@ impl PubkeyEncoding {
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Options {
+    /// Write the message up to and including its LENGTH field separately from
+    /// the rest of the message.
     pub separate_length_field_setting: bool,
+    /// Write the message up to and including the named field separately from
+    /// the rest of the message. Overrides the separate length field setting
+    /// for formats that have the field.
+    pub split_write_after_setting: Option<Identifier>,
+    /// Write the messages of a flight, which one role sends before the other
+    /// responds, to the network at once rather than one by one.
+    pub coalesce_flights_setting: bool,
 }
 
 impl Options {
-    pub fn new(separate_length_field_setting: bool) -> Self {
+    pub fn new(
+        separate_length_field_setting: bool,
+        split_write_after_setting: Option<Identifier>,
+        coalesce_flights_setting: bool,
+    ) -> Self {
         Options {
             separate_length_field_setting,
+            split_write_after_setting,
+            coalesce_flights_setting,
         }
     }
 }
 
+/// A distribution of numbers, from which we pick a bin with a chance
+/// proportional to its weight, and then a number in the bin uniformly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    /// The lowest and highest number of each bin, and its weight.
+    pub bins: Vec<(u64, u64, u32)>,
+}
+
+impl Histogram {
+    /// Picks a number, or None if no bin has a weight.
+    pub fn sample(&self) -> Option<u64> {
+        use rand::Rng;
+        use rand::seq::SliceRandom;
+
+        let mut rng = rand::thread_rng();
+        let (low, high, _) = self.bins.choose_weighted(&mut rng, |bin| bin.2).ok()?;
+        Some(rng.gen_range(*low..=*high))
+    }
+}
+
+/// How a sender spaces out the messages it writes to the network and splits
+/// them into bursts.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TimingSpec {
+    /// Milliseconds to wait before writing each message.
+    pub message_delay: Option<Histogram>,
+    /// The most bytes to write at once, splitting larger messages.
+    pub burst_size: Option<Histogram>,
+    /// Milliseconds to wait between the bursts of a message.
+    pub burst_delay: Option<Histogram>,
+    /// Milliseconds to wait for the application to send data before we send
+    /// a cover message instead.
+    pub cover_delay: Option<Histogram>,
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
@@ -980,6 +2109,41 @@ pub mod tests {
         format.maybe_size_of().unwrap();
     }
 
+    #[test]
+    fn test_split_into_segments() {
+        let mut format = make_unsized_format().into_inner();
+        format.fields.push(Field {
+            name: "Baz".parse().unwrap(),
+            dtype: PrimitiveArray(NumericType::U16.into(), 1).into(),
+        });
+        format.fields.push(Field {
+            name: "Qux".parse().unwrap(),
+            dtype: DynamicArray(UnaryOp::SizeOf("Baz".parse().unwrap())).into(),
+        });
+
+        let names = |segment: &Format| {
+            segment
+                .fields
+                .iter()
+                .map(|f| f.name.0.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let segments = format.split_into_segments();
+        assert_eq!(
+            segments.iter().map(names).collect::<Vec<_>>(),
+            ["Foo", "Bar,Baz", "Qux"]
+        );
+        assert_eq!(segments[1].name, "Handshake_segment1".parse().unwrap());
+
+        format.fields.remove(0);
+        let segments = format.split_into_segments();
+        assert_eq!(
+            segments.iter().map(names).collect::<Vec<_>>(),
+            ["", "Bar,Baz", "Qux"]
+        );
+    }
+
     #[test]
     fn test_dynamic_arrays() {
         let format = make_unsized_format();
@@ -1002,6 +2166,197 @@ pub mod tests {
         assert_eq!(abs_fmt_conretized, con_fmt);
     }
 
+    #[test]
+    fn test_varint() {
+        // From the examples in RFC 9000, appendix A.1, and the LEB128 article
+        // on Wikipedia.
+        let test_cases: [(VarIntType, u128, &[u8]); 7] = [
+            (VarIntType::Quic, 37, &[0x25]),
+            (VarIntType::Quic, 15293, &[0x7b, 0xbd]),
+            (VarIntType::Quic, 494878333, &[0x9d, 0x7f, 0x3e, 0x7d]),
+            (
+                VarIntType::Quic,
+                151288809941952652,
+                &[0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c],
+            ),
+            (VarIntType::Leb128, 0, &[0x00]),
+            (VarIntType::Leb128, 127, &[0x7f]),
+            (VarIntType::Leb128, 624485, &[0xe5, 0x8e, 0x26]),
+        ];
+
+        for (t, value, bytes) in test_cases {
+            assert_eq!(t.encode(value).unwrap(), bytes);
+            assert_eq!(t.decode(bytes), Some(value));
+            assert_eq!(t.remaining_nbytes(bytes), Some(0));
+            if bytes.len() > 1 {
+                assert_eq!(t.remaining_nbytes(&bytes[..bytes.len() - 1]), Some(1));
+            }
+        }
+
+        // The first byte of a QUIC varint gives its width, while LEB128 tells
+        // us one byte at a time.
+        assert_eq!(VarIntType::Quic.remaining_nbytes(&[0x9d]), Some(3));
+        assert_eq!(VarIntType::Leb128.remaining_nbytes(&[0xe5]), Some(1));
+
+        assert_eq!(VarIntType::Quic.encode(1 << 62), None);
+        assert_eq!(VarIntType::Quic.decode(&[0x40]), None);
+        assert_eq!(VarIntType::Leb128.decode(&[0x80]), None);
+        assert_eq!(VarIntType::Leb128.remaining_nbytes(&[0x80; 10]), None);
+    }
+
+    #[test]
+    fn test_concretize_varint() {
+        let format: AbstractFormat = Format {
+            name: "Handshake".parse().unwrap(),
+            fields: vec![Field {
+                name: "Foo".parse().unwrap(),
+                dtype: PrimitiveArray(PrimitiveType::VarInt(VarIntType::Quic, None), 1).into(),
+            }],
+        }
+        .into();
+        assert_eq!(format.get_dynamic_arrays(), vec!["Foo".id()]);
+
+        let format = format.concretize(&vec![("Foo".id(), 4)]);
+        assert_eq!(format.size_of(), 4);
+    }
+
+    #[test]
+    fn test_bit_groups() {
+        let field = |name: &str, t: PrimitiveType| Field {
+            name: name.id(),
+            dtype: PrimitiveArray(t, 1).into(),
+        };
+        let format = Format {
+            name: "Frame".parse().unwrap(),
+            fields: vec![
+                field("kind", NumericType::U8.into()),
+                field("fin", PrimitiveType::Bits(1)),
+                field("opcode", PrimitiveType::Bits(7)),
+                field("mask", PrimitiveType::Bits(4)),
+                field("len", PrimitiveType::Bits(4)),
+                field("trailer", NumericType::U16.into()),
+            ],
+        };
+
+        assert_eq!(
+            "u3".parse::<PrimitiveType>().unwrap(),
+            PrimitiveType::Bits(3)
+        );
+        assert_eq!(PrimitiveType::Bits(3).bounds(), (0, 7));
+        assert_eq!(format.maybe_size_of(), Some(5));
+        assert_eq!(format.bit_groups().len(), 1);
+        assert_eq!(
+            format.try_get_field_type_bit_offset_and_size(&"mask".id()),
+            Some((PrimitiveArray(PrimitiveType::Bits(4), 1).into(), 16, 4))
+        );
+        assert_eq!(
+            format.try_get_field_type_offset_and_size(&"mask".id()),
+            Some((PrimitiveArray(PrimitiveType::Bits(4), 1).into(), 1, 2))
+        );
+        assert_eq!(
+            format.try_get_field_type_offset_and_size(&"trailer".id()),
+            Some((PrimitiveArray(NumericType::U16.into(), 1).into(), 3, 2))
+        );
+
+        // A range counts the bytes its bitfields share.
+        let expr = LengthExpr::SizeOfRange("fin".id(), "trailer".id());
+        assert_eq!(
+            expr.expand_ranges(&format),
+            Some(LengthExpr::binary(
+                LengthExpr::Literal(2),
+                ArithOp::Add,
+                LengthExpr::SizeOf("trailer".id())
+            ))
+        );
+        assert_eq!(
+            expr.evaluate(&format, &|f| f.maybe_size_of().map(|n| n as i128)),
+            Some(4)
+        );
+    }
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram {
+            bins: vec![(0, 0, 0), (10, 20, 3), (50, 50, 1)],
+        };
+        for _ in 0..64 {
+            let n = histogram.sample().unwrap();
+            assert!((10..=20).contains(&n) || n == 50, "{n}");
+        }
+
+        let histogram = Histogram {
+            bins: vec![(1, 2, 0)],
+        };
+        assert_eq!(histogram.sample(), None);
+    }
+
+    #[test]
+    fn test_padding_policy() {
+        let policy = |size| PaddingPolicy {
+            size,
+            fill: PaddingFill::Random,
+        };
+
+        let block = policy(PaddingSize::Block);
+        assert_eq!(block.pick_nbytes(17, 40, Some(16)), Some(15));
+        assert_eq!(block.pick_nbytes(17, 40, None), None);
+        assert_eq!(block.max_nbytes(None), None);
+
+        let uniform = policy(PaddingSize::Uniform(8));
+        for _ in 0..16 {
+            assert!(uniform.pick_nbytes(17, 40, None).unwrap() <= 8);
+        }
+
+        let buckets = policy(PaddingSize::Buckets(vec![512, 256, 1500]));
+        assert_eq!(buckets.pick_nbytes(0, 256, None), Some(0));
+        assert_eq!(buckets.pick_nbytes(0, 300, None), Some(212));
+        assert_eq!(buckets.pick_nbytes(0, 1501, None), Some(1499));
+        assert_eq!(buckets.max_nbytes(None), Some(1500));
+
+        let distribution = policy(PaddingSize::Distribution(vec![(100, 0), (200, 1)]));
+        assert_eq!(distribution.pick_nbytes(0, 50, None), Some(150));
+        assert_eq!(distribution.pick_nbytes(0, 300, None), Some(0));
+
+        assert_eq!(block.fill(3).len(), 3);
+        assert_eq!(PaddingPolicy::default().fill(2), [0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_constrained_values() {
+        let one_of = FieldSemantic::OneOf(vec![
+            (PrimitiveValue::Int(0x0301), 1),
+            (PrimitiveValue::Int(0x0303), 0),
+        ]);
+        let range = FieldSemantic::Range(-2, 3);
+
+        for _ in 0..16 {
+            assert_eq!(one_of.pick_value(), Some(PrimitiveValue::Int(0x0301)));
+            assert!(range.allows(range.pick_value().unwrap()));
+        }
+        assert!(one_of.allows(PrimitiveValue::Int(0x0303)));
+        assert!(!one_of.allows(PrimitiveValue::Int(0x0302)));
+        assert!(range.allows(PrimitiveValue::Int(-2)));
+        assert!(!range.allows(PrimitiveValue::Int(4)));
+        assert!(!range.allows(PrimitiveValue::Bool(true)));
+        assert_eq!(FieldSemantic::Range(1, 0).pick_value(), None);
+    }
+
+    #[test]
+    fn test_timestamp_values() {
+        let millis = FieldSemantic::Timestamp(TimestampUnit::U64Millis, Some(30));
+        let PrimitiveValue::Int(now) = millis.pick_value().unwrap() else {
+            panic!("timestamp is not an integer");
+        };
+        assert!(millis.allows(PrimitiveValue::Int(now - 29_000)));
+        assert!(millis.allows(PrimitiveValue::Int(now + 29_000)));
+        assert!(!millis.allows(PrimitiveValue::Int(now - 31_000)));
+        assert!(!millis.allows(PrimitiveValue::Int(now + 31_000)));
+
+        let unchecked = FieldSemantic::Timestamp(TimestampUnit::U32Seconds, None);
+        assert!(unchecked.allows(PrimitiveValue::Int(0)));
+        assert!(!unchecked.allows(PrimitiveValue::Bool(true)));
+    }
+
     #[test]
     #[should_panic]
     fn test_concretize_panic() {
@@ -2,6 +2,7 @@ use std::fmt;
 use std::io::Cursor;
 use std::net::SocketAddr;
 use std::ops::Range;
+use std::sync::Arc;
 
 use anyhow::bail;
 use async_trait::async_trait;
@@ -9,6 +10,7 @@ use bytes::{Buf, Bytes, BytesMut};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::Mutex;
 
 use crate::net;
 
@@ -59,6 +61,8 @@ pub trait Connector<R: Reader, W: Writer> {
 #[async_trait]
 pub trait Reader {
     async fn read_bytes(&mut self, len: Range<usize>) -> anyhow::Result<Bytes>;
+    /// Returns the next `len` bytes without consuming them.
+    async fn peek_bytes(&mut self, len: usize) -> anyhow::Result<Bytes>;
     async fn read_frame<F, D>(&mut self, deserializer: &mut D) -> anyhow::Result<F>
     where
         D: Deserializer<F> + Send;
@@ -107,6 +111,17 @@ impl<R: AsyncRead + Send + Unpin> Reader for BufReader<R> {
         Ok(data.into())
     }
 
+    async fn peek_bytes(&mut self, len: usize) -> anyhow::Result<Bytes> {
+        while self.buffer.len() < len {
+            match self.source.read_buf(&mut self.buffer).await {
+                Ok(0) => bail!(net::Error::Eof),
+                Ok(_) => {}
+                Err(e) => bail!(net::Error::Io(e)),
+            }
+        }
+        Ok(Bytes::copy_from_slice(&self.buffer[..len]))
+    }
+
     async fn read_frame<F, D>(&mut self, deserializer: &mut D) -> anyhow::Result<F>
     where
         D: Deserializer<F> + Send,
@@ -160,6 +175,59 @@ impl<W: AsyncWrite + Send + Unpin> Writer for W {
     }
 }
 
+/// A writer that can be cloned, so that more than one task can write to it.
+/// The underlying writer closes when the last clone is dropped.
+pub struct SharedWriter<W: Writer> {
+    inner: Arc<Mutex<W>>,
+}
+
+impl<W: Writer> SharedWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(writer)),
+        }
+    }
+}
+
+impl<W: Writer> Clone for SharedWriter<W> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl<W: Writer + Send> Writer for SharedWriter<W> {
+    async fn write_bytes(&mut self, bytes: &Bytes) -> anyhow::Result<usize> {
+        self.inner.lock().await.write_bytes(bytes).await
+    }
+
+    async fn write_frame<F, S>(&mut self, serializer: &mut S, frame: F) -> anyhow::Result<usize>
+    where
+        S: Serializer<F> + Send,
+        F: Send,
+    {
+        self.inner.lock().await.write_frame(serializer, frame).await
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.lock().await.flush().await
+    }
+}
+
+/// The most bytes we read at once while forwarding.
+const FORWARD_NBYTES: usize = 2usize.pow(16u32); // 64 KiB
+
+/// Copies bytes from `src` to `dst` until either end closes. The `dst` is
+/// dropped on return, which closes it and propagates the EOF.
+pub async fn forward<R: Reader, W: Writer>(mut src: R, mut dst: W) -> anyhow::Result<()> {
+    loop {
+        let bytes = src.read_bytes(1..FORWARD_NBYTES).await?;
+        dst.write_bytes(&bytes).await?;
+    }
+}
+
 pub struct Connection<R: Reader, W: Writer> {
     pub src: R,
     pub dst: W,
@@ -342,6 +410,21 @@ pub mod tests {
         assert_eq!(&payload[..], &bytes[..]);
     }
 
+    #[tokio::test]
+    async fn peek() {
+        let payload = mock::payload(100);
+        let mut src = BufReader::new(Cursor::new(payload.clone()));
+
+        let bytes = src.peek_bytes(10).await.unwrap();
+        assert_eq!(&payload[..10], &bytes[..]);
+
+        // Peeking consumes nothing.
+        let bytes = src.read_bytes(100..101).await.unwrap();
+        assert_eq!(&payload[..], &bytes[..]);
+
+        assert!(src.peek_bytes(1).await.is_err());
+    }
+
     #[tokio::test]
     async fn writer() {
         let max_len = mock::tests::payload_len_iter().max().unwrap();
@@ -85,6 +85,49 @@ pub async fn run_socks5_server<R: Reader, W: Writer, C: Connector<R, W>>(
     Ok(result)
 }
 
+/// Sends the tunnel destination `addr` to the remote end of an established
+/// tunnel. We reuse the socks5 connect request frame so that the remote end can
+/// parse it with `recv_tunnel_destination` and dial the destination for us.
+pub async fn send_tunnel_destination<W: Writer>(
+    dst: &mut W,
+    addr: SocketAddr,
+) -> anyhow::Result<()> {
+    let request = ConnectRequest {
+        version: SOCKS_VERSION_5,
+        command: SOCKS_COMMAND_CONNECT,
+        reserved: SOCKS_NULL,
+        dest_addr: Socks5Address::IpAddr(addr.ip()),
+        dest_port: addr.port(),
+    };
+
+    dst.write_frame::<ConnectRequest, Formatter>(&mut Formatter::new(), request)
+        .await?;
+    dst.flush().await
+}
+
+/// Receives a tunnel destination written by `send_tunnel_destination`.
+pub async fn recv_tunnel_destination<R: Reader>(src: &mut R) -> anyhow::Result<SocketAddr> {
+    let request = src
+        .read_frame::<ConnectRequest, Formatter>(&mut Formatter::new())
+        .await?;
+
+    if request.version != SOCKS_VERSION_5 {
+        bail!("{}", Error::Version);
+    } else if request.command != SOCKS_COMMAND_CONNECT {
+        bail!("{}", Error::ConnectMethod);
+    } else if request.reserved != SOCKS_NULL {
+        bail!("{}", Error::Reserved);
+    }
+
+    match request.dest_addr {
+        Socks5Address::IpAddr(a) => Ok(SocketAddr::new(a, request.dest_port)),
+        _ => bail!(
+            "{}",
+            Error::Connect(String::from("Address type not supported"))
+        ),
+    }
+}
+
 struct Init<R: Reader, W: Writer> {
     conn: Connection<R, W>,
     fmt: Formatter,
@@ -542,6 +585,21 @@ mod tests {
         assert!(s.is_ok())
     }
 
+    #[tokio::test]
+    async fn tunnel_destination() {
+        let request = connect_request();
+        let reader = Builder::new().read(&request.serialize()).build();
+        let writer = Builder::new().write(&request.serialize()).build();
+
+        let addr: SocketAddr = "127.0.0.1:54321".parse().expect("Valid socket addr");
+
+        let mut dst = writer;
+        assert!(send_tunnel_destination(&mut dst, addr).await.is_ok());
+
+        let mut src = BufReader::new(reader);
+        assert_eq!(recv_tunnel_destination(&mut src).await.unwrap(), addr);
+    }
+
     #[tokio::test]
     async fn unsupported_auth_method() {
         let reader = Builder::new()